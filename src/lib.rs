@@ -0,0 +1,866 @@
+//! Execute WebAssembly programs that rely on the VEX V5 SDK and jump table.
+//!
+//! The [`Simulator`] can be driven over stdin and stdout using the Vexide Simulator Protocol, or embedded in another
+//! Rust program which sends it commands and collects its events directly.
+
+use std::{
+    io::BufRead,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, mpsc},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use bytes::{Buf, Bytes};
+use fs_err as fs;
+
+use protocol::{
+    warn_bt, ChannelTransport, CommandSender, ExitReason, Log, LogFilter, Outbound, Protocol,
+    ProtocolError, ReplayTransport, SimEvent, StdioTransport, Transport,
+};
+use rgb::RGB8;
+use sdk::{
+    display::{BLACK, WHITE},
+    Exit, Reboot, SwitchProgram,
+};
+use vexide_simulator_protocol::{Command, Event, VCodeSig};
+use wasmparser::{Parser, Payload};
+use wasmtime::*;
+
+use crate::sdk::{JumpTable, MemoryExt, SdkState};
+
+mod printf;
+pub mod protocol;
+mod sdk;
+
+pub use sdk::{ControllerPreset, ProgramArg, SdlRequest, WasiDir, WasiOptions};
+
+const HEADER_MAGIC: &[u8] = b"XVX5";
+
+/// Set when the host asks the simulator to stop (e.g. with Ctrl-C).
+///
+/// The simulator checks this whenever the program yields to it so that it can shut down cleanly.
+pub static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The size of a code signature: the magic number, program type, owner and options, then four reserved words.
+const CODE_SIG_LEN: usize = 32;
+
+const PROGRAM_TYPE_USER: u32 = 0;
+// const PROGRAM_OWNER_SYS: u32 = 0;
+// const PROGRAM_OWNER_VEX: u32 = 1;
+const PROGRAM_OWNER_PARTNER: u32 = 2;
+
+const PROGRAM_OPTIONS_INVERT_DEFAULT_GRAPHICS_COLORS: u32 = 1 << 0;
+const PROGRAM_OPTIONS_KILL_THREADS_WHEN_MAIN_EXITS: u32 = 1 << 1;
+const PROGRAM_OPTIONS_INVERT_GRAPHICS_BASED_ON_THEME: u32 = 1 << 2;
+
+/// Options parsed from the program's code signature/cold header.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramOptions {
+    /// The program type. PROS sets this to 0.
+    pub program_type: u32,
+    /// The owner of the program. PROS sets this to 2.
+    pub owner: u32,
+    pub invert_default_graphics_colors: bool,
+    pub kill_threads_when_main_exits: bool,
+    pub invert_graphics_based_on_theme: bool,
+}
+
+impl ProgramOptions {
+    pub const fn default_fg_color(&self) -> RGB8 {
+        if self.invert_default_graphics_colors {
+            BLACK
+        } else {
+            WHITE
+        }
+    }
+
+    pub const fn default_bg_color(&self) -> RGB8 {
+        if self.invert_default_graphics_colors {
+            WHITE
+        } else {
+            BLACK
+        }
+    }
+}
+
+/// Describes anything in a code signature that real programs don't have, which suggests that the signature is corrupt
+/// and the options parsed from it can't be trusted.
+fn code_sig_problems(program_type: u32, owner: u32, options: u32) -> Vec<String> {
+    const KNOWN_OPTIONS: u32 = PROGRAM_OPTIONS_INVERT_DEFAULT_GRAPHICS_COLORS
+        | PROGRAM_OPTIONS_KILL_THREADS_WHEN_MAIN_EXITS
+        | PROGRAM_OPTIONS_INVERT_GRAPHICS_BASED_ON_THEME;
+
+    let mut problems = Vec::new();
+    if program_type != PROGRAM_TYPE_USER {
+        problems.push(format!("unknown program type {program_type}"));
+    }
+    if owner > PROGRAM_OWNER_PARTNER {
+        problems.push(format!("unknown owner {owner}"));
+    }
+    let unknown_options = options & !KNOWN_OPTIONS;
+    if unknown_options != 0 {
+        problems.push(format!("unknown option bits {unknown_options:#x}"));
+    }
+    problems
+}
+
+fn parse_code_sig(
+    program: &[u8],
+    protocol: &mut Protocol,
+    config: &SimulatorConfig,
+) -> anyhow::Result<ProgramOptions> {
+    // in vexide programs the cold header is stored in a section called ".cold_magic"
+    let mut cold_header = None;
+    let parser = Parser::new(0);
+    for payload in parser.parse_all(&program) {
+        if let Payload::CustomSection(custom_section) = payload? {
+            if custom_section.name() == ".cold_magic" {
+                cold_header = Some(Bytes::copy_from_slice(custom_section.data()));
+                break;
+            }
+        }
+    }
+    let mut cold_header = cold_header.context("No cold header found in the program")?;
+    if cold_header.len() < CODE_SIG_LEN {
+        bail!(
+            "The code signature is {} bytes long, but it should be {CODE_SIG_LEN}",
+            cold_header.len()
+        );
+    }
+
+    // copy_to_bytes is used to remove the magic number from the start of the buffer
+    let v_code_sig = VCodeSig::new(&cold_header);
+    let magic = cold_header.copy_to_bytes(HEADER_MAGIC.len());
+    if magic != HEADER_MAGIC {
+        return Err(anyhow::anyhow!("Invalid magic number"));
+    }
+
+    // Parse the rest of the options, these are all the ones found in the public SDK
+    let program_type = cold_header.get_u32_le();
+    let owner = cold_header.get_u32_le();
+    let options = cold_header.get_u32_le();
+
+    // Check the signature before anything trusts it, so a corrupt one doesn't boot the program with nonsense options.
+    for problem in code_sig_problems(program_type, owner, options) {
+        if config.strict_code_sig {
+            bail!("The code signature looks malformed: {problem}");
+        }
+        protocol.warn(format!(
+            "The program's code signature looks malformed: {problem}"
+        ))?;
+    }
+
+    if config.verbose {
+        protocol.info(format!("Code signature: {v_code_sig:?}"))?;
+    }
+    protocol.send(&Event::VCodeSig(v_code_sig))?;
+
+    let cold_header = ProgramOptions {
+        program_type,
+        owner,
+        invert_default_graphics_colors: options & PROGRAM_OPTIONS_INVERT_DEFAULT_GRAPHICS_COLORS
+            != 0,
+        kill_threads_when_main_exits: options & PROGRAM_OPTIONS_KILL_THREADS_WHEN_MAIN_EXITS != 0,
+        invert_graphics_based_on_theme: options & PROGRAM_OPTIONS_INVERT_GRAPHICS_BASED_ON_THEME
+            != 0,
+    };
+    Ok(cold_header)
+}
+
+/// Parses the cold header of a user program and creates a module from it.
+fn load_program(
+    engine: &Engine,
+    program: &[u8],
+    protocol: &mut Protocol,
+    config: &SimulatorConfig,
+) -> Result<(Module, ProgramOptions)> {
+    let cold_header = parse_code_sig(program, protocol, config);
+
+    let cold_header = if config.relaxed_code_sig {
+        cold_header.unwrap_or_else(|err| {
+            protocol
+                .warn(format!("Failed to parse the program's code signature: {err} (falling back to default)."))
+                .unwrap();
+            ProgramOptions {
+                program_type: 0,
+                owner: 2,
+                invert_default_graphics_colors: false,
+                kill_threads_when_main_exits: false,
+                invert_graphics_based_on_theme: false,
+            }
+        })
+    } else {
+        cold_header.context("Failed to parse the program's code signature (this error is recoverable with --relaxed-code-sig)")?
+    };
+
+    // this operation will do a lot of JIT compilation so it's probably the slowest part of the program
+    let module = Module::from_binary(engine, program)?;
+    Ok((module, cold_header))
+}
+
+fn create_engine(config: &SimulatorConfig) -> Result<Engine> {
+    let backtrace_details = if config.fast_backtrace {
+        WasmBacktraceDetails::Disable
+    } else {
+        WasmBacktraceDetails::Enable
+    };
+    Engine::new(
+        Config::new()
+            .debug_info(true)
+            .wasm_backtrace_details(backtrace_details)
+            .epoch_interruption(config.yield_interval.is_some()),
+    )
+}
+
+/// Logs how the simulator interpreted the program's cold header and module, for `--verbose`.
+fn report_program(
+    module: &Module,
+    options: &ProgramOptions,
+    protocol: &mut Protocol,
+) -> Result<()> {
+    protocol.info(format!(
+        "Program type: {}, owner: {}",
+        options.program_type, options.owner
+    ))?;
+    protocol.info(format!(
+        "Program options: invert default graphics colors: {}, kill threads when main exits: {}, invert graphics based on theme: {}",
+        options.invert_default_graphics_colors,
+        options.kill_threads_when_main_exits,
+        options.invert_graphics_based_on_theme,
+    ))?;
+
+    let table = module.imports().find_map(|import| match import.ty() {
+        ExternType::Table(table_ty) => Some(table_ty),
+        _ => None,
+    });
+    match table {
+        Some(table) => {
+            let maximum = table
+                .maximum()
+                .map_or_else(|| "unlimited".to_string(), |maximum| maximum.to_string());
+            protocol.info(format!(
+                "Imported function table: {} entries, maximum {maximum}",
+                table.minimum()
+            ))?;
+        }
+        None => protocol.info("Imported function table: none")?,
+    }
+
+    protocol.info(format!("Exports: {}", module.exports().len()))?;
+    let exported_memory = module.exports().find_map(|export| match export.ty() {
+        ExternType::Memory(memory_ty) => Some(memory_ty),
+        _ => None,
+    });
+    match (imported_memory_ty(module)?, exported_memory) {
+        (Some(memory), _) => protocol.info(format!(
+            "Initial memory: {} pages, imported",
+            memory.minimum()
+        ))?,
+        (None, Some(memory)) => {
+            protocol.info(format!("Initial memory: {} pages", memory.minimum()))?
+        }
+        (None, None) => protocol.info("Initial memory: none exported")?,
+    }
+    Ok(())
+}
+
+/// Compiles and instantiates a program, exposing the jump table to it and returning its entrypoint.
+fn boot(
+    engine: &Engine,
+    program: &[u8],
+    mut protocol: Protocol,
+    config: &SimulatorConfig,
+) -> Result<(Store<SdkState>, TypedFunc<(), ()>)> {
+    let (module, cold_header) = load_program(engine, program, &mut protocol, config)
+        .context("Failed to load robot program")?;
+    if config.verbose {
+        report_program(&module, &cold_header, &mut protocol)?;
+    }
+
+    protocol.info("Booting...")?;
+
+    let state = SdkState::new(
+        module.clone(),
+        cold_header,
+        protocol,
+        config.sdl_request_channel.clone(),
+        &config.wasi,
+        config.local_text_metrics,
+    )?;
+
+    let mut store = Store::new(engine, state);
+    if config.stdin_passthrough {
+        store.data_mut().pass_through_host_stdin();
+    }
+    if config.tee_serial {
+        store.data_mut().tee_serial_to_host_stderr();
+    }
+    if let Some(limit) = config.max_memory {
+        store.data_mut().limit_memory(limit);
+    }
+    if let Some(limit) = config.max_table_elements {
+        store.data_mut().limit_tables(limit);
+    }
+    if let Some(limit) = config.commands_per_tick {
+        store.data_mut().limit_commands_per_tick(limit);
+    }
+    if config.reset_clock_on_start {
+        store.data_mut().reset_clock_on_start();
+    }
+    if !config.args.is_empty() {
+        store.data_mut().set_program_args(&config.args);
+    }
+    if config.restart_on_reboot {
+        store.data_mut().restart_on_reboot();
+    }
+    if let Some(timeout) = config.text_metrics_timeout {
+        store.data_mut().limit_text_metrics_wait(timeout);
+    }
+    if config.wrap_text {
+        store.data_mut().wrap_display_text();
+    }
+    if config.debug_memory {
+        store.data_mut().enable_debug_memory();
+    }
+    if let Some(root) = &config.usd {
+        store.data_mut().mount_usd(root)?;
+    }
+    if let Some(per_byte) = config.usd_write_delay {
+        store.data_mut().delay_usd_writes(per_byte);
+    }
+    if let Some(preset) = config.controller_preset {
+        store.data_mut().use_controller_preset(preset)?;
+    }
+    store.limiter(|state| state.limiter());
+
+    if config.yield_interval.is_some() {
+        // Every time the epoch ticks, the program is interrupted and the simulator gets a chance to run its tasks.
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(|mut store| {
+            store.data_mut().run_tasks()?;
+            sdk::apply_checkpoint_requests(&mut store)?;
+            sdk::apply_memory_requests(&mut store)?;
+            Ok(UpdateDeadline::Continue(1))
+        });
+    }
+
+    // Here we get the metadata of the imported indirect function table.
+    // User programs will request a varying starting number of entries.
+    // If the starting number of entries actually given to the program is too low, it will not start successfully.
+    let imported_table_ty = module
+        .imports()
+        .filter_map(|i| match i.ty() {
+            ExternType::Table(table_ty) => Some(table_ty),
+            _ => None,
+        })
+        .next()
+        .context("The program doesn't import an indirect function table")?;
+
+    let mut linker = Linker::new(engine);
+    let table = Table::new(&mut store, imported_table_ty, Ref::Func(None))?;
+    linker.define(&store, "env", "__indirect_function_table", table)?;
+
+    // Programs linked with `--import-memory` expect the simulator to provide their memory.
+    let imported_memory = match imported_memory_ty(&module)? {
+        Some(memory_ty) => {
+            let memory = Memory::new(&mut store, memory_ty)
+                .context("Failed to create the memory imported by the program")?;
+            linker.define(&store, "env", "memory", memory)?;
+            Some(memory)
+        }
+        None => None,
+    };
+    linker.func_wrap(
+        "env",
+        "sim_log_backtrace",
+        |mut caller: Caller<'_, SdkState>| {
+            let backtrace = WasmBacktrace::capture(&caller);
+            caller.data_mut().error(format!("{}", backtrace))?;
+            Ok(())
+        },
+    )?;
+
+    // Programs can check their own invariants with this. A failed assertion logs the NUL-terminated message and a
+    // backtrace, then traps so that the program stops.
+    linker.func_wrap(
+        "env",
+        "sim_assert",
+        |mut caller: Caller<'_, SdkState>, cond: i32, message: u32| -> Result<()> {
+            if cond != 0 {
+                return Ok(());
+            }
+            let memory = caller
+                .data()
+                .memory()
+                .context("The program's memory isn't set up yet")?;
+            let message = memory.read_c_string(&caller, message as usize)?;
+            let message = format!("Assertion failed: {}", message.to_string_lossy());
+            let backtrace = WasmBacktrace::capture(&caller);
+            caller.data_mut().error(message.clone())?;
+            caller.data_mut().error(format!("{}", backtrace))?;
+            bail!(message)
+        },
+    )?;
+
+    // The SDK has no way to invert part of the display, so programs that want to can import this instead.
+    linker.func_wrap(
+        "env",
+        "sim_display_invert_rect",
+        |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
+            caller.data_mut().display_ctx().invert_rect(x1, y1, x2, y2)
+        },
+    )?;
+
+    // Draws are opaque on a real brain, but overlays in the simulator can be made translucent with this.
+    linker.func_wrap(
+        "env",
+        "sim_display_set_opacity",
+        |mut caller: Caller<'_, SdkState>, opacity: i32| {
+            let opacity = opacity.clamp(0, u8::MAX.into()) as u8;
+            caller.data_mut().display_ctx().set_opacity(opacity)
+        },
+    )?;
+
+    // The SDK can't dim the screen either. Levels are percentages, clamped to the range 0 to 100.
+    linker.func_wrap(
+        "env",
+        "sim_display_set_brightness",
+        |mut caller: Caller<'_, SdkState>, level: i32| {
+            caller.data_mut().display_ctx().set_brightness(level)
+        },
+    )?;
+
+    // The SDK doesn't expose the brain's buttons, so the simulator provides them through this import.
+    linker.func_wrap(
+        "env",
+        "sim_brain_buttons",
+        |caller: Caller<'_, SdkState>| caller.data().brain_buttons(),
+    )?;
+
+    // Neither is the brain's status LED. Colors use the same 0xRRGGBB format as the SDK's display colors.
+    linker.func_wrap(
+        "env",
+        "sim_brain_led_set",
+        |mut caller: Caller<'_, SdkState>, color: u32| {
+            caller.data_mut().set_brain_led(RGB8 {
+                r: (color >> 16) as u8,
+                g: (color >> 8) as u8,
+                b: color as u8,
+            })
+        },
+    )?;
+
+    // Programs can time a section of code by calling this with the same NUL-terminated label before and after it.
+    linker.func_wrap(
+        "env",
+        "sim_mark_time",
+        |mut caller: Caller<'_, SdkState>, label: u32| -> Result<()> {
+            let memory = caller
+                .data()
+                .memory()
+                .context("The program's memory isn't set up yet")?;
+            let label = memory.read_c_string(&caller, label as usize)?;
+            caller
+                .data_mut()
+                .mark_time(label.to_string_lossy().into_owned())
+        },
+    )?;
+
+    // Test harnesses can pass parameters to the program with `--arg KEY=VALUE`. The value for the NUL-terminated key
+    // is copied into the buffer like `snprintf`, and its full length is returned, or -1 if it wasn't passed.
+    linker.func_wrap(
+        "env",
+        "sim_get_arg",
+        |mut caller: Caller<'_, SdkState>, key: u32, buf: u32, len: u32| -> Result<i32> {
+            let memory = caller
+                .data()
+                .memory()
+                .context("The program's memory isn't set up yet")?;
+            let key = memory.read_c_string(&caller, key as usize)?;
+            let Ok(key) = key.to_str() else {
+                // Keys come from the command line, so one that isn't UTF-8 can't have been passed.
+                warn_bt!(caller, "sim_get_arg: the key {key:?} isn't valid UTF-8")?;
+                return Ok(-1);
+            };
+            let Some(value) = caller.data().program_arg(key) else {
+                return Ok(-1);
+            };
+            let value = value.as_bytes().to_vec();
+            if len > 0 {
+                let copied = value.len().min(len as usize - 1);
+                memory.write_bytes(&mut caller, buf, &[&value[..copied], &[0]].concat())?;
+            }
+            Ok(value.len() as i32)
+        },
+    )?;
+
+    wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |sdk| sdk.wasi())?;
+
+    // Load and compile our module
+
+    let instance = linker.instantiate(&mut store, &module)?;
+
+    // Allocate space for the jump table. 0x700 total pages covers the entire range of the jump table.
+    let memory = match imported_memory {
+        Some(memory) => memory,
+        None => program_memory(&instance, &mut store)?,
+    };
+    let target_pages = 0x700;
+    let memory_size = memory.size(&store);
+    if memory_size < target_pages {
+        memory
+            .grow(&mut store, target_pages - memory_size)
+            .context("Failed to grow the program's memory to fit the jump table")?;
+    }
+
+    // Add the jump table to memory and create the WASM FFI interface.
+    let jump_table = JumpTable::new(&mut store, memory);
+    jump_table.expose(&mut store, &table, &memory)?;
+    store.data_mut().set_memory(memory);
+
+    let run = program_entrypoint(&instance, &mut store, config.entry.as_deref())?;
+    Ok((store, run))
+}
+
+/// The exports that are tried as the program's entrypoint, after the one passed with `--entry`.
+const DEFAULT_ENTRYPOINTS: &[&str] = &["_entry", "_start"];
+
+/// Finds the function that starts the program, trying `entry` first and then the usual entrypoint names.
+fn program_entrypoint(
+    instance: &Instance,
+    mut store: impl AsContextMut,
+    entry: Option<&str>,
+) -> Result<TypedFunc<(), ()>> {
+    let mut tried = Vec::new();
+    for name in entry.into_iter().chain(DEFAULT_ENTRYPOINTS.iter().copied()) {
+        if tried.contains(&name) {
+            continue;
+        }
+        tried.push(name);
+        if let Some(func) = instance.get_func(&mut store, name) {
+            return func.typed(&store).with_context(|| {
+                format!(
+                    "The program's entrypoint `{name}` must take no arguments and return nothing"
+                )
+            });
+        }
+    }
+    let tried = tried
+        .iter()
+        .map(|name| format!("`{name}`"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    bail!("The program doesn't export an entrypoint (tried {tried})")
+}
+
+/// The type of the memory the program imports as `env.memory`, if it imports its memory instead of exporting it.
+fn imported_memory_ty(module: &Module) -> Result<Option<MemoryType>> {
+    let Some(import) = module
+        .imports()
+        .find(|import| import.module() == "env" && import.name() == "memory")
+    else {
+        return Ok(None);
+    };
+    match import.ty() {
+        ExternType::Memory(memory_ty) if memory_ty.is_shared() => {
+            bail!("The program's memory is shared, which isn't supported (was it built with threads enabled?)")
+        }
+        ExternType::Memory(memory_ty) => Ok(Some(memory_ty)),
+        _ => bail!("The program's `env.memory` import isn't a memory"),
+    }
+}
+
+/// Finds the memory that the program's jump table lives in, which it must export as `memory` unless it imports it.
+fn program_memory(instance: &Instance, mut store: impl AsContextMut) -> Result<Memory> {
+    match instance.get_export(&mut store, "memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        Some(Extern::SharedMemory(_)) => {
+            bail!("The program's memory is shared, which isn't supported (was it built with threads enabled?)")
+        }
+        Some(_) => bail!("The program's `memory` export isn't a memory"),
+        None => bail!(
+            "The program doesn't export its memory as `memory` (link it with `-Clink-arg=--export-memory`) or import \
+             it as `env.memory`"
+        ),
+    }
+}
+
+/// Options for running a program in the [`Simulator`].
+#[derive(Debug, Clone, Default)]
+pub struct SimulatorConfig {
+    /// Skip the protocol handshake and immediately start execution.
+    pub imply_start: bool,
+    /// How long to wait for the frontend to start the handshake before giving up. Waits forever if `None`.
+    pub handshake_timeout: Option<Duration>,
+    /// Fall back to the default code signature if the program's code signature is missing or invalid.
+    pub relaxed_code_sig: bool,
+    /// Treat a code signature with an unknown program type, owner or options as invalid, instead of warning about it.
+    pub strict_code_sig: bool,
+    /// The minimum severity of log messages to send to the frontend.
+    pub log_level: LogFilter,
+    /// Don't resolve file and line information in backtraces.
+    pub fast_backtrace: bool,
+    /// Periodically interrupt the program to run simulator tasks, at this interval in milliseconds.
+    pub yield_interval: Option<u64>,
+    /// Estimate text metrics locally instead of asking the frontend, so measuring text never blocks.
+    pub local_text_metrics: bool,
+    /// How long to wait for the frontend to send text metrics before estimating them, as with `local_text_metrics`.
+    /// Waits forever if `None`.
+    pub text_metrics_timeout: Option<Duration>,
+    /// Wrap strings written on a line of the display onto the following lines when they're wider than the clip
+    /// region, instead of letting them run off the edge.
+    pub wrap_text: bool,
+    /// Start simulated time from zero when the frontend starts execution. Otherwise, it counts from when the program
+    /// was booted, including the time spent waiting for the frontend.
+    pub reset_clock_on_start: bool,
+    /// Start the program over with fresh memory when it reboots the brain with `vexSystemBoot`, instead of exiting
+    /// with [`ExitReason::Rebooted`].
+    pub restart_on_reboot: bool,
+    /// How long to spend booting after the program is started and before its entrypoint is called, so the frontend
+    /// can show a boot animation.
+    pub boot_delay: Option<Duration>,
+    /// The maximum number of renders and serial flushes sent to the frontend per second. Renders in between are
+    /// skipped in favor of the latest one, and serial output is combined into larger messages.
+    pub max_event_rate: Option<f64>,
+    /// The most commands to process each time the program yields to the simulator, such as with `vexTasksRun`.
+    /// Commands past the limit are processed the next time. Commands sent before the program starts aren't limited.
+    pub commands_per_tick: Option<usize>,
+    /// Log a summary of how the program's cold header and module were interpreted before running it.
+    pub verbose: bool,
+    /// Forward the host's stdin to the program's serial input. Only use this with a transport that doesn't read from
+    /// stdin, like [`TcpTransport`](protocol::TcpTransport).
+    pub stdin_passthrough: bool,
+    /// Write the program's serial output to the host's stderr as well as sending it to the frontend.
+    pub tee_serial: bool,
+    /// The name of the exported function to start the program with. `_entry` and then `_start` are tried if it's
+    /// `None` or isn't exported.
+    pub entry: Option<String>,
+    /// The state the primary controller starts with. It starts disconnected if this is `None`.
+    pub controller_preset: Option<ControllerPreset>,
+    /// The most memory the program can use, in bytes. The program's memory is grown to 112 MiB to make room for the
+    /// jump table, so smaller limits stop it from booting. If this is `None`, the program's own maximum applies.
+    pub max_memory: Option<usize>,
+    /// The most elements the program's tables can have. The jump table's functions are added to the end of the
+    /// program's indirect function table, so this has to leave room for them. If this is `None`, the tables' own
+    /// maximums apply.
+    pub max_table_elements: Option<u32>,
+    /// Let the frontend read and write the program's memory with
+    /// [`SimCommand::WriteMemory`](protocol::SimCommand::WriteMemory) and
+    /// [`SimCommand::ReadMemory`](protocol::SimCommand::ReadMemory), for fuzzing and debugging.
+    pub debug_memory: bool,
+    /// A host directory to use as the contents of the brain's SD card, which programs can list, read and write with
+    /// the `vexFile*` functions. Programs see no SD card inserted if this is `None`.
+    pub usd: Option<PathBuf>,
+    /// How long each byte written to the SD card takes, in simulated time, so that programs see writes as slow as
+    /// they are on a real card. Writes return immediately if this is `None`.
+    pub usd_write_delay: Option<Duration>,
+    /// Append every command received and event sent to this file as JSONL, with timestamps, so the session can be
+    /// replayed with [`Simulator::replay`] or a [`ReplayTransport`].
+    pub record: Option<PathBuf>,
+    /// Key-value pairs the program can read with the `sim_get_arg` import, such as parameters for a test.
+    pub args: Vec<ProgramArg>,
+    /// Options for the WASI environment provided to the program.
+    pub wasi: WasiOptions,
+    /// The channel used to ask the thread that owns SDL for controller states. Controllers identified by their SDL
+    /// GUID will keep a constant state if this isn't set.
+    pub sdl_request_channel: Option<mpsc::Sender<SdlRequest>>,
+}
+
+/// Runs a robot program, communicating with a frontend using the Vexide Simulator Protocol.
+pub struct Simulator {
+    config: SimulatorConfig,
+    protocol: Option<Protocol>,
+    commands: Option<CommandSender>,
+    events: Option<mpsc::Receiver<Outbound>>,
+}
+
+impl Simulator {
+    /// Creates a simulator that is driven from the same process.
+    ///
+    /// Commands are sent using [`Simulator::command_sender`] and events are collected with [`Simulator::events`].
+    pub fn new(config: SimulatorConfig) -> Self {
+        let (transport, commands, events) = ChannelTransport::new();
+        Self {
+            config,
+            protocol: Some(Protocol::new(transport)),
+            commands: Some(commands),
+            events: Some(events),
+        }
+    }
+
+    /// Creates a simulator that replays the commands from a session recorded with [`SimulatorConfig::record`].
+    ///
+    /// Events are collected with [`Simulator::events`] like with [`Simulator::new`], but commands can't be sent.
+    pub fn replay(config: SimulatorConfig, recording: impl BufRead) -> Result<Self> {
+        let (transport, _, events) = ChannelTransport::new();
+        let transport = ReplayTransport::new(recording, transport)?;
+        Ok(Self {
+            config,
+            protocol: Some(Protocol::new(transport)),
+            commands: None,
+            events: Some(events),
+        })
+    }
+
+    /// Creates a simulator that communicates with a frontend over stdin and stdout using JSONL.
+    pub fn stdio(config: SimulatorConfig) -> Self {
+        Self::with_transport(config, StdioTransport::default())
+    }
+
+    /// Creates a simulator that communicates with a frontend over a custom transport.
+    pub fn with_transport(config: SimulatorConfig, transport: impl Transport + 'static) -> Self {
+        Self {
+            config,
+            protocol: Some(Protocol::new(transport)),
+            commands: None,
+            events: None,
+        }
+    }
+
+    /// Returns a handle for sending commands to the simulator, which can be used from another thread while the
+    /// program is running.
+    ///
+    /// Returns `None` if the simulator wasn't created with [`Simulator::new`] or has already been run.
+    pub fn command_sender(&self) -> Option<CommandSender> {
+        self.commands.clone()
+    }
+
+    /// Returns the events that the simulator has sent so far without blocking.
+    ///
+    /// Always empty if the simulator wasn't created with [`Simulator::new`].
+    pub fn events(&self) -> impl Iterator<Item = Outbound> + '_ {
+        self.events.iter().flat_map(|events| events.try_iter())
+    }
+
+    /// Loads a WebAssembly robot program and prepares it to run without calling its entrypoint.
+    ///
+    /// This checks the program's code signature, imports and memory without needing a frontend, so it never performs
+    /// the handshake. A simulator can only check or run one program.
+    pub fn check(&mut self, program: &[u8]) -> Result<()> {
+        let mut protocol = self
+            .protocol
+            .take()
+            .context("The simulator has already run a program")?;
+        self.commands = None;
+        protocol.set_log_filter(self.config.log_level);
+
+        let engine = create_engine(&self.config)?;
+        boot(&engine, program, protocol, &self.config)?;
+        Ok(())
+    }
+
+    /// Runs a WebAssembly robot program until it finishes, returning the reason it stopped.
+    ///
+    /// The frontend can replace the program with another one using [`SimCommand::LoadProgram`](protocol::SimCommand),
+    /// but otherwise a simulator can only run one program.
+    pub fn run(&mut self, program: &[u8]) -> Result<ExitReason> {
+        let mut protocol = self
+            .protocol
+            .take()
+            .context("The simulator has already run a program")?;
+        // Once the caller drops its command senders, the simulator should notice instead of waiting forever.
+        self.commands = None;
+        let config = &self.config;
+
+        protocol.set_log_filter(config.log_level);
+        protocol.set_handshake_timeout(config.handshake_timeout);
+        protocol.set_max_event_rate(config.max_event_rate);
+        if let Some(path) = &config.record {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            protocol.record_to(file);
+        }
+        match protocol.handshake(config.imply_start) {
+            Ok(()) => {}
+            Err(err @ ProtocolError::HandshakeTimedOut { .. }) => {
+                protocol.error(err.to_string())?;
+                let reason = ExitReason::HandshakeTimedOut;
+                protocol.send_sim(&SimEvent::Exited { reason })?;
+                protocol.flush()?;
+                return Ok(reason);
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let engine = create_engine(config)?;
+        if let Some(interval) = config.yield_interval {
+            let engine = engine.clone();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_millis(interval));
+                engine.increment_epoch();
+            });
+        }
+
+        // The engine is reused when the frontend switches programs or the program reboots, but everything else starts
+        // over.
+        let mut program = program.to_vec();
+        let mut rebooted = false;
+        loop {
+            protocol.info("Compiling...")?;
+            let (mut store, run) = boot(&engine, &program, protocol, config)?;
+            let err = match run_program(&mut store, run, config, rebooted) {
+                Ok(reason) => return Ok(reason),
+                Err(err) => err,
+            };
+            let switch_to = match err.downcast::<SwitchProgram>() {
+                Ok(SwitchProgram(path)) => Some(path),
+                Err(err) if err.is::<Reboot>() => None,
+                Err(err) => return Err(err),
+            };
+            protocol = store.into_data().into_protocol();
+            rebooted = switch_to.is_none();
+            if let Some(path) = switch_to {
+                program = fs::read(&path)?;
+            }
+        }
+    }
+}
+
+/// Runs a booted program until it finishes, returning the reason it stopped.
+///
+/// Fails with [`SwitchProgram`] if the frontend asked for a different program to be loaded, or [`Reboot`] if the
+/// program rebooted and should start over. Programs starting over after a reboot don't wait for the frontend to start
+/// execution again.
+fn run_program(
+    store: &mut Store<SdkState>,
+    run: TypedFunc<(), ()>,
+    config: &SimulatorConfig,
+    rebooted: bool,
+) -> Result<ExitReason> {
+    if config.imply_start || rebooted {
+        store.data_mut().execute_command(Command::StartExecution)?;
+    }
+    let result = store
+        .data_mut()
+        .setup()
+        .context("Failed to setup the program for execution")
+        .and_then(|()| match config.boot_delay {
+            Some(delay) => store.data_mut().boot(delay),
+            None => Ok(()),
+        })
+        .and_then(|()| {
+            // We should be ready to actually run the entrypoint now.
+            store.data_mut().trace("Calling _entry()")?;
+            run.call(&mut *store, ())
+                .context("Call to _entry() failed")?;
+            if store.data().program_options().kill_threads_when_main_exits {
+                Ok(())
+            } else {
+                store.data_mut().keep_running()
+            }
+        });
+    match result {
+        Ok(()) => {
+            _ = store.data_mut().exit(ExitReason::Completed);
+            Ok(ExitReason::Completed)
+        }
+        Err(err) => match err.downcast_ref::<Exit>() {
+            Some(Exit(reason)) => Ok(*reason),
+            None => Err(err),
+        },
+    }
+}
@@ -1,32 +1,29 @@
 use std::{
-    path::{Path, PathBuf},
-    sync::mpsc,
+    io::BufReader,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{atomic::Ordering, mpsc},
     thread,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
-use bytes::{Buf, Bytes};
 use clap::Parser as _;
 use fs_err as fs;
 
-use protocol::{Log, Protocol};
-use rgb::RGB8;
-use sdk::{
-    display::{BLACK, WHITE},
-    SdlRequest,
-};
 use sdl2::controller::{Axis, Button};
-use vexide_simulator_protocol::{Command, ControllerState, Event, VCodeSig};
-use wasmparser::{Parser, Payload};
-use wasmtime::*;
-
-use crate::sdk::{JumpTable, SdkState};
-
-mod printf;
-mod protocol;
-mod sdk;
+use v5wasm::{
+    protocol::{
+        self, ExitReason, LogFilter, Outbound, ProtocolFormat, ReplayTransport, SimEvent,
+        StdioTransport, TcpTransport, Transport,
+    },
+    ControllerPreset, ProgramArg, SdlRequest, Simulator, SimulatorConfig, WasiDir, WasiOptions,
+    SHUTDOWN_REQUESTED,
+};
+use vexide_simulator_protocol::{ControllerState, Event};
 
-const HEADER_MAGIC: &[u8] = b"XVX5";
+/// How long to wait for the program to yield after a shutdown is requested before exiting anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
 
 /// Execute WebAssembly programs that rely on the VEX V5 SDK and jump table.
 ///
@@ -40,208 +37,213 @@ const HEADER_MAGIC: &[u8] = b"XVX5";
 struct Args {
     /// The path to the WebAssembly robot program that will be executed.
     program: PathBuf,
+    /// Load the program and expose the jump table to it, then exit without running it.
+    ///
+    /// Exits with a non-zero status if the program couldn't be loaded. No frontend is needed, and diagnostics are
+    /// printed to stderr.
+    #[clap(long)]
+    check: bool,
     /// Skip the protocol handshake and immediately start execution.
     #[clap(long, short = 'I')]
     imply_start: bool,
+    /// How long to wait for the frontend to start the handshake before exiting, in milliseconds. 0 waits forever.
+    #[clap(long, value_name = "MS", default_value_t = 5000)]
+    handshake_timeout: u64,
     /// Fall back to the default code signature if the program's code signature is missing or invalid.
     #[clap(long, short = 'S')]
     relaxed_code_sig: bool,
+    /// Treat a code signature with an unknown program type, owner or options as invalid instead of warning about it.
+    #[clap(long)]
+    strict: bool,
+    /// The minimum severity of log messages to send to the frontend.
+    #[clap(long, value_enum, default_value_t = LogFilter::Trace)]
+    log_level: LogFilter,
+    /// Don't resolve file and line information in backtraces, making warnings much cheaper to emit.
+    ///
+    /// Backtraces will only contain function names and code offsets, which can be symbolicated separately.
+    #[clap(long)]
+    fast_backtrace: bool,
+    /// Periodically interrupt the program to process commands and flush output, even if it never calls
+    /// `vexTasksRun`. The value is the interval between interruptions in milliseconds.
+    #[clap(long, value_name = "MS")]
+    yield_interval: Option<u64>,
+    /// Run the protocol over a TCP connection instead of stdio. The simulator waits for a frontend to connect to this
+    /// address before starting.
+    #[clap(long, value_name = "ADDR")]
+    listen: Option<SocketAddr>,
+    /// Forward everything typed into this terminal to the program's serial input. The protocol uses stdin when it
+    /// runs over stdio, so this requires `--listen`.
+    #[clap(long, requires = "listen")]
+    stdin_passthrough: bool,
+    /// Also write the program's serial output to this terminal's stderr, which the protocol never uses.
+    #[clap(long)]
+    tee_serial: bool,
+    /// How protocol messages are encoded. Frontends must advertise the `msgpack` extension during the handshake to use
+    /// MessagePack.
+    #[clap(long, value_enum, default_value_t = ProtocolFormat::Json)]
+    protocol_format: ProtocolFormat,
+    /// Estimate text sizes with a built-in monospace approximation instead of asking the frontend.
+    ///
+    /// This makes `vexDisplayStringWidthGet` and `vexDisplayStringHeightGet` return immediately, but widths are only
+    /// approximate for proportional fonts.
+    #[clap(long)]
+    local_metrics: bool,
+    /// How long to wait for the frontend to send text metrics before estimating them and warning, in milliseconds. 0
+    /// waits forever.
+    #[clap(long, value_name = "MS", default_value_t = 5000)]
+    text_metrics_timeout: u64,
+    /// Wrap strings written on a line of the display with `vexDisplayString` onto the following lines when they're
+    /// too wide, breaking them between words.
+    #[clap(long)]
+    wrap_text: bool,
+    /// Start the program's clock from zero when the frontend starts execution, instead of when the program was
+    /// loaded.
+    #[clap(long)]
+    reset_clock_on_start: bool,
+    /// Start the program over when it reboots the brain, like a warm reboot, instead of exiting.
+    #[clap(long)]
+    restart_on_reboot: bool,
+    /// Wait this long after the frontend starts execution before running the program, in milliseconds, so the frontend
+    /// can show a boot animation. Commands sent while booting are still applied.
+    #[clap(long, value_name = "MS")]
+    boot_delay: Option<u64>,
+    /// Send at most this many display renders and serial messages to the frontend per second.
+    ///
+    /// Renders that happen too soon after the last one are skipped in favor of the latest frame. Serial output is
+    /// never dropped, only combined into fewer, larger messages.
+    #[clap(long, value_name = "HZ")]
+    max_event_rate: Option<f64>,
+    /// Process at most this many commands each time the program yields to the simulator, leaving the rest for the
+    /// next time, so that a flood of commands can't stall the program.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    commands_per_tick: Option<u64>,
+    /// Log a summary of how the simulator interpreted the program's cold header and module before running it.
+    #[clap(long, short = 'v')]
+    verbose: bool,
+    /// The exported function to start the program with. Falls back to `_entry` and then `_start` if the program
+    /// doesn't export it.
+    #[clap(long, value_name = "NAME")]
+    entry: Option<String>,
+    /// Connect the primary controller at startup with a fixed state, for testing without a frontend that sends
+    /// controller updates.
+    #[clap(long, value_enum, value_name = "PRESET")]
+    controller_preset: Option<ControllerPreset>,
+    /// The most memory the program can use, in MiB. The program's memory is grown to 112 MiB to make room for the jump
+    /// table, so this must be at least 112. Growing past the limit fails with an out of memory warning.
+    #[clap(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(112..4096))]
+    max_memory: Option<u64>,
+    /// The most elements the program's tables can have. The jump table's functions are added to the program's indirect
+    /// function table, so this needs to leave room for them. Growing past the limit fails with a warning.
+    #[clap(long, value_name = "ELEMENTS")]
+    max_table: Option<u32>,
+    /// Let the frontend read and write the program's memory, for fuzzing and debugging. The frontend can corrupt
+    /// the program's state arbitrarily, so only use this with frontends you trust.
+    #[clap(long)]
+    debug_memory: bool,
+    /// Use this directory as the contents of the brain's SD card. Programs can't reach files outside of it.
+    #[clap(long, value_name = "DIR")]
+    usd: Option<PathBuf>,
+    /// Make writes to the SD card take this long per byte, in microseconds of simulated time, like a real card's
+    /// limited write speed.
+    #[clap(long, value_name = "US_PER_BYTE", requires = "usd")]
+    usd_write_delay: Option<u64>,
+    /// Append every command received and event sent to this file as JSONL, with timestamps, to reproduce the session
+    /// later with `--replay`.
+    #[clap(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Replay the commands from a session recorded with `--record` instead of reading them from the frontend. Events
+    /// are still sent to the frontend, but commands it sends are ignored.
+    #[clap(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+    /// Pass a key-value pair to the program, which it can read with the `sim_get_arg` import. Can be passed more than
+    /// once.
+    #[clap(long, value_name = "KEY=VALUE")]
+    arg: Vec<ProgramArg>,
+    /// Make a host directory available to the program through WASI file APIs. Can be passed more than once.
+    #[clap(long, value_name = "HOST:GUEST")]
+    wasi_dir: Vec<WasiDir>,
+    /// Make WASI clocks follow simulated time and seed WASI randomness, so that runs are reproducible.
+    #[clap(long)]
+    deterministic: bool,
+    /// The seed for WASI random sources. Defaults to 0 when `--deterministic` is passed.
+    #[clap(long)]
+    seed: Option<u64>,
+    /// The SDL joystick index of the gamepad to use as the partner controller, regardless of the GUID the frontend
+    /// asks for. By default, the partner controller uses the second gamepad matching its GUID, if there is one.
+    #[clap(long, value_name = "INDEX")]
+    partner_controller: Option<u32>,
 }
 
-// const PROGRAM_TYPE_USER: u32 = 0;
-// const PROGRAM_OWNER_SYS: u32 = 0;
-// const PROGRAM_OWNER_VEX: u32 = 1;
-// const PROGRAM_OWNER_PARTNER: u32 = 2;
-
-/// Options parsed from the program's code signature/cold header.
-#[derive(Debug, Clone, Copy)]
-pub struct ProgramOptions {
-    /// The program type. PROS sets this to 0.
-    pub program_type: u32,
-    /// The owner of the program. PROS sets this to 2.
-    pub owner: u32,
-    pub invert_default_graphics_colors: bool,
-    pub kill_threads_when_main_exits: bool,
-    pub invert_graphics_based_on_theme: bool,
-}
-
-impl ProgramOptions {
-    pub const fn default_fg_color(&self) -> RGB8 {
-        if self.invert_default_graphics_colors {
-            BLACK
-        } else {
-            WHITE
-        }
-    }
-
-    pub const fn default_bg_color(&self) -> RGB8 {
-        if self.invert_default_graphics_colors {
-            WHITE
-        } else {
-            BLACK
-        }
-    }
-}
-
-fn parse_code_sig(program: &[u8], protocol: &mut Protocol) -> anyhow::Result<ProgramOptions> {
-    const PROGRAM_OPTIONS_INVERT_DEFAULT_GRAPHICS_COLORS: u32 = 1 << 0;
-    const PROGRAM_OPTIONS_KILL_THREADS_WHEN_MAIN_EXITS: u32 = 1 << 1;
-    const PROGRAM_OPTIONS_INVERT_GRAPHICS_BASED_ON_THEME: u32 = 1 << 2;
-
-    // in vexide programs the cold header is stored in a section called ".cold_magic"
-    let mut cold_header = None;
-    let parser = Parser::new(0);
-    for payload in parser.parse_all(&program) {
-        if let Payload::CustomSection(custom_section) = payload? {
-            if custom_section.name() == ".cold_magic" {
-                cold_header = Some(Bytes::copy_from_slice(custom_section.data()));
-                break;
-            }
+impl Args {
+    fn config(&self, sdl_request_channel: Option<mpsc::Sender<SdlRequest>>) -> SimulatorConfig {
+        SimulatorConfig {
+            imply_start: self.imply_start,
+            handshake_timeout: (self.handshake_timeout != 0)
+                .then(|| Duration::from_millis(self.handshake_timeout)),
+            relaxed_code_sig: self.relaxed_code_sig,
+            strict_code_sig: self.strict,
+            log_level: self.log_level,
+            fast_backtrace: self.fast_backtrace,
+            yield_interval: self.yield_interval,
+            local_text_metrics: self.local_metrics,
+            text_metrics_timeout: (self.text_metrics_timeout != 0)
+                .then(|| Duration::from_millis(self.text_metrics_timeout)),
+            wrap_text: self.wrap_text,
+            reset_clock_on_start: self.reset_clock_on_start,
+            restart_on_reboot: self.restart_on_reboot,
+            boot_delay: self.boot_delay.map(Duration::from_millis),
+            max_event_rate: self.max_event_rate,
+            commands_per_tick: self.commands_per_tick.map(|limit| limit as usize),
+            verbose: self.verbose,
+            entry: self.entry.clone(),
+            stdin_passthrough: self.stdin_passthrough,
+            tee_serial: self.tee_serial,
+            controller_preset: self.controller_preset,
+            max_memory: self.max_memory.map(|mib| (mib << 20) as usize),
+            max_table_elements: self.max_table,
+            debug_memory: self.debug_memory,
+            usd: self.usd.clone(),
+            usd_write_delay: self.usd_write_delay.map(Duration::from_micros),
+            record: self.record.clone(),
+            args: self.arg.clone(),
+            wasi: WasiOptions {
+                dirs: self.wasi_dir.clone(),
+                deterministic: self.deterministic,
+                seed: self.seed,
+            },
+            sdl_request_channel,
         }
     }
-    let mut cold_header = cold_header.context("No cold header found in the program")?;
-
-    // copy_to_bytes is used to remove the magic number from the start of the buffer
-    let v_code_sig = VCodeSig::new(&cold_header);
-    let magic = cold_header.copy_to_bytes(HEADER_MAGIC.len());
-    if magic != HEADER_MAGIC {
-        return Err(anyhow::anyhow!("Invalid magic number"));
-    }
-
-    protocol.send(&Event::VCodeSig(v_code_sig))?;
-
-    // Parse the rest of the options, these are all the ones found in the public SDK
-    let program_type = cold_header.get_u32_le();
-    let owner = cold_header.get_u32_le();
-    let options = cold_header.get_u32_le();
-    let cold_header = ProgramOptions {
-        program_type,
-        owner,
-        invert_default_graphics_colors: options & PROGRAM_OPTIONS_INVERT_DEFAULT_GRAPHICS_COLORS
-            != 0,
-        kill_threads_when_main_exits: options & PROGRAM_OPTIONS_KILL_THREADS_WHEN_MAIN_EXITS != 0,
-        invert_graphics_based_on_theme: options & PROGRAM_OPTIONS_INVERT_GRAPHICS_BASED_ON_THEME
-            != 0,
-    };
-    Ok(cold_header)
-}
-
-/// Loads a user program from a file, parsing the cold header and creating a module.
-fn load_program(
-    engine: &Engine,
-    path: &Path,
-    protocol: &mut Protocol,
-    args: &Args,
-) -> Result<(Module, ProgramOptions)> {
-    let program = fs::read(path)?;
-
-    let cold_header = parse_code_sig(&program, protocol);
-
-    let cold_header = if args.relaxed_code_sig {
-        cold_header.unwrap_or_else(|err| {
-            protocol
-                .warn(format!("Failed to parse the program's code signature: {err} (falling back to default)."))
-                .unwrap();
-            ProgramOptions {
-                program_type: 0,
-                owner: 2,
-                invert_default_graphics_colors: false,
-                kill_threads_when_main_exits: false,
-                invert_graphics_based_on_theme: false,
-            }
-        })
-    } else {
-        cold_header.context("Failed to parse the program's code signature (this error is recoverable with --relaxed-code-sig)")?
-    };
-
-    // this operation will do a lot of JIT compilation so it's probably the slowest part of the program
-    let module = Module::from_binary(engine, &program)?;
-    Ok((module, cold_header))
-}
-
-fn start(args: Args, sdl_request_channel: mpsc::Sender<SdlRequest>) -> Result<()> {
-    let mut protocol = Protocol::open();
-    protocol.handshake(args.imply_start)?;
-
-    protocol.info("Compiling...")?;
-    let engine = Engine::new(
-        Config::new()
-            .debug_info(true)
-            .wasm_backtrace_details(WasmBacktraceDetails::Enable),
-    )?;
-    let (module, cold_header) = load_program(&engine, &args.program, &mut protocol, &args)
-        .context("Failed to load robot program")?;
-
-    protocol.info("Booting...")?;
-
-    let state = SdkState::new(module.clone(), cold_header, protocol, sdl_request_channel);
-
-    let mut store = Store::new(&engine, state);
-
-    // Here we get the metadata of the imported indirect function table.
-    // User programs will request a varying starting number of entries.
-    // If the starting number of entries actually given to the program is too low, it will not start successfully.
-    let imported_table_ty = module
-        .imports()
-        .filter_map(|i| match i.ty() {
-            ExternType::Table(table_ty) => Some(table_ty),
-            _ => None,
-        })
-        .next()
-        .unwrap();
-
-    let mut linker = Linker::new(&engine);
-    let table = Table::new(&mut store, imported_table_ty, Ref::Func(None))?;
-    linker.define(&store, "env", "__indirect_function_table", table)?;
-    linker.func_wrap(
-        "env",
-        "sim_log_backtrace",
-        |mut caller: Caller<'_, SdkState>| {
-            let backtrace = WasmBacktrace::capture(&caller);
-            caller.data_mut().error(format!("{}", backtrace))?;
-            Ok(())
-        },
-    )?;
-
-    wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |sdk| sdk.wasi())?;
-
-    // Load and compile our module
-
-    let instance = linker.instantiate(&mut store, &module)?;
-
-    // Allocate space for the jump table. 0x700 total pages covers the entire range of the jump table.
-    let memory = instance.get_memory(&mut store, "memory").unwrap();
-    let target_pages = 0x700;
-    let memory_size = memory.size(&store);
-    memory.grow(&mut store, target_pages - memory_size)?;
-
-    // Add the jump table to memory and create the WASM FFI interface.
-    let jump_table = JumpTable::new(&mut store, memory);
-    jump_table.expose(&mut store, &table, &memory)?;
-
-    let run = instance.get_typed_func::<(), ()>(&mut store, "_entry")?;
-    if args.imply_start {
-        store.data_mut().execute_command(Command::StartExecution)?;
-    }
-    store
-        .data_mut()
-        .setup()
-        .context("Failed to setup the program for execution")?;
-    // We should be ready to actually run the entrypoint now.
-    store.data_mut().trace("Calling _entry()")?;
-    run.call(&mut store, ())
-        .context("Call to _entry() failed")?;
-    Ok(())
 }
 
-fn main() -> Result<()> {
+fn main() -> anyhow::Result<()> {
+    protocol::install_panic_hook();
     ctrlc::set_handler(move || {
+        SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+        // The program might be stuck and never give the simulator a chance to shut down.
+        thread::sleep(SHUTDOWN_TIMEOUT);
+        protocol::write_to_stdout(&SimEvent::Exited {
+            reason: ExitReason::Interrupted,
+        });
         std::process::exit(0);
     })
     .unwrap();
 
     let args = Args::parse();
 
+    if args.check {
+        let program = fs::read(&args.program)?;
+        let mut simulator = Simulator::new(args.config(None));
+        let result = simulator.check(&program);
+        // There's no frontend to send diagnostics to, so the ones found while loading the program are printed instead.
+        for event in simulator.events() {
+            if let Outbound::Event(Event::Log { level, message }) = event {
+                eprintln!("{level:?}: {message}");
+            }
+        }
+        return result;
+    }
+
     // This is required for certain controllers to work on Windows without the
     // video subsystem enabled:
     sdl2::hint::set("SDL_JOYSTICK_THREAD", "1");
@@ -253,8 +255,29 @@ fn main() -> Result<()> {
     let joystick_subsystem = sdl.joystick().unwrap();
     let controller_subsystem = sdl.game_controller().unwrap();
 
+    let partner_controller = args.partner_controller;
     let handle = thread::spawn(move || {
-        start(args, tx).unwrap();
+        let program = fs::read(&args.program).unwrap();
+        let config = args.config(Some(tx));
+        let transport: Box<dyn Transport> = match args.listen {
+            Some(addr) => Box::new(
+                TcpTransport::listen(addr, args.protocol_format)
+                    .with_context(|| format!("Failed to accept a frontend connection on {addr}"))
+                    .unwrap(),
+            ),
+            None => Box::new(StdioTransport::new(args.protocol_format)),
+        };
+        let mut simulator = match &args.replay {
+            Some(path) => {
+                let recording = BufReader::new(fs::File::open(path).unwrap());
+                let transport = ReplayTransport::new(recording, transport)
+                    .with_context(|| format!("Failed to read the recording {}", path.display()))
+                    .unwrap();
+                Simulator::with_transport(config, transport)
+            }
+            None => Simulator::with_transport(config, transport),
+        };
+        simulator.run(&program).unwrap();
     });
 
     while let Ok(req) = rx.recv() {
@@ -262,53 +285,61 @@ fn main() -> Result<()> {
             SdlRequest::EventPump => {
                 event_pump.pump_events();
             }
-            SdlRequest::V5Controller { guid, response } => {
+            SdlRequest::V5Controller { id, guid, response } => {
                 let val = || {
                     let joysticks = controller_subsystem
                         .num_joysticks()
                         .map_err(|s| anyhow!(s))?;
+                    let forced = (id == 1).then_some(partner_controller).flatten();
+                    let mut candidates = Vec::new();
                     for idx in 0..joysticks {
+                        if forced.is_some_and(|forced| forced != idx) {
+                            continue;
+                        }
                         if controller_subsystem.is_game_controller(idx) {
                             let Ok(joystick) = joystick_subsystem.open(idx) else {
                                 break;
                             };
-                            if joystick.guid() != guid || !joystick.attached() {
+                            if (forced.is_none() && joystick.guid() != guid) || !joystick.attached()
+                            {
                                 continue;
                             }
-                            let Ok(sdl_controller) = controller_subsystem.open(idx) else {
-                                continue;
-                            };
-
-                            return anyhow::Ok(Some(ControllerState {
-                                axis1: (sdl_controller.axis(Axis::LeftX) as i32) * 127
-                                    / (i16::MAX as i32),
-                                axis2: -(sdl_controller.axis(Axis::LeftY) as i32) * 127
-                                    / (i16::MAX as i32),
-                                axis3: -(sdl_controller.axis(Axis::RightY) as i32) * 127
-                                    / (i16::MAX as i32),
-                                axis4: (sdl_controller.axis(Axis::RightX) as i32) * 127
-                                    / (i16::MAX as i32),
-                                button_l1: sdl_controller.button(Button::LeftShoulder),
-                                button_l2: sdl_controller.axis(Axis::TriggerLeft) > 0,
-                                button_r1: sdl_controller.button(Button::RightShoulder),
-                                button_r2: sdl_controller.axis(Axis::TriggerRight) > 0,
-                                button_up: sdl_controller.button(Button::DPadUp),
-                                button_down: sdl_controller.button(Button::DPadDown),
-                                button_left: sdl_controller.button(Button::DPadLeft),
-                                button_right: sdl_controller.button(Button::DPadRight),
-                                button_x: sdl_controller.button(Button::X),
-                                button_b: sdl_controller.button(Button::B),
-                                button_y: sdl_controller.button(Button::Y),
-                                button_a: sdl_controller.button(Button::A),
-                                battery_capacity: 0,
-                                battery_level: 0,
-                                button_all: false,
-                                button_sel: false,
-                                flags: 0,
-                            }));
+                            candidates.push(idx);
                         }
                     }
-                    Ok(None)
+
+                    // When two gamepads of the same model are plugged in, the partner controller gets the second one.
+                    let Some(&idx) = candidates.get(id as usize).or(candidates.first()) else {
+                        return Ok(None);
+                    };
+                    let Ok(sdl_controller) = controller_subsystem.open(idx) else {
+                        return Ok(None);
+                    };
+
+                    anyhow::Ok(Some(ControllerState {
+                        axis1: (sdl_controller.axis(Axis::LeftX) as i32) * 127 / (i16::MAX as i32),
+                        axis2: -(sdl_controller.axis(Axis::LeftY) as i32) * 127 / (i16::MAX as i32),
+                        axis3: -(sdl_controller.axis(Axis::RightY) as i32) * 127
+                            / (i16::MAX as i32),
+                        axis4: (sdl_controller.axis(Axis::RightX) as i32) * 127 / (i16::MAX as i32),
+                        button_l1: sdl_controller.button(Button::LeftShoulder),
+                        button_l2: sdl_controller.axis(Axis::TriggerLeft) > 0,
+                        button_r1: sdl_controller.button(Button::RightShoulder),
+                        button_r2: sdl_controller.axis(Axis::TriggerRight) > 0,
+                        button_up: sdl_controller.button(Button::DPadUp),
+                        button_down: sdl_controller.button(Button::DPadDown),
+                        button_left: sdl_controller.button(Button::DPadLeft),
+                        button_right: sdl_controller.button(Button::DPadRight),
+                        button_x: sdl_controller.button(Button::X),
+                        button_b: sdl_controller.button(Button::B),
+                        button_y: sdl_controller.button(Button::Y),
+                        button_a: sdl_controller.button(Button::A),
+                        battery_capacity: 0,
+                        battery_level: 0,
+                        button_all: false,
+                        button_sel: false,
+                        flags: 0,
+                    }))
                 };
 
                 _ = response.send(val());
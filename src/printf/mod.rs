@@ -32,7 +32,7 @@ use std::{ffi::CStr, mem::size_of};
 
 use argument::*;
 use bytemuck::{AnyBitPattern, NoUninit};
-pub use parser::format;
+pub use parser::{FormatCache, Formatted, ParsedFormat, MAX_FIELD_WIDTH};
 use wasmtime::{AsContext, AsContextMut, Memory};
 
 #[derive(Debug, Clone)]
@@ -63,9 +63,56 @@ impl WasmVaList {
 
     pub fn next_str<'a>(&mut self, ctx: &'a impl AsContext) -> Option<&'a CStr> {
         let ptr: &u32 = self.next(ctx);
-        let bytes = &self.memory.data(ctx)[(*ptr as usize)..];
+        let bytes = self.memory.data(ctx).get(*ptr as usize..)?;
         CStr::from_bytes_until_nul(bytes).ok()
     }
+
+    /// Reads a pointer to a NUL-terminated string of 32-bit `wchar_t`s, returning its bytes without the terminator.
+    pub fn next_wide_str<'a>(&mut self, ctx: &'a impl AsContext) -> Option<&'a [u8]> {
+        let ptr: &u32 = self.next(ctx);
+        let bytes = self.memory.data(ctx).get(*ptr as usize..)?;
+        let len = bytes.chunks_exact(4).position(|unit| unit == [0; 4])?;
+        Some(&bytes[..len * 4])
+    }
+}
+
+/// A page of WebAssembly memory to format strings against in tests, as if a program had passed a `va_list`.
+#[cfg(test)]
+struct MockMemory {
+    store: wasmtime::Store<()>,
+    memory: Memory,
+}
+
+#[cfg(test)]
+impl MockMemory {
+    /// Where the `va_list` starts.
+    const ARGS: u32 = 0x100;
+
+    /// Lays out `args` like a `va_list`, each argument's bytes following the last.
+    fn new(args: &[&[u8]]) -> Self {
+        let mut store = wasmtime::Store::default();
+        let memory = Memory::new(&mut store, wasmtime::MemoryType::new(1, None)).unwrap();
+        memory
+            .write(&mut store, Self::ARGS as usize, &args.concat())
+            .unwrap();
+        Self { store, memory }
+    }
+
+    /// Copies bytes to an address, such as a string that one of the arguments points to.
+    fn write(&mut self, address: u32, bytes: &[u8]) {
+        self.memory
+            .write(&mut self.store, address as usize, bytes)
+            .unwrap();
+    }
+
+    /// Formats a string with the arguments, returning the output and the outcome.
+    fn format(&self, format: &[u8]) -> (String, Formatted) {
+        let mut output = String::new();
+        let args = WasmVaList::new(Self::ARGS, self.memory);
+        let formatted =
+            ParsedFormat::parse(format).format(args, &self.store, output::fmt_write(&mut output));
+        (output, formatted)
+    }
 }
 
 pub mod argument {
@@ -348,10 +395,20 @@ pub mod argument {
         String(&'a CStr),
         /// `c`
         Char(u8),
+        /// `lc`, a 32-bit `wchar_t`
+        WideChar(u32),
+        /// `ls`
+        ///
+        /// The little-endian bytes of a string of 32-bit `wchar_t`s, without the null terminator.
+        WideString(&'a [u8]),
         /// `x`
         Hex(UnsignedInt),
         /// `X`
         UpperHex(UnsignedInt),
+        /// `b`
+        Binary(UnsignedInt),
+        /// `B`
+        UpperBinary(UnsignedInt),
         /// `p`
         Pointer(u32),
         /// `n`
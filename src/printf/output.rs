@@ -2,12 +2,11 @@
 
 use core::cell::Cell;
 use core::fmt;
-use core::str::from_utf8;
-use std::ffi::*;
+use std::{ffi::*, sync::Arc};
 
 use wasmtime::AsContext;
 
-use super::{Argument, DoubleFormat, Flags, Specifier, WasmVaList};
+use super::{Argument, DoubleFormat, Flags, ParsedFormat, Specifier, WasmVaList};
 
 struct DummyWriter(usize);
 
@@ -18,6 +17,104 @@ impl fmt::Write for DummyWriter {
     }
 }
 
+/// Converts the bytes of a 32-bit `wchar_t` string to UTF-8, replacing invalid code points.
+fn decode_wide_str(bytes: &[u8]) -> String {
+    bytes
+        .chunks_exact(4)
+        .map(|unit| {
+            let unit = u32::from_le_bytes(unit.try_into().unwrap());
+            char::from_u32(unit).unwrap_or(char::REPLACEMENT_CHARACTER)
+        })
+        .collect()
+}
+
+/// Writes an integer in binary for `%b` and `%B`, which Rust's formatting can't do with C's precision rules.
+///
+/// The precision is the minimum number of digits, and the `#` flag adds `prefix` to non-zero values.
+fn write_binary(
+    w: &mut impl fmt::Write,
+    flags: Flags,
+    width: c_int,
+    precision: Option<c_int>,
+    value: u64,
+    prefix: &str,
+) -> fmt::Result {
+    let digits = if value == 0 && precision == Some(0) {
+        String::new()
+    } else {
+        format!("{value:0prec$b}", prec = precision.unwrap_or(1) as usize)
+    };
+    let prefix = if flags.contains(Flags::ALTERNATE_FORM) && value != 0 {
+        prefix
+    } else {
+        ""
+    };
+    let width = width as usize;
+    if flags.contains(Flags::LEFT_ALIGN) {
+        write!(w, "{:<width$}", format!("{prefix}{digits}"))
+    } else if flags.contains(Flags::PREPEND_ZERO) && precision.is_none() {
+        let zeros = width.saturating_sub(prefix.len() + digits.len());
+        write!(w, "{prefix}{:0>zeros$}{digits}", "")
+    } else {
+        write!(w, "{:>width$}", format!("{prefix}{digits}"))
+    }
+}
+
+/// Writes `inf` or `nan` the way C does, since Rust would write `inf` and `NaN` and handle flags differently.
+///
+/// Precision and zero padding don't apply, but the sign flags and alignment do.
+fn write_non_finite(
+    w: &mut impl fmt::Write,
+    flags: Flags,
+    width: c_int,
+    value: f64,
+    upper: bool,
+) -> fmt::Result {
+    let sign = if value.is_sign_negative() {
+        "-"
+    } else if flags.contains(Flags::PREPEND_PLUS) {
+        "+"
+    } else if flags.contains(Flags::PREPEND_SPACE) {
+        " "
+    } else {
+        ""
+    };
+    let name = match (value.is_nan(), upper) {
+        (true, false) => "nan",
+        (true, true) => "NAN",
+        (false, false) => "inf",
+        (false, true) => "INF",
+    };
+    let text = format!("{sign}{name}");
+    if flags.contains(Flags::LEFT_ALIGN) {
+        write!(w, "{text:<width$}", width = width as usize)
+    } else {
+        write!(w, "{text:>width$}", width = width as usize)
+    }
+}
+
+/// Nudges a value that lies exactly halfway between two decimals with `precision` digits to the next float away from
+/// zero.
+///
+/// Rust rounds these ties to even when formatting, but the V5's `printf` rounds them away from zero, so `0.125` must
+/// print as `0.13` rather than `0.12`. A tie at `precision` digits is an odd multiple of `2^-(precision + 1)`, which
+/// is checked exactly by scaling with a power of two. Values that only look like ties, such as `2.675`, aren't
+/// affected because their exact binary value is already on one side.
+fn round_ties_away_from_zero(value: f64, precision: c_int) -> f64 {
+    if !value.is_finite() || precision < 0 {
+        return value;
+    }
+    let scale = 2f64.powi(precision.saturating_add(1));
+    let scaled = value * scale;
+    let is_tie = scaled.is_finite() && scaled.fract() == 0.0 && (scaled / 2.0).fract() != 0.0;
+    if is_tie {
+        // Incrementing the bits moves the magnitude up for both positive and negative values.
+        f64::from_bits(value.to_bits() + 1)
+    } else {
+        value
+    }
+}
+
 struct WriteCounter<'a, T: fmt::Write>(&'a mut T, usize);
 
 impl<'a, T: fmt::Write> fmt::Write for WriteCounter<'a, T> {
@@ -34,7 +131,9 @@ fn write_str(
     precision: Option<c_int>,
     b: &[u8],
 ) -> fmt::Result {
-    let string = from_utf8(b).map_err(|_| fmt::Error)?;
+    // Programs can print text that isn't UTF-8 (e.g. Latin-1), which is shown with replacement characters instead of
+    // failing the whole call.
+    let string = String::from_utf8_lossy(b);
     let precision = precision.unwrap_or(string.len() as c_int);
     if flags.contains(Flags::LEFT_ALIGN) {
         write!(
@@ -251,10 +350,17 @@ pub fn fmt_write(w: &mut impl fmt::Write) -> impl FnMut(Argument) -> c_int + '_
             Specifier::Octal(data) => {
                 define_unumeric!(w, data, flags, width, precision.unwrap_or(0), "o")
             }
+            Specifier::Binary(data) => write_binary(w, flags, width, precision, data.into(), "0b"),
+            Specifier::UpperBinary(data) => {
+                write_binary(w, flags, width, precision, data.into(), "0B")
+            }
             Specifier::Uint(data) => {
                 define_unumeric!(w, data, flags, width, precision.unwrap_or(0))
             }
             Specifier::Int(data) => define_numeric!(w, data, flags, width, precision.unwrap_or(0)),
+            Specifier::Double { value, format } if !value.is_finite() => {
+                write_non_finite(w, flags, width, value, format.is_upper())
+            }
             Specifier::Double { value, format } => match format {
                 DoubleFormat::Normal
                 | DoubleFormat::UpperNormal
@@ -262,7 +368,9 @@ pub fn fmt_write(w: &mut impl fmt::Write) -> impl FnMut(Argument) -> c_int + '_
                 | DoubleFormat::UpperAuto
                 | DoubleFormat::Hex
                 | DoubleFormat::UpperHex => {
-                    define_numeric!(w, value, flags, width, precision.unwrap_or(6))
+                    let precision = precision.unwrap_or(6);
+                    let value = round_ties_away_from_zero(value, precision);
+                    define_numeric!(w, value, flags, width, precision)
                 }
                 DoubleFormat::Scientific => {
                     define_numeric!(w, value, flags, width, precision.unwrap_or(6), "e")
@@ -278,6 +386,18 @@ pub fn fmt_write(w: &mut impl fmt::Write) -> impl FnMut(Argument) -> c_int + '_
                     write!(w, "{:>width$}", data as char, width = width as usize)
                 }
             }
+            Specifier::WideChar(data) => {
+                let data = char::from_u32(data).unwrap_or(char::REPLACEMENT_CHARACTER);
+                if flags.contains(Flags::LEFT_ALIGN) {
+                    write!(w, "{:width$}", data, width = width as usize)
+                } else {
+                    write!(w, "{:>width$}", data, width = width as usize)
+                }
+            }
+            Specifier::WideString(data) => {
+                let string = decode_wide_str(data);
+                write_str(w, flags, width, precision, string.as_bytes())
+            }
             Specifier::Pointer(data) => {
                 if flags.contains(Flags::LEFT_ALIGN) {
                     write!(
@@ -317,7 +437,7 @@ pub fn fmt_write(w: &mut impl fmt::Write) -> impl FnMut(Argument) -> c_int + '_
 ///
 /// This shares the same caveats as [`fmt_write`].
 pub fn display<T: AsContext>(
-    format: CString,
+    format: Arc<ParsedFormat>,
     va_list: WasmVaList,
     ctx: &T,
 ) -> VaListDisplay<'_, T> {
@@ -326,6 +446,7 @@ pub fn display<T: AsContext>(
         va_list,
         ctx,
         written: Cell::new(0),
+        clamped: Cell::new(false),
     }
 }
 
@@ -349,10 +470,11 @@ pub fn display<T: AsContext>(
 /// If you have access to [`std`], i.e. not an embedded platform, you can use
 /// [`std::os::raw`] instead of [`cty`].
 pub struct VaListDisplay<'a, T: AsContext> {
-    format: CString,
+    format: Arc<ParsedFormat>,
     va_list: WasmVaList,
     ctx: &'a T,
     written: Cell<c_int>,
+    clamped: Cell<bool>,
 }
 
 impl<T: AsContext> VaListDisplay<'_, T> {
@@ -360,17 +482,23 @@ impl<T: AsContext> VaListDisplay<'_, T> {
     pub fn bytes_written(&self) -> c_int {
         self.written.get()
     }
+
+    /// Whether a width or precision had to be clamped to [`MAX_FIELD_WIDTH`](super::MAX_FIELD_WIDTH).
+    pub fn clamped(&self) -> bool {
+        self.clamped.get()
+    }
 }
 
 impl<'a, T: AsContext> fmt::Display for VaListDisplay<'a, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let bytes = super::format(
-            self.format.as_bytes(),
-            self.va_list.clone(),
-            self.ctx,
-            fmt_write(f),
-        );
+        let super::Formatted {
+            written: bytes,
+            clamped,
+        } = self
+            .format
+            .format(self.va_list.clone(), self.ctx, fmt_write(f));
         self.written.set(bytes);
+        self.clamped.set(clamped);
         if bytes < 0 {
             Err(fmt::Error)
         } else {
@@ -378,3 +506,66 @@ impl<'a, T: AsContext> fmt::Display for VaListDisplay<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::printf::MockMemory;
+
+    /// Formats a string with 32-bit integer arguments.
+    fn format_ints(format: &[u8], args: &[i32]) -> String {
+        let args: Vec<[u8; 4]> = args.iter().map(|arg| arg.to_le_bytes()).collect();
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_slice()).collect();
+        MockMemory::new(&args).format(format).0
+    }
+
+    /// Formats a string with a single double argument.
+    fn format_double(format: &[u8], value: f64) -> String {
+        MockMemory::new(&[&value.to_le_bytes()]).format(format).0
+    }
+
+    #[test]
+    fn pads_and_aligns_integers() {
+        assert_eq!(
+            format_ints(b"%5d|%-5d|%05d", &[42, 42, 42]),
+            "   42|42   |00042"
+        );
+    }
+
+    #[test]
+    fn writes_signs_of_integers() {
+        assert_eq!(format_ints(b"%+d % d %d", &[3, 3, -3]), "+3  3 -3");
+    }
+
+    #[test]
+    fn writes_integers_in_other_bases() {
+        assert_eq!(
+            format_ints(b"%x %#x %X %o %b %#B", &[255, 255, 255, 8, 5, 5]),
+            "ff 0xff FF 10 101 0B101"
+        );
+    }
+
+    #[test]
+    fn rounds_ties_away_from_zero() {
+        assert_eq!(format_double(b"%.2f", 0.125), "0.13");
+        assert_eq!(format_double(b"%.2f", -0.125), "-0.13");
+        assert_eq!(format_double(b"%.2f", 2.675), "2.67");
+    }
+
+    #[test]
+    fn writes_non_finite_doubles_like_c() {
+        assert_eq!(format_double(b"%f", f64::INFINITY), "inf");
+        assert_eq!(format_double(b"%+F", f64::INFINITY), "+INF");
+        assert_eq!(format_double(b"%-5.1f|", f64::NAN), "nan  |");
+    }
+
+    #[test]
+    fn writes_wide_strings() {
+        let mut memory = MockMemory::new(&[&0x800u32.to_le_bytes()]);
+        let text: Vec<u8> = "héllo\0"
+            .chars()
+            .flat_map(|c| u32::from(c).to_le_bytes())
+            .collect();
+        memory.write(0x800, &text);
+        assert_eq!(memory.format(b"[%7ls]").0, "[  héllo]");
+    }
+}
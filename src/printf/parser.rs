@@ -1,4 +1,4 @@
-use std::ffi::*;
+use std::{collections::HashMap, ffi::*, sync::Arc};
 
 use super::{Argument, DoubleFormat, Flags, SignedInt, Specifier, UnsignedInt, WasmVaList};
 use itertools::Itertools;
@@ -26,36 +26,64 @@ fn parse_flags(mut sub: &[u8]) -> (Flags, &[u8]) {
     (flags, sub)
 }
 
+/// The largest width or precision that will be used. Larger values are clamped to this, so that a pathological format
+/// string can't make the simulator allocate gigabytes of padding.
+pub const MAX_FIELD_WIDTH: c_int = 0x10000;
+
+/// A width or precision, which is either written in the format string or passed as an argument with `*`.
+#[derive(Debug, Copy, Clone)]
+enum Field {
+    Fixed(c_int),
+    Arg,
+}
+
+impl Field {
+    fn resolve(self, args: &mut WasmVaList, ctx: &impl AsContext) -> c_int {
+        match self {
+            Field::Fixed(value) => value,
+            Field::Arg => *args.next(ctx),
+        }
+    }
+}
+
 /// Parse the [Width field](https://en.wikipedia.org/wiki/Printf_format_string#Width_field).
-fn parse_width<'a>(
-    mut sub: &'a [u8],
-    args: &mut WasmVaList,
-    ctx: &mut impl AsContext,
-) -> (c_int, &'a [u8]) {
+///
+/// Widths written in the format string saturate instead of overflowing. Widths passed with `*` may be negative.
+fn parse_width(mut sub: &[u8]) -> (Field, &[u8]) {
     let mut width: c_int = 0;
     if sub.first() == Some(&b'*') {
-        return (*args.next(ctx), next_char(sub));
+        return (Field::Arg, next_char(sub));
     }
     while let Some(&ch) = sub.first() {
         match ch {
             // https://rust-malaysia.github.io/code/2020/07/11/faster-integer-parsing.html#the-bytes-solution
-            b'0'..=b'9' => width = width * 10 + (ch & 0x0f) as c_int,
+            b'0'..=b'9' => {
+                width = width
+                    .saturating_mul(10)
+                    .saturating_add((ch & 0x0f) as c_int)
+            }
             _ => break,
         }
         sub = next_char(sub);
     }
-    (width, sub)
+    (Field::Fixed(width), sub)
+}
+
+/// Limits a width or precision to [`MAX_FIELD_WIDTH`], recording whether it had to be clamped.
+fn clamp_field(value: c_uint, clamped: &mut bool) -> c_int {
+    if value > MAX_FIELD_WIDTH as c_uint {
+        *clamped = true;
+        MAX_FIELD_WIDTH
+    } else {
+        value as c_int
+    }
 }
 
 /// Parse the [Precision field](https://en.wikipedia.org/wiki/Printf_format_string#Precision_field).
-fn parse_precision<'a>(
-    sub: &'a [u8],
-    args: &mut WasmVaList,
-    ctx: &mut impl AsContext,
-) -> (Option<c_int>, &'a [u8]) {
+fn parse_precision(sub: &[u8]) -> (Option<Field>, &[u8]) {
     match sub.first() {
         Some(&b'.') => {
-            let (prec, sub) = parse_width(next_char(sub), args, ctx);
+            let (prec, sub) = parse_width(next_char(sub));
             (Some(prec), sub)
         }
         _ => (None, sub),
@@ -121,88 +149,321 @@ fn parse_length(sub: &[u8]) -> (Length, &[u8]) {
     }
 }
 
-/// Parse a format parameter and write it somewhere.
-pub fn format(
-    str: &[u8],
-    mut args: WasmVaList,
-    mut ctx: impl AsContext,
-    mut handler: impl FnMut(Argument) -> c_int,
-) -> c_int {
-    let mut iter = str.split(|&c| c == b'%');
-    let mut written = 0;
-
-    macro_rules! err {
-        ($ex: expr) => {{
-            let res = $ex;
-            if res < 0 {
-                return -1;
-            } else {
-                written += res;
+/// The conversion characters that end a specifier, apart from `%`.
+const CONVERSIONS: &[u8] = b"diuoxXbBfFeEgGaAscpn";
+
+/// Finds the conversion character that ends a specifier, without consuming any arguments.
+///
+/// Returns `None` if the specifier ends before a conversion character.
+fn conversion(sub: &[u8]) -> Option<u8> {
+    let (_, mut sub) = parse_flags(sub);
+    while let Some(b'0'..=b'9' | b'*' | b'.') = sub.first() {
+        sub = next_char(sub);
+    }
+    let (_, sub) = parse_length(sub);
+    sub.first().copied()
+}
+
+/// The outcome of formatting a `printf`-style string.
+#[derive(Debug, Clone, Copy)]
+pub struct Formatted {
+    /// The number of bytes written, or -1 if there was an error.
+    pub written: c_int,
+    /// Whether a width or precision was larger than [`MAX_FIELD_WIDTH`] and had to be clamped.
+    pub clamped: bool,
+}
+
+/// A conversion specifier with everything needed to format its argument.
+#[derive(Debug, Clone, Copy)]
+struct Spec {
+    flags: Flags,
+    width: Field,
+    precision: Option<Field>,
+    length: Length,
+    conversion: u8,
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Bytes(Vec<u8>),
+    Spec(Spec),
+}
+
+/// A format string split into literal text and specifiers, so it can be formatted many times without being parsed
+/// again.
+#[derive(Debug, Clone)]
+pub struct ParsedFormat {
+    source: Vec<u8>,
+    tokens: Vec<Token>,
+}
+
+impl ParsedFormat {
+    pub fn parse(str: &[u8]) -> Self {
+        let mut tokens = Vec::new();
+        let push_bytes = |tokens: &mut Vec<Token>, bytes: &[u8]| {
+            if !bytes.is_empty() {
+                tokens.push(Token::Bytes(bytes.to_vec()));
             }
-        }};
+        };
+
+        let mut iter = str.split(|&c| c == b'%');
+        if let Some(begin) = iter.next() {
+            push_bytes(&mut tokens, begin);
+        }
+        let mut last_was_percent = false;
+        for (sub, next) in iter.map(Some).chain(core::iter::once(None)).tuple_windows() {
+            let sub = match sub {
+                Some(sub) => sub,
+                None => break,
+            };
+            if last_was_percent {
+                push_bytes(&mut tokens, sub);
+                last_was_percent = false;
+                continue;
+            }
+            let is_known = match conversion(sub) {
+                Some(ch) => CONVERSIONS.contains(&ch),
+                // The specifier runs into the next `%`, making it `%%` with some flags.
+                None => next.is_some(),
+            };
+            if !is_known {
+                // Like C, an unknown or truncated specifier is written out as-is instead of consuming an argument.
+                push_bytes(&mut tokens, b"%");
+                push_bytes(&mut tokens, sub);
+                continue;
+            }
+            let (flags, sub) = parse_flags(sub);
+            let (width, sub) = parse_width(sub);
+            let (precision, sub) = parse_precision(sub);
+            let (length, sub) = parse_length(sub);
+            let conversion = sub.first().copied().unwrap_or(b'%');
+            last_was_percent = conversion == b'%';
+            tokens.push(Token::Spec(Spec {
+                flags,
+                width,
+                precision,
+                length,
+                conversion,
+            }));
+            push_bytes(&mut tokens, next_char(sub));
+        }
+
+        Self {
+            source: str.to_vec(),
+            tokens,
+        }
     }
-    if let Some(begin) = iter.next() {
-        err!(handler(Specifier::Bytes(begin).into()));
+
+    /// The format string that was parsed.
+    pub fn source(&self) -> &[u8] {
+        &self.source
     }
-    let mut last_was_percent = false;
-    for (sub, next) in iter.map(Some).chain(core::iter::once(None)).tuple_windows() {
-        let sub = match sub {
-            Some(sub) => sub,
-            None => break,
-        };
-        if last_was_percent {
-            err!(handler(Specifier::Bytes(sub).into()));
-            last_was_percent = false;
-            continue;
+
+    /// Substitute arguments into the format string and write it somewhere.
+    pub fn format(
+        &self,
+        mut args: WasmVaList,
+        ctx: impl AsContext,
+        mut handler: impl FnMut(Argument) -> c_int,
+    ) -> Formatted {
+        let mut written = 0;
+        let mut clamped = false;
+
+        macro_rules! err {
+            ($ex: expr) => {{
+                let res = $ex;
+                if res < 0 {
+                    return Formatted {
+                        written: -1,
+                        clamped,
+                    };
+                } else {
+                    written += res;
+                }
+            }};
         }
-        let (flags, sub) = parse_flags(sub);
-        let (width, sub) = parse_width(sub, &mut args, &mut ctx);
-        let (precision, sub) = parse_precision(sub, &mut args, &mut ctx);
-        let (length, sub) = parse_length(sub);
-        let ch = sub
-            .first()
-            .unwrap_or(if next.is_some() { &b'%' } else { &0 });
-        err!(handler(Argument {
-            flags,
-            width,
-            precision,
-            specifier: match ch {
-                b'%' => {
-                    last_was_percent = true;
-                    Specifier::Percent
+        for token in &self.tokens {
+            let spec = match token {
+                Token::Bytes(bytes) => {
+                    err!(handler(Specifier::Bytes(bytes).into()));
+                    continue;
                 }
-                b'd' | b'i' => Specifier::Int(length.parse_signed(&mut args, &ctx)),
-                b'x' => Specifier::Hex(length.parse_unsigned(&mut args, &ctx)),
-                b'X' => Specifier::UpperHex(length.parse_unsigned(&mut args, &ctx)),
-                b'u' => Specifier::Uint(length.parse_unsigned(&mut args, &ctx)),
-                b'o' => Specifier::Octal(length.parse_unsigned(&mut args, &ctx)),
-                b'f' | b'F' => Specifier::Double {
-                    value: *args.next(&ctx),
-                    format: DoubleFormat::Normal.set_upper(ch.is_ascii_uppercase()),
-                },
-                b'e' | b'E' => Specifier::Double {
-                    value: *args.next(&ctx),
-                    format: DoubleFormat::Scientific.set_upper(ch.is_ascii_uppercase()),
-                },
-                b'g' | b'G' => Specifier::Double {
-                    value: *args.next(&ctx),
-                    format: DoubleFormat::Auto.set_upper(ch.is_ascii_uppercase()),
-                },
-                b'a' | b'A' => Specifier::Double {
-                    value: *args.next(&ctx),
-                    format: DoubleFormat::Hex.set_upper(ch.is_ascii_uppercase()),
+                Token::Spec(spec) => *spec,
+            };
+            let Spec {
+                mut flags,
+                width,
+                precision,
+                length,
+                conversion: ch,
+            } = spec;
+            let width = width.resolve(&mut args, &ctx);
+            let precision = precision.map(|precision| precision.resolve(&mut args, &ctx));
+            // Like C, a negative width from `*` means left alignment and a negative precision means it was omitted.
+            if width < 0 {
+                flags.insert(Flags::LEFT_ALIGN);
+            }
+            let width = clamp_field(width.unsigned_abs(), &mut clamped);
+            let precision = precision
+                .filter(|&precision| precision >= 0)
+                .map(|precision| clamp_field(precision as c_uint, &mut clamped));
+            err!(handler(Argument {
+                flags,
+                width,
+                precision,
+                specifier: match ch {
+                    b'%' => Specifier::Percent,
+                    b'd' | b'i' => Specifier::Int(length.parse_signed(&mut args, &ctx)),
+                    b'x' => Specifier::Hex(length.parse_unsigned(&mut args, &ctx)),
+                    b'X' => Specifier::UpperHex(length.parse_unsigned(&mut args, &ctx)),
+                    b'u' => Specifier::Uint(length.parse_unsigned(&mut args, &ctx)),
+                    b'o' => Specifier::Octal(length.parse_unsigned(&mut args, &ctx)),
+                    b'b' => Specifier::Binary(length.parse_unsigned(&mut args, &ctx)),
+                    b'B' => Specifier::UpperBinary(length.parse_unsigned(&mut args, &ctx)),
+                    b'f' | b'F' => Specifier::Double {
+                        value: *args.next(&ctx),
+                        format: DoubleFormat::Normal.set_upper(ch.is_ascii_uppercase()),
+                    },
+                    b'e' | b'E' => Specifier::Double {
+                        value: *args.next(&ctx),
+                        format: DoubleFormat::Scientific.set_upper(ch.is_ascii_uppercase()),
+                    },
+                    b'g' | b'G' => Specifier::Double {
+                        value: *args.next(&ctx),
+                        format: DoubleFormat::Auto.set_upper(ch.is_ascii_uppercase()),
+                    },
+                    b'a' | b'A' => Specifier::Double {
+                        value: *args.next(&ctx),
+                        format: DoubleFormat::Hex.set_upper(ch.is_ascii_uppercase()),
+                    },
+                    b's' if matches!(length, Length::Long) => {
+                        Specifier::WideString(match args.next_wide_str(&ctx) {
+                            Some(s) => s,
+                            None => {
+                                return Formatted {
+                                    written: -1,
+                                    clamped,
+                                }
+                            }
+                        })
+                    }
+                    b's' => Specifier::String(match args.next_str(&ctx) {
+                        Some(s) => s,
+                        None => {
+                            return Formatted {
+                                written: -1,
+                                clamped,
+                            };
+                        }
+                    }),
+                    b'c' if matches!(length, Length::Long) => {
+                        Specifier::WideChar(*args.next(&ctx))
+                    }
+                    b'c' => Specifier::Char(*args.next(&ctx)),
+                    b'p' => Specifier::Pointer(*args.next(&ctx)),
+                    b'n' => Specifier::WriteBytesWritten(written, *args.next(&ctx)),
+                    _ => {
+                        return Formatted {
+                            written: -1,
+                            clamped,
+                        };
+                    }
                 },
-                b's' => Specifier::String(match args.next_str(&ctx) {
-                    Some(s) => s,
-                    None => return -1,
-                }),
-                b'c' => Specifier::Char(*args.next(&ctx)),
-                b'p' => Specifier::Pointer(*args.next(&ctx)),
-                b'n' => Specifier::WriteBytesWritten(written, *args.next(&ctx)),
-                _ => return -1,
-            },
-        }));
-        err!(handler(Specifier::Bytes(next_char(sub)).into()));
-    }
-    written
+            }));
+        }
+        Formatted { written, clamped }
+    }
+}
+
+/// Format strings that have already been parsed, keyed by their address in the program's memory.
+///
+/// Programs tend to print the same few string literals, often many times a second, so this saves parsing them on every
+/// call. An entry is only used while the string at its address is unchanged.
+#[derive(Debug, Default)]
+pub struct FormatCache {
+    entries: HashMap<u32, Arc<ParsedFormat>>,
+}
+
+impl FormatCache {
+    const CAPACITY: usize = 64;
+
+    /// Returns the parsed format string at `ptr`, if it was parsed from the same `source`.
+    pub fn get(&self, ptr: u32, source: &[u8]) -> Option<Arc<ParsedFormat>> {
+        self.entries
+            .get(&ptr)
+            .filter(|parsed| parsed.source() == source)
+            .cloned()
+    }
+
+    pub fn insert(&mut self, ptr: u32, parsed: Arc<ParsedFormat>) {
+        if self.entries.len() >= Self::CAPACITY && !self.entries.contains_key(&ptr) {
+            // Formats are cheap to parse again, so there's no need to track which ones are least recently used.
+            self.entries.clear();
+        }
+        self.entries.insert(ptr, parsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printf::MockMemory;
+
+    #[test]
+    fn writes_literal_text_and_escaped_percents() {
+        let (output, formatted) = MockMemory::new(&[]).format(b"100%% done");
+        assert_eq!(output, "100% done");
+        assert_eq!(formatted.written, 9);
+    }
+
+    #[test]
+    fn writes_unknown_and_truncated_specifiers_as_is() {
+        // Neither specifier consumes an argument, so the 7 goes to the `%d`.
+        let (output, _) = MockMemory::new(&[&7i32.to_le_bytes()]).format(b"%q %d at 50%");
+        assert_eq!(output, "%q 7 at 50%");
+    }
+
+    #[test]
+    fn reads_widths_and_precisions_from_arguments() {
+        // A negative width from `*` left-aligns, and a negative precision is ignored.
+        let memory = MockMemory::new(&[
+            &(-4i32).to_le_bytes(),
+            &5i32.to_le_bytes(),
+            &3i32.to_le_bytes(),
+            &(-1i32).to_le_bytes(),
+            &6i32.to_le_bytes(),
+        ]);
+        let (output, _) = memory.format(b"%*d|%*.*d|");
+        assert_eq!(output, "5   |  6|");
+    }
+
+    #[test]
+    fn clamps_huge_widths() {
+        let (output, formatted) = MockMemory::new(&[&1i32.to_le_bytes()]).format(b"%99999999999d");
+        assert!(formatted.clamped);
+        assert_eq!(output.len(), MAX_FIELD_WIDTH as usize);
+        assert_eq!(formatted.written, MAX_FIELD_WIDTH);
+    }
+
+    #[test]
+    fn reads_strings_from_memory() {
+        let mut memory = MockMemory::new(&[&0x800u32.to_le_bytes()]);
+        memory.write(0x800, b"hi\0");
+        assert_eq!(memory.format(b"%s!").0, "hi!");
+    }
+
+    #[test]
+    fn fails_on_strings_outside_memory() {
+        let memory = MockMemory::new(&[&u32::MAX.to_le_bytes()]);
+        assert_eq!(memory.format(b"%s").1.written, -1);
+    }
+
+    #[test]
+    fn only_uses_cached_formats_with_the_same_source() {
+        let mut cache = FormatCache::default();
+        cache.insert(16, Arc::new(ParsedFormat::parse(b"%d")));
+        assert!(cache.get(16, b"%d").is_some());
+        assert!(cache.get(16, b"%x").is_none());
+        assert!(cache.get(32, b"%d").is_none());
+    }
 }
@@ -0,0 +1,84 @@
+use std::io::{self, BufRead, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+use snafu::ResultExt;
+
+use super::{EncodeMessagePackSnafu, IoSnafu, MalformedMessageSnafu, Result};
+
+/// How protocol messages are encoded on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProtocolFormat {
+    /// Newline-delimited JSON.
+    #[default]
+    Json,
+    /// MessagePack, with each message prefixed by its length as a big-endian `u32`.
+    #[value(name = "msgpack")]
+    MessagePack,
+}
+
+impl ProtocolFormat {
+    /// The protocol extensions the frontend has to support to use this format.
+    pub fn extensions(self) -> Vec<String> {
+        match self {
+            Self::Json => vec![],
+            Self::MessagePack => vec!["msgpack".to_owned()],
+        }
+    }
+
+    /// Reads the next message, returning `None` if the stream has ended.
+    pub fn read<T: DeserializeOwned>(self, reader: &mut impl BufRead) -> Result<Option<T>> {
+        match self {
+            Self::Json => {
+                let mut line = Vec::new();
+                if reader.read_until(b'\n', &mut line).context(IoSnafu)? == 0 {
+                    return Ok(None);
+                }
+                let msg = jsonl::read(&line[..]).map_err(|err| {
+                    MalformedMessageSnafu {
+                        message: String::from_utf8_lossy(&line).trim_end().to_owned(),
+                        reason: err.to_string(),
+                    }
+                    .build()
+                })?;
+                Ok(Some(msg))
+            }
+            Self::MessagePack => {
+                let mut len = [0; 4];
+                match reader.read_exact(&mut len) {
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    result => result.context(IoSnafu)?,
+                }
+                let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+                reader.read_exact(&mut buf).context(IoSnafu)?;
+                let msg = rmp_serde::from_slice(&buf).map_err(|err| {
+                    MalformedMessageSnafu {
+                        message: format!("<{} bytes of MessagePack>", buf.len()),
+                        reason: err.to_string(),
+                    }
+                    .build()
+                })?;
+                Ok(Some(msg))
+            }
+        }
+    }
+
+    /// Writes a message without flushing the writer.
+    pub fn write(self, writer: &mut impl Write, msg: &impl Serialize) -> Result<()> {
+        match self {
+            Self::Json => {
+                // `jsonl::write` flushes after every message, which would defeat any buffering.
+                let mut buf = Vec::new();
+                jsonl::write(&mut buf, msg)?;
+                writer.write_all(&buf).context(IoSnafu)?;
+            }
+            Self::MessagePack => {
+                let buf = rmp_serde::to_vec_named(msg).context(EncodeMessagePackSnafu)?;
+                writer
+                    .write_all(&(buf.len() as u32).to_be_bytes())
+                    .context(IoSnafu)?;
+                writer.write_all(&buf).context(IoSnafu)?;
+            }
+        }
+        Ok(())
+    }
+}
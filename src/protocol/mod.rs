@@ -0,0 +1,946 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{stdout, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, Snafu};
+use vexide_simulator_protocol::{
+    Command, CompMode, CompetitionMode, Device, Event, LogLevel, Rect, TextMetrics, V5FontFamily,
+    V5FontSize, V5Text,
+};
+
+#[derive(Debug, Snafu)]
+pub enum ProtocolError {
+    #[snafu(context(false))]
+    Send {
+        source: jsonl::WriteError,
+    },
+    Io {
+        source: std::io::Error,
+    },
+    EncodeMessagePack {
+        source: rmp_serde::encode::Error,
+    },
+    /// A message from the frontend couldn't be decoded. This isn't fatal; the next message can still be read.
+    #[snafu(display("Received a malformed message ({reason}): {message}"))]
+    MalformedMessage {
+        message: String,
+        reason: String,
+    },
+    RecvWorkerStopped,
+    ReceivedInvalidCommandDuringHandshake {
+        command: Inbound,
+    },
+    ReceivedHandshakeAttemptAfterHandshakeFinished,
+    IncompatibleFrontendVersion {
+        expected: i32,
+        got: i32,
+    },
+    MissingFrontendExtension {
+        extension: String,
+    },
+    #[snafu(display(
+        "Timed out after {timeout:?} waiting for the frontend to start the handshake"
+    ))]
+    HandshakeTimedOut {
+        timeout: Duration,
+    },
+}
+
+pub type Result<T, E = ProtocolError> = std::result::Result<T, E>;
+
+pub use format::ProtocolFormat;
+pub use record::ReplayTransport;
+pub use transport::{ChannelTransport, CommandSender, StdioTransport, TcpTransport, Transport};
+
+use record::Recorder;
+
+mod format;
+mod record;
+mod transport;
+
+/// Events sent by this simulator that aren't part of the upstream protocol yet.
+///
+/// These are serialized the same way as [`Event`]s, so frontends that understand them can handle them alongside the
+/// standard events and others can ignore them.
+#[derive(Debug, Clone, Serialize)]
+pub enum SimEvent {
+    /// The simulator is about to exit.
+    Exited { reason: ExitReason },
+    /// A snapshot of the simulator's state, sent in response to [`SimCommand::Query`].
+    State(SimState),
+    /// A checkpoint was taken in response to [`SimCommand::Snapshot`] and can be restored using this ID.
+    SnapshotTaken { id: u32 },
+    /// The checkpoint with this ID was restored in response to [`SimCommand::Restore`].
+    Restored { id: u32 },
+    /// A line of a controller's screen changed. Lines are numbered from 1, and `text` is the whole line without
+    /// trailing spaces, so it's empty when the line was cleared.
+    ControllerScreen { id: u32, line: u32, text: String },
+    /// A controller was told to rumble. The pattern is made of `.` for short pulses, `-` for long pulses and spaces
+    /// for pauses.
+    ControllerRumble { id: u32, pattern: String },
+    /// Sent periodically while the simulated brain is booting, before the program starts, so frontends can show a
+    /// boot animation. `progress` goes from 0 to 1.
+    Booting { progress: f64 },
+    /// Sent whenever the program renders a frame. `frame_time_us` is the time since the previous frame (0 for the
+    /// first one), and `draw_count` is how many drawing operations made up the frame.
+    FrameStats { frame_time_us: u64, draw_count: u32 },
+    /// Sent when the program inverts the colors of the pixels in `rect`, limited to `clip_region`. The upstream
+    /// protocol has no draw command for this.
+    ScreenInvert { rect: Rect, clip_region: Rect },
+    /// The opacity of the program's drawing changed. Frontends should alpha-blend the draws in subsequent
+    /// [`Event::ScreenDraw`]s onto the display with this opacity, where 0 is transparent and 255 is opaque.
+    ScreenOpacity { opacity: u8 },
+    /// The program changed the brightness of the screen, as a percentage from 0 to 100. Frontends should dim their
+    /// rendering of the display to match.
+    ScreenBrightness { level: u8 },
+    /// A device was plugged into a smart port in response to [`Command::ConfigureDevice`]. Ports are numbered
+    /// from 1.
+    DeviceConfigured { port: u32, device_type: DeviceKind },
+    /// The program changed the color of the brain's status LED.
+    BrainLed { rgb: [u8; 3] },
+    /// Every SDK function in the jump table, in order of address. Sent once the jump table has been exposed to the
+    /// program.
+    JumpTable { functions: Vec<JumpTableFunction> },
+    /// The competition mode changed, whether the frontend sent a new one, field control connected or disconnected,
+    /// the program set it, or a checkpoint was restored.
+    CompetitionPhase {
+        enabled: bool,
+        mode: CompMode,
+        connected: bool,
+    },
+    /// The contents of the program's memory at `addr`, in response to [`SimCommand::ReadMemory`].
+    MemoryContents { addr: u32, bytes_base64: String },
+    /// The program asked to reboot the brain. If `restart` is set, the program is loaded again with fresh memory
+    /// and starts without waiting for [`Command::StartExecution`], otherwise the simulator exits.
+    Reboot { restart: bool },
+}
+
+/// Commands accepted by this simulator that aren't part of the upstream protocol yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SimCommand {
+    /// Makes simulated time run `factor` times as fast as wall time. A factor of 0 pauses simulated time.
+    SetTimeScale { factor: f64 },
+    /// Asks for a [`SimEvent::State`] snapshot containing the requested parts of the simulator's state. Everything is
+    /// included if `what` is empty.
+    Query { what: Vec<QueryTopic> },
+    /// Checkpoints the program's memory and the simulated devices, competition mode and time. The checkpoint is taken
+    /// the next time the program yields to the simulator, and its ID is sent in a [`SimEvent::SnapshotTaken`].
+    ///
+    /// Each checkpoint holds a full copy of the program's memory, so only a few can be saved at once. Once the limit
+    /// is reached, further snapshots are ignored with a warning until some are dropped with
+    /// [`SimCommand::DropSnapshot`].
+    Snapshot,
+    /// Rolls the program back to a checkpoint taken with [`SimCommand::Snapshot`].
+    ///
+    /// The program continues from wherever it is when it next yields, so this is only consistent if the program yields
+    /// from the same place it did when the checkpoint was taken, such as a loop waiting for a competition mode. The
+    /// display, serial buffers and controller inputs aren't restored.
+    ///
+    /// Only linear memory is restored. WASM globals such as `__stack_pointer` and the TLS base keep their current
+    /// values, and the live call stack isn't rewound, so restoring from anywhere but the checkpoint's yield point can
+    /// leave the program with a stack that doesn't match its memory.
+    Restore { id: u32 },
+    /// Deletes a checkpoint taken with [`SimCommand::Snapshot`] to free its memory. Like taking and restoring
+    /// checkpoints, this happens the next time the program yields.
+    DropSnapshot { id: u32 },
+    /// Simulates losing or regaining the connection to field control without changing the autonomous/driver mode.
+    ///
+    /// Real fields disable robots that lose their connection, so a disconnect also disables the robot unless
+    /// `keep_enabled` is set. Reconnecting doesn't enable it again; send a competition mode for that.
+    FieldConnection {
+        connected: bool,
+        #[serde(default)]
+        keep_enabled: bool,
+    },
+    /// Tells the simulator how far a font extends above and below its baseline, so text in different sizes can be
+    /// lined up by baseline. Text written on a line is only moved to line up with the normal size once the metrics of
+    /// both its size and the normal size have been sent.
+    SetFontMetrics {
+        font_family: V5FontFamily,
+        font_size: V5FontSize,
+        metrics: FontMetrics,
+    },
+    /// Warms the text metrics cache with the metrics of many strings at once, so a UI with many labels doesn't stall
+    /// on a [`Event::TextMetricsRequest`] for each of them the first time it's drawn.
+    SetTextMetricsBatch { entries: Vec<(V5Text, TextMetrics)> },
+    /// Sets the reading of a three-wire port on the ADI expander plugged into a smart port. Ports are numbered from 1
+    /// and three-wire ports from 0. For quadrature encoders and ultrasonic rangefinders, the value is set on the first
+    /// of their two ports and is the tick count or distance in millimeters.
+    ///
+    /// Voltages on the brain's own three-wire ports are sent with [`Command::AdiInput`] instead. This is for readings
+    /// that aren't a voltage, and for ports on expanders.
+    SetAdiValue {
+        port: u32,
+        adi_port: u32,
+        value: i32,
+    },
+    /// Stops the program and loads the one at `path` in its place, without restarting the simulator. The current
+    /// program exits with [`ExitReason::ProgramSwitched`], and the new one starts once the frontend sends
+    /// [`Command::StartExecution`] again.
+    LoadProgram { path: PathBuf },
+    /// Presses or releases one of the buttons on the brain.
+    BrainButton { button: BrainButton, pressed: bool },
+    /// Moves one axis of a connected controller, keeping the rest of its state. Controllers are numbered from 0, the
+    /// primary controller. Unlike [`Command::ControllerUpdate`], this doesn't connect the controller.
+    ControllerAxis {
+        id: u32,
+        axis: ControllerAxis,
+        value: i32,
+    },
+    /// Presses or releases one button of a connected controller, keeping the rest of its state.
+    ControllerButton {
+        id: u32,
+        button: ControllerButton,
+        pressed: bool,
+    },
+    /// Overwrites the program's memory at `addr` with the given bytes the next time the program yields to the
+    /// simulator. Only allowed with `--debug-memory`.
+    WriteMemory { addr: u32, bytes_base64: String },
+    /// Reads `len` bytes of the program's memory at `addr` the next time the program yields to the simulator, and
+    /// sends them in a [`SimEvent::MemoryContents`]. Only allowed with `--debug-memory`.
+    ReadMemory { addr: u32, len: u32 },
+}
+
+/// One of the analog axes of a controller, named like the fields of [`ControllerState`].
+///
+/// [`ControllerState`]: vexide_simulator_protocol::ControllerState
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControllerAxis {
+    Axis1,
+    Axis2,
+    Axis3,
+    Axis4,
+}
+
+/// One of the buttons of a controller, named like the fields of [`ControllerState`].
+///
+/// [`ControllerState`]: vexide_simulator_protocol::ControllerState
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControllerButton {
+    L1,
+    L2,
+    R1,
+    R2,
+    Up,
+    Down,
+    Left,
+    Right,
+    X,
+    B,
+    Y,
+    A,
+    Sel,
+}
+
+/// One of the buttons on the brain, which programs can read with the `sim_brain_buttons` import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BrainButton {
+    Power,
+    Up,
+    Down,
+}
+
+impl BrainButton {
+    /// The bit that's set in `sim_brain_buttons` while this button is pressed.
+    pub fn mask(self) -> u32 {
+        match self {
+            Self::Power => 1 << 0,
+            Self::Up => 1 << 1,
+            Self::Down => 1 << 2,
+        }
+    }
+}
+
+/// The vertical metrics of a font, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FontMetrics {
+    /// How far the font extends above its baseline.
+    pub ascent: u32,
+    /// How far the font extends below its baseline.
+    pub descent: u32,
+}
+
+/// A part of the simulator's state that can be requested with [`SimCommand::Query`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueryTopic {
+    Extensions,
+    Devices,
+    Competition,
+    Time,
+    Display,
+    ControllerScreens,
+    Memory,
+}
+
+/// A read-only snapshot of the simulator's state. Fields that weren't requested are left out.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SimState {
+    /// The protocol extensions negotiated during the handshake.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Vec<String>>,
+    /// The devices plugged into the brain's smart ports.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<PortState>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub competition: Option<CompetitionMode>,
+    /// The amount of simulated time that has passed since the program started, in microseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_micros: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<DisplayGeometry>,
+    /// The text on each controller's screen.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controller_screens: Option<Vec<ControllerScreenState>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryUsage>,
+}
+
+/// A smart port with a device plugged into it.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortState {
+    /// The port number, starting at 1 like the labels on the brain.
+    pub port: u32,
+    pub device: DeviceKind,
+}
+
+/// The kinds of devices that can be plugged into a smart port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceKind {
+    Motor,
+    /// A three-wire expander, or the brain's built-in three-wire ports.
+    Adi,
+}
+
+impl DeviceKind {
+    /// The kind of a device from [`Command::ConfigureDevice`], or `None` if the simulator doesn't model it.
+    pub fn from_device(device: &Device) -> Option<Self> {
+        match device {
+            Device::Motor { .. } => Some(Self::Motor),
+            _ => None,
+        }
+    }
+}
+
+/// How much linear memory the program is using.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MemoryUsage {
+    /// The current size of the program's memory in bytes, including the space reserved for the jump table.
+    pub size: u64,
+    /// The most memory the program can grow to in bytes, or `None` if it only has the 4 GiB limit of `wasm32`.
+    pub maximum: Option<u64>,
+}
+
+/// An SDK function in the jump table.
+#[derive(Debug, Clone, Serialize)]
+pub struct JumpTableFunction {
+    /// The function's offset from the start of the jump table, the same as in the `vex-sdk` crate.
+    pub address: u32,
+    pub name: String,
+    /// Whether the simulator implements the function. Functions that it doesn't are stubs that warn when called.
+    pub implemented: bool,
+    /// The extension the frontend would have had to request during the handshake for this function to be
+    /// implemented. Calling the function traps instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_extension: Option<String>,
+}
+
+/// The text on a controller's screen.
+#[derive(Debug, Clone, Serialize)]
+pub struct ControllerScreenState {
+    /// 0 for the primary controller and 1 for the partner controller.
+    pub id: u32,
+    /// Each line of the screen, from top to bottom, without trailing spaces.
+    pub lines: Vec<String>,
+}
+
+/// The size of the simulated display, in pixels.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DisplayGeometry {
+    pub width: i32,
+    pub height: i32,
+    /// The height of the program header at the top of the display, which programs can't draw over.
+    pub header_height: i32,
+}
+
+/// A message received from the frontend.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Inbound {
+    Command(Command),
+    Sim(SimCommand),
+}
+
+/// A message sent to the frontend.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Outbound {
+    Event(Event),
+    Sim(SimEvent),
+}
+
+/// Why the simulator stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ExitReason {
+    /// The program's entrypoint returned normally.
+    Completed,
+    /// The program asked to exit using `vexSystemExitRequest`.
+    ExitRequested,
+    /// The simulator was interrupted by the host (e.g. with Ctrl-C).
+    Interrupted,
+    /// The frontend didn't start the handshake in time.
+    HandshakeTimedOut,
+    /// The frontend replaced the program with another one using [`SimCommand::LoadProgram`].
+    ProgramSwitched,
+    /// The program rebooted the brain using `vexSystemBoot`. If the simulator is set to restart on reboots, the
+    /// program starts over from scratch afterwards.
+    Rebooted,
+}
+
+/// The minimum severity of log messages that are sent to the frontend.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum LogFilter {
+    #[default]
+    Trace,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogFilter {
+    /// Returns whether a message with the given level passes the filter.
+    pub fn allows(self, level: &LogLevel) -> bool {
+        let severity = match level {
+            LogLevel::Trace => Self::Trace,
+            LogLevel::Info => Self::Info,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Error => Self::Error,
+        };
+        severity >= self
+    }
+}
+
+/// Extensions that frontends can request during the handshake to only be given some of the optional groups of SDK
+/// functions, such as when they can't simulate motors. Frontends that request none of them get every group, and the
+/// display, serial and system functions are always available.
+pub const SDK_GROUP_EXTENSIONS: &[&str] = &[
+    "sdk-controller",
+    "sdk-devices",
+    "sdk-motor",
+    "sdk-adi",
+    "sdk-usd",
+];
+
+pub struct Protocol {
+    handshake_finished: bool,
+    extensions: Vec<String>,
+    handshake_timeout: Option<Duration>,
+    log_filter: LogFilter,
+    transport: Box<dyn Transport>,
+    /// Commands that were received while waiting for a different one, in the order they arrived.
+    ///
+    /// These are always older than anything still in the transport, so taking from the front of this queue before
+    /// reading new commands applies every command in arrival order.
+    command_process_queue: VecDeque<Inbound>,
+    /// The shortest time allowed between renders or between serial flushes, if they're throttled.
+    event_interval: Option<Duration>,
+    /// Where every message exchanged with the frontend is logged, if the session is being recorded.
+    recorder: Option<Recorder>,
+    /// How many times the program has yielded to the simulator. Recorded commands are stamped with this rather than
+    /// the time they arrived, so replays don't depend on how fast the host runs the program.
+    tick: u64,
+}
+
+impl Protocol {
+    /// Opens a JSONL protocol session over stdin and stdout.
+    pub fn open() -> Self {
+        Self::new(StdioTransport::default())
+    }
+
+    /// Creates a protocol session that communicates over the given transport.
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            handshake_finished: false,
+            extensions: Vec::new(),
+            handshake_timeout: None,
+            log_filter: LogFilter::Trace,
+            transport: Box::new(transport),
+            command_process_queue: VecDeque::new(),
+            event_interval: None,
+            recorder: None,
+            tick: 0,
+        }
+    }
+
+    /// Sets the minimum severity of log messages that will be sent.
+    pub fn set_log_filter(&mut self, filter: LogFilter) {
+        self.log_filter = filter;
+    }
+
+    /// Returns the protocol extensions that were negotiated during the handshake.
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    /// Returns whether the SDK functions for the group with this extension should be registered, because the frontend
+    /// requested it or didn't request any of the [`SDK_GROUP_EXTENSIONS`].
+    pub fn wants_sdk_group(&self, extension: &str) -> bool {
+        let restricted = self
+            .extensions
+            .iter()
+            .any(|negotiated| SDK_GROUP_EXTENSIONS.contains(&negotiated.as_str()));
+        !restricted
+            || self
+                .extensions
+                .iter()
+                .any(|negotiated| negotiated == extension)
+    }
+
+    /// Sets how long to wait for the frontend to start the handshake. Waits forever if `None`.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Limits how many renders and serial flushes are sent per second. Events aren't throttled if `None`.
+    pub fn set_max_event_rate(&mut self, rate: Option<f64>) {
+        self.event_interval = rate
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate));
+    }
+
+    /// Logs every command received and event sent from now on to the given writer, so the session can be replayed
+    /// with a [`ReplayTransport`].
+    pub fn record_to(&mut self, writer: impl std::io::Write + Send + 'static) {
+        self.recorder = Some(Recorder::new(writer));
+    }
+
+    fn record_command(&mut self, command: &Inbound) -> Result<()> {
+        match &mut self.recorder {
+            Some(recorder) => recorder.record_command(command, self.tick),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a yield from the program to the simulator, which commands are recorded and replayed against.
+    pub fn tick(&mut self) {
+        self.tick += 1;
+        self.transport.set_tick(self.tick);
+    }
+
+    /// Returns whether a throttled event can be sent now, given when the last one of its kind was sent.
+    pub fn event_rate_allows(&self, last_sent: Option<Instant>) -> bool {
+        match (self.event_interval, last_sent) {
+            (Some(interval), Some(last_sent)) => last_sent.elapsed() >= interval,
+            _ => true,
+        }
+    }
+
+    pub fn send(&mut self, event: &Event) -> Result<()> {
+        self.write(&Outbound::Event(event.clone()))
+    }
+
+    pub fn send_sim(&mut self, event: &SimEvent) -> Result<()> {
+        self.write(&Outbound::Sim(event.clone()))
+    }
+
+    fn write(&mut self, message: &Outbound) -> Result<()> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_event(message)?;
+        }
+        self.transport.write_event(message)
+    }
+
+    /// Sends any buffered events to the frontend.
+    ///
+    /// Events are buffered so that bursts of them (like a frame's worth of draw calls) don't each need their own write.
+    /// This is called before blocking on a command, so the frontend is never left waiting on events it hasn't
+    /// received.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush()?;
+        }
+        self.transport.flush()
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<Inbound>> {
+        let cmd = self
+            .command_process_queue
+            .pop_front()
+            .map_or_else(|| self.try_recv(), |x| Ok(Some(x)))?;
+        Ok(cmd)
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<Inbound>> {
+        loop {
+            match self.transport.try_read_command() {
+                Ok(Some(Inbound::Command(Command::Handshake { .. })))
+                    if self.handshake_finished =>
+                {
+                    return ReceivedHandshakeAttemptAfterHandshakeFinishedSnafu.fail();
+                }
+                Err(err @ ProtocolError::MalformedMessage { .. }) => self.error(err.to_string())?,
+                Ok(Some(msg)) => {
+                    self.record_command(&msg)?;
+                    return Ok(Some(msg));
+                }
+                msg => return msg,
+            }
+        }
+    }
+
+    pub fn next(&mut self) -> Result<Inbound> {
+        let cmd = self
+            .command_process_queue
+            .pop_front()
+            .map_or_else(|| self.recv(), Ok)?;
+        Ok(cmd)
+    }
+
+    pub fn recv(&mut self) -> Result<Inbound> {
+        let msg = loop {
+            self.flush()?;
+            match self.transport.read_command() {
+                Err(err @ ProtocolError::MalformedMessage { .. }) => self.error(err.to_string())?,
+                msg => break msg?,
+            }
+        };
+        self.record_command(&msg)?;
+        if matches!(msg, Inbound::Command(Command::Handshake { .. })) && self.handshake_finished {
+            return ReceivedHandshakeAttemptAfterHandshakeFinishedSnafu.fail();
+        }
+        Ok(msg)
+    }
+
+    /// Like [`Protocol::recv`], but returns `None` if nothing is received before the timeout elapses.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Inbound>> {
+        let deadline = Instant::now() + timeout;
+        let msg = loop {
+            self.flush()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.transport.read_command_timeout(remaining) {
+                Err(err @ ProtocolError::MalformedMessage { .. }) => self.error(err.to_string())?,
+                msg => break msg?,
+            }
+        };
+        if let Some(msg) = &msg {
+            self.record_command(msg)?;
+        }
+        if matches!(msg, Some(Inbound::Command(Command::Handshake { .. })))
+            && self.handshake_finished
+        {
+            return ReceivedHandshakeAttemptAfterHandshakeFinishedSnafu.fail();
+        }
+        Ok(msg)
+    }
+
+    pub fn handshake(&mut self, implied: bool) -> Result<()> {
+        if self.handshake_finished {
+            panic!("Attempted to perform handshake twice");
+        }
+
+        if implied {
+            self.handshake_finished = true;
+            return Ok(());
+        }
+
+        const COMPATIBLE_PROTOCOL_VERSION: i32 = 1;
+
+        let handshake = match self.handshake_timeout {
+            Some(timeout) => match self.command_process_queue.pop_front() {
+                Some(msg) => msg,
+                None => self
+                    .recv_timeout(timeout)?
+                    .context(HandshakeTimedOutSnafu { timeout })?,
+            },
+            None => self.next()?,
+        };
+        let (version, frontend_extensions) = match handshake {
+            Inbound::Command(Command::Handshake {
+                version,
+                extensions,
+            }) => (version, extensions),
+            command => return ReceivedInvalidCommandDuringHandshakeSnafu { command }.fail(),
+        };
+
+        if version < COMPATIBLE_PROTOCOL_VERSION {
+            return IncompatibleFrontendVersionSnafu {
+                expected: COMPATIBLE_PROTOCOL_VERSION,
+                got: version,
+            }
+            .fail();
+        }
+
+        let mut extensions = self.transport.extensions();
+        if let Some(extension) = extensions
+            .iter()
+            .find(|extension| !frontend_extensions.contains(extension))
+        {
+            return MissingFrontendExtensionSnafu {
+                extension: extension.clone(),
+            }
+            .fail();
+        }
+        extensions.extend(
+            frontend_extensions
+                .iter()
+                .filter(|extension| SDK_GROUP_EXTENSIONS.contains(&extension.as_str()))
+                .cloned(),
+        );
+
+        self.send(&Event::Handshake {
+            version: COMPATIBLE_PROTOCOL_VERSION,
+            extensions: extensions.clone(),
+        })?;
+        self.flush()?;
+        self.extensions = extensions;
+
+        self.handshake_finished = true;
+
+        Ok(())
+    }
+
+    /// Blocks until a command has been received that satisfies the condition, then returns it.
+    ///
+    /// Commands that don't satisfy the condition are deferred, and will be returned by [`Protocol::next`] and
+    /// [`Protocol::try_next`] in the order they arrived. A matching command that was already deferred is returned
+    /// without waiting.
+    pub fn wait_for_command(
+        &mut self,
+        check: impl Fn(&Inbound) -> bool,
+    ) -> anyhow::Result<Inbound> {
+        if let Some(index) = self.command_process_queue.iter().position(&check) {
+            return Ok(self.command_process_queue.remove(index).unwrap());
+        }
+        loop {
+            let cmd = self.recv()?;
+            if check(&cmd) {
+                return Ok(cmd);
+            } else {
+                self.command_process_queue.push_back(cmd);
+            }
+        }
+    }
+
+    /// Like [`Protocol::wait_for_command`], but returns `None` if no matching command is received before the timeout
+    /// elapses.
+    pub fn wait_for_command_timeout(
+        &mut self,
+        check: impl Fn(&Inbound) -> bool,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<Inbound>> {
+        if let Some(index) = self.command_process_queue.iter().position(&check) {
+            return Ok(self.command_process_queue.remove(index));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(cmd) = self.recv_timeout(remaining)? else {
+                return Ok(None);
+            };
+            if check(&cmd) {
+                return Ok(Some(cmd));
+            } else {
+                self.command_process_queue.push_back(cmd);
+            }
+        }
+    }
+}
+
+/// Writes a message directly to stdout, for when the [`Protocol`] instance isn't available.
+///
+/// Errors are ignored because this is only used while the simulator is already going down.
+pub fn write_to_stdout(message: &impl Serialize) {
+    let mut stdout = stdout().lock();
+    _ = jsonl::write(&mut stdout, message);
+    _ = stdout.flush();
+}
+
+/// Installs a panic hook that reports panics to the frontend as error log events, then exits the process.
+///
+/// Without this, a panic in a host function would unwind the simulator thread and the frontend would never find out
+/// why the simulator stopped.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let location = info
+            .location()
+            .map(|location| format!(" at {location}"))
+            .unwrap_or_default();
+
+        write_to_stdout(&Event::Log {
+            level: LogLevel::Error,
+            message: format!("The simulator panicked{location}: {payload}"),
+        });
+
+        default_hook(info);
+        std::process::exit(101);
+    }));
+}
+
+pub trait Log {
+    fn log(&mut self, level: LogLevel, message: String) -> Result<()>;
+    fn trace(&mut self, message: impl Into<String>) -> Result<()> {
+        self.log(LogLevel::Trace, message.into())
+    }
+    fn info(&mut self, message: impl Into<String>) -> Result<()> {
+        self.log(LogLevel::Info, message.into())
+    }
+    fn warn(&mut self, message: impl Into<String>) -> Result<()> {
+        self.log(LogLevel::Warn, message.into())
+    }
+    fn error(&mut self, message: impl Into<String>) -> Result<()> {
+        self.log(LogLevel::Error, message.into())
+    }
+}
+
+impl Log for Protocol {
+    fn log(&mut self, level: LogLevel, message: String) -> Result<()> {
+        if !self.log_filter.allows(&level) {
+            return Ok(());
+        }
+        self.send(&Event::Log { level, message })
+    }
+}
+
+/// The result of checking a diagnostic against a [`RepeatLimiter`].
+pub enum Repeated {
+    /// This is the first time the diagnostic has been seen, so it should be reported in full.
+    First,
+    /// The diagnostic has been repeated this many times since it was last reported.
+    Summary(u64),
+    /// The diagnostic was reported recently and should be suppressed.
+    Suppressed,
+}
+
+/// Where a diagnostic was raised in the simulator's source, which is what [`RepeatLimiter`] groups diagnostics by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CallSite {
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+    /// Tells apart diagnostics raised from the same place on behalf of different things, such as each unimplemented
+    /// SDK function.
+    pub key: u64,
+}
+
+/// A diagnostic that [`RepeatLimiter`] has seen, and how often it's been repeated since it was last reported.
+struct RepeatState {
+    level: LogLevel,
+    /// The most recent message raised from the call site.
+    message: String,
+    count: u64,
+    last_report: Instant,
+}
+
+/// Collapses repeated diagnostics so that a program hitting the same bad SDK call in a loop doesn't flood the
+/// protocol with identical backtraces.
+///
+/// Diagnostics are considered the same if they were raised from the same place in the simulator, even if their
+/// messages differ, so a message that includes the program's arguments is still collapsed. Call sites are fixed when
+/// the simulator is compiled, so only a bounded number of diagnostics are ever tracked.
+#[derive(Default)]
+pub struct RepeatLimiter {
+    seen: HashMap<CallSite, RepeatState>,
+}
+
+impl RepeatLimiter {
+    /// How often a summary of a repeated diagnostic is reported.
+    const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+    /// The most call sites that are tracked. Diagnostics from any more are always reported in full.
+    const MAX_CALL_SITES: usize = 1024;
+
+    pub fn check(&mut self, site: CallSite, level: LogLevel, message: &str) -> Repeated {
+        let Some(state) = self.seen.get_mut(&site) else {
+            if self.seen.len() < Self::MAX_CALL_SITES {
+                let state = RepeatState {
+                    level,
+                    message: message.to_owned(),
+                    count: 0,
+                    last_report: Instant::now(),
+                };
+                self.seen.insert(site, state);
+            }
+            return Repeated::First;
+        };
+
+        state.count += 1;
+        if state.last_report.elapsed() < Self::SUMMARY_INTERVAL {
+            state.message.clear();
+            state.message.push_str(message);
+            return Repeated::Suppressed;
+        }
+        state.last_report = Instant::now();
+        Repeated::Summary(std::mem::take(&mut state.count))
+    }
+
+    /// Takes a summary of every diagnostic that was suppressed since it was last reported, so the counts aren't lost
+    /// when the program exits.
+    pub fn take_pending(&mut self) -> Vec<(LogLevel, String)> {
+        self.seen
+            .values_mut()
+            .filter(|state| state.count > 0)
+            .map(|state| {
+                let count = std::mem::take(&mut state.count);
+                let message = format!("{} (repeated {count} times)", state.message);
+                (state.level.clone(), message)
+            })
+            .collect()
+    }
+}
+
+/// Logs a diagnostic raised by the program along with a backtrace, collapsing repeats with [`RepeatLimiter`].
+///
+/// The backtrace is only captured when the diagnostic is reported in full, since capturing one walks the whole stack.
+macro_rules! log_bt {
+    ($ctx:expr, $level:expr, $key:expr, $($arg:tt)*) => {{
+        let site = $crate::protocol::CallSite {
+            file: file!(),
+            line: line!(),
+            column: column!(),
+            key: $key,
+        };
+        let message = format!($($arg)*);
+        match $ctx.data_mut().check_repeated(site, $level, &message) {
+            $crate::protocol::Repeated::First => {
+                let bt = wasmtime::WasmBacktrace::capture(&$ctx);
+                $ctx.data_mut().log($level, message)?;
+                $ctx.data_mut().log($level, bt.to_string())?;
+            }
+            $crate::protocol::Repeated::Summary(count) => {
+                $ctx.data_mut().log($level, format!("{message} (repeated {count} times)"))?;
+            }
+            $crate::protocol::Repeated::Suppressed => {}
+        }
+        Ok::<(), anyhow::Error>(())
+    }};
+}
+
+pub(crate) use log_bt;
+
+/// Logs a warning with a backtrace. Warnings raised from the same place on behalf of different things can be told
+/// apart with `key = <u64>`.
+macro_rules! warn_bt {
+    ($ctx:expr, key = $key:expr, $($arg:tt)*) => {
+        $crate::protocol::log_bt!($ctx, vexide_simulator_protocol::LogLevel::Warn, $key, $($arg)*)
+    };
+    ($ctx:expr, $($arg:tt)*) => {
+        $crate::protocol::log_bt!($ctx, vexide_simulator_protocol::LogLevel::Warn, 0, $($arg)*)
+    };
+}
+
+pub(crate) use warn_bt;
+
+macro_rules! error_bt {
+    ($ctx:expr, $($arg:tt)*) => {
+        $crate::protocol::log_bt!($ctx, vexide_simulator_protocol::LogLevel::Error, 0, $($arg)*)
+    };
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+
+use super::{
+    Inbound, IoSnafu, Outbound, ProtocolFormat, RecvWorkerStoppedSnafu, Result, Transport,
+};
+
+/// A message exchanged with the frontend, as written to a recording.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedMessage<'a> {
+    Command(&'a Inbound),
+    Event(&'a Outbound),
+}
+
+/// A line of a recording. `elapsed_us` is the wall time since the recording started, for reference, and `tick` is
+/// how many times the program had yielded to the simulator when a command was received.
+#[derive(Serialize)]
+struct RecordedLine<'a> {
+    elapsed_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tick: Option<u64>,
+    #[serde(flatten)]
+    message: RecordedMessage<'a>,
+}
+
+/// A line of a recording, as read back for replaying. Events are skipped since only commands are replayed.
+#[derive(Deserialize)]
+struct ReplayedLine {
+    #[serde(default)]
+    tick: u64,
+    #[serde(default)]
+    command: Option<Inbound>,
+}
+
+/// Writes every message exchanged with the frontend to a JSONL log, so the session can be replayed later with a
+/// [`ReplayTransport`].
+pub struct Recorder {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: BufWriter::new(Box::new(writer)),
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, message: RecordedMessage, tick: Option<u64>) -> Result<()> {
+        let line = RecordedLine {
+            elapsed_us: self.start.elapsed().as_micros() as u64,
+            tick,
+            message,
+        };
+        ProtocolFormat::Json.write(&mut self.writer, &line)
+    }
+
+    /// Records a command along with how many times the program had yielded to the simulator when it was received.
+    pub fn record_command(&mut self, command: &Inbound, tick: u64) -> Result<()> {
+        self.record(RecordedMessage::Command(command), Some(tick))
+    }
+
+    pub fn record_event(&mut self, event: &Outbound) -> Result<()> {
+        self.record(RecordedMessage::Event(event), None)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().context(IoSnafu)
+    }
+}
+
+/// Feeds the commands from a recording made with [`Recorder`] to the simulator instead of reading them from a
+/// frontend, and sends events over another transport.
+///
+/// Commands become available to polling once the program has yielded to the simulator as many times as it had when
+/// they were recorded, so they reach the program at the same point of its run however fast the host is. Blocking
+/// reads return the next one immediately. Once every command has been replayed, the transport behaves as if the
+/// frontend had disconnected. Commands sent over the other transport are ignored.
+pub struct ReplayTransport {
+    commands: VecDeque<(u64, Inbound)>,
+    tick: u64,
+    events: Box<dyn Transport>,
+}
+
+impl ReplayTransport {
+    /// Reads the commands from a recording, failing if any line of it is malformed.
+    pub fn new(mut recording: impl BufRead, events: impl Transport + 'static) -> Result<Self> {
+        let mut commands = VecDeque::new();
+        while let Some(line) = ProtocolFormat::Json.read::<ReplayedLine>(&mut recording)? {
+            if let Some(command) = line.command {
+                commands.push_back((line.tick, command));
+            }
+        }
+        Ok(Self {
+            commands,
+            tick: 0,
+            events: Box::new(events),
+        })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read_command(&mut self) -> Result<Inbound> {
+        let (_, command) = self.commands.pop_front().context(RecvWorkerStoppedSnafu)?;
+        Ok(command)
+    }
+
+    fn try_read_command(&mut self) -> Result<Option<Inbound>> {
+        match self.commands.front() {
+            Some((tick, _)) if *tick <= self.tick => {
+                Ok(self.commands.pop_front().map(|(_, command)| command))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn read_command_timeout(&mut self, _timeout: Duration) -> Result<Option<Inbound>> {
+        self.read_command().map(Some)
+    }
+
+    fn write_event(&mut self, message: &Outbound) -> Result<()> {
+        self.events.write_event(message)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.events.flush()
+    }
+
+    fn extensions(&self) -> Vec<String> {
+        self.events.extensions()
+    }
+
+    fn set_tick(&mut self, tick: u64) {
+        self.tick = tick;
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Stdout, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, RecvTimeoutError, TryRecvError},
+    time::Duration,
+};
+
+use snafu::{OptionExt, ResultExt};
+use vexide_simulator_protocol::Command;
+
+use super::{
+    Inbound, IoSnafu, Outbound, ProtocolFormat, RecvWorkerStoppedSnafu, Result, SimCommand,
+};
+
+/// A connection to a frontend that protocol messages are exchanged over.
+pub trait Transport: Send {
+    /// Blocks until the next message from the frontend is received.
+    fn read_command(&mut self) -> Result<Inbound>;
+    /// Returns the next message from the frontend, or `None` if one hasn't been received yet.
+    fn try_read_command(&mut self) -> Result<Option<Inbound>>;
+    /// Blocks until the next message from the frontend is received, or returns `None` if the timeout elapses first.
+    fn read_command_timeout(&mut self, timeout: Duration) -> Result<Option<Inbound>>;
+    /// Sends a message to the frontend. The message may be buffered until the transport is flushed.
+    fn write_event(&mut self, message: &Outbound) -> Result<()>;
+    /// Sends any buffered messages to the frontend.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// The protocol extensions the frontend has to support to use this transport.
+    fn extensions(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Tells the transport how many times the program has yielded to the simulator. Only replays use this, to release
+    /// commands at the same point of the program's run as they were recorded.
+    fn set_tick(&mut self, _tick: u64) {}
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read_command(&mut self) -> Result<Inbound> {
+        (**self).read_command()
+    }
+
+    fn try_read_command(&mut self) -> Result<Option<Inbound>> {
+        (**self).try_read_command()
+    }
+
+    fn read_command_timeout(&mut self, timeout: Duration) -> Result<Option<Inbound>> {
+        (**self).read_command_timeout(timeout)
+    }
+
+    fn write_event(&mut self, message: &Outbound) -> Result<()> {
+        (**self).write_event(message)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn extensions(&self) -> Vec<String> {
+        (**self).extensions()
+    }
+
+    fn set_tick(&mut self, tick: u64) {
+        (**self).set_tick(tick)
+    }
+}
+
+type InboundReceiver = mpsc::Receiver<Result<Inbound>>;
+
+/// Reads messages on a new thread and forwards them to the returned channel. The process exits when the stream ends.
+fn spawn_reader(
+    format: ProtocolFormat,
+    mut reader: impl BufRead + Send + 'static,
+) -> InboundReceiver {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || loop {
+        let msg = match format.read(&mut reader) {
+            Ok(Some(msg)) => Ok(msg),
+            Ok(None) => std::process::exit(0),
+            Err(err) => Err(err),
+        };
+
+        if tx.send(msg).is_err() {
+            break;
+        }
+    });
+    rx
+}
+
+fn recv(inbound: &InboundReceiver) -> Result<Inbound> {
+    inbound.recv().ok().context(RecvWorkerStoppedSnafu)?
+}
+
+fn recv_timeout(inbound: &InboundReceiver, timeout: Duration) -> Result<Option<Inbound>> {
+    match inbound.recv_timeout(timeout) {
+        Ok(msg) => Ok(Some(msg?)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => RecvWorkerStoppedSnafu.fail(),
+    }
+}
+
+fn try_recv(inbound: &InboundReceiver) -> Result<Option<Inbound>> {
+    match inbound.try_recv() {
+        Ok(msg) => Ok(Some(msg?)),
+        Err(TryRecvError::Empty) => Ok(None),
+        Err(TryRecvError::Disconnected) => RecvWorkerStoppedSnafu.fail(),
+    }
+}
+
+/// Exchanges messages over stdin and stdout.
+///
+/// Stdin is read on a separate thread so that commands can be polled without blocking. The process exits when stdin
+/// is closed.
+pub struct StdioTransport {
+    format: ProtocolFormat,
+    stdout: BufWriter<Stdout>,
+    inbound: InboundReceiver,
+}
+
+impl StdioTransport {
+    pub fn new(format: ProtocolFormat) -> Self {
+        Self {
+            format,
+            stdout: BufWriter::new(stdout()),
+            inbound: spawn_reader(format, BufReader::new(stdin())),
+        }
+    }
+}
+
+impl Default for StdioTransport {
+    fn default() -> Self {
+        Self::new(ProtocolFormat::default())
+    }
+}
+
+impl Transport for StdioTransport {
+    fn read_command(&mut self) -> Result<Inbound> {
+        recv(&self.inbound)
+    }
+
+    fn try_read_command(&mut self) -> Result<Option<Inbound>> {
+        try_recv(&self.inbound)
+    }
+
+    fn read_command_timeout(&mut self, timeout: Duration) -> Result<Option<Inbound>> {
+        recv_timeout(&self.inbound, timeout)
+    }
+
+    fn write_event(&mut self, message: &Outbound) -> Result<()> {
+        self.format.write(&mut self.stdout, message)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stdout.flush().context(IoSnafu)
+    }
+
+    fn extensions(&self) -> Vec<String> {
+        self.format.extensions()
+    }
+}
+
+/// Exchanges messages with a frontend connected over TCP.
+///
+/// Like [`StdioTransport`], the connection is read on a separate thread and the process exits when the frontend
+/// disconnects.
+pub struct TcpTransport {
+    format: ProtocolFormat,
+    stream: BufWriter<TcpStream>,
+    inbound: InboundReceiver,
+}
+
+impl TcpTransport {
+    /// Listens on the given address and blocks until a frontend connects.
+    pub fn listen(addr: impl ToSocketAddrs, format: ProtocolFormat) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        Self::new(stream, format)
+    }
+
+    /// Uses an existing connection to a frontend.
+    pub fn new(stream: TcpStream, format: ProtocolFormat) -> io::Result<Self> {
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self {
+            format,
+            stream: BufWriter::new(stream),
+            inbound: spawn_reader(format, reader),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read_command(&mut self) -> Result<Inbound> {
+        recv(&self.inbound)
+    }
+
+    fn try_read_command(&mut self) -> Result<Option<Inbound>> {
+        try_recv(&self.inbound)
+    }
+
+    fn read_command_timeout(&mut self, timeout: Duration) -> Result<Option<Inbound>> {
+        recv_timeout(&self.inbound, timeout)
+    }
+
+    fn write_event(&mut self, message: &Outbound) -> Result<()> {
+        self.format.write(&mut self.stream, message)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush().context(IoSnafu)
+    }
+
+    fn extensions(&self) -> Vec<String> {
+        self.format.extensions()
+    }
+}
+
+/// Exchanges messages over in-memory channels, for driving the simulator from the same process.
+pub struct ChannelTransport {
+    outbound: mpsc::Sender<Outbound>,
+    inbound: InboundReceiver,
+}
+
+impl ChannelTransport {
+    /// Creates a transport along with the handles the other side uses to send commands and receive events.
+    pub fn new() -> (Self, CommandSender, mpsc::Receiver<Outbound>) {
+        let (inbound_tx, inbound_rx) = mpsc::channel();
+        let (outbound_tx, outbound_rx) = mpsc::channel();
+        let transport = Self {
+            outbound: outbound_tx,
+            inbound: inbound_rx,
+        };
+        (transport, CommandSender { tx: inbound_tx }, outbound_rx)
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn read_command(&mut self) -> Result<Inbound> {
+        recv(&self.inbound)
+    }
+
+    fn try_read_command(&mut self) -> Result<Option<Inbound>> {
+        // Dropping every sender only means no more commands are coming. The program can keep running until it has to
+        // wait for one.
+        match self.inbound.try_recv() {
+            Ok(msg) => Ok(Some(msg?)),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+
+    fn read_command_timeout(&mut self, timeout: Duration) -> Result<Option<Inbound>> {
+        recv_timeout(&self.inbound, timeout)
+    }
+
+    fn write_event(&mut self, message: &Outbound) -> Result<()> {
+        // Nobody is listening anymore, which isn't the simulator's problem.
+        _ = self.outbound.send(message.clone());
+        Ok(())
+    }
+}
+
+/// Sends commands to a [`ChannelTransport`].
+#[derive(Debug, Clone)]
+pub struct CommandSender {
+    tx: mpsc::Sender<Result<Inbound>>,
+}
+
+impl CommandSender {
+    /// Queues a command. Returns false if the simulator has stopped listening.
+    pub fn send(&self, command: Command) -> bool {
+        self.tx.send(Ok(Inbound::Command(command))).is_ok()
+    }
+
+    /// Queues a simulator-specific command. Returns false if the simulator has stopped listening.
+    pub fn send_sim(&self, command: SimCommand) -> bool {
+        self.tx.send(Ok(Inbound::Sim(command))).is_ok()
+    }
+}
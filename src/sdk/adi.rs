@@ -0,0 +1,209 @@
+use anyhow::bail;
+use wasmtime::*;
+
+use crate::{
+    protocol::{warn_bt, Log},
+    sdk::SdkState,
+};
+
+use super::JumpTableBuilder;
+
+// MARK: Constants
+
+/// `vex-sdk` excerpt.
+mod constants {
+    #![allow(non_camel_case_types)]
+    #![allow(non_upper_case_globals)]
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct V5_AdiPortConfiguration(pub core::ffi::c_uint);
+
+    impl V5_AdiPortConfiguration {
+        pub const kAdiPortTypeQuadEncoder: Self = Self(14);
+        pub const kAdiPortTypeSonar: Self = Self(15);
+        pub const kAdiPortTypeUndefined: Self = Self(255);
+    }
+}
+
+use constants::*;
+
+// MARK: Jump table
+
+/// Get the ADI expander referred to by a device handle.
+fn expander<'a>(caller: &'a mut Caller<'_, SdkState>, device: u32) -> Result<&'a mut AdiExpander> {
+    caller.data_mut().devices.adi(device)
+}
+
+pub fn build_adi_jump_table(_memory: Memory, builder: &mut JumpTableBuilder) {
+    builder.insert(
+        0x208,
+        "vexDeviceAdiPortConfigSet",
+        move |mut caller: Caller<'_, SdkState>,
+              device: u32,
+              port: u32,
+              config: u32|
+              -> Result<()> {
+            let config = V5_AdiPortConfiguration(config);
+            if let Err(err) = expander(&mut caller, device)?.configure(port, config) {
+                warn_bt!(caller, "vexDeviceAdiPortConfigSet: {err}")?;
+            }
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x20c,
+        "vexDeviceAdiPortConfigGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, port: u32| -> Result<u32> {
+            Ok(expander(&mut caller, device)?.port(port)?.config.0)
+        },
+    );
+
+    builder.insert(
+        0x210,
+        "vexDeviceAdiValueSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, port: u32, value: i32| -> Result<()> {
+            expander(&mut caller, device)?.port_mut(port)?.value = value;
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x214,
+        "vexDeviceAdiValueGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, port: u32| -> Result<i32> {
+            Ok(expander(&mut caller, device)?.port(port)?.value)
+        },
+    );
+}
+
+// MARK: API
+
+/// The number of three-wire ports on an ADI expander (or the brain's built-in ADI ports).
+pub const ADI_PORTS: usize = 8;
+/// The smart port that the brain's built-in three-wire ports are read through.
+pub const BRAIN_ADI_PORT: u32 = 22;
+/// The voltage of an analog input at the top of its range.
+const ANALOG_MAX_VOLTAGE: f64 = 5.0;
+/// The reading of an analog input at [`ANALOG_MAX_VOLTAGE`].
+const ANALOG_MAX_VALUE: i32 = 4095;
+
+/// The reading of an analog input at the given voltage, clamped to the input's range.
+pub fn analog_value(voltage: f64) -> i32 {
+    let value = (voltage / ANALOG_MAX_VOLTAGE * f64::from(ANALOG_MAX_VALUE)).round();
+    value.clamp(0.0, f64::from(ANALOG_MAX_VALUE)) as i32
+}
+
+/// One of the three-wire ports on an ADI expander.
+#[derive(Debug, Clone, Copy)]
+pub struct AdiPort {
+    pub config: V5_AdiPortConfiguration,
+    /// The port's reading, or for the first port of a quadrature encoder or ultrasonic rangefinder, the encoder's tick
+    /// count or the distance in millimeters.
+    pub value: i32,
+}
+
+impl Default for AdiPort {
+    fn default() -> Self {
+        Self {
+            config: V5_AdiPortConfiguration::kAdiPortTypeUndefined,
+            value: 0,
+        }
+    }
+}
+
+/// The three-wire ports of an ADI expander plugged into a smart port.
+///
+/// Quadrature encoders and ultrasonic rangefinders use two adjacent ports, starting at an odd-numbered port (A, C, E
+/// or G). Both ports are configured as the device, and it's read and written through the first one.
+#[derive(Debug, Clone, Default)]
+pub struct AdiExpander {
+    ports: [AdiPort; ADI_PORTS],
+}
+
+impl AdiExpander {
+    pub fn port(&self, port: u32) -> Result<&AdiPort> {
+        match self.ports.get(port as usize) {
+            Some(port) => Ok(port),
+            None => bail!("Invalid ADI port {port}"),
+        }
+    }
+
+    pub fn port_mut(&mut self, port: u32) -> Result<&mut AdiPort> {
+        match self.ports.get_mut(port as usize) {
+            Some(port) => Ok(port),
+            None => bail!("Invalid ADI port {port}"),
+        }
+    }
+
+    /// Changes what's plugged into a port.
+    ///
+    /// Devices that use two ports reserve the next port as well, and replacing one frees its second port. Fails
+    /// without changing anything if the new device would overlap another one.
+    ///
+    /// Readings come from the frontend, so they're kept as they are. Programs reset encoders by setting their value.
+    pub fn configure(&mut self, port: u32, config: V5_AdiPortConfiguration) -> Result<()> {
+        let index = port as usize;
+        if index >= ADI_PORTS {
+            bail!("Invalid ADI port {port}");
+        }
+        if index % 2 != 0 && uses_two_ports(self.ports[index - 1].config) {
+            bail!(
+                "port {} is the second port of the {} on port {}",
+                port_letter(index),
+                describe(self.ports[index - 1].config),
+                port_letter(index - 1)
+            );
+        }
+
+        let replaced = self.ports[index].config;
+        if uses_two_ports(config) {
+            if index % 2 != 0 {
+                bail!(
+                    "a {} must start on port A, C, E or G, but got port {}",
+                    describe(config),
+                    port_letter(index)
+                );
+            }
+            let next = self.ports[index + 1].config;
+            if next != V5_AdiPortConfiguration::kAdiPortTypeUndefined && !uses_two_ports(replaced) {
+                bail!(
+                    "a {} on port {} needs port {} as well, but it's configured as a {}",
+                    describe(config),
+                    port_letter(index),
+                    port_letter(index + 1),
+                    describe(next)
+                );
+            }
+            self.ports[index + 1].config = config;
+        } else if uses_two_ports(replaced) {
+            self.ports[index + 1].config = V5_AdiPortConfiguration::kAdiPortTypeUndefined;
+        }
+        self.ports[index].config = config;
+        Ok(())
+    }
+}
+
+/// Whether the devices configured this way use two adjacent ports.
+fn uses_two_ports(config: V5_AdiPortConfiguration) -> bool {
+    matches!(
+        config,
+        V5_AdiPortConfiguration::kAdiPortTypeQuadEncoder
+            | V5_AdiPortConfiguration::kAdiPortTypeSonar
+    )
+}
+
+/// The letter printed on the brain or expander next to a port, from A to H.
+fn port_letter(index: usize) -> char {
+    char::from(b'A' + index as u8)
+}
+
+/// A readable name for a port configuration, for use in warnings.
+fn describe(config: V5_AdiPortConfiguration) -> String {
+    match config {
+        V5_AdiPortConfiguration::kAdiPortTypeQuadEncoder => "quadrature encoder".to_string(),
+        V5_AdiPortConfiguration::kAdiPortTypeSonar => "ultrasonic rangefinder".to_string(),
+        V5_AdiPortConfiguration(config) => format!("port type {config}"),
+    }
+}
@@ -0,0 +1,125 @@
+use std::{collections::HashMap, time::Duration};
+
+use vexide_simulator_protocol::CompetitionMode;
+use wasmtime::*;
+
+use crate::{
+    protocol::{Log, SimEvent},
+    sdk::SdkState,
+};
+
+use super::device::Devices;
+
+/// A copy of the program's memory and the simulator state that goes with it.
+struct Checkpoint {
+    memory: Vec<u8>,
+    elapsed: Duration,
+    competition_mode: CompetitionMode,
+    devices: Devices,
+}
+
+enum CheckpointRequest {
+    Take,
+    Restore(u32),
+    Drop(u32),
+}
+
+/// The most checkpoints that can be saved at once, since each one is a copy of the program's whole memory.
+const MAX_CHECKPOINTS: usize = 16;
+
+/// Checkpoints taken at the frontend's request, which can later be restored to rewind the program.
+///
+/// Requests are queued when the commands are received and carried out by [`apply_checkpoint_requests`], because
+/// copying memory needs access to the store.
+#[derive(Default)]
+pub struct Checkpoints {
+    saved: HashMap<u32, Checkpoint>,
+    next_id: u32,
+    requests: Vec<CheckpointRequest>,
+}
+
+impl Checkpoints {
+    /// Queues a checkpoint to be taken the next time the program yields to the simulator.
+    pub fn request_snapshot(&mut self) {
+        self.requests.push(CheckpointRequest::Take);
+    }
+
+    /// Queues the checkpoint with the given ID to be restored the next time the program yields to the simulator.
+    pub fn request_restore(&mut self, id: u32) {
+        self.requests.push(CheckpointRequest::Restore(id));
+    }
+
+    /// Queues the checkpoint with the given ID to be deleted the next time the program yields to the simulator.
+    pub fn request_drop(&mut self, id: u32) {
+        self.requests.push(CheckpointRequest::Drop(id));
+    }
+}
+
+/// Takes and restores the checkpoints requested since the last call.
+///
+/// The program's memory is restored, along with simulated time, the competition mode and connected devices. The
+/// display, serial buffers and controller inputs are left as they are, and any memory the program grew after the
+/// checkpoint was taken is zeroed. WASM globals and the call stack aren't part of a checkpoint, so they're left as they
+/// are too.
+pub fn apply_checkpoint_requests(mut store: impl AsContextMut<Data = SdkState>) -> Result<()> {
+    let mut store = store.as_context_mut();
+    let requests = std::mem::take(&mut store.data_mut().checkpoints.requests);
+    if requests.is_empty() {
+        return Ok(());
+    }
+    let Some(memory) = store.data().memory else {
+        store
+            .data_mut()
+            .warn("Ignoring checkpoint requests received before the program was loaded")?;
+        return Ok(());
+    };
+
+    for request in requests {
+        match request {
+            CheckpointRequest::Take => {
+                let (bytes, state) = memory.data_and_store_mut(&mut store);
+                if state.checkpoints.saved.len() >= MAX_CHECKPOINTS {
+                    state.warn(format!(
+                        "Ignoring snapshot request because {MAX_CHECKPOINTS} checkpoints are already saved"
+                    ))?;
+                    continue;
+                }
+                let checkpoint = Checkpoint {
+                    memory: bytes.to_vec(),
+                    elapsed: state.clock.elapsed(),
+                    competition_mode: state.competition_mode,
+                    devices: state.devices.clone(),
+                };
+                let id = state.checkpoints.next_id;
+                state.checkpoints.next_id += 1;
+                state.checkpoints.saved.insert(id, checkpoint);
+                state.protocol.send_sim(&SimEvent::SnapshotTaken { id })?;
+            }
+            CheckpointRequest::Restore(id) => {
+                let (bytes, state) = memory.data_and_store_mut(&mut store);
+                let Some(checkpoint) = state.checkpoints.saved.get(&id) else {
+                    state.warn(format!(
+                        "Ignoring request to restore unknown checkpoint {id}"
+                    ))?;
+                    continue;
+                };
+                let (restored, grown) = bytes.split_at_mut(checkpoint.memory.len());
+                restored.copy_from_slice(&checkpoint.memory);
+                grown.fill(0);
+
+                let competition_mode = checkpoint.competition_mode;
+                state.clock.set_elapsed(checkpoint.elapsed);
+                state.devices = checkpoint.devices.clone();
+                state.set_competition_mode(competition_mode)?;
+                state.protocol.send_sim(&SimEvent::Restored { id })?;
+            }
+            CheckpointRequest::Drop(id) => {
+                let state = store.data_mut();
+                if state.checkpoints.saved.remove(&id).is_none() {
+                    state.warn(format!("Ignoring request to drop unknown checkpoint {id}"))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
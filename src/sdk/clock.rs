@@ -0,0 +1,93 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use wasmtime_wasi::{HostMonotonicClock, HostWallClock};
+
+/// Tracks how much simulated time has passed since the program started.
+///
+/// Simulated time can be made to run faster or slower than wall time. Changing the rate doesn't make the elapsed time
+/// jump, because the time elapsed so far is saved whenever the rate changes.
+///
+/// Clones share the same underlying clock, so the WASI context can read the same time as the SDK.
+#[derive(Clone)]
+pub struct SimClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+struct ClockState {
+    /// The wall time at which `base` was recorded.
+    anchor: Instant,
+    /// The simulated time that had elapsed at `anchor`.
+    base: Duration,
+    scale: f64,
+}
+
+impl ClockState {
+    fn elapsed(&self) -> Duration {
+        self.base + self.anchor.elapsed().mul_f64(self.scale)
+    }
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClockState {
+                anchor: Instant::now(),
+                base: Duration::ZERO,
+                scale: 1.0,
+            })),
+        }
+    }
+
+    /// Returns the amount of simulated time that has passed.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed()
+    }
+
+    /// Sets how many times faster than wall time simulated time passes. A scale of 0 pauses simulated time.
+    ///
+    /// The scale must be finite and non-negative.
+    pub fn set_scale(&self, scale: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.base = state.elapsed();
+        state.anchor = Instant::now();
+        state.scale = scale;
+    }
+
+    /// Sets the amount of simulated time that has passed, keeping the current scale.
+    pub fn set_elapsed(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.base = elapsed;
+        state.anchor = Instant::now();
+    }
+
+    /// Returns whether simulated time is paused.
+    pub fn paused(&self) -> bool {
+        self.state.lock().unwrap().scale == 0.0
+    }
+}
+
+/// Lets WASI programs read simulated time instead of the host's monotonic clock.
+impl HostMonotonicClock for SimClock {
+    fn resolution(&self) -> u64 {
+        1
+    }
+
+    fn now(&self) -> u64 {
+        self.elapsed().as_nanos() as u64
+    }
+}
+
+/// Lets WASI programs read simulated time instead of the host's wall clock. The simulated wall clock starts at the Unix
+/// epoch when the program starts.
+impl HostWallClock for SimClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> Duration {
+        self.elapsed()
+    }
+}
@@ -5,7 +5,10 @@ use sdl2::joystick::Guid;
 use vexide_simulator_protocol::{ControllerState, ControllerUpdate};
 use wasmtime::*;
 
-use crate::sdk::SdkState;
+use crate::{
+    protocol::{warn_bt, ControllerAxis, ControllerButton, ControllerScreenState, Log, SimEvent},
+    sdk::{clone_c_string, MemoryExt, SdkState},
+};
 
 use super::JumpTableBuilder;
 
@@ -88,9 +91,9 @@ mod constants {
 pub fn build_controller_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
     use constants::*;
 
-    // vexControllerGet
     builder.insert(
         0x1a4,
+        "vexControllerGet",
         move |mut caller: Caller<'_, SdkState>, id: u32, index: u32| -> Result<i32> {
             let index = V5_ControllerIndex(index);
 
@@ -101,11 +104,25 @@ pub fn build_controller_jump_table(memory: Memory, builder: &mut JumpTableBuilde
                 .context("Invalid controller id")?;
             if let Some(controller) = controller {
                 let states = controller.current_state;
+                let axis = match index {
+                    V5_ControllerIndex::AnaLeftX => Some(states.axis1),
+                    V5_ControllerIndex::AnaLeftY => Some(states.axis2),
+                    V5_ControllerIndex::AnaRightX => Some(states.axis4),
+                    V5_ControllerIndex::AnaRightY => Some(states.axis3),
+                    _ => None,
+                };
+                if let Some(value) = axis {
+                    // Frontends may send raw values, but programs expect axes to stay within the SDK's range.
+                    let clamped = value.clamp(-127, 127);
+                    if clamped != value {
+                        warn_bt!(
+                            caller,
+                            "vexControllerGet: axis value {value} is out of range, reporting {clamped} instead"
+                        )?;
+                    }
+                    return Ok(clamped);
+                }
                 match index {
-                    V5_ControllerIndex::AnaLeftX => Ok(states.axis1),
-                    V5_ControllerIndex::AnaLeftY => Ok(states.axis2),
-                    V5_ControllerIndex::AnaRightX => Ok(states.axis4),
-                    V5_ControllerIndex::AnaRightY => Ok(states.axis3),
                     V5_ControllerIndex::ButtonL1 => Ok(states.button_l1 as i32),
                     V5_ControllerIndex::ButtonL2 => Ok(states.button_l2 as i32),
                     V5_ControllerIndex::ButtonR1 => Ok(states.button_r1 as i32),
@@ -131,17 +148,173 @@ pub fn build_controller_jump_table(memory: Memory, builder: &mut JumpTableBuilde
         },
     );
 
-    // vexControllerConnectionStatusGet
     builder.insert(
         0x1a8,
+        "vexControllerConnectionStatusGet",
         move |mut caller: Caller<'_, SdkState>, id: u32| -> Result<i32> {
             caller.data_mut().inputs.connected(id).map(|c| c as i32)
         },
     );
+
+    builder.insert(
+        0x1ac,
+        "vexControllerTextSet",
+        move |mut caller: Caller<'_, SdkState>,
+              id: u32,
+              line: u32,
+              col: u32,
+              buf: u32|
+              -> Result<u32> {
+            let text = clone_c_string!(buf as usize, from caller using memory);
+            if let Some(err) = caller.data_mut().inputs.screen(id).err() {
+                warn_bt!(caller, "vexControllerTextSet: {err}")?;
+                return Ok(0);
+            }
+
+            // Rumble patterns are sent through the same function as text, on line 0, so they're never drawn on the
+            // screen.
+            if line == 0 {
+                if text.is_empty() {
+                    warn_bt!(caller, "vexControllerTextSet: empty rumble pattern")?;
+                    return Ok(0);
+                }
+                if let Some(invalid) = text.chars().find(|c| !matches!(c, '.' | '-' | ' ')) {
+                    warn_bt!(
+                        caller,
+                        "vexControllerTextSet: invalid character {invalid:?} in rumble pattern {text:?}"
+                    )?;
+                    return Ok(0);
+                }
+                caller
+                    .data_mut()
+                    .protocol
+                    .send_sim(&SimEvent::ControllerRumble { id, pattern: text })?;
+                return Ok(1);
+            }
+
+            // Lines and columns are numbered from 1 in the SDK.
+            if !(1..=CONTROLLER_SCREEN_LINES as u32).contains(&line)
+                || !(1..=CONTROLLER_SCREEN_COLUMNS as u32).contains(&col)
+            {
+                warn_bt!(
+                    caller,
+                    "vexControllerTextSet: line {line}, column {col} is off the controller screen"
+                )?;
+                return Ok(0);
+            }
+
+            let sdk = caller.data_mut();
+            let screen = sdk.inputs.screen(id)?;
+            screen.write(line as usize - 1, col as usize - 1, &text);
+            let text = screen.line(line as usize - 1).to_owned();
+            sdk.protocol
+                .send_sim(&SimEvent::ControllerScreen { id, line, text })?;
+            Ok(1)
+        },
+    );
 }
 
 // MARK: API
 
+/// The number of lines of text on a controller's screen.
+pub const CONTROLLER_SCREEN_LINES: usize = 3;
+/// The number of characters that fit on each line of a controller's screen.
+pub const CONTROLLER_SCREEN_COLUMNS: usize = 19;
+
+/// The text shown on a controller's screen.
+///
+/// The SDK has no function to clear the screen, so programs clear it by writing blank lines.
+#[derive(Debug, Clone, Default)]
+pub struct ControllerScreen {
+    lines: [String; CONTROLLER_SCREEN_LINES],
+}
+
+impl ControllerScreen {
+    /// Overwrites part of a line, starting at the given column. Text that doesn't fit on the line is cut off.
+    pub fn write(&mut self, line: usize, col: usize, text: &str) {
+        let mut chars: Vec<char> = self.lines[line].chars().collect();
+        if chars.len() < col {
+            chars.resize(col, ' ');
+        }
+        for (offset, c) in text
+            .chars()
+            .take(CONTROLLER_SCREEN_COLUMNS - col)
+            .enumerate()
+        {
+            match chars.get_mut(col + offset) {
+                Some(existing) => *existing = c,
+                None => chars.push(c),
+            }
+        }
+        // Trailing spaces aren't visible, so a line of spaces is stored as an empty line.
+        self.lines[line] = chars.into_iter().collect::<String>().trim_end().to_owned();
+    }
+
+    /// Returns the text on a line, without trailing spaces.
+    pub fn line(&self, line: usize) -> &str {
+        &self.lines[line]
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+/// A controller with its sticks centered and no buttons pressed.
+// TODO: use Default::default()
+fn neutral_state() -> ControllerState {
+    ControllerState {
+        axis1: 0,
+        axis2: 0,
+        axis3: 0,
+        axis4: 0,
+        button_l1: false,
+        button_l2: false,
+        button_r1: false,
+        button_r2: false,
+        button_up: false,
+        button_down: false,
+        button_left: false,
+        button_right: false,
+        button_x: false,
+        button_b: false,
+        button_y: false,
+        button_a: false,
+        button_sel: false,
+        battery_level: 0,
+        button_all: false,
+        flags: 0,
+        battery_capacity: 0,
+    }
+}
+
+/// A fixed controller state that the primary controller can start with, for testing programs without a frontend
+/// that sends controller updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ControllerPreset {
+    /// Both sticks centered and no buttons pressed.
+    AllNeutral,
+    /// Both sticks pushed all the way forward, which drives forward with tank or arcade controls.
+    FullForward,
+    /// Both sticks pulled all the way back.
+    FullReverse,
+}
+
+impl ControllerPreset {
+    pub fn state(self) -> ControllerState {
+        let forward = match self {
+            ControllerPreset::AllNeutral => 0,
+            ControllerPreset::FullForward => 127,
+            ControllerPreset::FullReverse => -127,
+        };
+        ControllerState {
+            axis2: forward,
+            axis3: forward,
+            ..neutral_state()
+        }
+    }
+}
+
 pub struct V5Controller {
     pub current_state: ControllerState,
     pub sdl_guid: Option<Guid>,
@@ -149,6 +322,8 @@ pub struct V5Controller {
 
 pub enum SdlRequest {
     V5Controller {
+        /// The V5 controller slot being read: 0 for the primary controller and 1 for the partner.
+        id: u32,
         guid: Guid,
         response: oneshot::Sender<Result<Option<ControllerState>>>,
     },
@@ -157,13 +332,18 @@ pub enum SdlRequest {
 
 pub struct Inputs {
     controllers: [Option<V5Controller>; 2],
-    request_channel: mpsc::Sender<SdlRequest>,
+    /// The controller screens are kept separately from the controllers, since the program can write to them while the
+    /// controller is disconnected.
+    screens: [ControllerScreen; 2],
+    /// The channel used to talk to the thread that owns SDL, if there is one.
+    request_channel: Option<mpsc::Sender<SdlRequest>>,
 }
 
 impl Inputs {
-    pub fn new(request_channel: mpsc::Sender<SdlRequest>) -> Self {
+    pub fn new(request_channel: Option<mpsc::Sender<SdlRequest>>) -> Self {
         Inputs {
             controllers: Default::default(),
+            screens: Default::default(),
             request_channel,
         }
     }
@@ -186,30 +366,7 @@ impl Inputs {
                         sdl_guid: None,
                     },
                     ControllerUpdate::UUID(uuid) => V5Controller {
-                        // TODO: use Default::default()
-                        current_state: ControllerState {
-                            axis1: 0,
-                            axis2: 0,
-                            axis3: 0,
-                            axis4: 0,
-                            button_l1: false,
-                            button_l2: false,
-                            button_r1: false,
-                            button_r2: false,
-                            button_up: false,
-                            button_down: false,
-                            button_left: false,
-                            button_right: false,
-                            button_x: false,
-                            button_b: false,
-                            button_y: false,
-                            button_a: false,
-                            button_sel: false,
-                            battery_level: 0,
-                            button_all: false,
-                            flags: 0,
-                            battery_capacity: 0,
-                        },
+                        current_state: neutral_state(),
                         sdl_guid: Some(Guid::from_string(&uuid)?),
                     },
                 };
@@ -223,6 +380,49 @@ impl Inputs {
         Ok(())
     }
 
+    /// Moves one axis of a connected controller, keeping the rest of its state.
+    pub fn set_axis(&mut self, id: u32, axis: ControllerAxis, value: i32) -> Result<()> {
+        let state = self.state_mut(id)?;
+        let field = match axis {
+            ControllerAxis::Axis1 => &mut state.axis1,
+            ControllerAxis::Axis2 => &mut state.axis2,
+            ControllerAxis::Axis3 => &mut state.axis3,
+            ControllerAxis::Axis4 => &mut state.axis4,
+        };
+        *field = value;
+        Ok(())
+    }
+
+    /// Presses or releases one button of a connected controller, keeping the rest of its state.
+    pub fn set_button(&mut self, id: u32, button: ControllerButton, pressed: bool) -> Result<()> {
+        let state = self.state_mut(id)?;
+        let field = match button {
+            ControllerButton::L1 => &mut state.button_l1,
+            ControllerButton::L2 => &mut state.button_l2,
+            ControllerButton::R1 => &mut state.button_r1,
+            ControllerButton::R2 => &mut state.button_r2,
+            ControllerButton::Up => &mut state.button_up,
+            ControllerButton::Down => &mut state.button_down,
+            ControllerButton::Left => &mut state.button_left,
+            ControllerButton::Right => &mut state.button_right,
+            ControllerButton::X => &mut state.button_x,
+            ControllerButton::B => &mut state.button_b,
+            ControllerButton::Y => &mut state.button_y,
+            ControllerButton::A => &mut state.button_a,
+            ControllerButton::Sel => &mut state.button_sel,
+        };
+        *field = pressed;
+        Ok(())
+    }
+
+    /// The stored state of a connected controller, without asking SDL for a new one.
+    fn state_mut(&mut self, id: u32) -> Result<&mut ControllerState> {
+        let controller = self
+            .controller(id, true)?
+            .context("the controller isn't connected")?;
+        Ok(&mut controller.current_state)
+    }
+
     /// Returns whether the controller with the given id is connected.
     ///
     /// Fails if the id is invalid.
@@ -245,10 +445,14 @@ impl Inputs {
         if lazy {
             return Ok(Some(controller));
         }
-        if let Some(guid) = controller.sdl_guid {
+        if let (Some(guid), Some(request_channel)) = (controller.sdl_guid, &self.request_channel) {
             let (tx, rx) = oneshot::channel();
-            let request = SdlRequest::V5Controller { guid, response: tx };
-            self.request_channel.send(request).ok();
+            let request = SdlRequest::V5Controller {
+                id,
+                guid,
+                response: tx,
+            };
+            request_channel.send(request).ok();
             let res = rx.recv().map_err(|_| {
                 anyhow!("Controller request failed: main thread is not listening")
             })??;
@@ -261,16 +465,38 @@ impl Inputs {
             }
             Ok(Some(controller))
         } else {
-            // The frontend didn't provide a controller ID for updating it so we're just left with a constant controller state.
+            // The frontend didn't provide a controller ID for updating it (or SDL isn't available) so we're just left with
+            // a constant controller state.
             Ok(Some(controller))
         }
     }
 
+    /// Get the screen of the controller with the given id.
+    pub fn screen(&mut self, id: u32) -> Result<&mut ControllerScreen> {
+        self.screens
+            .get_mut(id as usize)
+            .context("Invalid controller id")
+    }
+
+    /// Returns the text on every controller's screen.
+    pub fn screens(&self) -> Vec<ControllerScreenState> {
+        self.screens
+            .iter()
+            .enumerate()
+            .map(|(id, screen)| ControllerScreenState {
+                id: id as u32,
+                lines: screen.lines().to_vec(),
+            })
+            .collect()
+    }
+
     /// Get new events from the SDL event pump and update the SDK's representation of the controller states.
     pub fn update(&mut self) -> anyhow::Result<()> {
-        self.request_channel
-            .send(SdlRequest::EventPump)
-            .map_err(|_| anyhow!("Event pump request failed: main thread is not listening"))?;
+        if let Some(request_channel) = &self.request_channel {
+            request_channel
+                .send(SdlRequest::EventPump)
+                .map_err(|_| anyhow!("Event pump request failed: main thread is not listening"))?;
+        }
 
         for index in 0..self.controllers.len() {
             self.controller(index as u32, true)?;
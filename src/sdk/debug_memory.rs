@@ -0,0 +1,98 @@
+use base64::prelude::*;
+use wasmtime::*;
+
+use crate::{
+    protocol::{Log, SimEvent},
+    sdk::SdkState,
+};
+
+enum MemoryRequest {
+    Write { addr: u32, bytes: Vec<u8> },
+    Read { addr: u32, len: u32 },
+}
+
+/// Reads and writes of the program's memory requested by the frontend, for fuzzing and debugging.
+///
+/// These are only allowed with `--debug-memory`, since they let the frontend corrupt the program. Like checkpoints,
+/// requests are queued when the commands are received and carried out by [`apply_memory_requests`].
+#[derive(Default)]
+pub struct DebugMemory {
+    enabled: bool,
+    requests: Vec<MemoryRequest>,
+}
+
+impl DebugMemory {
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Queues `bytes_base64` to be written to memory at `addr` the next time the program yields to the simulator.
+    pub fn request_write(&mut self, addr: u32, bytes_base64: &str) -> anyhow::Result<()> {
+        let bytes = BASE64_STANDARD.decode(bytes_base64)?;
+        self.requests.push(MemoryRequest::Write { addr, bytes });
+        Ok(())
+    }
+
+    /// Queues `len` bytes at `addr` to be sent in a [`SimEvent::MemoryContents`] the next time the program yields to
+    /// the simulator.
+    pub fn request_read(&mut self, addr: u32, len: u32) {
+        self.requests.push(MemoryRequest::Read { addr, len });
+    }
+}
+
+/// Carries out the memory reads and writes requested since the last call.
+///
+/// Requests that reach past the end of the program's memory are ignored with a warning.
+pub fn apply_memory_requests(mut store: impl AsContextMut<Data = SdkState>) -> Result<()> {
+    let mut store = store.as_context_mut();
+    let requests = std::mem::take(&mut store.data_mut().debug_memory.requests);
+    if requests.is_empty() {
+        return Ok(());
+    }
+    let Some(memory) = store.data().memory else {
+        store
+            .data_mut()
+            .warn("Ignoring memory requests received before the program was loaded")?;
+        return Ok(());
+    };
+
+    for request in requests {
+        let (bytes, state) = memory.data_and_store_mut(&mut store);
+        match request {
+            MemoryRequest::Write { addr, bytes: data } => {
+                let Some(range) = bytes_range(bytes.len(), addr, data.len()) else {
+                    state.warn(format!(
+                        "Ignoring write of {} bytes at {addr:#x}, which is outside the program's memory",
+                        data.len()
+                    ))?;
+                    continue;
+                };
+                bytes[range].copy_from_slice(&data);
+            }
+            MemoryRequest::Read { addr, len } => {
+                let Some(range) = bytes_range(bytes.len(), addr, len as usize) else {
+                    state.warn(format!(
+                        "Ignoring read of {len} bytes at {addr:#x}, which is outside the program's memory"
+                    ))?;
+                    continue;
+                };
+                let bytes_base64 = BASE64_STANDARD.encode(&bytes[range]);
+                state
+                    .protocol
+                    .send_sim(&SimEvent::MemoryContents { addr, bytes_base64 })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The range of `len` bytes starting at `addr`, if it fits within a memory of `size` bytes.
+fn bytes_range(size: usize, addr: u32, len: usize) -> Option<std::ops::Range<usize>> {
+    let start = addr as usize;
+    let end = start.checked_add(len)?;
+    (end <= size).then_some(start..end)
+}
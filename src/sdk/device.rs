@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use wasmtime::*;
+
+use crate::protocol::{DeviceKind, PortState};
+
+use super::{adi::AdiExpander, motor::Motor, JumpTableBuilder};
+
+// MARK: Jump table
+
+pub fn build_device_jump_table(_memory: Memory, builder: &mut JumpTableBuilder) {
+    builder.insert(
+        0x19c,
+        "vexDeviceGetByIndex",
+        move |index: u32| -> Result<u32> {
+            if index as usize >= MAX_DEVICE_PORTS {
+                bail!("Invalid device index {index}");
+            }
+            Ok(index + 1)
+        },
+    );
+}
+
+// MARK: API
+
+/// The number of device ports addressable through `vexDeviceGetByIndex`.
+pub const MAX_DEVICE_PORTS: usize = 32;
+
+/// A device plugged into one of the brain's smart ports.
+#[derive(Clone)]
+pub enum SmartDevice {
+    Motor(Motor),
+    Adi(AdiExpander),
+}
+
+impl SmartDevice {
+    /// Creates a device of the given kind, as if it had just been plugged in.
+    pub fn new(kind: DeviceKind, now: Duration) -> Self {
+        match kind {
+            DeviceKind::Motor => Self::Motor(Motor::new(now)),
+            DeviceKind::Adi => Self::Adi(AdiExpander::default()),
+        }
+    }
+
+    pub fn kind(&self) -> DeviceKind {
+        match self {
+            Self::Motor(_) => DeviceKind::Motor,
+            Self::Adi(_) => DeviceKind::Adi,
+        }
+    }
+}
+
+/// The devices connected to the simulated brain.
+///
+/// Device handles (`V5_DeviceT`) are opaque to the program, so they're encoded as the port index plus one so that a
+/// valid handle is never null.
+#[derive(Clone)]
+pub struct Devices {
+    ports: [Option<SmartDevice>; MAX_DEVICE_PORTS],
+}
+
+impl Devices {
+    pub fn new() -> Self {
+        Self {
+            ports: Default::default(),
+        }
+    }
+
+    /// Returns the port index that the given device handle refers to.
+    pub fn port_index(handle: u32) -> Result<usize> {
+        let index = handle
+            .checked_sub(1)
+            .context("Device handle must not be null")? as usize;
+        if index >= MAX_DEVICE_PORTS {
+            bail!("Invalid device handle {handle:#x}");
+        }
+        Ok(index)
+    }
+
+    /// Get the motor on the port referred to by `handle`, stepping its simulation forward to `now`.
+    ///
+    /// A motor is simulated on any empty port the program drives as a motor.
+    pub fn motor(&mut self, handle: u32, now: Duration) -> Result<&mut Motor> {
+        let index = Self::port_index(handle)?;
+        let device = self.ports[index].get_or_insert_with(|| SmartDevice::Motor(Motor::new(now)));
+        match device {
+            SmartDevice::Motor(motor) => {
+                motor.update(now);
+                Ok(motor)
+            }
+            device => bail!(
+                "Expected a motor on port {}, but it has a {:?}",
+                index + 1,
+                device.kind()
+            ),
+        }
+    }
+
+    /// Get the ADI expander on the port referred to by `handle`.
+    ///
+    /// An expander is simulated on any empty port the program uses as one, which includes the brain's built-in ADI
+    /// ports on port 22.
+    pub fn adi(&mut self, handle: u32) -> Result<&mut AdiExpander> {
+        let index = Self::port_index(handle)?;
+        let device =
+            self.ports[index].get_or_insert_with(|| SmartDevice::Adi(AdiExpander::default()));
+        match device {
+            SmartDevice::Adi(expander) => Ok(expander),
+            device => bail!(
+                "Expected an ADI expander on port {}, but it has a {:?}",
+                index + 1,
+                device.kind()
+            ),
+        }
+    }
+
+    /// Plugs a device of the given kind into a port, numbered from 1.
+    ///
+    /// A device of the same kind that's already plugged in is kept as it is. Fails if the port doesn't exist or has a
+    /// different kind of device plugged into it.
+    pub fn configure(&mut self, port: u32, kind: DeviceKind, now: Duration) -> Result<()> {
+        let index = Self::port_index(port).with_context(|| format!("Port {port} doesn't exist"))?;
+        match &self.ports[index] {
+            Some(device) if device.kind() != kind => {
+                bail!(
+                    "Port {port} already has a {:?} plugged into it",
+                    device.kind()
+                )
+            }
+            Some(_) => {}
+            None => self.ports[index] = Some(SmartDevice::new(kind, now)),
+        }
+        Ok(())
+    }
+
+    /// Lists the ports that have a device plugged into them.
+    pub fn ports(&self) -> Vec<PortState> {
+        self.ports
+            .iter()
+            .enumerate()
+            .filter_map(|(index, device)| {
+                Some(PortState {
+                    port: index as u32 + 1,
+                    device: device.as_ref()?.kind(),
+                })
+            })
+            .collect()
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     io::Cursor,
     mem::size_of,
     num::NonZeroU16,
@@ -9,7 +10,7 @@ use std::{
 use anyhow::{bail, Context};
 use base64::prelude::*;
 use bytemuck::{Pod, Zeroable};
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
 use embedded_graphics_core::{
     geometry::Dimensions,
     pixelcolor::{
@@ -29,11 +30,13 @@ use wasmtime::*;
 
 use crate::{
     printf::{output::display, WasmVaList},
-    protocol::{warn_bt, Log, Protocol},
+    protocol::{warn_bt, FontMetrics, Inbound, Log, Protocol, SimEvent},
     ProgramOptions,
 };
 
-use super::{clone_c_string, JumpTableBuilder, MemoryExt, SdkState};
+use super::{
+    clone_c_string, parsed_format, warn_clamped_printf_field, JumpTableBuilder, MemoryExt, SdkState,
+};
 
 // MARK: Jump Table
 
@@ -47,34 +50,59 @@ struct V5Image {
     pub p: u32,
 }
 
+/// Formats a `printf`-style string from the program for drawing on the display.
+fn format_text(
+    caller: &mut Caller<'_, SdkState>,
+    memory: Memory,
+    format_ptr: u32,
+    args: u32,
+) -> Result<String> {
+    let format = parsed_format(caller, memory, format_ptr)?;
+    let text = display(format, WasmVaList::new(args, memory), &*caller);
+    let data = text.to_string();
+    if text.clamped() {
+        warn_clamped_printf_field(caller)?;
+    }
+    Ok(data)
+}
+
 pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
-    // vexDisplayForegroundColor
-    builder.insert(0x640, move |mut caller: Caller<'_, SdkState>, col: u32| {
-        caller.data_mut().display.foreground_color = RGB8 {
-            r: (col >> 16) as u8,
-            g: (col >> 8) as u8,
-            b: col as u8,
-        };
-    });
-
-    // vexDisplayBackgroundColor
-    builder.insert(0x644, move |mut caller: Caller<'_, SdkState>, col: u32| {
-        caller.data_mut().display.background_color = RGB8 {
-            r: (col >> 16) as u8,
-            g: (col >> 8) as u8,
-            b: col as u8,
-        };
-    });
+    builder.insert(
+        0x640,
+        "vexDisplayForegroundColor",
+        move |mut caller: Caller<'_, SdkState>, col: u32| {
+            caller.data_mut().display.foreground_color = RGB8 {
+                r: (col >> 16) as u8,
+                g: (col >> 8) as u8,
+                b: col as u8,
+            };
+        },
+    );
 
-    // vexDisplayErase
-    builder.insert(0x648, move |mut caller: Caller<'_, SdkState>| {
-        caller.data_mut().display_ctx().erase()?;
-        Ok(())
-    });
+    builder.insert(
+        0x644,
+        "vexDisplayBackgroundColor",
+        move |mut caller: Caller<'_, SdkState>, col: u32| {
+            caller.data_mut().display.background_color = RGB8 {
+                r: (col >> 16) as u8,
+                g: (col >> 8) as u8,
+                b: col as u8,
+            };
+        },
+    );
+
+    builder.insert(
+        0x648,
+        "vexDisplayErase",
+        move |mut caller: Caller<'_, SdkState>| {
+            caller.data_mut().display_ctx().erase()?;
+            Ok(())
+        },
+    );
 
-    // vexDisplayScroll
     builder.insert(
         0x64c,
+        "vexDisplayScroll",
         move |mut caller: Caller<'_, SdkState>, n_start_line: i32, n_lines: i32| {
             caller
                 .data_mut()
@@ -84,9 +112,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayScrollRect
     builder.insert(
         0x650,
+        "vexDisplayScrollRect",
         move |mut caller: Caller<'_, SdkState>,
               x1: i32,
               y1: i32,
@@ -104,9 +132,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayCopyRect
     builder.insert(
         0x654,
+        "vexDisplayCopyRect",
         move |mut caller: Caller<'_, SdkState>,
               x1: i32,
               y1: i32,
@@ -115,55 +143,72 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
               buffer_ptr: u32,
               stride: u32|
               -> Result<()> {
-            let buffer_len = (x2 - x1) as usize * (y2 - y1) as usize * 4;
-            let buffer = memory.data(&mut caller)[buffer_ptr as usize..][..buffer_len].to_vec();
+            if stride == 0 {
+                bail!("Unexpected stride value {stride:?}");
+            }
+
+            // Only the part of the rect inside the clip region is read from the buffer, so that a rect hanging off
+            // the screen doesn't read memory past the end of the buffer.
+            let clip = caller.data().display.clip_region;
+            let left = x1.max(clip.top_left.x);
+            let top = y1.max(clip.top_left.y);
+            let right = x2.min(clip.bottom_right.x);
+            let bottom = y2.min(clip.bottom_right.y);
+            if (left, top, right, bottom) != (x1, y1, x2, y2) {
+                warn_bt!(caller, "vexDisplayCopyRect: only ({left}, {top})-({right}, {bottom}) of ({x1}, {y1})-({x2}, {y2}) is inside the clip region")?;
+            }
+            if left >= right || top >= bottom {
+                return Ok(());
+            }
+
+            let width = (right - left) as usize;
+            let mut buffer = Vec::with_capacity(width * (bottom - top) as usize * 4);
+            for y in top..bottom {
+                let offset = ((y - y1) as usize * stride as usize + (left - x1) as usize) * 4;
+                let row_ptr = u32::try_from(offset)
+                    .ok()
+                    .and_then(|offset| buffer_ptr.checked_add(offset))
+                    .context("vexDisplayCopyRect: buffer address overflowed")?;
+                buffer.extend_from_slice(memory.read_bytes(&caller, row_ptr, width * 4)?);
+            }
 
             caller.data_mut().display_ctx().draw_buffer(
                 &buffer,
-                [x1, y1],
-                [x2, y2],
-                NonZeroU16::new(stride as u16)
-                    .with_context(|| format!("Unexpected stride value {stride:?}"))?,
+                [left, top],
+                [right, bottom],
+                NonZeroU16::new(width as u16).context("Display copy is too wide")?,
             )?;
             Ok(())
         },
     );
 
-    // vexDisplayPixelSet
     builder.insert(
         0x658,
+        "vexDisplayPixelSet",
         move |mut caller: Caller<'_, SdkState>, x: i32, y: i32| {
-            caller.data_mut().display_ctx().draw(
-                Shape::Rectangle {
-                    top_left: [x, y].into(),
-                    bottom_right: [x, y].into(),
-                },
-                false,
-                false,
-            )?;
+            caller
+                .data_mut()
+                .display_ctx()
+                .draw(pixel([x, y].into()), false, false)?;
             Ok(())
         },
     );
 
-    // vexDisplayPixelClear
     builder.insert(
         0x65c,
+        "vexDisplayPixelClear",
         move |mut caller: Caller<'_, SdkState>, x: i32, y: i32| {
-            caller.data_mut().display_ctx().draw(
-                Shape::Rectangle {
-                    top_left: [x, y].into(),
-                    bottom_right: [x, y].into(),
-                },
-                false,
-                true,
-            )?;
+            caller
+                .data_mut()
+                .display_ctx()
+                .draw(pixel([x, y].into()), false, true)?;
             Ok(())
         },
     );
 
-    // vexDisplayLineDraw
     builder.insert(
         0x660,
+        "vexDisplayLineDraw",
         move |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Line {
@@ -177,9 +222,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayLineClear
     builder.insert(
         0x664,
+        "vexDisplayLineClear",
         move |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Line {
@@ -193,9 +238,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayRectDraw
     builder.insert(
         0x668,
+        "vexDisplayRectDraw",
         move |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Rectangle {
@@ -209,9 +254,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayRectClear
     builder.insert(
         0x66c,
+        "vexDisplayRectClear",
         move |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Rectangle {
@@ -225,9 +270,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayRectFill
     builder.insert(
         0x670,
+        "vexDisplayRectFill",
         move |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Rectangle {
@@ -241,9 +286,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayCircleDraw
     builder.insert(
         0x674,
+        "vexDisplayCircleDraw",
         move |mut caller: Caller<'_, SdkState>, cx: i32, cy: i32, radius: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Circle {
@@ -257,9 +302,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayCircleClear
     builder.insert(
         0x678,
+        "vexDisplayCircleClear",
         move |mut caller: Caller<'_, SdkState>, cx: i32, cy: i32, radius: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Circle {
@@ -273,9 +318,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayCircleFill
     builder.insert(
         0x67c,
+        "vexDisplayCircleFill",
         move |mut caller: Caller<'_, SdkState>, cx: i32, cy: i32, radius: i32| {
             caller.data_mut().display_ctx().draw(
                 Shape::Circle {
@@ -289,31 +334,43 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayTextSize
-    builder.insert(0x6a8, move |_u: u32, _d: u32| -> Result<()> {
-        bail!("vexDisplayTextSize is not implemented");
-    });
+    builder.insert(
+        0x6a8,
+        "vexDisplayTextSize",
+        move |_u: u32, _d: u32| -> Result<()> {
+            bail!("vexDisplayTextSize is not implemented");
+        },
+    );
 
-    // vexDisplayFontNamedSet
-    builder.insert(0x6b4, move |_name: u32| -> Result<()> {
-        bail!("vexDisplayFontNamedSet is not implemented");
-    });
+    builder.insert(
+        0x6b4,
+        "vexDisplayFontNamedSet",
+        move |_name: u32| -> Result<()> {
+            bail!("vexDisplayFontNamedSet is not implemented");
+        },
+    );
 
-    // vexDisplayForegroundColorGet
-    builder.insert(0x6b8, move |caller: Caller<'_, SdkState>| -> u32 {
-        let color = caller.data().display.foreground_color;
-        (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32
-    });
+    builder.insert(
+        0x6b8,
+        "vexDisplayForegroundColorGet",
+        move |caller: Caller<'_, SdkState>| -> u32 {
+            let color = caller.data().display.foreground_color;
+            (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32
+        },
+    );
 
-    // vexDisplayBackgroundColorGet
-    builder.insert(0x6bc, move |caller: Caller<'_, SdkState>| -> u32 {
-        let color = caller.data().display.background_color;
-        (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32
-    });
+    builder.insert(
+        0x6bc,
+        "vexDisplayBackgroundColorGet",
+        move |caller: Caller<'_, SdkState>| -> u32 {
+            let color = caller.data().display.background_color;
+            (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32
+        },
+    );
 
-    // vexDisplayStringWidthGet
     builder.insert(
         0x6c0,
+        "vexDisplayStringWidthGet",
         move |mut caller: Caller<'_, SdkState>, string_ptr: i32| {
             let string = clone_c_string!(string_ptr as usize, from caller using memory);
 
@@ -328,9 +385,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayStringHeightGet
     builder.insert(
         0x6c4,
+        "vexDisplayStringHeightGet",
         move |mut caller: Caller<'_, SdkState>, string_ptr: i32| {
             let string = clone_c_string!(string_ptr as usize, from caller using memory);
 
@@ -345,26 +402,40 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayClipRegionSet
     builder.insert(
         0x794,
+        "vexDisplayClipRegionSet",
         move |mut caller: Caller<'_, SdkState>, x1: i32, y1: i32, x2: i32, y2: i32| {
             caller.data_mut().display.set_clip_region(x1, y1, x2, y2);
             Ok(())
         },
     );
 
-    // vexDisplayRender
+    builder.insert(
+        0x798,
+        "vexDisplayClipRegionClear",
+        move |mut caller: Caller<'_, SdkState>| {
+            caller.data_mut().display.clear_clip_region();
+            Ok(())
+        },
+    );
+
     builder.insert(
         0x7a0,
+        "vexDisplayRender",
         move |mut caller: Caller<'_, SdkState>, vsync_wait: i32, run_scheduler: i32| {
             caller.data_mut().display_ctx().render()?;
-            let vsync_finish = Instant::now() + Duration::from_secs_f64(1.0 / 60.0);
+            let sdk = caller.data_mut();
             if vsync_wait != 0 {
-                let sdk = caller.data_mut();
-                while Instant::now() < vsync_finish {
-                    sleep(Duration::from_millis(1));
-                    if run_scheduler != 0 {
+                let vsync_finish = sdk.display.next_vsync(sdk.clock.elapsed());
+                loop {
+                    let now = sdk.clock.elapsed();
+                    if now >= vsync_finish {
+                        break;
+                    }
+                    sleep((vsync_finish - now).min(Duration::from_millis(1)));
+                    // Commands have to be processed while simulated time is paused, otherwise it could never resume.
+                    if run_scheduler != 0 || sdk.clock.paused() {
                         sdk.recv_all_commands()?;
                     }
                 }
@@ -373,15 +444,18 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayDoubleBufferDisable
-    builder.insert(0x7a4, move |mut caller: Caller<'_, SdkState>| {
-        caller.data_mut().display_ctx().set_double_buffered(false)?;
-        Ok(())
-    });
+    builder.insert(
+        0x7a4,
+        "vexDisplayDoubleBufferDisable",
+        move |mut caller: Caller<'_, SdkState>| {
+            caller.data_mut().display_ctx().set_double_buffered(false)?;
+            Ok(())
+        },
+    );
 
-    // vexDisplayClipRegionSetWithIndex
     builder.insert(
         0x7a8,
+        "vexDisplayClipRegionSetWithIndex",
         move |mut caller: Caller<'_, SdkState>, index: i32, x1: i32, y1: i32, x2: i32, y2: i32| {
             if index != 0 {
                 warn_bt!(caller, "vexDisplayClipRegionSetWithIndex: the only supported index is 0, but got {index:?} instead")?;
@@ -394,9 +468,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexImageBmpRead
     builder.insert(
         0x990,
+        "vexImageBmpRead",
         move |mut caller: Caller<'_, SdkState>,
               i_buf: u32,
               o_buf: u32,
@@ -412,11 +486,11 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
                 return Ok(0);
             }
 
-            let mut img = {
-                let o_buf_mem =
-                    &mut memory.data_mut(&mut caller)[o_buf as usize..][..size_of::<V5Image>()];
-                *bytemuck::from_bytes_mut::<V5Image>(o_buf_mem)
-            };
+            let mut img = *bytemuck::from_bytes::<V5Image>(memory.read_bytes(
+                &caller,
+                o_buf,
+                size_of::<V5Image>(),
+            )?);
 
             if img.data == 0 {
                 warn_bt!(caller, "vexImageBmpRead: oBuf data field must not be null")?;
@@ -424,7 +498,9 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
             }
 
             let bmp = {
-                let i_buf_mem = &memory.data(&mut caller)[i_buf as usize..];
+                // The BMP's size isn't known yet, so it may extend to the end of memory.
+                let i_buf_len = memory.data_size(&caller).saturating_sub(i_buf as usize);
+                let i_buf_mem = memory.read_bytes(&caller, i_buf, i_buf_len)?;
                 match Bmp::<Rgb888>::from_slice(i_buf_mem) {
                     Ok(bmp) => bmp.to_owned(),
                     Err(err) => {
@@ -446,7 +522,7 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
                 return Ok(0);
             }
 
-            let mut bytes = Bytes::from_iter(
+            let bytes = Bytes::from_iter(
                 bmp.pixels()
                     .flat_map(|p| RawU24::from(p.1).into_inner().to_le_bytes()),
             );
@@ -459,22 +535,23 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
             }
 
             let data_ptr = u32::from_le(img.data);
-            let out_data = &mut memory.data_mut(&mut caller)[(data_ptr as usize)..][..bytes.len()];
-            bytes.copy_to_slice(out_data);
+            if let Err(err) = memory.write_bytes(&mut caller, data_ptr, &bytes) {
+                warn_bt!(caller, "vexImageBmpRead: {err}")?;
+                return Ok(0);
+            }
 
             img.width = (size.width as u16).to_le();
             img.height = (size.height as u16).to_le();
             img.p = (data_ptr + (size.width * 4)).to_le();
 
-            memory.data_mut(&mut caller)[o_buf as usize..][..size_of::<V5Image>()]
-                .copy_from_slice(bytemuck::bytes_of(&img));
+            memory.write_bytes(&mut caller, o_buf, bytemuck::bytes_of(&img))?;
             Ok(1)
         },
     );
 
-    // vexImagePngRead
     builder.insert(
         0x994,
+        "vexImagePngRead",
         move |mut caller: Caller<'_, SdkState>,
               i_buf: u32,
               o_buf: u32,
@@ -491,18 +568,18 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
                 return Ok(0);
             }
 
-            let mut img = {
-                let o_buf_mem =
-                    &mut memory.data_mut(&mut caller)[o_buf as usize..][..size_of::<V5Image>()];
-                *bytemuck::from_bytes_mut::<V5Image>(o_buf_mem)
-            };
+            let mut img = *bytemuck::from_bytes::<V5Image>(memory.read_bytes(
+                &caller,
+                o_buf,
+                size_of::<V5Image>(),
+            )?);
 
             if img.data == 0 {
                 warn_bt!(caller, "vexImagePngRead: oBuf data field must not be null")?;
                 return Ok(0);
             }
 
-            let i_buf_mem = &memory.data(&mut caller)[i_buf as usize..][..i_buf_len as usize];
+            let i_buf_mem = memory.read_bytes(&caller, i_buf, i_buf_len as usize)?;
             let Ok(png) = PngDecoder::new(Cursor::new(i_buf_mem)) else {
                 warn_bt!(caller, "vexImagePngRead: failed to read PNG")?;
                 return Ok(0);
@@ -531,22 +608,20 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
             }
 
             let data_ptr = u32::from_le(img.data);
-            let out_data = &mut memory.data_mut(&mut caller)[(data_ptr as usize)..][..data.len()];
-            out_data.copy_from_slice(&data);
+            memory.write_bytes(&mut caller, data_ptr, &data)?;
 
             img.width = (width as u16).to_le();
             img.height = (height as u16).to_le();
 
-            memory.data_mut(&mut caller)[o_buf as usize..][..size_of::<V5Image>()]
-                .copy_from_slice(bytemuck::bytes_of(&img));
+            memory.write_bytes(&mut caller, o_buf, bytemuck::bytes_of(&img))?;
 
             Ok(1)
         },
     );
 
-    // vexDisplayVPrintf
     builder.insert(
         0x680,
+        "vexDisplayVPrintf",
         move |mut caller: Caller<'_, SdkState>,
               x_pos: i32,
               y_pos: i32,
@@ -554,9 +629,7 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
               format_ptr: u32,
               args: u32|
               -> Result<()> {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
             caller.data_mut().display_ctx().write(
                 V5Text {
@@ -573,43 +646,38 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayVString
     builder.insert(
         0x684,
+        "vexDisplayVString",
         move |mut caller: Caller<'_, SdkState>,
               line_number: i32,
               format_ptr: u32,
               args: u32|
               -> Result<()> {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
-            caller.data_mut().display_ctx().write(
+            caller.data_mut().display_ctx().write_line(
                 V5Text {
                     data,
                     font_family: Default::default(),
                     font_size: Default::default(),
                 },
-                TextLocation::Line { line: line_number },
-                true,
+                line_number,
             )?;
             Ok(())
         },
     );
 
-    // vexDisplayVStringAt
     builder.insert(
         0x688,
+        "vexDisplayVStringAt",
         move |mut caller: Caller<'_, SdkState>,
               x_pos: i32,
               y_pos: i32,
               format_ptr: u32,
               args: u32|
               -> Result<()> {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
             caller.data_mut().display_ctx().write(
                 V5Text {
@@ -626,39 +694,34 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayVBigString
     builder.insert(
         0x68c,
+        "vexDisplayVBigString",
         move |mut caller: Caller<'_, SdkState>, line_number: i32, format_ptr: u32, args: u32| {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
-            caller.data_mut().display_ctx().write(
+            caller.data_mut().display_ctx().write_line(
                 V5Text {
                     data,
                     font_family: Default::default(),
                     font_size: V5FontSize::Large,
                 },
-                TextLocation::Line { line: line_number },
-                true,
+                line_number,
             )?;
             Ok(())
         },
     );
 
-    // vexDisplayVBigStringAt
     builder.insert(
         0x690,
+        "vexDisplayVBigStringAt",
         move |mut caller: Caller<'_, SdkState>,
               x_pos: i32,
               y_pos: i32,
               format_ptr: u32,
               args: u32|
               -> Result<()> {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
             caller.data_mut().display_ctx().write(
                 V5Text {
@@ -675,18 +738,16 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    // vexDisplayVSmallStringAt
     builder.insert(
         0x6b0,
+        "vexDisplayVSmallStringAt",
         move |mut caller: Caller<'_, SdkState>,
               x_pos: i32,
               y_pos: i32,
               format_ptr: u32,
               args: u32|
               -> Result<()> {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
             caller.data_mut().display_ctx().write(
                 V5Text {
@@ -703,57 +764,37 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         },
     );
 
-    const LINE_NUM_OFFSET: i32 = 34;
-    const LINE_HEIGHT: i32 = 20;
-
-    let display_centered_text = |sdk: &mut SdkState, text: V5Text, line: i32| {
-        let size = sdk.display_ctx().get_text_metrics(text.clone())?;
-        // FIXME: why is this a usize?
-        let x = (DISPLAY_WIDTH - i32::try_from(size.width).unwrap()) / 2;
-        let y = LINE_NUM_OFFSET + line * LINE_HEIGHT;
-        sdk.display_ctx().write(
-            text,
-            TextLocation::Coordinates {
-                point: [x, y].into(),
-            },
-            true,
-        )?;
-        Ok(())
-    };
-
-    // vexDisplayVCenteredString
     builder.insert(
-        0x6b4,
+        0x694,
+        "vexDisplayVCenteredString",
         move |mut caller: Caller<'_, SdkState>, line_number: i32, format_ptr: u32, args: u32| {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
-            let sdk = caller.data_mut();
-            let text = V5Text {
-                data,
-                font_family: V5FontFamily::UserMono,
-                font_size: V5FontSize::Normal,
-            };
-            display_centered_text(sdk, text, line_number)
+            caller.data_mut().display_ctx().write_centered(
+                V5Text {
+                    data,
+                    font_family: V5FontFamily::UserMono,
+                    font_size: V5FontSize::Normal,
+                },
+                line_number,
+            )
         },
     );
 
-    // vexDisplayVBigCenteredString
     builder.insert(
-        0x6b4,
+        0x698,
+        "vexDisplayVBigCenteredString",
         move |mut caller: Caller<'_, SdkState>, line_number: i32, format_ptr: u32, args: u32| {
-            let format = memory.read_c_string(&caller, format_ptr as usize)?;
-            let va_list = WasmVaList::new(args, memory);
-            let data = display(format, va_list, &caller).to_string();
+            let data = format_text(&mut caller, memory, format_ptr, args)?;
 
-            let sdk = caller.data_mut();
-            let text = V5Text {
-                data,
-                font_family: V5FontFamily::UserMono,
-                font_size: V5FontSize::Large,
-            };
-            display_centered_text(sdk, text, line_number)
+            caller.data_mut().display_ctx().write_centered(
+                V5Text {
+                    data,
+                    font_family: V5FontFamily::UserMono,
+                    font_size: V5FontSize::Large,
+                },
+                line_number,
+            )
         },
     );
 }
@@ -763,11 +804,50 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
 pub const DISPLAY_HEIGHT: i32 = 272;
 pub const DISPLAY_WIDTH: i32 = 480;
 pub const HEADER_HEIGHT: i32 = 32;
+/// How long the display takes to refresh, at 60 frames per second.
+const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// The brightest the screen can be, as a percentage. The screen starts out this bright.
+const MAX_BRIGHTNESS: u8 = 100;
+/// The part of the display below the header, which programs draw in by default.
+const USER_AREA: Rect = Rect {
+    top_left: Point2 {
+        x: 0,
+        y: HEADER_HEIGHT,
+    },
+    bottom_right: Point2 {
+        x: DISPLAY_WIDTH,
+        y: DISPLAY_HEIGHT,
+    },
+};
+/// The y coordinate of line 0 when writing text by line number.
+const LINE_NUM_OFFSET: i32 = 34;
+const LINE_HEIGHT: i32 = 20;
 
 pub const BLACK: RGB8 = RGB8::new(0, 0, 0);
 pub const WHITE: RGB8 = RGB8::new(255, 255, 255);
 pub const HEADER_BG: RGB8 = RGB8::new(0x00, 0x99, 0xCC);
 
+/// A 1x1 rectangle covering the pixel at `point`, which is how single pixels are drawn.
+fn pixel(point: Point2<i32>) -> Shape {
+    Shape::Rectangle {
+        top_left: point,
+        bottom_right: point,
+    }
+}
+
+/// The pixel a shape covers if it has no size, or `None` if it's bigger than that.
+fn single_pixel(shape: &Shape) -> Option<Point2<i32>> {
+    match *shape {
+        Shape::Line { start, end } if start == end => Some(start),
+        Shape::Rectangle {
+            top_left,
+            bottom_right,
+        } if top_left == bottom_right => Some(top_left),
+        Shape::Circle { center, radius: 0 } => Some(center),
+        _ => None,
+    }
+}
+
 pub struct DisplayCtx<'a> {
     display: &'a mut Display,
     protocol: &'a mut Protocol,
@@ -783,6 +863,7 @@ impl<'a> DisplayCtx<'a> {
         stride: NonZeroU16,
     ) -> anyhow::Result<()> {
         let buffer = BASE64_STANDARD.encode(buf);
+        self.display.draw_count += 1;
         self.protocol.send(&Event::ScreenDraw {
             command: DrawCommand::CopyBuffer {
                 top_left: top_left.into(),
@@ -798,7 +879,16 @@ impl<'a> DisplayCtx<'a> {
     }
 
     /// Draws or strokes a shape on the display, using the current foreground color.
+    ///
+    /// Like on a real brain, shapes with no size still cover a pixel. A line that starts and ends at the same point, a
+    /// rectangle with the same corners and a circle with a radius of 0 are all sent as a filled pixel, the same way
+    /// `vexDisplayPixelSet` draws one, so frontends don't have to agree on how to draw them.
     pub fn draw(&mut self, shape: Shape, stroke: bool, erase: bool) -> anyhow::Result<()> {
+        let (shape, stroke) = match single_pixel(&shape) {
+            Some(point) => (pixel(point), false),
+            None => (shape, stroke),
+        };
+        self.display.draw_count += 1;
         self.protocol.send(&Event::ScreenDraw {
             command: if stroke {
                 DrawCommand::Stroke { shape }
@@ -822,7 +912,19 @@ impl<'a> DisplayCtx<'a> {
         location: TextLocation,
         opaque: bool,
     ) -> anyhow::Result<()> {
+        // Frontends put the top of the text at the top of the line, so text that isn't the line's normal size is
+        // written at coordinates instead to keep its baseline in line with the rest.
+        let location = match location {
+            TextLocation::Line { line } => match self.display.text_top(&text, line) {
+                Some(y) => TextLocation::Coordinates {
+                    point: [0, y].into(),
+                },
+                None => location,
+            },
+            location => location,
+        };
         self.display.last_font_size = text.font_size;
+        self.display.draw_count += 1;
         self.protocol.send(&Event::ScreenDraw {
             command: DrawCommand::Write {
                 text,
@@ -836,6 +938,145 @@ impl<'a> DisplayCtx<'a> {
         Ok(())
     }
 
+    /// Writes text on a line of the display.
+    ///
+    /// If text wrapping is enabled, text that's wider than the clip region is broken at spaces and continues on the
+    /// following lines. Words that don't fit on a line by themselves are broken wherever they reach the edge.
+    pub fn write_line(&mut self, text: V5Text, line: i32) -> anyhow::Result<()> {
+        let width = self.display.clip_region.bottom_right.x;
+        if !self.display.wrap_text || width <= 0 {
+            return self.write(text, TextLocation::Line { line }, true);
+        }
+
+        let height = match self.display.font_metrics(&text.font_family, text.font_size) {
+            Some(metrics) => i32::try_from(metrics.ascent + metrics.descent).unwrap_or(i32::MAX),
+            None => i32::try_from(estimate_text_metrics(&text).height).unwrap_or(i32::MAX),
+        };
+        let lines_per_row = ((height + LINE_HEIGHT - 1) / LINE_HEIGHT).max(1);
+        let rows = self.wrap(&text, width as u32)?;
+        for (row, data) in (0..).zip(rows) {
+            self.write(
+                V5Text {
+                    data,
+                    ..text.clone()
+                },
+                TextLocation::Line {
+                    line: line.saturating_add(row * lines_per_row),
+                },
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Breaks text into rows that are each at most `width` pixels wide, preferring to break at spaces.
+    fn wrap(&mut self, text: &V5Text, width: u32) -> anyhow::Result<Vec<String>> {
+        let mut rows = Vec::new();
+        let mut row = String::new();
+        for word in text.data.split(' ') {
+            let extended = if row.is_empty() {
+                word.to_string()
+            } else {
+                format!("{row} {word}")
+            };
+            if self.fits(text, &extended, width)? {
+                row = extended;
+                continue;
+            }
+
+            if !row.is_empty() {
+                rows.push(std::mem::take(&mut row));
+            }
+            row = word.to_string();
+            while !self.fits(text, &row, width)? {
+                let rest = row.split_off(self.fitting_prefix_len(text, &row, width)?);
+                rows.push(std::mem::replace(&mut row, rest));
+            }
+        }
+        rows.push(row);
+        Ok(rows)
+    }
+
+    /// Whether `data` is at most `width` pixels wide when written in the same font as `text`.
+    fn fits(&mut self, text: &V5Text, data: &str, width: u32) -> anyhow::Result<bool> {
+        let metrics = self.get_text_metrics(V5Text {
+            data: data.to_string(),
+            ..text.clone()
+        })?;
+        Ok(metrics.width <= width)
+    }
+
+    /// The length in bytes of the longest prefix of `data` that fits in `width` pixels, which is at least one
+    /// character so that wrapping always makes progress.
+    fn fitting_prefix_len(
+        &mut self,
+        text: &V5Text,
+        data: &str,
+        width: u32,
+    ) -> anyhow::Result<usize> {
+        let mut ends = data.char_indices().map(|(i, c)| i + c.len_utf8());
+        let mut len = ends.next().unwrap_or(0);
+        for end in ends {
+            if !self.fits(text, &data[..end], width)? {
+                break;
+            }
+            len = end;
+        }
+        Ok(len)
+    }
+
+    /// Inverts the colors of the pixels in a rectangle, within the clip region.
+    pub fn invert_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32) -> anyhow::Result<()> {
+        self.display.draw_count += 1;
+        self.protocol.send_sim(&SimEvent::ScreenInvert {
+            rect: Rect {
+                top_left: [x1.min(x2), y1.min(y2)].into(),
+                bottom_right: [x1.max(x2), y1.max(y2)].into(),
+            },
+            clip_region: self.display.clip_region,
+        })?;
+        Ok(())
+    }
+
+    /// Sets the opacity of subsequent draws, where 0 is transparent and 255 is opaque.
+    pub fn set_opacity(&mut self, opacity: u8) -> anyhow::Result<()> {
+        if opacity != self.display.opacity {
+            self.display.opacity = opacity;
+            self.protocol
+                .send_sim(&SimEvent::ScreenOpacity { opacity })?;
+        }
+        Ok(())
+    }
+
+    /// Sets the brightness of the screen as a percentage, clamping it to the range 0 to 100.
+    pub fn set_brightness(&mut self, level: i32) -> anyhow::Result<()> {
+        let level = level.clamp(0, MAX_BRIGHTNESS.into()) as u8;
+        if level != self.display.brightness {
+            self.display.brightness = level;
+            self.protocol
+                .send_sim(&SimEvent::ScreenBrightness { level })?;
+        }
+        Ok(())
+    }
+
+    /// Writes text on a line of the display, centered horizontally about the display's midline.
+    pub fn write_centered(&mut self, text: V5Text, line: i32) -> anyhow::Result<()> {
+        let metrics = self.get_text_metrics(text.clone())?;
+        let width = i32::try_from(metrics.width).unwrap_or(i32::MAX);
+        let x = (DISPLAY_WIDTH - width) / 2;
+        let y = self
+            .display
+            .text_top(&text, line)
+            .unwrap_or(LINE_NUM_OFFSET.saturating_add(line.saturating_mul(LINE_HEIGHT)));
+        self.write(
+            text,
+            TextLocation::Coordinates {
+                point: [x, y].into(),
+            },
+            true,
+        )
+    }
+
     pub fn with_colors<R>(&mut self, fg: RGB8, bg: RGB8, func: impl FnOnce(&mut Self) -> R) -> R {
         let old_fg = self.display.foreground_color;
         let old_bg = self.display.background_color;
@@ -904,34 +1145,99 @@ impl<'a> DisplayCtx<'a> {
 
     /// Fetches how big a string will be when rendered.
     ///
-    /// Caches the result so that the same text and options don't have to be calculated multiple times in a row.
+    /// Caches the results so that recently measured text and options don't have to be calculated again.
     pub fn get_text_metrics(&mut self, text: V5Text) -> anyhow::Result<TextMetrics> {
-        if let Some((cached_text, metrics)) = &self.display.text_metrics_cache {
-            if cached_text == &text {
-                return Ok(*metrics);
-            }
+        if self.display.local_text_metrics {
+            return Ok(estimate_text_metrics(&text));
+        }
+        if let Some(metrics) = self.display.text_metrics_cache.get(&text) {
+            return Ok(metrics);
         }
         self.protocol
             .send(&Event::TextMetricsRequest { text: text.clone() })?;
 
-        let cmd = self.protocol.wait_for_command(
-            |c| matches!(c, Command::SetTextMetrics { text: recv_text, .. } if *recv_text == text),
-        )?;
+        let is_response = |c: &Inbound| match c {
+            Inbound::Command(Command::SetTextMetrics {
+                text: recv_text, ..
+            }) => *recv_text == text,
+            _ => false,
+        };
+        let cmd = match self.display.text_metrics_timeout {
+            Some(timeout) => match self
+                .protocol
+                .wait_for_command_timeout(is_response, timeout)?
+            {
+                Some(cmd) => cmd,
+                None => {
+                    self.protocol.warn(format!(
+                        "The frontend didn't send text metrics for {:?} within {timeout:?}, so they were estimated",
+                        text.data
+                    ))?;
+                    // The estimate is cached like real metrics, but a late response from the frontend replaces it.
+                    let metrics = estimate_text_metrics(&text);
+                    self.display.text_metrics_cache.insert(text, metrics);
+                    return Ok(metrics);
+                }
+            },
+            None => self.protocol.wait_for_command(is_response)?,
+        };
         let metrics = match cmd {
-            Command::SetTextMetrics { metrics, .. } => metrics,
+            Inbound::Command(Command::SetTextMetrics { metrics, .. }) => metrics,
             _ => unreachable!(),
         };
-        self.display.text_metrics_cache = Some((text, metrics));
+        self.display.text_metrics_cache.insert(text, metrics);
         Ok(metrics)
     }
 
     pub fn render(&mut self) -> anyhow::Result<()> {
         self.set_double_buffered(true)?;
+        if !self.protocol.event_rate_allows(self.display.last_render) {
+            // Only the latest frame matters, so it's sent by `render_pending` once the event rate allows.
+            self.display.render_pending = true;
+            return Ok(());
+        }
+        self.send_render()
+    }
+
+    /// Sends a frame that was held back by the event rate limit, if it's now allowed.
+    pub fn render_pending(&mut self) -> anyhow::Result<()> {
+        if self.display.render_pending && self.protocol.event_rate_allows(self.display.last_render)
+        {
+            self.send_render()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a frame that was held back by the event rate limit, regardless of the event rate.
+    pub fn render_all(&mut self) -> anyhow::Result<()> {
+        if self.display.render_pending {
+            self.send_render()?;
+        }
+        Ok(())
+    }
+
+    fn send_render(&mut self) -> anyhow::Result<()> {
+        self.display.render_pending = false;
         self.protocol.send(&Event::ScreenRender)?;
+
+        let now = Instant::now();
+        let frame_time = self
+            .display
+            .last_render
+            .replace(now)
+            .map_or(Duration::ZERO, |last_render| now - last_render);
+        self.protocol.send_sim(&SimEvent::FrameStats {
+            frame_time_us: frame_time.as_micros() as u64,
+            draw_count: std::mem::take(&mut self.display.draw_count),
+        })?;
+
+        // The end of a frame is a natural point to send everything that was drawn.
+        self.protocol.flush()?;
         Ok(())
     }
 
     pub fn scroll(&mut self, bounds: ScrollLocation, lines: i32) -> anyhow::Result<()> {
+        self.display.draw_count += 1;
         self.protocol.send(&Event::ScreenScroll {
             location: bounds,
             lines,
@@ -942,6 +1248,69 @@ impl<'a> DisplayCtx<'a> {
     }
 }
 
+/// Estimates how big a string will be when rendered, without asking the frontend.
+///
+/// Every font is treated as monospace with a fixed advance width and line height per size, so this is only
+/// approximate for proportional fonts.
+fn estimate_text_metrics(text: &V5Text) -> TextMetrics {
+    let (advance, line_height) = match text.font_size {
+        V5FontSize::Small => (7, 13),
+        V5FontSize::Normal => (10, 20),
+        V5FontSize::Large => (20, 40),
+    };
+    TextMetrics {
+        width: (text.data.chars().count() * advance) as _,
+        height: line_height as _,
+    }
+}
+
+/// A small least-recently-used cache of text layout calculations.
+///
+/// Metrics come from the frontend, so every miss costs a protocol round-trip. Entries are ordered from most to least
+/// recently used, and the cache is small enough that a linear scan is cheap.
+struct TextMetricsCache {
+    entries: VecDeque<(V5Text, TextMetrics)>,
+    /// How many entries are kept. This starts at [`Self::CAPACITY`] and grows to fit batches preloaded by the
+    /// frontend, so that none of a batch is evicted by the rest of it.
+    capacity: usize,
+}
+
+impl Default for TextMetricsCache {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity: Self::CAPACITY,
+        }
+    }
+}
+
+impl TextMetricsCache {
+    const CAPACITY: usize = 32;
+
+    fn get(&mut self, text: &V5Text) -> Option<TextMetrics> {
+        let index = self.entries.iter().position(|(cached, _)| cached == text)?;
+        let entry = self.entries.remove(index)?;
+        let metrics = entry.1;
+        self.entries.push_front(entry);
+        Some(metrics)
+    }
+
+    fn insert(&mut self, text: V5Text, metrics: TextMetrics) {
+        if let Some(index) = self.entries.iter().position(|(cached, _)| *cached == text) {
+            self.entries.remove(index);
+        }
+        self.entries.push_front((text, metrics));
+        self.entries.truncate(self.capacity);
+    }
+
+    fn insert_batch(&mut self, entries: Vec<(V5Text, TextMetrics)>) {
+        self.capacity = self.capacity.max(entries.len());
+        for (text, metrics) in entries {
+            self.insert(text, metrics);
+        }
+    }
+}
+
 pub struct Display {
     /// The display's saved foreground color.
     pub foreground_color: RGB8,
@@ -949,33 +1318,60 @@ pub struct Display {
     pub background_color: RGB8,
     start_instant: Instant,
     program_options: ProgramOptions,
-    /// Cache for text layout calculations, to avoid re-calculating the same text layout multiple times in a row.
-    text_metrics_cache: Option<(V5Text, TextMetrics)>,
+    /// Whether text metrics are estimated locally instead of being requested from the frontend.
+    local_text_metrics: bool,
+    /// How long to wait for the frontend to send requested text metrics before estimating them instead. Waits forever
+    /// if `None`.
+    text_metrics_timeout: Option<Duration>,
+    /// Whether text written on a line wraps onto the following lines instead of running off the clip region.
+    wrap_text: bool,
+    /// Cache for text layout calculations, to avoid re-calculating recently used text layouts.
+    text_metrics_cache: TextMetricsCache,
+    /// Vertical metrics of fonts, as sent by the frontend.
+    font_metrics: Vec<(V5FontFamily, V5FontSize, FontMetrics)>,
     last_font_size: V5FontSize,
     double_buffered: bool,
     clip_region: Rect,
+    /// How opaque draws are, from 0 (transparent) to 255 (opaque).
+    opacity: u8,
+    /// The brightness of the screen as a percentage.
+    brightness: u8,
+    /// When the last frame was rendered, or `None` if no frame has been rendered yet.
+    last_render: Option<Instant>,
+    /// The number of drawing operations since the last frame was rendered.
+    draw_count: u32,
+    /// Whether the program rendered a frame that hasn't been sent because of the event rate limit.
+    render_pending: bool,
+    /// The simulated time of the last vsync a render waited for, which later vsyncs are a whole number of frames
+    /// after.
+    last_vsync: Option<Duration>,
 }
 
 impl Display {
-    pub fn new(program_options: ProgramOptions, start_instant: Instant) -> Self {
+    pub fn new(
+        program_options: ProgramOptions,
+        start_instant: Instant,
+        local_text_metrics: bool,
+    ) -> Self {
         Self {
+            local_text_metrics,
+            wrap_text: false,
+            text_metrics_timeout: None,
             foreground_color: program_options.default_fg_color(),
             background_color: program_options.default_bg_color(),
             program_options,
-            text_metrics_cache: None,
+            text_metrics_cache: TextMetricsCache::default(),
+            font_metrics: Vec::new(),
             start_instant,
             last_font_size: V5FontSize::Normal,
             double_buffered: false,
-            clip_region: Rect {
-                top_left: Point2 {
-                    x: 0,
-                    y: HEADER_HEIGHT,
-                },
-                bottom_right: Point2 {
-                    x: DISPLAY_WIDTH,
-                    y: DISPLAY_HEIGHT,
-                },
-            },
+            clip_region: USER_AREA,
+            opacity: u8::MAX,
+            brightness: MAX_BRIGHTNESS,
+            last_render: None,
+            draw_count: 0,
+            render_pending: false,
+            last_vsync: None,
         }
     }
 
@@ -986,8 +1382,90 @@ impl Display {
         }
     }
 
+    /// Sets how long to wait for the frontend to send text metrics before estimating them. Waits forever if `None`.
+    pub fn set_text_metrics_timeout(&mut self, timeout: Option<Duration>) {
+        self.text_metrics_timeout = timeout;
+    }
+
+    /// Makes text written on a line wrap onto the following lines when it's wider than the clip region.
+    pub fn set_wrap_text(&mut self, enable: bool) {
+        self.wrap_text = enable;
+    }
+
     pub fn set_metrics_cache(&mut self, text: V5Text, metrics: TextMetrics) {
-        self.text_metrics_cache = Some((text, metrics));
+        self.text_metrics_cache.insert(text, metrics);
+    }
+
+    /// Preloads the metrics of many strings at once, so drawing them later doesn't need a round-trip each.
+    pub fn set_metrics_cache_batch(&mut self, entries: Vec<(V5Text, TextMetrics)>) {
+        self.text_metrics_cache.insert_batch(entries);
+    }
+
+    pub fn set_font_metrics(
+        &mut self,
+        font_family: V5FontFamily,
+        font_size: V5FontSize,
+        metrics: FontMetrics,
+    ) {
+        self.font_metrics
+            .retain(|(family, size, _)| (family, size) != (&font_family, &font_size));
+        self.font_metrics.push((font_family, font_size, metrics));
+    }
+
+    /// Returns the vertical metrics of a font, or `None` if the frontend hasn't sent them.
+    fn font_metrics(
+        &self,
+        font_family: &V5FontFamily,
+        font_size: V5FontSize,
+    ) -> Option<FontMetrics> {
+        self.font_metrics
+            .iter()
+            .find(|(family, size, _)| (family, size) == (font_family, &font_size))
+            .map(|&(_, _, metrics)| metrics)
+    }
+
+    /// How far below the top of a line the given text has to start for its baseline to line up with text in the
+    /// normal size, or `None` if the frontend hasn't sent the metrics of both sizes.
+    fn baseline_offset(&self, text: &V5Text) -> Option<i32> {
+        let line_ascent = self
+            .font_metrics(&text.font_family, V5FontSize::Normal)?
+            .ascent;
+        let ascent = self.font_metrics(&text.font_family, text.font_size)?.ascent;
+        Some(i32::try_from(i64::from(line_ascent) - i64::from(ascent)).unwrap_or(0))
+    }
+
+    /// The y coordinate to write text at so that it sits on the given line, or `None` if it can be left to the
+    /// frontend to put on the line.
+    ///
+    /// Text is never moved up into the header, even if that puts it below the line's baseline.
+    fn text_top(&self, text: &V5Text, line: i32) -> Option<i32> {
+        let offset = self.baseline_offset(text).filter(|&offset| offset != 0)?;
+        let top = LINE_NUM_OFFSET
+            .saturating_add(line.saturating_mul(LINE_HEIGHT))
+            .saturating_add(offset);
+        Some(top.max(USER_AREA.top_left.y))
+    }
+
+    /// Resets the clip region so that drawing is allowed anywhere below the header.
+    pub fn clear_clip_region(&mut self) {
+        self.clip_region = USER_AREA;
+    }
+
+    /// Returns the simulated time of the first vsync after `now`, which the next render then waits for.
+    ///
+    /// Vsyncs happen at a fixed cadence so that a program rendering every frame holds a steady frame rate, no matter
+    /// how long each frame took to draw or how late the simulator woke up from the last one. The cadence starts over
+    /// from `now` if simulated time went backwards, such as when a checkpoint was restored.
+    pub fn next_vsync(&mut self, now: Duration) -> Duration {
+        let next = match self.last_vsync {
+            Some(last) if last <= now => {
+                let frames = (now - last).as_nanos() / FRAME_DURATION.as_nanos() + 1;
+                last + Duration::from_nanos((FRAME_DURATION.as_nanos() * frames) as u64)
+            }
+            _ => now + FRAME_DURATION,
+        };
+        self.last_vsync = Some(next);
+        next
     }
 
     pub fn set_clip_region(&mut self, x1: i32, y1: i32, x2: i32, y2: i32) {
@@ -1,49 +1,201 @@
 use std::{
-    collections::HashMap,
-    ffi::{CStr, CString, FromBytesUntilNulError},
-    sync::mpsc,
-    time::Instant,
+    collections::{HashMap, HashSet},
+    ffi::{CStr, CString},
+    mem::size_of,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::Ordering, mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use bitflags::bitflags;
+use rand::{rngs::StdRng, SeedableRng};
+use rgb::RGB8;
 
 use component::ResourceTable;
 
 use display::DisplayCtx;
 use serial::{build_serial_jump_table, Serial};
-use vexide_simulator_protocol::{Command, CompMode, CompetitionMode, Event, LogLevel};
+use vexide_simulator_protocol::{
+    Command, CompMode, CompetitionMode, ControllerUpdate, Event, LogLevel, Port,
+};
 use wasmtime::*;
-use wasmtime_wasi::{preview1::WasiP1Ctx, WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi::{preview1::WasiP1Ctx, DirPerms, FilePerms, WasiCtx, WasiCtxBuilder, WasiView};
 
 use crate::{
-    protocol::{self, Log, Protocol},
-    ProgramOptions,
+    printf::{FormatCache, ParsedFormat, MAX_FIELD_WIDTH},
+    protocol::{
+        self, warn_bt, CallSite, DeviceKind, DisplayGeometry, ExitReason, Inbound,
+        JumpTableFunction, Log, MemoryUsage, Protocol, ProtocolError, QueryTopic, RepeatLimiter,
+        Repeated, SimCommand, SimEvent, SimState,
+    },
+    ProgramOptions, SHUTDOWN_REQUESTED,
 };
 
 use self::{
+    adi::{analog_value, build_adi_jump_table, BRAIN_ADI_PORT},
+    checkpoint::Checkpoints,
+    clock::SimClock,
     controller::{build_controller_jump_table, Inputs},
+    debug_memory::DebugMemory,
+    device::{build_device_jump_table, Devices},
     display::{build_display_jump_table, Display},
+    motor::build_motor_jump_table,
+    unimplemented::build_unimplemented_jump_table,
+    usd::{build_usd_jump_table, Usd},
 };
 
+mod adi;
+mod checkpoint;
+mod clock;
 mod controller;
+mod debug_memory;
+mod device;
 pub mod display;
+mod motor;
 mod serial;
+mod unimplemented;
+mod usd;
+
+pub use checkpoint::apply_checkpoint_requests;
+pub use controller::{ControllerPreset, SdlRequest};
+pub use debug_memory::apply_memory_requests;
+
+/// A host directory made available to the program through WASI.
+#[derive(Debug, Clone)]
+pub struct WasiDir {
+    /// The path to the directory on the host.
+    pub host: PathBuf,
+    /// The path the program can access the directory at.
+    pub guest: String,
+}
+
+impl FromStr for WasiDir {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, guest) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected HOST:GUEST, got {s:?}"))?;
+        Ok(Self {
+            host: host.into(),
+            guest: guest.to_owned(),
+        })
+    }
+}
+
+/// A key-value pair passed to the program with `--arg`, which it can read with the `sim_get_arg` import.
+#[derive(Debug, Clone)]
+pub struct ProgramArg {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for ProgramArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got {s:?}"))?;
+        Ok(Self {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+}
+
+/// The error used to stop the program once the simulator has decided to exit.
+#[derive(Debug, Clone, Copy)]
+pub struct Exit(pub ExitReason);
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The simulator exited ({:?})", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// An error which stops the program when it is returned from a host function, so that the program at this path can
+/// be loaded in its place.
+#[derive(Debug)]
+pub struct SwitchProgram(pub PathBuf);
+
+impl std::fmt::Display for SwitchProgram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The program is being replaced by {}", self.0.display())
+    }
+}
+
+impl std::error::Error for SwitchProgram {}
 
-pub use controller::SdlRequest;
+/// An error which stops the program when it is returned from a host function, so that it can be started over as if
+/// the brain had rebooted.
+#[derive(Debug)]
+pub struct Reboot;
+
+impl std::fmt::Display for Reboot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The program is restarting after a reboot")
+    }
+}
+
+impl std::error::Error for Reboot {}
+
+/// Options for the WASI environment provided to the program.
+#[derive(Debug, Clone, Default)]
+pub struct WasiOptions {
+    /// Host directories to make available to the program.
+    pub dirs: Vec<WasiDir>,
+    /// Make WASI clocks follow simulated time instead of the host's clocks.
+    pub deterministic: bool,
+    /// The seed for WASI random sources. Defaults to 0 if `deterministic` is set, otherwise randomness is not seeded.
+    pub seed: Option<u64>,
+}
 
 /// The state of the SDK, containing the program's WASM module, the robot display, and other peripherals.
 pub struct SdkState {
     module: Module,
-    program_start: Instant,
+    clock: SimClock,
     display: Display,
     program_options: ProgramOptions,
     inputs: Inputs,
     competition_mode: CompetitionMode,
+    /// The buttons on the brain that are currently pressed, as a mask of [`protocol::BrainButton::mask`]s.
+    brain_buttons: u32,
+    /// The color of the brain's status LED.
+    brain_led: RGB8,
+    /// Whether simulated time starts over from zero when execution starts.
+    reset_clock_on_start: bool,
+    /// Whether the program starts over when it reboots the brain, instead of the simulator exiting.
+    restart_on_reboot: bool,
+    /// The simulated time when the program last reinitialized the watchdog.
+    watchdog_reset: Duration,
+    /// The most commands processed each time the simulator runs its tasks, so a flood of commands can't hold up the
+    /// program. There's no limit if this is `None`.
+    commands_per_tick: Option<usize>,
     protocol: Protocol,
     is_executing: bool,
     serial: Serial,
+    devices: Devices,
+    repeat_limiter: RepeatLimiter,
     wasi: WasiP1Ctx,
+    /// The program's linear memory, once it has been instantiated.
+    memory: Option<Memory>,
+    memory_usage: MemoryTracker,
+    /// The most elements the simulator lets the program's tables have, regardless of their own maximums.
+    table_limit: Option<u32>,
+    checkpoints: Checkpoints,
+    debug_memory: DebugMemory,
+    usd: Usd,
+    /// The arguments passed with `--arg`, by key.
+    program_args: HashMap<String, String>,
+    /// When each section being timed with `sim_mark_time` started, by label.
+    time_marks: HashMap<String, Instant>,
+    format_cache: FormatCache,
 }
 
 impl SdkState {
@@ -51,50 +203,265 @@ impl SdkState {
         module: Module,
         program_options: ProgramOptions,
         protocol: Protocol,
-        sdl_request_channel: mpsc::Sender<SdlRequest>,
-    ) -> Self {
+        sdl_request_channel: Option<mpsc::Sender<SdlRequest>>,
+        wasi_options: &WasiOptions,
+        local_text_metrics: bool,
+    ) -> anyhow::Result<Self> {
         let start = Instant::now();
-        SdkState {
+        let clock = SimClock::new();
+        let serial = Serial::new();
+
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.stdout(serial.wasi_stream())
+            .stderr(serial.wasi_stream())
+            .allow_blocking_current_thread(true)
+            .allow_tcp(false)
+            .allow_udp(false);
+        for dir in &wasi_options.dirs {
+            wasi.preopened_dir(&dir.host, &dir.guest, DirPerms::all(), FilePerms::all())
+                .with_context(|| format!("Failed to open WASI directory {:?}", dir.host))?;
+        }
+        if wasi_options.deterministic {
+            wasi.monotonic_clock(clock.clone())
+                .wall_clock(clock.clone());
+        }
+        let seed = wasi_options
+            .seed
+            .or(wasi_options.deterministic.then_some(0));
+        if let Some(seed) = seed {
+            wasi.secure_random(StdRng::seed_from_u64(seed))
+                .insecure_random(StdRng::seed_from_u64(seed))
+                .insecure_random_seed(seed.into());
+        }
+
+        Ok(SdkState {
             module,
-            display: Display::new(program_options, start),
+            display: Display::new(program_options, start, local_text_metrics),
             program_options,
             inputs: Inputs::new(sdl_request_channel),
-            program_start: start,
+            clock,
             competition_mode: CompetitionMode::default(),
+            brain_buttons: 0,
+            brain_led: RGB8::default(),
+            reset_clock_on_start: false,
+            restart_on_reboot: false,
+            watchdog_reset: Duration::ZERO,
+            commands_per_tick: None,
             protocol,
             is_executing: false,
-            serial: Serial::new(),
-            wasi: WasiCtxBuilder::new()
-                .allow_blocking_current_thread(true)
-                .allow_tcp(false)
-                .allow_udp(false)
-                .build_p1(),
-        }
+            serial,
+            devices: Devices::new(),
+            repeat_limiter: RepeatLimiter::default(),
+            wasi: wasi.build_p1(),
+            memory: None,
+            memory_usage: MemoryTracker::default(),
+            table_limit: None,
+            checkpoints: Checkpoints::default(),
+            debug_memory: DebugMemory::default(),
+            usd: Usd::default(),
+            program_args: HashMap::new(),
+            time_marks: HashMap::new(),
+            format_cache: FormatCache::default(),
+        })
     }
 
     /// Signal that the simulator is ready to begin and process all setup commands.
+    ///
+    /// Commands that were queued behind the start command are applied as well, so that the program sees them at boot
+    /// even if execution was started implicitly.
     pub fn setup(&mut self) -> anyhow::Result<()> {
         self.protocol.send(&Event::Ready)?;
         while !self.is_executing {
             self.recv_command()?;
         }
+        self.recv_all_commands()?;
         Ok(())
     }
 
+    /// Waits for the given delay before the program starts, like a real brain's boot sequence.
+    ///
+    /// Commands are still processed while waiting, and a [`SimEvent::Booting`] event is sent periodically.
+    pub fn boot(&mut self, delay: Duration) -> anyhow::Result<()> {
+        const BOOT_EVENT_INTERVAL: Duration = Duration::from_millis(50);
+
+        let start = Instant::now();
+        loop {
+            self.recv_all_commands()?;
+            let elapsed = start.elapsed();
+            let progress = (elapsed.as_secs_f64() / delay.as_secs_f64()).min(1.0);
+            self.protocol.send_sim(&SimEvent::Booting { progress })?;
+            self.protocol.flush()?;
+            if elapsed >= delay {
+                return Ok(());
+            }
+            thread::sleep(BOOT_EVENT_INTERVAL.min(delay - elapsed));
+        }
+    }
+
     /// Process the next command, blocking if it hasn't been received yet.
     pub fn recv_command(&mut self) -> anyhow::Result<()> {
-        let cmd = self.protocol.next()?;
-        self.execute_command(cmd)
+        let msg = self.protocol.next()?;
+        self.execute(msg)
     }
 
     /// Process all available commands.
+    ///
+    /// Exits the simulator if the host has requested a shutdown.
     pub fn recv_all_commands(&mut self) -> anyhow::Result<()> {
-        while let Some(cmd) = self.protocol.try_next()? {
-            self.execute_command(cmd)?;
+        self.recv_commands(usize::MAX)
+    }
+
+    /// Process up to `limit` of the available commands, leaving the rest queued.
+    ///
+    /// Exits the simulator if the host has requested a shutdown.
+    fn recv_commands(&mut self, limit: usize) -> anyhow::Result<()> {
+        if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+            return Err(self.exit(ExitReason::Interrupted));
+        }
+        for _ in 0..limit {
+            let Some(msg) = self.protocol.try_next()? else {
+                break;
+            };
+            self.execute(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Process a message from the frontend.
+    pub fn execute(&mut self, msg: Inbound) -> anyhow::Result<()> {
+        match msg {
+            Inbound::Command(cmd) => self.execute_command(cmd),
+            Inbound::Sim(cmd) => self.execute_sim_command(cmd),
+        }
+    }
+
+    /// Process a simulator-specific command.
+    pub fn execute_sim_command(&mut self, cmd: SimCommand) -> anyhow::Result<()> {
+        match cmd {
+            SimCommand::SetTimeScale { factor } => {
+                if !factor.is_finite() || factor < 0.0 {
+                    self.warn(format!("Ignoring invalid time scale {factor:?}"))?;
+                    return Ok(());
+                }
+                self.clock.set_scale(factor);
+            }
+            SimCommand::Query { what } => {
+                let state = self.query(&what);
+                self.protocol.send_sim(&SimEvent::State(state))?;
+            }
+            SimCommand::Snapshot => self.checkpoints.request_snapshot(),
+            SimCommand::Restore { id } => self.checkpoints.request_restore(id),
+            SimCommand::DropSnapshot { id } => self.checkpoints.request_drop(id),
+            SimCommand::FieldConnection {
+                connected,
+                keep_enabled,
+            } => {
+                let mut mode = self.competition_mode;
+                mode.connected = connected;
+                mode.is_competition = connected;
+                if connected {
+                    self.info("Field control connected")?;
+                } else if keep_enabled {
+                    self.info("Field control disconnected")?;
+                } else {
+                    mode.enabled = false;
+                    self.info("Field control disconnected, disabling the robot")?;
+                }
+                self.set_competition_mode(mode)?;
+            }
+            SimCommand::SetFontMetrics {
+                font_family,
+                font_size,
+                metrics,
+            } => self
+                .display
+                .set_font_metrics(font_family, font_size, metrics),
+            SimCommand::SetTextMetricsBatch { entries } => {
+                self.display.set_metrics_cache_batch(entries);
+            }
+            SimCommand::SetAdiValue {
+                port,
+                adi_port,
+                value,
+            } => {
+                let result = self
+                    .devices
+                    .adi(port)
+                    .and_then(|expander| expander.port_mut(adi_port));
+                match result {
+                    Ok(adi_port) => adi_port.value = value,
+                    Err(err) => self.warn(format!(
+                        "Couldn't set the value of ADI port {adi_port} on port {port}: {err}"
+                    ))?,
+                }
+            }
+            SimCommand::WriteMemory { addr, bytes_base64 } => {
+                if !self.debug_memory.enabled() {
+                    self.warn("Ignoring a memory write because --debug-memory isn't enabled")?;
+                } else if let Err(err) = self.debug_memory.request_write(addr, &bytes_base64) {
+                    self.warn(format!(
+                        "Ignoring a memory write with invalid base64: {err}"
+                    ))?;
+                }
+            }
+            SimCommand::ReadMemory { addr, len } => {
+                if self.debug_memory.enabled() {
+                    self.debug_memory.request_read(addr, len);
+                } else {
+                    self.warn("Ignoring a memory read because --debug-memory isn't enabled")?;
+                }
+            }
+            SimCommand::LoadProgram { path } => {
+                self.info(format!("Switching to the program at {}", path.display()))?;
+                self.stop(ExitReason::ProgramSwitched);
+                return Err(SwitchProgram(path).into());
+            }
+            SimCommand::BrainButton { button, pressed } => {
+                if pressed {
+                    self.brain_buttons |= button.mask();
+                } else {
+                    self.brain_buttons &= !button.mask();
+                }
+            }
+            SimCommand::ControllerAxis { id, axis, value } => {
+                if let Err(err) = self.inputs.set_axis(id, axis, value) {
+                    self.warn(format!("Couldn't move {axis:?} of controller {id}: {err}"))?;
+                }
+            }
+            SimCommand::ControllerButton {
+                id,
+                button,
+                pressed,
+            } => {
+                if let Err(err) = self.inputs.set_button(id, button, pressed) {
+                    self.warn(format!(
+                        "Couldn't set button {button:?} of controller {id}: {err}"
+                    ))?;
+                }
+            }
         }
         Ok(())
     }
 
+    /// Takes a snapshot of the requested parts of the simulator's state, or all of it if nothing is requested.
+    pub fn query(&self, what: &[QueryTopic]) -> SimState {
+        let wants = |topic| what.is_empty() || what.contains(&topic);
+        SimState {
+            extensions: wants(QueryTopic::Extensions).then(|| self.protocol.extensions().to_vec()),
+            devices: wants(QueryTopic::Devices).then(|| self.devices.ports()),
+            competition: wants(QueryTopic::Competition).then_some(self.competition_mode),
+            elapsed_micros: wants(QueryTopic::Time)
+                .then(|| self.clock.elapsed().as_micros() as u64),
+            display: wants(QueryTopic::Display).then_some(DisplayGeometry {
+                width: display::DISPLAY_WIDTH,
+                height: display::DISPLAY_HEIGHT,
+                header_height: display::HEADER_HEIGHT,
+            }),
+            controller_screens: wants(QueryTopic::ControllerScreens).then(|| self.inputs.screens()),
+            memory: wants(QueryTopic::Memory).then(|| self.memory_usage.usage()),
+        }
+    }
+
     /// Process a command.
     pub fn execute_command(&mut self, cmd: Command) -> anyhow::Result<()> {
         match cmd {
@@ -106,42 +473,260 @@ impl SdkState {
                 self.inputs.set_controller(0, primary)?;
                 self.inputs.set_controller(1, partner)?;
             }
-            Command::USD { root } => todo!(),
+            Command::USD { root } => match root {
+                Some(root) => {
+                    if let Err(err) = self.usd.mount(&root) {
+                        self.warn(format!("Couldn't insert the SD card: {err}"))?;
+                    }
+                }
+                None => self.usd.eject(),
+            },
             Command::VEXLinkOpened { port, mode } => todo!(),
             Command::VEXLinkClosed { port } => todo!(),
-            Command::CompetitionMode(mode) => {
-                self.competition_mode = mode;
+            Command::CompetitionMode(mode) => self.set_competition_mode(mode)?,
+            Command::ConfigureDevice { port, device } => {
+                let (&Port::Smart(port), Some(kind)) = (&port, DeviceKind::from_device(&device))
+                else {
+                    self.warn(format!(
+                        "Couldn't configure a {device:?} on {port:?}: it isn't supported"
+                    ))?;
+                    return Ok(());
+                };
+                let port = u32::from(port);
+                let now = self.clock.elapsed();
+                match self.devices.configure(port, kind, now) {
+                    Ok(()) => self.protocol.send_sim(&SimEvent::DeviceConfigured {
+                        port,
+                        device_type: kind,
+                    })?,
+                    Err(err) => self.warn(format!(
+                        "Couldn't configure a {kind:?} on port {port}: {err}"
+                    ))?,
+                }
+            }
+            Command::AdiInput { port, voltage } => {
+                let result = self
+                    .devices
+                    .adi(BRAIN_ADI_PORT)
+                    .and_then(|expander| expander.port_mut(u32::from(port)));
+                match result {
+                    Ok(adi_port) => adi_port.value = analog_value(voltage),
+                    Err(err) => self.warn(format!(
+                        "Couldn't set the voltage of the brain's three-wire port: {err}"
+                    ))?,
+                }
             }
-            Command::ConfigureDevice { port, device } => todo!(),
-            Command::AdiInput { port, voltage } => todo!(),
             Command::StartExecution => {
                 if self.is_executing {
                     bail!("Cannot start execution twice");
                 }
 
                 self.is_executing = true;
+                if self.reset_clock_on_start {
+                    self.clock.set_elapsed(Duration::ZERO);
+                }
             }
             Command::SetBatteryCapacity { capacity } => todo!(),
             Command::SetTextMetrics { text, metrics } => {
                 self.display.set_metrics_cache(text, metrics);
             }
             Command::Serial(serial_data) => {
-                self.serial
+                let dropped = self
+                    .serial
                     .buffer_input(serial_data.channel, &serial_data.to_bytes()?)?;
+                self.warn_dropped_input(dropped)?;
             }
         }
         Ok(())
     }
 
+    /// Warns that serial input was dropped because the program didn't read it before the input buffer filled up.
+    fn warn_dropped_input(&mut self, dropped: usize) -> anyhow::Result<()> {
+        if dropped > 0 {
+            self.warn(format!(
+                "Dropped {dropped} bytes of serial input because the program's input buffer is full"
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// The options from the program's cold header.
+    pub fn program_options(&self) -> ProgramOptions {
+        self.program_options
+    }
+
     /// Returns whether the simulator is in the execution phase.
     pub fn executing(&self) -> bool {
         self.is_executing
     }
 
     pub fn run_tasks(&mut self) -> anyhow::Result<()> {
-        self.recv_all_commands()?;
+        self.protocol.tick();
+        self.recv_commands(self.commands_per_tick.unwrap_or(usize::MAX))?;
+        let dropped = self.serial.receive_host_input()?;
+        self.warn_dropped_input(dropped)?;
         self.inputs.update()?;
         self.serial.flush(&mut self.protocol)?;
+        self.display_ctx().render_pending()?;
+        self.protocol.flush()?;
+        Ok(())
+    }
+
+    /// Keeps the simulator running after the program's entrypoint returns, like a brain whose program still has tasks
+    /// running, until the frontend stops sending commands.
+    ///
+    /// Programs that set `kill_threads_when_main_exits` in their cold header stop when their entrypoint returns
+    /// instead, so this shouldn't be called for them.
+    pub fn keep_running(&mut self) -> anyhow::Result<()> {
+        self.info("The program's entrypoint returned, but it keeps running because it doesn't kill its threads")?;
+        loop {
+            self.run_tasks()?;
+            match self.protocol.next() {
+                Ok(msg) => self.execute(msg)?,
+                Err(ProtocolError::RecvWorkerStopped) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Flushes pending output and tells the frontend why the simulator is stopping.
+    ///
+    /// Returns an [`Exit`] error which stops the program when it is returned from a host function.
+    pub fn exit(&mut self, reason: ExitReason) -> anyhow::Error {
+        self.stop(reason);
+        Exit(reason).into()
+    }
+
+    /// Sends the program's remaining output and tells the frontend why it stopped.
+    fn stop(&mut self, reason: ExitReason) {
+        for (level, message) in self.repeat_limiter.take_pending() {
+            _ = self.protocol.log(level, message);
+        }
+        _ = self.serial.flush_all(&mut self.protocol);
+        _ = self.display_ctx().render_all();
+        _ = self.protocol.send_sim(&SimEvent::Exited { reason });
+        _ = self.protocol.flush();
+    }
+
+    /// Tears down the program's state, returning the connection to the frontend so another program can use it.
+    pub fn into_protocol(self) -> Protocol {
+        self.protocol
+    }
+
+    /// Forwards the host's stdin to serial channel 1. The protocol must be using a transport other than stdio.
+    pub fn pass_through_host_stdin(&mut self) {
+        self.serial.pass_through_host_stdin();
+    }
+
+    /// Copies serial output to the host's stderr as well as sending it to the frontend.
+    pub fn tee_serial_to_host_stderr(&mut self) {
+        self.serial.tee_to_host_stderr();
+    }
+
+    /// Makes simulated time start from zero when the frontend starts execution, rather than when the program was
+    /// booted.
+    pub fn reset_clock_on_start(&mut self) {
+        self.reset_clock_on_start = true;
+    }
+
+    /// Makes `vexSystemBoot` restart the program from scratch instead of exiting the simulator.
+    pub fn restart_on_reboot(&mut self) {
+        self.restart_on_reboot = true;
+    }
+
+    /// Limits how many commands are processed each time the simulator runs its tasks. The rest are left for later.
+    pub fn limit_commands_per_tick(&mut self, limit: usize) {
+        self.commands_per_tick = Some(limit);
+    }
+
+    /// Estimates text metrics if the frontend doesn't send them within `timeout` of being asked, instead of waiting
+    /// forever.
+    pub fn limit_text_metrics_wait(&mut self, timeout: Duration) {
+        self.display.set_text_metrics_timeout(Some(timeout));
+    }
+
+    /// Makes long strings written on a line of the display wrap onto the following lines.
+    pub fn wrap_display_text(&mut self) {
+        self.display.set_wrap_text(true);
+    }
+
+    /// Lets the frontend read and write the program's memory with [`SimCommand::WriteMemory`] and
+    /// [`SimCommand::ReadMemory`].
+    pub fn enable_debug_memory(&mut self) {
+        self.debug_memory.enable();
+    }
+
+    /// Makes a host directory the contents of the simulated SD card. Without one, programs see no card inserted.
+    pub fn mount_usd(&mut self, root: &Path) -> anyhow::Result<()> {
+        self.usd.mount(root)
+    }
+
+    /// Makes every byte written to the SD card take the given amount of simulated time.
+    pub fn delay_usd_writes(&mut self, per_byte: Duration) {
+        self.usd.set_write_delay(per_byte);
+    }
+
+    /// Connects the primary controller with the preset's state, as if the frontend had sent it.
+    pub fn use_controller_preset(&mut self, preset: ControllerPreset) -> anyhow::Result<()> {
+        self.inputs
+            .set_controller(0, Some(ControllerUpdate::Raw(preset.state())))?;
+        Ok(())
+    }
+
+    /// Changes the competition mode, letting the frontend know if the match phase changed.
+    pub fn set_competition_mode(&mut self, mode: CompetitionMode) -> protocol::Result<()> {
+        let old = std::mem::replace(&mut self.competition_mode, mode);
+        if (old.enabled, old.mode, old.connected) != (mode.enabled, mode.mode, mode.connected) {
+            self.protocol.send_sim(&SimEvent::CompetitionPhase {
+                enabled: mode.enabled,
+                mode: mode.mode,
+                connected: mode.connected,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Makes key-value pairs available to the program through `sim_get_arg`. Later pairs replace earlier ones with
+    /// the same key.
+    pub fn set_program_args(&mut self, args: &[ProgramArg]) {
+        for ProgramArg { key, value } in args {
+            self.program_args.insert(key.clone(), value.clone());
+        }
+    }
+
+    /// The value passed with `--arg` for a key, if there was one.
+    pub fn program_arg(&self, key: &str) -> Option<&str> {
+        self.program_args.get(key).map(String::as_str)
+    }
+
+    /// Starts timing the section with this label, or finishes timing it and logs how long it took in wall time if it
+    /// was already started.
+    pub fn mark_time(&mut self, label: String) -> anyhow::Result<()> {
+        match self.time_marks.remove(&label) {
+            Some(start) => {
+                let elapsed = start.elapsed().as_micros();
+                self.info(format!("{label} took {elapsed}us"))?;
+            }
+            None => {
+                self.time_marks.insert(label, Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    /// The buttons on the brain that are currently pressed, as a mask of [`protocol::BrainButton::mask`]s.
+    pub fn brain_buttons(&self) -> u32 {
+        self.brain_buttons
+    }
+
+    /// Changes the color of the brain's status LED, letting the frontend know if it changed.
+    pub fn set_brain_led(&mut self, color: RGB8) -> anyhow::Result<()> {
+        if color != self.brain_led {
+            self.brain_led = color;
+            self.protocol.send_sim(&SimEvent::BrainLed {
+                rgb: [color.r, color.g, color.b],
+            })?;
+        }
         Ok(())
     }
 
@@ -152,12 +737,101 @@ impl SdkState {
     pub fn wasi(&mut self) -> &mut WasiP1Ctx {
         &mut self.wasi
     }
+
+    /// Returns the resource limiter that keeps track of how much memory the program is using.
+    pub fn limiter(&mut self) -> &mut dyn ResourceLimiter {
+        self
+    }
+
+    /// Stops the program's memory from growing past `limit` bytes.
+    pub fn limit_memory(&mut self, limit: usize) {
+        self.memory_usage.limit = Some(limit);
+    }
+
+    /// Stops the program's tables from growing past `limit` elements.
+    pub fn limit_tables(&mut self, limit: u32) {
+        self.table_limit = Some(limit);
+    }
+
+    /// Sets the memory that checkpoints are taken from, once the program has been instantiated.
+    pub fn set_memory(&mut self, memory: Memory) {
+        self.memory = Some(memory);
+    }
+
+    /// The program's linear memory, if it has been instantiated.
+    pub fn memory(&self) -> Option<Memory> {
+        self.memory
+    }
+
+    /// Checks whether a diagnostic raised from the program has been reported recently.
+    pub fn check_repeated(&mut self, site: CallSite, level: LogLevel, message: &str) -> Repeated {
+        self.repeat_limiter.check(site, level, message)
+    }
 }
 
 impl Log for SdkState {
     fn log(&mut self, level: LogLevel, message: String) -> protocol::Result<()> {
-        self.protocol.send(&Event::Log { level, message })?;
-        Ok(())
+        self.protocol.log(level, message)
+    }
+}
+
+/// Records the size of the program's memory as it grows, so it can be reported without access to the store.
+#[derive(Debug, Default)]
+struct MemoryTracker {
+    size: usize,
+    maximum: Option<usize>,
+    /// The most memory the simulator lets the program use, regardless of its own maximum.
+    limit: Option<usize>,
+}
+
+impl MemoryTracker {
+    fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            size: self.size as u64,
+            maximum: self.maximum.map(|maximum| maximum as u64),
+        }
+    }
+}
+
+/// Keeps track of the program's memory, warning when it runs out because it reached its own maximum or the
+/// simulator's limit. Tables are limited the same way. The program sees the failed `memory.grow` or `table.grow` as
+/// usual.
+impl ResourceLimiter for SdkState {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let maximum = match (maximum, self.memory_usage.limit) {
+            (Some(maximum), Some(limit)) => Some(maximum.min(limit)),
+            (maximum, limit) => maximum.or(limit),
+        };
+        self.memory_usage.maximum = maximum;
+        if let Some(maximum) = maximum.filter(|&maximum| desired > maximum) {
+            self.warn(format!(
+                "Out of memory: the program tried to grow its memory from {current} to {desired} bytes, but it's \
+                 limited to {maximum} bytes"
+            ))?;
+            return Ok(false);
+        }
+        self.memory_usage.size = desired;
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, current: u32, desired: u32, maximum: Option<u32>) -> Result<bool> {
+        let maximum = match (maximum, self.table_limit) {
+            (Some(maximum), Some(limit)) => Some(maximum.min(limit)),
+            (maximum, limit) => maximum.or(limit),
+        };
+        if let Some(maximum) = maximum.filter(|&maximum| desired > maximum) {
+            self.warn(format!(
+                "Table limit reached: the program tried to grow a table from {current} to {desired} elements, but \
+                 it's limited to {maximum} elements"
+            ))?;
+            return Ok(false);
+        }
+        Ok(true)
     }
 }
 
@@ -167,22 +841,75 @@ const JUMP_TABLE_START: usize = 0x037FC000;
 pub struct JumpTableBuilder<'a> {
     store: &'a mut Store<SdkState>,
     jump_table: JumpTable,
+    /// The extension of the optional group being built, if the frontend didn't request it.
+    missing_extension: Option<&'static str>,
 }
 
 impl<'a> JumpTableBuilder<'a> {
     /// Inserts a function into the jump table at the given address.
+    ///
+    /// The name is the function's name in the SDK, which is used to identify it in diagnostics.
     pub fn insert<Params, Results>(
         &mut self,
         address: usize,
+        name: &'static str,
         func: impl IntoFunc<SdkState, Params, Results>,
     ) {
-        debug_assert!(
-            !self.jump_table.api.contains_key(&address),
-            "Duplicate jump table function at address {:#x}",
-            address
-        );
         let func = Func::wrap(&mut self.store, func);
+        match self.missing_extension {
+            Some(extension) => self.insert_missing(address, name, extension, func.ty(&*self.store)),
+            None => self.insert_func(address, name, func),
+        }
+    }
+
+    fn insert_func(&mut self, address: usize, name: &'static str, func: Func) {
+        if let Some(existing) = self.jump_table.names.get(&address) {
+            debug_assert!(
+                false,
+                "Duplicate jump table function at address {address:#x} ({existing} and {name})"
+            );
+        }
         self.jump_table.api.insert(address, func);
+        self.jump_table.names.insert(address, name);
+    }
+
+    /// Inserts a stub for a function that the simulator doesn't implement.
+    fn insert_stub(&mut self, address: usize, name: &'static str, func: Func) {
+        self.insert_func(address, name, func);
+        self.jump_table.stubs.insert(address);
+    }
+
+    /// Inserts a function from an optional group that the frontend didn't request, which traps with a message naming
+    /// the group's extension instead of doing anything.
+    fn insert_missing(
+        &mut self,
+        address: usize,
+        name: &'static str,
+        extension: &'static str,
+        ty: FuncType,
+    ) {
+        let func = Func::new(&mut self.store, ty, move |_caller, _params, _results| {
+            bail!(
+                "{name} is part of the {extension:?} extension, which the frontend didn't request during the handshake"
+            )
+        });
+        self.insert_stub(address, name, func);
+        self.jump_table
+            .missing_extensions
+            .insert(address, extension);
+    }
+
+    /// Builds an optional group of functions. If the frontend didn't request the group's extension, every function
+    /// in it traps when called.
+    fn group(&mut self, extension: &'static str, wanted: bool, build: impl FnOnce(&mut Self)) {
+        self.missing_extension = (!wanted).then_some(extension);
+        build(self);
+        self.missing_extension = None;
+    }
+
+    /// Returns whether a function has already been inserted at the given address.
+    pub fn contains(&self, address: usize) -> bool {
+        self.jump_table.api.contains_key(&address)
     }
 }
 
@@ -191,39 +918,145 @@ impl<'a> JumpTableBuilder<'a> {
 /// Addresses are the same as in the real VEX SDK and the `vex-sdk` rust crate.
 pub struct JumpTable {
     api: HashMap<usize, Func>,
+    names: HashMap<usize, &'static str>,
+    /// The addresses of functions that are only stubs.
+    stubs: HashSet<usize>,
+    /// The extension needed by each function in an optional group that the frontend didn't request.
+    missing_extensions: HashMap<usize, &'static str>,
 }
 
 impl JumpTable {
     /// Creates a new jump table which will use the given memory, and populates it with the default API.
     ///
     /// No changes are actually to the user program made apart from creating the resources for the jump table.
+    ///
+    /// Functions in optional groups that the frontend didn't request during the handshake trap when called, with a
+    /// message naming the extension that would have enabled them.
     pub fn new(store: &mut Store<SdkState>, memory: Memory) -> Self {
+        let protocol = &store.data().protocol;
+        let controller = protocol.wants_sdk_group("sdk-controller");
+        let motor = protocol.wants_sdk_group("sdk-motor");
+        let adi = protocol.wants_sdk_group("sdk-adi");
+        // Motors and ADI expanders are found through the generic device functions.
+        let devices = protocol.wants_sdk_group("sdk-devices") || motor || adi;
+        let usd = protocol.wants_sdk_group("sdk-usd");
+
         let mut builder = JumpTableBuilder {
             store,
             jump_table: JumpTable {
                 api: HashMap::new(),
+                names: HashMap::new(),
+                stubs: HashSet::new(),
+                missing_extensions: HashMap::new(),
             },
+            missing_extension: None,
         };
 
         build_display_jump_table(memory, &mut builder);
-        build_controller_jump_table(memory, &mut builder);
         build_serial_jump_table(memory, &mut builder);
-
-        // vexTasksRun
-        builder.insert(0x05c, move |mut caller: Caller<'_, SdkState>| {
-            caller.data_mut().run_tasks()
+        builder.group("sdk-controller", controller, |builder| {
+            build_controller_jump_table(memory, builder)
         });
-
-        // vexSystemHighResTimeGet
-        builder.insert(0x134, move |caller: Caller<'_, SdkState>| -> Result<u64> {
-            Ok(caller.data().program_start.elapsed().as_micros() as u64)
+        builder.group("sdk-devices", devices, |builder| {
+            build_device_jump_table(memory, builder)
         });
-
-        // vexSystemExitRequest
-        builder.insert(0x130, move || {
-            std::process::exit(0);
+        builder.group("sdk-motor", motor, |builder| {
+            build_motor_jump_table(memory, builder)
+        });
+        builder.group("sdk-adi", adi, |builder| {
+            build_adi_jump_table(memory, builder)
+        });
+        builder.group("sdk-usd", usd, |builder| {
+            build_usd_jump_table(memory, builder)
         });
 
+        builder.insert(
+            0x05c,
+            "vexTasksRun",
+            move |mut caller: Caller<'_, SdkState>| {
+                caller.data_mut().run_tasks()?;
+                apply_checkpoint_requests(&mut caller)?;
+                apply_memory_requests(&mut caller)
+            },
+        );
+
+        builder.insert(
+            0x134,
+            "vexSystemHighResTimeGet",
+            move |caller: Caller<'_, SdkState>| -> Result<u64> {
+                Ok(caller.data().clock.elapsed().as_micros() as u64)
+            },
+        );
+
+        builder.insert(
+            0x118,
+            "vexSystemTimeGet",
+            move |caller: Caller<'_, SdkState>| -> u32 {
+                caller.data().clock.elapsed().as_millis() as u32
+            },
+        );
+
+        builder.insert(
+            0x130,
+            "vexSystemExitRequest",
+            move |mut caller: Caller<'_, SdkState>| -> Result<()> {
+                Err(caller.data_mut().exit(ExitReason::ExitRequested))
+            },
+        );
+
+        builder.insert(
+            0x174,
+            "vexSystemBoot",
+            move |mut caller: Caller<'_, SdkState>| -> Result<()> {
+                let sdk = caller.data_mut();
+                let restart = sdk.restart_on_reboot;
+                sdk.protocol.send_sim(&SimEvent::Reboot { restart })?;
+                if restart {
+                    sdk.stop(ExitReason::Rebooted);
+                    Err(Reboot.into())
+                } else {
+                    Err(sdk.exit(ExitReason::Rebooted))
+                }
+            },
+        );
+
+        // Programs with their own scheduler set up the hardware timer and watchdog during startup. The simulator has
+        // no hardware timer and runs tasks when the program calls `vexTasksRun`, so setting it up only reports
+        // success. The watchdog is never fed or fired, but reading it counts milliseconds of simulated time since it
+        // was reinitialized.
+        builder.insert(0x158, "vexSystemTimerStop", move || {});
+        builder.insert(0x15c, "vexSystemTimerClearInterrupt", move || {});
+        builder.insert(
+            0x160,
+            "vexSystemTimerReinitForRtos",
+            move |_priority: u32, _handler: u32| -> i32 { 0 },
+        );
+        builder.insert(
+            0x164,
+            "vexSystemApplicationIRQHandler",
+            move |_icciar: u32| {},
+        );
+        builder.insert(
+            0x168,
+            "vexSystemWatchdogReinitRtos",
+            move |mut caller: Caller<'_, SdkState>| -> i32 {
+                let sdk = caller.data_mut();
+                sdk.watchdog_reset = sdk.clock.elapsed();
+                0
+            },
+        );
+        builder.insert(
+            0x16c,
+            "vexSystemWatchdogGet",
+            move |caller: Caller<'_, SdkState>| -> u32 {
+                let sdk = caller.data();
+                sdk.clock
+                    .elapsed()
+                    .saturating_sub(sdk.watchdog_reset)
+                    .as_millis() as u32
+            },
+        );
+
         bitflags! {
             /// The status bits returned by [`vex_sdk::vexCompetitionStatus`].
             #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -242,24 +1075,51 @@ impl JumpTable {
             }
         }
 
-        // vexCompetitionStatus
-        builder.insert(0x9d8, move |caller: Caller<'_, SdkState>| -> u32 {
-            let status = caller.data().competition_mode;
-            let mut bits = CompetitionStatus::empty();
-            if !status.enabled {
-                bits |= CompetitionStatus::DISABLED;
-            }
-            if status.mode == CompMode::Auto {
-                bits |= CompetitionStatus::AUTONOMOUS;
+        impl CompetitionStatus {
+            fn from_mode(mode: &CompetitionMode) -> Self {
+                let mut bits = Self::empty();
+                bits.set(Self::DISABLED, !mode.enabled);
+                bits.set(Self::AUTONOMOUS, mode.mode == CompMode::Auto);
+                bits.set(Self::CONNECTED, mode.connected);
+                bits.set(Self::SYSTEM, mode.is_competition);
+                bits
             }
-            if status.connected {
-                bits |= CompetitionStatus::CONNECTED;
-            }
-            if status.is_competition {
-                bits |= CompetitionStatus::SYSTEM;
+
+            fn to_mode(self) -> CompetitionMode {
+                CompetitionMode {
+                    enabled: !self.contains(Self::DISABLED),
+                    mode: if self.contains(Self::AUTONOMOUS) {
+                        CompMode::Auto
+                    } else {
+                        CompMode::Driver
+                    },
+                    connected: self.contains(Self::CONNECTED),
+                    is_competition: self.contains(Self::SYSTEM),
+                }
             }
-            bits.bits()
-        });
+        }
+
+        builder.insert(
+            0x9d8,
+            "vexCompetitionStatus",
+            move |caller: Caller<'_, SdkState>| -> u32 {
+                CompetitionStatus::from_mode(&caller.data().competition_mode).bits()
+            },
+        );
+
+        // The control word uses the same bits as the status, so setting it and reading the status back round-trips.
+        builder.insert(
+            0x9dc,
+            "vexCompetitionControl",
+            move |mut caller: Caller<'_, SdkState>, data: u32| -> Result<()> {
+                let mode = CompetitionStatus::from_bits_truncate(data).to_mode();
+                caller.data_mut().set_competition_mode(mode)?;
+                Ok(())
+            },
+        );
+
+        // This must come last so that it only fills in the functions that weren't implemented above.
+        build_unimplemented_jump_table(&mut builder);
 
         builder.jump_table
     }
@@ -267,11 +1127,36 @@ impl JumpTable {
     /// Applies the memory and table changes required to expose the jump table to the WebAssembly module.
     ///
     /// The memory must be big enough to hold the jump table. The indirect function table will be expanded with
-    /// enough new slots to hold all the functions in the jump table.
+    /// enough new slots to hold all the functions in the jump table, after the entries the program asked for.
     pub fn expose(self, store: &mut Store<SdkState>, table: &Table, memory: &Memory) -> Result<()> {
-        let sdk_base = table.size(&mut *store);
+        // Check every address before changing anything, so a bad entry is reported by name instead of failing partway
+        // through with an opaque out of bounds error.
+        let region_size = memory.data_size(&*store).saturating_sub(JUMP_TABLE_START);
+        let out_of_range = self
+            .names
+            .iter()
+            .filter(|(&address, _)| address.saturating_add(size_of::<u32>()) > region_size)
+            .min_by_key(|(&address, _)| address);
+        if let Some((address, name)) = out_of_range {
+            bail!(
+                "The jump table entry for {name} at address {address:#x} is outside the jump table's memory, which \
+                 ends at address {region_size:#x}"
+            );
+        }
+
+        let table_ty = table.ty(&*store);
+        let sdk_base = table.size(&*store).max(table_ty.minimum());
         let api_size = self.api.len() as u32;
-        table.grow(&mut *store, api_size, Ref::Func(None))?;
+        if let Some(maximum) = table_ty.maximum() {
+            if sdk_base + api_size > maximum {
+                bail!(
+                    "The program's indirect function table is limited to {maximum} entries, but {sdk_base} are used by \
+                     the program and {api_size} more are needed for the jump table"
+                );
+            }
+        }
+        let grow_by = sdk_base + api_size - table.size(&*store);
+        table.grow(&mut *store, grow_by, Ref::Func(None))?;
 
         for (offset, (address, method)) in self.api.into_iter().enumerate() {
             let sdk_index = sdk_base + (offset as u32);
@@ -284,9 +1169,23 @@ impl JumpTable {
                 &sdk_index.to_le_bytes(),
             )?;
         }
-        store
-            .data_mut()
-            .trace(format!("Jump table exposed with {api_size} functions"))?;
+        let mut functions: Vec<_> = self
+            .names
+            .into_iter()
+            .map(|(address, name)| JumpTableFunction {
+                address: address as u32,
+                name: name.to_string(),
+                implemented: !self.stubs.contains(&address),
+                missing_extension: self
+                    .missing_extensions
+                    .get(&address)
+                    .map(|extension| extension.to_string()),
+            })
+            .collect();
+        functions.sort_by_key(|function| function.address);
+        let sdk = store.data_mut();
+        sdk.trace(format!("Jump table exposed with {api_size} functions"))?;
+        sdk.protocol.send_sim(&SimEvent::JumpTable { functions })?;
         Ok(())
     }
 }
@@ -296,40 +1195,73 @@ pub trait MemoryExt {
     ///
     /// The string is guaranteed to exist for its entire lifetime, but because it is borrowed, it isn't possible for
     /// API consumers to call back into WASM code while holding it.
-    fn c_str<'a>(
-        &self,
-        store: &'a impl AsContext,
-        offset: usize,
-    ) -> Result<&'a CStr, FromBytesUntilNulError>;
-    fn read_c_string(
-        &self,
-        store: &impl AsContext,
-        offset: usize,
-    ) -> Result<CString, FromBytesUntilNulError>;
+    fn c_str<'a>(&self, store: &'a impl AsContext, offset: usize) -> Result<&'a CStr>;
+    fn read_c_string(&self, store: &impl AsContext, offset: usize) -> Result<CString>;
+    /// Borrows `len` bytes of this memory starting at `ptr`.
+    ///
+    /// Fails instead of panicking if any of the bytes are out of bounds, since pointers come from the program.
+    fn read_bytes<'a>(&self, store: &'a impl AsContext, ptr: u32, len: usize) -> Result<&'a [u8]>;
+    /// Copies `bytes` into this memory starting at `ptr`, failing if any of them would be out of bounds.
+    fn write_bytes(&self, store: &mut impl AsContextMut, ptr: u32, bytes: &[u8]) -> Result<()>;
 }
 
 impl MemoryExt for Memory {
-    fn c_str<'a>(
-        &self,
-        store: &'a impl AsContext,
-        offset: usize,
-    ) -> Result<&'a CStr, FromBytesUntilNulError> {
-        let bytes = &self.data(store)[offset..];
-        CStr::from_bytes_until_nul(bytes)
-    }
-    fn read_c_string(
-        &self,
-        store: &impl AsContext,
-        offset: usize,
-    ) -> Result<CString, FromBytesUntilNulError> {
+    fn c_str<'a>(&self, store: &'a impl AsContext, offset: usize) -> Result<&'a CStr> {
+        let bytes = self
+            .data(store)
+            .get(offset..)
+            .with_context(|| format!("Out of bounds string pointer {offset:#x}"))?;
+        Ok(CStr::from_bytes_until_nul(bytes)?)
+    }
+    fn read_c_string(&self, store: &impl AsContext, offset: usize) -> Result<CString> {
         self.c_str(store, offset).map(|s| s.to_owned())
     }
+    fn read_bytes<'a>(&self, store: &'a impl AsContext, ptr: u32, len: usize) -> Result<&'a [u8]> {
+        self.data(store)
+            .get(ptr as usize..)
+            .and_then(|bytes| bytes.get(..len))
+            .with_context(|| format!("Out of bounds memory read of {len} bytes at {ptr:#x}"))
+    }
+    fn write_bytes(&self, store: &mut impl AsContextMut, ptr: u32, bytes: &[u8]) -> Result<()> {
+        let len = bytes.len();
+        self.data_mut(store)
+            .get_mut(ptr as usize..)
+            .and_then(|dest| dest.get_mut(..len))
+            .with_context(|| format!("Out of bounds memory write of {len} bytes at {ptr:#x}"))?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
 }
 
 /// Utility macro for cloning a C-style string into simulator memory.
+///
+/// Invalid UTF-8 is replaced with `U+FFFD`, but a string without a NUL terminator is still an error.
 macro_rules! clone_c_string {
     ($addr:expr, from $caller:ident using $memory:ident) => {
-        $memory.c_str(&mut $caller, $addr)?.to_str()?.to_owned()
+        String::from_utf8_lossy($memory.c_str(&mut $caller, $addr)?.to_bytes()).into_owned()
     };
 }
 pub(crate) use clone_c_string;
+
+/// Reads the `printf` format string at `ptr`, reusing the parsed format from an earlier call if it hasn't changed.
+fn parsed_format(
+    caller: &mut Caller<'_, SdkState>,
+    memory: Memory,
+    ptr: u32,
+) -> Result<Arc<ParsedFormat>> {
+    let source = memory.c_str(&*caller, ptr as usize)?.to_bytes();
+    if let Some(parsed) = caller.data().format_cache.get(ptr, source) {
+        return Ok(parsed);
+    }
+    let parsed = Arc::new(ParsedFormat::parse(source));
+    caller.data_mut().format_cache.insert(ptr, parsed.clone());
+    Ok(parsed)
+}
+
+/// Warns that a `printf` width or precision from the program was too large and had to be clamped.
+fn warn_clamped_printf_field(caller: &mut Caller<'_, SdkState>) -> Result<()> {
+    warn_bt!(
+        caller,
+        "printf width or precision is larger than {MAX_FIELD_WIDTH}, so it was clamped"
+    )
+}
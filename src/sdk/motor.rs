@@ -0,0 +1,625 @@
+use std::{mem::size_of, time::Duration};
+
+use wasmtime::*;
+
+use crate::{
+    protocol::{warn_bt, Log},
+    sdk::SdkState,
+};
+
+use super::{JumpTableBuilder, MemoryExt};
+
+// MARK: Constants
+
+/// `vex-sdk` excerpt.
+mod constants {
+    #![allow(non_camel_case_types)]
+    #![allow(non_upper_case_globals)]
+
+    use bytemuck::{Pod, Zeroable};
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct V5MotorGearset(pub core::ffi::c_uint);
+
+    impl V5MotorGearset {
+        pub const kMotorGearSet_36: Self = Self(0);
+        pub const kMotorGearSet_18: Self = Self(1);
+        pub const kMotorGearSet_06: Self = Self(2);
+    }
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct V5MotorEncoderUnits(pub core::ffi::c_uint);
+
+    impl V5MotorEncoderUnits {
+        pub const kMotorEncoderDegrees: Self = Self(0);
+        pub const kMotorEncoderRotations: Self = Self(1);
+        pub const kMotorEncoderCounts: Self = Self(2);
+    }
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct V5MotorBrakeMode(pub core::ffi::c_uint);
+
+    impl V5MotorBrakeMode {
+        pub const kV5MotorBrakeModeCoast: Self = Self(0);
+    }
+
+    #[repr(C, packed)]
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Pod, Zeroable)]
+    pub struct V5_DeviceMotorPid {
+        pub kf: u8,
+        pub kp: u8,
+        pub ki: u8,
+        pub kd: u8,
+        pub filter: u8,
+        pub pad1: u8,
+        pub limit: u16,
+        pub threshold: u8,
+        pub loopspeed: u8,
+        pub pad2: [u8; 2],
+    }
+}
+
+use constants::*;
+
+// MARK: Jump table
+
+/// Get the motor referred to by a device handle, bringing its simulation up to date.
+fn motor<'a>(caller: &'a mut Caller<'_, SdkState>, device: u32) -> Result<&'a mut Motor> {
+    let sdk = caller.data_mut();
+    let now = sdk.clock.elapsed();
+    sdk.devices.motor(device, now)
+}
+
+pub fn build_motor_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
+    builder.insert(
+        0x2d0,
+        "vexDeviceMotorVelocitySet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, velocity: i32| -> Result<()> {
+            motor(&mut caller, device)?.control = MotorControl::Velocity(velocity);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x2d4,
+        "vexDeviceMotorVelocityGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<i32> {
+            Ok(motor(&mut caller, device)?.commanded_velocity())
+        },
+    );
+
+    builder.insert(
+        0x2d8,
+        "vexDeviceMotorActualVelocityGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<f64> {
+            Ok(motor(&mut caller, device)?.velocity())
+        },
+    );
+
+    builder.insert(
+        0x2dc,
+        "vexDeviceMotorDirectionGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<i32> {
+            let velocity = motor(&mut caller, device)?.velocity();
+            Ok(if velocity.abs() < 1.0 {
+                0
+            } else {
+                velocity.signum() as i32
+            })
+        },
+    );
+
+    builder.insert(
+        0x31c,
+        "vexDeviceMotorReverseFlagSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, reverse: u32| -> Result<()> {
+            motor(&mut caller, device)?.set_reversed(reverse != 0);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x320,
+        "vexDeviceMotorReverseFlagGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<u32> {
+            Ok(motor(&mut caller, device)?.reversed().into())
+        },
+    );
+
+    builder.insert(
+        0x324,
+        "vexDeviceMotorEncoderUnitsSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, units: u32| -> Result<()> {
+            motor(&mut caller, device)?.encoder_units = V5MotorEncoderUnits(units);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x328,
+        "vexDeviceMotorEncoderUnitsGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<u32> {
+            Ok(motor(&mut caller, device)?.encoder_units.0)
+        },
+    );
+
+    builder.insert(
+        0x32c,
+        "vexDeviceMotorBrakeModeSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, mode: u32| -> Result<()> {
+            motor(&mut caller, device)?.brake_mode = V5MotorBrakeMode(mode);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x330,
+        "vexDeviceMotorBrakeModeGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<u32> {
+            Ok(motor(&mut caller, device)?.brake_mode.0)
+        },
+    );
+
+    builder.insert(
+        0x334,
+        "vexDeviceMotorPositionSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, position: f64| -> Result<()> {
+            motor(&mut caller, device)?.set_position(position);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x338,
+        "vexDeviceMotorPositionGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<f64> {
+            Ok(motor(&mut caller, device)?.position())
+        },
+    );
+
+    builder.insert(
+        0x340,
+        "vexDeviceMotorPositionReset",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<()> {
+            motor(&mut caller, device)?.set_position(0.0);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x344,
+        "vexDeviceMotorTargetGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<f64> {
+            Ok(motor(&mut caller, device)?.target())
+        },
+    );
+
+    builder.insert(
+        0x34c,
+        "vexDeviceMotorAbsoluteTargetSet",
+        move |mut caller: Caller<'_, SdkState>,
+              device: u32,
+              position: f64,
+              velocity: i32|
+              -> Result<()> {
+            let motor = motor(&mut caller, device)?;
+            motor.control = MotorControl::Position {
+                target: motor.units_to_degrees(position) + motor.zero,
+                max_velocity: velocity,
+            };
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x350,
+        "vexDeviceMotorRelativeTargetSet",
+        move |mut caller: Caller<'_, SdkState>,
+              device: u32,
+              position: f64,
+              velocity: i32|
+              -> Result<()> {
+            let motor = motor(&mut caller, device)?;
+            motor.control = MotorControl::Position {
+                target: motor.absolute_position() + motor.units_to_degrees(position),
+                max_velocity: velocity,
+            };
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x35c,
+        "vexDeviceMotorVoltageSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, voltage: i32| -> Result<()> {
+            motor(&mut caller, device)?.control = MotorControl::Voltage(voltage);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x360,
+        "vexDeviceMotorVoltageGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<i32> {
+            match motor(&mut caller, device)?.control {
+                MotorControl::Voltage(voltage) => Ok(voltage),
+                _ => Ok(0),
+            }
+        },
+    );
+
+    builder.insert(
+        0x364,
+        "vexDeviceMotorGearingSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, gearset: u32| -> Result<()> {
+            motor(&mut caller, device)?.gearset = V5MotorGearset(gearset);
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x368,
+        "vexDeviceMotorGearingGet",
+        move |mut caller: Caller<'_, SdkState>, device: u32| -> Result<u32> {
+            Ok(motor(&mut caller, device)?.gearset.0)
+        },
+    );
+
+    builder.insert(
+        0x374,
+        "vexDeviceMotorVelocityUpdate",
+        move |mut caller: Caller<'_, SdkState>, device: u32, velocity: i32| -> Result<()> {
+            let motor = motor(&mut caller, device)?;
+            match &mut motor.control {
+                MotorControl::Position { max_velocity, .. } => *max_velocity = velocity,
+                control => *control = MotorControl::Velocity(velocity),
+            }
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x378,
+        "vexDeviceMotorPositionPidSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, pid_ptr: u32| -> Result<()> {
+            if pid_ptr == 0 {
+                warn_bt!(caller, "vexDeviceMotorPositionPidSet: pid must not be null")?;
+                return Ok(());
+            }
+            let pid = read_pid(&caller, memory, pid_ptr)?;
+            motor(&mut caller, device)?.position_pid = pid;
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x37c,
+        "vexDeviceMotorVelocityPidSet",
+        move |mut caller: Caller<'_, SdkState>, device: u32, pid_ptr: u32| -> Result<()> {
+            if pid_ptr == 0 {
+                warn_bt!(caller, "vexDeviceMotorVelocityPidSet: pid must not be null")?;
+                return Ok(());
+            }
+            let pid = read_pid(&caller, memory, pid_ptr)?;
+            motor(&mut caller, device)?.velocity_pid = pid;
+            Ok(())
+        },
+    );
+}
+
+fn read_pid(
+    caller: &Caller<'_, SdkState>,
+    memory: Memory,
+    pid_ptr: u32,
+) -> Result<V5_DeviceMotorPid> {
+    let pid_mem = memory.read_bytes(caller, pid_ptr, size_of::<V5_DeviceMotorPid>())?;
+    Ok(*bytemuck::from_bytes::<V5_DeviceMotorPid>(pid_mem))
+}
+
+// MARK: API
+
+/// The highest voltage that can be commanded to a motor, in millivolts.
+const MAX_VOLTAGE: f64 = 12_000.0;
+/// Time constant of the motor's open-loop response to a voltage command, in seconds.
+const VOLTAGE_TIME_CONSTANT: f64 = 0.05;
+/// Converts the velocity controller's proportional gain into a response time constant, in seconds.
+const VELOCITY_KP_SCALE: f64 = 1.5;
+/// Converts the position controller's proportional gain into RPM commanded per degree of error.
+const POSITION_KP_SCALE: f64 = 0.05;
+/// The largest step a position move is integrated over at once while it's settling, which keeps the loop stable.
+const MAX_STEP: Duration = Duration::from_millis(1);
+/// How close to its target, in degrees, and how slow, in RPM, a position move has to get before it's snapped to the
+/// target. This is far finer than the encoder can see.
+const SETTLED: f64 = 1e-3;
+
+/// What the motor is currently being told to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotorControl {
+    /// Open-loop voltage in millivolts.
+    Voltage(i32),
+    /// Closed-loop velocity in RPM.
+    Velocity(i32),
+    /// Closed-loop move to a target position, in degrees in the program's direction of rotation. This doesn't include
+    /// the offset set with [`Motor::set_position`].
+    Position { target: f64, max_velocity: i32 },
+}
+
+/// A simulated V5 Smart Motor.
+///
+/// The motor is modeled as a first-order system: the shaft velocity approaches the controller's desired velocity with
+/// a time constant derived from the velocity PID's proportional gain, and position moves command a velocity
+/// proportional to the remaining error using the position PID's proportional gain. Higher gains settle faster.
+///
+/// The model runs in the shaft's own direction of rotation. Commands and readings are mirrored when the motor is
+/// reversed, and reversing it keeps the reported position where it was instead of negating it.
+#[derive(Clone)]
+pub struct Motor {
+    pub control: MotorControl,
+    pub gearset: V5MotorGearset,
+    pub encoder_units: V5MotorEncoderUnits,
+    pub brake_mode: V5MotorBrakeMode,
+    pub position_pid: V5_DeviceMotorPid,
+    pub velocity_pid: V5_DeviceMotorPid,
+    /// The shaft's position in degrees since power-on.
+    position: f64,
+    /// The shaft's velocity in RPM.
+    velocity: f64,
+    /// Whether the program has reversed the motor's direction.
+    reversed: bool,
+    /// Added to the shaft's position in the program's direction, so the reported position doesn't jump when the motor
+    /// is reversed.
+    reversal_offset: f64,
+    /// The position in the program's direction that the program considers to be zero.
+    zero: f64,
+    last_update: Duration,
+}
+
+impl Motor {
+    pub fn new(now: Duration) -> Self {
+        Self {
+            control: MotorControl::Voltage(0),
+            gearset: V5MotorGearset::kMotorGearSet_18,
+            encoder_units: V5MotorEncoderUnits::kMotorEncoderDegrees,
+            brake_mode: V5MotorBrakeMode::kV5MotorBrakeModeCoast,
+            position_pid: V5_DeviceMotorPid {
+                kp: 40,
+                ..Default::default()
+            },
+            velocity_pid: V5_DeviceMotorPid {
+                kp: 30,
+                ..Default::default()
+            },
+            position: 0.0,
+            velocity: 0.0,
+            reversed: false,
+            reversal_offset: 0.0,
+            zero: 0.0,
+            last_update: now,
+        }
+    }
+
+    /// Advances the simulation to `now`.
+    pub fn update(&mut self, now: Duration) {
+        let mut elapsed = now.saturating_sub(self.last_update).as_secs_f64();
+        self.last_update = now;
+        while elapsed > 0.0 {
+            let Some(dt) = self.step_length() else {
+                break;
+            };
+            let dt = dt.min(elapsed);
+            elapsed -= dt;
+            self.step(dt);
+        }
+    }
+
+    /// How long the controller's desired velocity is known to stay the same, which is how far the next step can go.
+    ///
+    /// Voltage and velocity control ask for the same velocity until the program changes them, so they're always one
+    /// step. Position moves are too while the motor is far enough from its target to be held at its velocity limit,
+    /// then they're stepped through until they settle. A settled move is snapped to its target and returns `None`.
+    fn step_length(&mut self) -> Option<f64> {
+        let MotorControl::Position {
+            target,
+            max_velocity,
+        } = self.control
+        else {
+            return Some(f64::INFINITY);
+        };
+
+        let (limit, gain) = self.position_limit_and_gain(max_velocity);
+        let error = (target - self.absolute_position()).abs();
+        if limit == 0.0 || gain == 0.0 {
+            // The controller asks the motor to stop wherever it is.
+            return Some(f64::INFINITY);
+        }
+        if error < SETTLED && self.velocity.abs() < SETTLED {
+            self.position = self.direction() * (target - self.reversal_offset);
+            self.velocity = 0.0;
+            return None;
+        }
+
+        // The error can't shrink faster than the motor moves.
+        let top_speed = self.velocity.abs().max(self.free_speed()) * 6.0;
+        let limited_for = (error - limit / gain) / top_speed;
+        Some(limited_for.max(MAX_STEP.as_secs_f64()))
+    }
+
+    /// The velocity limit in RPM and the gain in RPM per degree of error of a position move.
+    fn position_limit_and_gain(&self, max_velocity: i32) -> (f64, f64) {
+        let limit = f64::from(max_velocity.unsigned_abs()).min(self.free_speed());
+        let gain = f64::from(self.position_pid.kp) * POSITION_KP_SCALE;
+        (limit, gain)
+    }
+
+    /// Moves the shaft towards the controller's desired velocity for `dt` seconds, taking the desired velocity to be
+    /// constant over the step.
+    fn step(&mut self, dt: f64) {
+        let free_speed = self.free_speed();
+        let (desired_velocity, time_constant) = match self.control {
+            MotorControl::Voltage(voltage) => (
+                free_speed * f64::from(voltage) / MAX_VOLTAGE,
+                VOLTAGE_TIME_CONSTANT,
+            ),
+            MotorControl::Velocity(velocity) => {
+                (f64::from(velocity), self.velocity_time_constant())
+            }
+            MotorControl::Position {
+                target,
+                max_velocity,
+            } => {
+                let (limit, gain) = self.position_limit_and_gain(max_velocity);
+                (
+                    ((target - self.absolute_position()) * gain).clamp(-limit, limit),
+                    self.velocity_time_constant(),
+                )
+            }
+        };
+        let desired_velocity = (self.direction() * desired_velocity).clamp(-free_speed, free_speed);
+
+        let decay = (-dt / time_constant).exp();
+        let initial_error = self.velocity - desired_velocity;
+        self.velocity = desired_velocity + initial_error * decay;
+        // The integral of the exponential approach, converted from RPM to degrees per second
+        self.position +=
+            (desired_velocity * dt + initial_error * time_constant * (1.0 - decay)) * 6.0;
+    }
+
+    fn velocity_time_constant(&self) -> f64 {
+        VELOCITY_KP_SCALE / f64::from(self.velocity_pid.kp.max(1))
+    }
+
+    /// The speed of the output shaft with no load at full voltage, in RPM.
+    fn free_speed(&self) -> f64 {
+        match self.gearset {
+            V5MotorGearset::kMotorGearSet_36 => 100.0,
+            V5MotorGearset::kMotorGearSet_06 => 600.0,
+            _ => 200.0,
+        }
+    }
+
+    /// The number of encoder counts per revolution of the output shaft.
+    fn counts_per_revolution(&self) -> f64 {
+        match self.gearset {
+            V5MotorGearset::kMotorGearSet_36 => 1800.0,
+            V5MotorGearset::kMotorGearSet_06 => 300.0,
+            _ => 900.0,
+        }
+    }
+
+    /// Converts a value in the program's encoder units to degrees.
+    fn units_to_degrees(&self, value: f64) -> f64 {
+        match self.encoder_units {
+            V5MotorEncoderUnits::kMotorEncoderRotations => value * 360.0,
+            V5MotorEncoderUnits::kMotorEncoderCounts => {
+                value * 360.0 / self.counts_per_revolution()
+            }
+            _ => value,
+        }
+    }
+
+    /// Converts a value in degrees to the program's encoder units.
+    fn degrees_to_units(&self, degrees: f64) -> f64 {
+        degrees / self.units_to_degrees(1.0)
+    }
+
+    /// 1 if the motor spins in the shaft's own direction, or -1 if it's reversed.
+    fn direction(&self) -> f64 {
+        if self.reversed {
+            -1.0
+        } else {
+            1.0
+        }
+    }
+
+    /// The shaft's position in degrees in the program's direction, before the program's zero is applied.
+    fn absolute_position(&self) -> f64 {
+        self.direction() * self.position + self.reversal_offset
+    }
+
+    pub fn reversed(&self) -> bool {
+        self.reversed
+    }
+
+    /// Reverses the motor's direction, so commands and readings are mirrored. The reported position stays where it
+    /// was, so reversing a moving motor doesn't make its position jump.
+    pub fn set_reversed(&mut self, reversed: bool) {
+        if reversed != self.reversed {
+            self.reversal_offset += 2.0 * self.direction() * self.position;
+            self.reversed = reversed;
+        }
+    }
+
+    /// The shaft's velocity in RPM, in the program's direction.
+    pub fn velocity(&self) -> f64 {
+        self.direction() * self.velocity
+    }
+
+    /// The position of the motor in the program's encoder units.
+    pub fn position(&self) -> f64 {
+        self.degrees_to_units(self.absolute_position() - self.zero)
+    }
+
+    /// Makes the motor's current position read as `position` (in the program's encoder units).
+    ///
+    /// The target of a position move is measured from the same zero, so it's moved along with it and reads the same
+    /// as before.
+    pub fn set_position(&mut self, position: f64) {
+        let zero = self.absolute_position() - self.units_to_degrees(position);
+        if let MotorControl::Position { target, .. } = &mut self.control {
+            *target += zero - self.zero;
+        }
+        self.zero = zero;
+    }
+
+    /// The target of the current position move in the program's encoder units, or 0 if the motor isn't moving to a
+    /// position.
+    pub fn target(&self) -> f64 {
+        match self.control {
+            MotorControl::Position { target, .. } => self.degrees_to_units(target - self.zero),
+            _ => 0.0,
+        }
+    }
+
+    /// The velocity the motor is being told to spin at, in RPM.
+    pub fn commanded_velocity(&self) -> i32 {
+        match self.control {
+            MotorControl::Velocity(velocity) => velocity,
+            MotorControl::Position { max_velocity, .. } => max_velocity,
+            MotorControl::Voltage(_) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_voltage_commands_over_long_gaps() {
+        let mut motor = Motor::new(Duration::ZERO);
+        motor.control = MotorControl::Voltage(12_000);
+        motor.update(Duration::from_secs(3600));
+
+        // A green motor at full voltage falls one time constant behind its free speed of 200 RPM.
+        let expected = 200.0 * 6.0 * (3600.0 - VOLTAGE_TIME_CONSTANT);
+        assert!((motor.velocity() - 200.0).abs() < 1e-6);
+        assert!((motor.position() - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn settles_position_moves_over_long_gaps() {
+        let mut motor = Motor::new(Duration::ZERO);
+        motor.set_reversed(true);
+        motor.control = MotorControl::Position {
+            target: 10_000.0,
+            max_velocity: 200,
+        };
+        motor.update(Duration::from_secs(3600));
+
+        assert!((motor.position() - 10_000.0).abs() < 1e-9);
+        assert!(motor.velocity().abs() < 1e-9);
+    }
+}
@@ -1,66 +1,84 @@
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor, Read, Write},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Instant,
+};
 
-use anyhow::{anyhow, bail, Context};
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
 use vexide_simulator_protocol::{Event, SerialData};
 use wasmtime::*;
+use wasmtime_wasi::{HostOutputStream, StdoutStream, StreamResult, Subscribe};
 
 use crate::{
-    printf::{self, format, WasmVaList},
-    protocol::Protocol,
+    printf::{self, Formatted, WasmVaList},
+    protocol::{warn_bt, Log, Protocol},
     sdk::SdkState,
 };
 
-use super::{JumpTableBuilder, MemoryExt};
+use super::{parsed_format, warn_clamped_printf_field, JumpTableBuilder, MemoryExt};
 
 // MARK: Jump table
 
 pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
-    // vexSerialWriteChar
     builder.insert(
         0x898,
+        "vexSerialWriteChar",
         move |mut caller: Caller<'_, SdkState>, channel: u32, c: u32| -> Result<i32> {
             let written = caller.data_mut().serial.write(channel, &[c as u8]);
             Ok(written.map(|w| w as i32).unwrap_or(-1))
         },
     );
-    // vexSerialWriteBuffer
     builder.insert(
         0x89c,
+        "vexSerialWriteBuffer",
         move |mut caller: Caller<'_, SdkState>, channel: u32, data: u32, len: u32| -> Result<i32> {
-            let (memory, sdk) = memory.data_and_store_mut(&mut caller);
-            let buffer = &memory[data as usize..(data + len) as usize];
-            let written = sdk.serial.write(channel, buffer);
-            Ok(written.map(|w| w as i32).unwrap_or(-1))
+            let buffer = memory.read_bytes(&caller, data, len as usize)?.to_vec();
+            let sdk = caller.data_mut();
+            let Ok(mut written) = sdk.serial.write(channel, &buffer) else {
+                return Ok(-1);
+            };
+            if written < buffer.len() {
+                // Programs often don't check for short writes, so make room in the buffer and try once more.
+                sdk.serial.flush(&mut sdk.protocol)?;
+                written += sdk.serial.write(channel, &buffer[written..]).unwrap_or(0);
+            }
+            Ok(written as i32)
         },
     );
-    // vexSerialReadChar
     builder.insert(
         0x8a0,
+        "vexSerialReadChar",
         move |mut caller: Caller<'_, SdkState>, channel: u32| -> Result<i32> {
-            let byte = caller
-                .data_mut()
-                .serial
-                .read_byte(channel)
-                .map(|c| c as i32);
-            Ok(byte.unwrap_or(-1))
+            // Programs poll for input, so an empty buffer isn't worth a warning but a bad channel is.
+            match caller.data_mut().serial.read_byte(channel) {
+                Ok(byte) => Ok(byte.map_or(-1, |c| c as i32)),
+                Err(err) => {
+                    warn_bt!(caller, "vexSerialReadChar: {err}")?;
+                    Ok(-1)
+                }
+            }
         },
     );
-    // vexSerialPeekChar
     builder.insert(
         0x8a4,
+        "vexSerialPeekChar",
         move |mut caller: Caller<'_, SdkState>, channel: u32| -> Result<i32> {
-            let byte = caller
-                .data_mut()
-                .serial
-                .peek_byte(channel)
-                .map(|c| c as i32);
-            Ok(byte.unwrap_or(-1))
+            match caller.data_mut().serial.peek_byte(channel) {
+                Ok(byte) => Ok(byte.map_or(-1, |c| c as i32)),
+                Err(err) => {
+                    warn_bt!(caller, "vexSerialPeekChar: {err}")?;
+                    Ok(-1)
+                }
+            }
         },
     );
-    // vexSerialWriteFree
     // TODO: Can this return input buffer capacity?
     builder.insert(
         0x8ac,
+        "vexSerialWriteFree",
         move |mut caller: Caller<'_, SdkState>, channel: u32| -> Result<i32> {
             let num_free = caller
                 .data_mut()
@@ -72,19 +90,18 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
         },
     );
 
-    // vex_vprintf
     builder.insert(
         0x0f0,
+        "vex_vprintf",
         move |mut caller: Caller<'_, SdkState>, format_ptr: u32, args: u32| -> Result<i32> {
-            let fmt_str = memory.read_c_string(&caller, format_ptr as usize)?;
+            let format = parsed_format(&mut caller, memory, format_ptr)?;
             let va_list = WasmVaList::new(args, memory);
             let mut buf = String::new();
-            let written = format(
-                fmt_str.as_bytes(),
-                va_list,
-                &caller,
-                printf::output::fmt_write(&mut buf),
-            );
+            let Formatted { written, clamped } =
+                format.format(va_list, &caller, printf::output::fmt_write(&mut buf));
+            if clamped {
+                warn_clamped_printf_field(&mut caller)?;
+            }
             if written == -1 {
                 return Ok(-1);
             }
@@ -100,56 +117,52 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
         },
     );
 
-    // vex_vsprintf
     builder.insert(
         0x0f4,
+        "vex_vsprintf",
         move |mut caller: Caller<'_, SdkState>,
               buffer: u32,
               format_ptr: u32,
               args: u32|
               -> Result<i32> {
-            let fmt_str = memory.read_c_string(&caller, format_ptr as usize)?;
+            let format = parsed_format(&mut caller, memory, format_ptr)?;
             let va_list = WasmVaList::new(args, memory);
             let mut buf = String::new();
-            let written = format(
-                fmt_str.as_bytes(),
-                va_list,
-                &caller,
-                printf::output::fmt_write(&mut buf),
-            );
+            let Formatted { written, clamped } =
+                format.format(va_list, &caller, printf::output::fmt_write(&mut buf));
+            if clamped {
+                warn_clamped_printf_field(&mut caller)?;
+            }
             if written == -1 {
                 return Ok(-1);
             }
-            memory.data_mut(&mut caller)[buffer as usize..][..buf.len()]
-                .copy_from_slice(buf.as_bytes());
+            memory.write_bytes(&mut caller, buffer, buf.as_bytes())?;
             Ok(written)
         },
     );
 
-    // vex_vsnprintf
     builder.insert(
         0x0f8,
+        "vex_vsnprintf",
         move |mut caller: Caller<'_, SdkState>,
               buffer: u32,
               size: u32,
               format_ptr: u32,
               args: u32|
               -> Result<i32> {
-            let fmt_str = memory.read_c_string(&caller, format_ptr as usize)?;
+            let format = parsed_format(&mut caller, memory, format_ptr)?;
             let va_list = WasmVaList::new(args, memory);
             let mut buf = String::new();
-            let written = format(
-                fmt_str.as_bytes(),
-                va_list,
-                &caller,
-                printf::output::fmt_write(&mut buf),
-            );
+            let Formatted { written, clamped } =
+                format.format(va_list, &caller, printf::output::fmt_write(&mut buf));
+            if clamped {
+                warn_clamped_printf_field(&mut caller)?;
+            }
             if written == -1 {
                 return Ok(-1);
             }
             let len = std::cmp::min(size as usize, buf.len());
-            memory.data_mut(&mut caller)[buffer as usize..][..len]
-                .copy_from_slice(&buf.as_bytes()[..len]);
+            memory.write_bytes(&mut caller, buffer, &buf.as_bytes()[..len])?;
             Ok(written)
         },
     );
@@ -160,16 +173,73 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
 const STDOUT_BUFFER_SIZE: usize = 2048;
 const STDIN_BUFFER_SIZE: usize = 4096;
 
+type StdoutBuffer = Cursor<[u8; STDOUT_BUFFER_SIZE]>;
+
 pub struct Serial {
-    stdout_buffer: Cursor<[u8; STDOUT_BUFFER_SIZE]>,
-    stdin_buffer: Cursor<[u8; STDIN_BUFFER_SIZE]>,
+    /// Shared with the WASI stdout and stderr streams so that they end up in the same place as SDK serial writes.
+    stdout_buffer: Arc<Mutex<StdoutBuffer>>,
+    /// Input that the program hasn't read yet, oldest first.
+    stdin_buffer: VecDeque<u8>,
+    /// Bytes read from the host's stdin by the passthrough thread, if it was started.
+    host_input: Option<mpsc::Receiver<Vec<u8>>>,
+    /// Output that was taken from the buffer but hasn't been sent yet because of the event rate limit.
+    pending_output: Vec<u8>,
+    last_flush: Option<Instant>,
+    /// Whether output is also written to the host's stderr as it's taken from the buffer.
+    tee_to_host_stderr: bool,
 }
 
 impl Serial {
     pub fn new() -> Self {
         Self {
-            stdout_buffer: Cursor::new([0; STDOUT_BUFFER_SIZE]),
-            stdin_buffer: Cursor::new([0; STDIN_BUFFER_SIZE]),
+            stdout_buffer: Arc::new(Mutex::new(Cursor::new([0; STDOUT_BUFFER_SIZE]))),
+            stdin_buffer: VecDeque::new(),
+            host_input: None,
+            pending_output: Vec::new(),
+            last_flush: None,
+            tee_to_host_stderr: false,
+        }
+    }
+
+    /// Starts a thread that forwards everything typed into the host's stdin to serial channel 1.
+    ///
+    /// This must only be used when the protocol isn't also reading from stdin.
+    pub fn pass_through_host_stdin(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut buffer = [0; 256];
+            // Stops at the end of stdin, or once the simulator has gone away.
+            while let Ok(len @ 1..) = stdin.read(&mut buffer) {
+                if tx.send(buffer[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        self.host_input = Some(rx);
+    }
+
+    /// Writes output to the host's stderr as well, without holding it back for the event rate limit.
+    ///
+    /// Stderr is used since stdout may be carrying the protocol.
+    pub fn tee_to_host_stderr(&mut self) {
+        self.tee_to_host_stderr = true;
+    }
+
+    /// Moves bytes received from the host's stdin into the input buffer of serial channel 1, returning how many
+    /// didn't fit and were dropped.
+    pub fn receive_host_input(&mut self) -> Result<usize> {
+        let Some(host_input) = &self.host_input else {
+            return Ok(0);
+        };
+        let bytes: Vec<u8> = host_input.try_iter().flatten().collect();
+        self.buffer_input(1, &bytes)
+    }
+
+    /// Creates a WASI output stream that writes to serial channel 1.
+    pub fn wasi_stream(&self) -> WasiSerialStream {
+        WasiSerialStream {
+            buffer: self.stdout_buffer.clone(),
         }
     }
 
@@ -178,6 +248,8 @@ impl Serial {
             1 => {
                 let count = self
                     .stdout_buffer
+                    .lock()
+                    .unwrap()
                     .write(buffer)
                     .context("Failed to write to stdout")?;
                 Ok(count)
@@ -190,6 +262,8 @@ impl Serial {
         match channel {
             1 => {
                 self.stdout_buffer
+                    .lock()
+                    .unwrap()
                     .write_all(buffer)
                     .context("Failed to write to stdout")?;
                 Ok(())
@@ -198,62 +272,126 @@ impl Serial {
         }
     }
 
-    pub fn buffer_input(&mut self, channel: u32, buffer: &[u8]) -> Result<()> {
+    /// Adds bytes to the end of a channel's input buffer, returning how many didn't fit and were dropped.
+    pub fn buffer_input(&mut self, channel: u32, buffer: &[u8]) -> Result<usize> {
         match channel {
             1 => {
-                self.stdin_buffer
-                    .write_all(buffer)
-                    .context("Failed to write to stdin")?;
-                Ok(())
+                let free = STDIN_BUFFER_SIZE - self.stdin_buffer.len();
+                let (kept, dropped) = buffer.split_at(buffer.len().min(free));
+                self.stdin_buffer.extend(kept);
+                Ok(dropped.len())
             }
             _ => Err(anyhow!("Invalid channel")),
         }
     }
 
-    pub fn read_byte(&mut self, channel: u32) -> Result<u8> {
+    /// Takes the next byte of input from a channel, or returns `None` if none has been received.
+    pub fn read_byte(&mut self, channel: u32) -> Result<Option<u8>> {
         match channel {
-            1 => {
-                let byte = self.peek_byte(channel)?;
-                self.stdin_buffer.seek(SeekFrom::Current(-1)).unwrap();
-                Ok(byte)
-            }
+            1 => Ok(self.stdin_buffer.pop_front()),
             _ => Err(anyhow!("Invalid channel")),
         }
     }
 
-    pub fn peek_byte(&mut self, channel: u32) -> Result<u8> {
+    /// Returns the next byte of input from a channel without taking it, or `None` if none has been received.
+    pub fn peek_byte(&mut self, channel: u32) -> Result<Option<u8>> {
         match channel {
-            1 => {
-                let pos = self.stdin_buffer.position();
-                if pos == 0 {
-                    bail!("No data in stdin buffer");
-                }
-                let idx = pos - 1;
-                let byte = self.stdin_buffer.get_ref()[idx as usize];
-                Ok(byte)
-            }
+            1 => Ok(self.stdin_buffer.front().copied()),
             _ => Err(anyhow!("Invalid channel")),
         }
     }
 
     pub fn num_free_bytes(&mut self, channel: u32) -> Result<usize> {
         match channel {
-            1 => Ok(STDOUT_BUFFER_SIZE - self.stdout_buffer.position() as usize),
+            1 => Ok(STDOUT_BUFFER_SIZE - self.stdout_buffer.lock().unwrap().position() as usize),
             _ => Err(anyhow!("Invalid channel")),
         }
     }
 
+    /// Sends buffered output to the frontend if the event rate allows it.
+    ///
+    /// When it doesn't, the output is moved out of the buffer to be sent with the next flush, so the program can keep
+    /// writing without any bytes being lost.
     pub fn flush(&mut self, protocol: &mut Protocol) -> Result<()> {
-        if self.stdout_buffer.position() == 0 {
-            return Ok(());
+        self.take_buffered_output();
+        if protocol.event_rate_allows(self.last_flush) {
+            self.send_pending_output(protocol)?;
+        }
+        Ok(())
+    }
+
+    /// Sends all buffered output to the frontend, regardless of the event rate.
+    pub fn flush_all(&mut self, protocol: &mut Protocol) -> Result<()> {
+        self.take_buffered_output();
+        self.send_pending_output(protocol)
+    }
+
+    fn take_buffered_output(&mut self) {
+        let mut stdout_buffer = self.stdout_buffer.lock().unwrap();
+        if stdout_buffer.position() == 0 {
+            return;
         }
-        let stdout = std::mem::replace(
-            &mut self.stdout_buffer,
-            Cursor::new([0; STDOUT_BUFFER_SIZE]),
-        );
+        let stdout = std::mem::replace(&mut *stdout_buffer, Cursor::new([0; STDOUT_BUFFER_SIZE]));
+        drop(stdout_buffer);
         let len = stdout.position() as usize;
-        let bytes = &stdout.into_inner()[0..len];
-        protocol.send(&Event::Serial(SerialData::new(1, bytes)))?;
+        let stdout = stdout.into_inner();
+        let output = &stdout[0..len];
+        if self.tee_to_host_stderr {
+            // Losing the copy in the terminal isn't worth stopping the program over.
+            let mut stderr = io::stderr().lock();
+            _ = stderr.write_all(output).and_then(|()| stderr.flush());
+        }
+        self.pending_output.extend_from_slice(output);
+    }
+
+    fn send_pending_output(&mut self, protocol: &mut Protocol) -> Result<()> {
+        if self.pending_output.is_empty() {
+            return Ok(());
+        }
+        let bytes = std::mem::take(&mut self.pending_output);
+        protocol.send(&Event::Serial(SerialData::new(1, bytes.as_slice())))?;
+        self.last_flush = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// A WASI stdout/stderr stream that forwards writes into the serial channel 1 output buffer.
+///
+/// The program can't be interrupted in the middle of a WASI write to flush the buffer, so bytes that don't fit are
+/// dropped instead of blocking forever.
+#[derive(Clone)]
+pub struct WasiSerialStream {
+    buffer: Arc<Mutex<StdoutBuffer>>,
+}
+
+impl StdoutStream for WasiSerialStream {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+impl HostOutputStream for WasiSerialStream {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        // A full buffer isn't an error here; the overflow is intentionally discarded.
+        _ = self.buffer.lock().unwrap().write(&bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        // Output is sent to the frontend the next time the simulator runs its tasks.
         Ok(())
     }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(STDOUT_BUFFER_SIZE)
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscribe for WasiSerialStream {
+    async fn ready(&mut self) {}
 }
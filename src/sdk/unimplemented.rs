@@ -0,0 +1,141 @@
+use wasmtime::*;
+
+use crate::{
+    protocol::{warn_bt, Log},
+    sdk::SdkState,
+};
+
+use self::Ty::*;
+use super::JumpTableBuilder;
+
+/// A WebAssembly value type used in the signatures of SDK functions.
+#[derive(Debug, Clone, Copy)]
+enum Ty {
+    I32,
+    I64,
+    F64,
+}
+
+impl Ty {
+    /// The value returned by functions that haven't been implemented.
+    const fn default_value(self) -> Val {
+        match self {
+            Ty::I32 => Val::I32(0),
+            Ty::I64 => Val::I64(0),
+            Ty::F64 => Val::F64(0),
+        }
+    }
+}
+
+impl From<Ty> for ValType {
+    fn from(ty: Ty) -> Self {
+        match ty {
+            Ty::I32 => ValType::I32,
+            Ty::I64 => ValType::I64,
+            Ty::F64 => ValType::F64,
+        }
+    }
+}
+
+/// An SDK function which the simulator knows about but doesn't implement yet.
+struct SdkFunction {
+    address: usize,
+    name: &'static str,
+    params: &'static [Ty],
+    results: &'static [Ty],
+}
+
+const fn sdk_fn(
+    address: usize,
+    name: &'static str,
+    params: &'static [Ty],
+    results: &'static [Ty],
+) -> SdkFunction {
+    SdkFunction {
+        address,
+        name,
+        params,
+        results,
+    }
+}
+
+/// Signatures of SDK functions that programs may call but which have no implementation in the jump table.
+///
+/// Pointers and device handles are `I32` since programs are compiled for `wasm32`.
+const UNIMPLEMENTED_FUNCTIONS: &[SdkFunction] = &[
+    sdk_fn(0x11c, "vexGettime", &[I32], &[]),
+    sdk_fn(0x120, "vexGetdate", &[I32], &[]),
+    sdk_fn(0x138, "vexSystemPowerupTimeGet", &[], &[I64]),
+    sdk_fn(0x190, "vexDevicesGetNumber", &[], &[I32]),
+    sdk_fn(0x194, "vexDevicesGetNumberByType", &[I32], &[I32]),
+    sdk_fn(0x198, "vexDevicesGet", &[], &[I32]),
+    sdk_fn(0x2e0, "vexDeviceMotorModeSet", &[I32, I32], &[]),
+    sdk_fn(0x2e4, "vexDeviceMotorModeGet", &[I32], &[I32]),
+    sdk_fn(0x2e8, "vexDeviceMotorPwmSet", &[I32, I32], &[]),
+    sdk_fn(0x2ec, "vexDeviceMotorPwmGet", &[I32], &[I32]),
+    sdk_fn(0x2f0, "vexDeviceMotorCurrentLimitSet", &[I32, I32], &[]),
+    sdk_fn(0x2f4, "vexDeviceMotorCurrentLimitGet", &[I32], &[I32]),
+    sdk_fn(0x2f8, "vexDeviceMotorCurrentGet", &[I32], &[I32]),
+    sdk_fn(0x2fc, "vexDeviceMotorPowerGet", &[I32], &[F64]),
+    sdk_fn(0x300, "vexDeviceMotorTorqueGet", &[I32], &[F64]),
+    sdk_fn(0x304, "vexDeviceMotorEfficiencyGet", &[I32], &[F64]),
+    sdk_fn(0x308, "vexDeviceMotorTemperatureGet", &[I32], &[F64]),
+    sdk_fn(0x30c, "vexDeviceMotorOverTempFlagGet", &[I32], &[I32]),
+    sdk_fn(0x310, "vexDeviceMotorCurrentLimitFlagGet", &[I32], &[I32]),
+    sdk_fn(0x314, "vexDeviceMotorZeroVelocityFlagGet", &[I32], &[I32]),
+    sdk_fn(0x318, "vexDeviceMotorZeroPositionFlagGet", &[I32], &[I32]),
+    sdk_fn(0x33c, "vexDeviceMotorPositionRawGet", &[I32, I32], &[I32]),
+    sdk_fn(0x348, "vexDeviceMotorServoTargetSet", &[I32, F64], &[]),
+    sdk_fn(0x354, "vexDeviceMotorFaultsGet", &[I32], &[I32]),
+    sdk_fn(0x358, "vexDeviceMotorFlagsGet", &[I32], &[I32]),
+    sdk_fn(0x36c, "vexDeviceMotorVoltageLimitSet", &[I32, I32], &[]),
+    sdk_fn(0x370, "vexDeviceMotorVoltageLimitGet", &[I32], &[I32]),
+    sdk_fn(
+        0x380,
+        "vexDeviceMotorExternalProfileSet",
+        &[I32, F64, I32],
+        &[],
+    ),
+    sdk_fn(0xa00, "vexBatteryVoltageGet", &[], &[I32]),
+    sdk_fn(0xa04, "vexBatteryCurrentGet", &[], &[I32]),
+    sdk_fn(0xa08, "vexBatteryTemperatureGet", &[], &[F64]),
+    sdk_fn(0xa0c, "vexBatteryCapacityGet", &[], &[F64]),
+];
+
+/// Fills the addresses of known SDK functions that haven't been implemented with stubs that warn about which one was
+/// called and return zero, rather than leaving them empty and letting the program trap on a null function pointer.
+pub fn build_unimplemented_jump_table(builder: &mut JumpTableBuilder) {
+    for function in UNIMPLEMENTED_FUNCTIONS {
+        if builder.contains(function.address) {
+            continue;
+        }
+
+        let ty = FuncType::new(
+            builder.store.engine(),
+            function.params.iter().map(|&ty| ty.into()),
+            function.results.iter().map(|&ty| ty.into()),
+        );
+        let SdkFunction {
+            address,
+            name,
+            results,
+            ..
+        } = *function;
+        let func = Func::new(
+            &mut builder.store,
+            ty,
+            move |mut caller: Caller<'_, SdkState>, _params, out: &mut [Val]| {
+                warn_bt!(
+                    caller,
+                    key = address as u64,
+                    "called unimplemented SDK function at {address:#x} ({name})"
+                )?;
+                for (out, ty) in out.iter_mut().zip(results) {
+                    *out = ty.default_value();
+                }
+                Ok(())
+            },
+        );
+        builder.insert_stub(address, name, func);
+    }
+}
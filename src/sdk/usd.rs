@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use wasmtime::*;
+
+use crate::{
+    protocol::{warn_bt, Log},
+    sdk::{clone_c_string, MemoryExt, SdkState},
+};
+
+use super::JumpTableBuilder;
+
+// MARK: Constants
+
+/// `vex-sdk` excerpt.
+mod constants {
+    #![allow(non_camel_case_types)]
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct FRESULT(pub core::ffi::c_uint);
+
+    impl FRESULT {
+        pub const FR_OK: Self = Self(0);
+        pub const FR_NOT_READY: Self = Self(3);
+        pub const FR_NO_PATH: Self = Self(5);
+        pub const FR_INVALID_NAME: Self = Self(6);
+        pub const FR_DENIED: Self = Self(7);
+    }
+}
+
+use constants::*;
+
+// MARK: Jump table
+
+pub fn build_usd_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
+    builder.insert(
+        0x7d0,
+        "vexFileMountSD",
+        move |caller: Caller<'_, SdkState>| -> u32 {
+            if caller.data().usd.mounted() {
+                FRESULT::FR_OK.0
+            } else {
+                FRESULT::FR_NOT_READY.0
+            }
+        },
+    );
+
+    builder.insert(
+        0x7d4,
+        "vexFileDirectoryGet",
+        move |mut caller: Caller<'_, SdkState>, path: u32, buffer: u32, len: u32| -> Result<u32> {
+            let path = clone_c_string!(path as usize, from caller using memory);
+            let names = match caller.data().usd.list_dir(&path) {
+                Ok(names) => names,
+                Err(result) => return Ok(result.0),
+            };
+            // Names are separated by newlines, and as many as fit are written before the NUL terminator.
+            if len > 0 {
+                let mut listing: Vec<u8> = names
+                    .iter()
+                    .flat_map(|name| [name.as_bytes(), b"\n"])
+                    .flatten()
+                    .copied()
+                    .collect();
+                listing.truncate(len as usize - 1);
+                listing.push(0);
+                memory.write_bytes(&mut caller, buffer, &listing)?;
+            }
+            Ok(FRESULT::FR_OK.0)
+        },
+    );
+
+    builder.insert(
+        0x7d8,
+        "vexFileOpen",
+        move |mut caller: Caller<'_, SdkState>, path: u32, _mode: u32| -> Result<u32> {
+            let path = clone_c_string!(path as usize, from caller using memory);
+            // A null pointer tells the program that the file couldn't be opened.
+            Ok(caller.data_mut().usd.open_read(&path).unwrap_or(0))
+        },
+    );
+
+    builder.insert(
+        0x7dc,
+        "vexFileOpenWrite",
+        move |mut caller: Caller<'_, SdkState>, path: u32| -> Result<u32> {
+            let path = clone_c_string!(path as usize, from caller using memory);
+            Ok(caller.data_mut().usd.open_write(&path, true).unwrap_or(0))
+        },
+    );
+
+    builder.insert(
+        0x7e0,
+        "vexFileOpenCreate",
+        move |mut caller: Caller<'_, SdkState>, path: u32| -> Result<u32> {
+            let path = clone_c_string!(path as usize, from caller using memory);
+            Ok(caller.data_mut().usd.open_write(&path, false).unwrap_or(0))
+        },
+    );
+
+    builder.insert(
+        0x7e4,
+        "vexFileClose",
+        move |mut caller: Caller<'_, SdkState>, fdp: u32| -> Result<()> {
+            if let Err(err) = caller.data_mut().usd.close(fdp) {
+                warn_bt!(caller, "vexFileClose: {err}")?;
+            }
+            Ok(())
+        },
+    );
+
+    builder.insert(
+        0x7ec,
+        "vexFileWrite",
+        move |mut caller: Caller<'_, SdkState>,
+              buf: u32,
+              size: u32,
+              n_items: u32,
+              fdp: u32|
+              -> Result<i32> {
+            let len = size as usize * n_items as usize;
+            let bytes = memory.read_bytes(&caller, buf, len)?.to_vec();
+            if let Err(err) = caller.data_mut().usd.write(fdp, &bytes) {
+                warn_bt!(caller, "vexFileWrite: {err}")?;
+                return Ok(-1);
+            }
+            let sdk = caller.data_mut();
+            if let Some(delay) = sdk.usd.write_delay(len as u32) {
+                wait_for_card(sdk, delay)?;
+            }
+            Ok(len as i32)
+        },
+    );
+
+    builder.insert(
+        0x7f0,
+        "vexFileSize",
+        move |mut caller: Caller<'_, SdkState>, fdp: u32| -> Result<i32> {
+            match caller.data_mut().usd.size(fdp) {
+                Ok(size) => Ok(size.try_into().unwrap_or(i32::MAX)),
+                Err(err) => {
+                    warn_bt!(caller, "vexFileSize: {err}")?;
+                    Ok(-1)
+                }
+            }
+        },
+    );
+
+    builder.insert(
+        0x7f8,
+        "vexFileRead",
+        move |mut caller: Caller<'_, SdkState>,
+              buf: u32,
+              size: u32,
+              n_items: u32,
+              fdp: u32|
+              -> Result<i32> {
+            let len = size as u64 * n_items as u64;
+            let chunk = match caller.data_mut().usd.read(fdp, len) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    warn_bt!(caller, "vexFileRead: {err}")?;
+                    return Ok(-1);
+                }
+            };
+            memory.write_bytes(&mut caller, buf, &chunk)?;
+            Ok(chunk.len() as i32)
+        },
+    );
+
+    builder.insert(
+        0x7fc,
+        "vexFileDriveStatus",
+        move |caller: Caller<'_, SdkState>, _drive: u32| -> u32 {
+            caller.data().usd.mounted().into()
+        },
+    );
+
+    builder.insert(
+        0x800,
+        "vexFileTell",
+        move |mut caller: Caller<'_, SdkState>, fdp: u32| -> Result<i32> {
+            match caller.data_mut().usd.tell(fdp) {
+                Ok(offset) => Ok(offset.try_into().unwrap_or(i32::MAX)),
+                Err(err) => {
+                    warn_bt!(caller, "vexFileTell: {err}")?;
+                    Ok(-1)
+                }
+            }
+        },
+    );
+
+    builder.insert(
+        0x808,
+        "vexFileStatus",
+        move |mut caller: Caller<'_, SdkState>, path: u32| -> Result<u32> {
+            let path = clone_c_string!(path as usize, from caller using memory);
+            Ok(caller.data().usd.status(&path) as u32)
+        },
+    );
+}
+
+/// Blocks until `delay` of simulated time has passed, as if the card were still busy writing.
+///
+/// Like waiting for vsync, commands are processed while simulated time is paused, since it could never resume
+/// otherwise.
+fn wait_for_card(sdk: &mut SdkState, delay: Duration) -> Result<()> {
+    let finish = sdk.clock.elapsed() + delay;
+    loop {
+        let now = sdk.clock.elapsed();
+        if now >= finish {
+            return Ok(());
+        }
+        sleep((finish - now).min(Duration::from_millis(1)));
+        if sdk.clock.paused() {
+            sdk.recv_all_commands()?;
+        }
+    }
+}
+
+// MARK: API
+
+/// What a path on the SD card refers to, as returned by `vexFileStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Missing = 0,
+    File = 1,
+    Directory = 2,
+}
+
+/// The simulated SD card, which is a host directory mounted with `--usd`.
+///
+/// Programs see the directory as the root of the card, and can't reach anything outside of it, whether through `..`
+/// or symbolic links. Without a mounted directory, the card behaves as if it isn't inserted.
+#[derive(Default)]
+pub struct Usd {
+    /// The mounted directory, canonicalized so that paths inside it can be checked against it.
+    root: Option<PathBuf>,
+    /// Files the program has open, by the handle it was given in place of a `FIL` pointer.
+    files: HashMap<u32, File>,
+    last_handle: u32,
+    /// How long writing each byte takes, set with `--usd-write-delay`.
+    write_delay: Option<Duration>,
+}
+
+impl Usd {
+    /// Uses a host directory as the contents of the SD card, replacing any card that was already inserted.
+    pub fn mount(&mut self, root: &Path) -> anyhow::Result<()> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to open the SD card directory {}", root.display()))?;
+        if !root.is_dir() {
+            bail!("The SD card directory {} isn't a directory", root.display());
+        }
+        self.eject();
+        self.root = Some(root);
+        Ok(())
+    }
+
+    /// Removes the SD card, closing every file the program has open on it.
+    pub fn eject(&mut self) {
+        self.root = None;
+        self.files.clear();
+    }
+
+    pub fn mounted(&self) -> bool {
+        self.root.is_some()
+    }
+
+    /// Finds where a path on the SD card is on the host, if it exists inside the mounted directory.
+    ///
+    /// Paths can optionally start with `/usd`, like the ones that vexide and PROS programs use.
+    fn resolve(&self, path: impl AsRef<Path>) -> Result<PathBuf, FRESULT> {
+        let root = self.root.as_ref().ok_or(FRESULT::FR_NOT_READY)?;
+        let path = path.as_ref();
+        let path = path.strip_prefix("/usd").unwrap_or(path);
+
+        let mut host = root.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => host.push(name),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(FRESULT::FR_INVALID_NAME)
+                }
+            }
+        }
+        let host = host.canonicalize().map_err(|_| FRESULT::FR_NO_PATH)?;
+        if !host.starts_with(root) {
+            return Err(FRESULT::FR_DENIED);
+        }
+        Ok(host)
+    }
+
+    /// Finds where a file that doesn't exist yet would be created on the host, if its directory exists inside the
+    /// mounted directory.
+    fn resolve_new(&self, path: &str) -> Result<PathBuf, FRESULT> {
+        let path = Path::new(path);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(FRESULT::FR_INVALID_NAME);
+        };
+        let host = self.resolve(dir)?.join(name);
+        // A dangling symbolic link would otherwise let the program create a file wherever it points.
+        if host.symlink_metadata().is_ok() {
+            return Err(FRESULT::FR_DENIED);
+        }
+        Ok(host)
+    }
+
+    /// Lists the names of the files and directories in a directory on the SD card, in alphabetical order.
+    ///
+    /// Entries that are symbolic links leading out of the mounted directory, or whose names aren't valid UTF-8, are
+    /// left out.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<String>, FRESULT> {
+        let dir = self.resolve(path)?;
+        if !dir.is_dir() {
+            return Err(FRESULT::FR_NO_PATH);
+        }
+        let root = self.root.as_ref().ok_or(FRESULT::FR_NOT_READY)?;
+        let entries = fs::read_dir(&dir).map_err(|_| FRESULT::FR_DENIED)?;
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .path()
+                    .canonicalize()
+                    .is_ok_and(|path| path.starts_with(root))
+            })
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Opens a file on the SD card for reading, returning the handle the program uses to refer to it.
+    pub fn open_read(&mut self, path: &str) -> Result<u32, FRESULT> {
+        let host = self.resolve(path)?;
+        if host.is_dir() {
+            return Err(FRESULT::FR_DENIED);
+        }
+        let file = File::open(host).map_err(|_| FRESULT::FR_DENIED)?;
+        Ok(self.insert_file(file))
+    }
+
+    /// Opens a file on the SD card for writing, creating it if it doesn't exist. Writes are added to the end of the
+    /// file when appending, and otherwise replace its contents.
+    pub fn open_write(&mut self, path: &str, append: bool) -> Result<u32, FRESULT> {
+        let host = match self.resolve(path) {
+            Err(FRESULT::FR_NO_PATH) => self.resolve_new(path)?,
+            host => host?,
+        };
+        if host.is_dir() {
+            return Err(FRESULT::FR_DENIED);
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(host)
+            .map_err(|_| FRESULT::FR_DENIED)?;
+        Ok(self.insert_file(file))
+    }
+
+    fn insert_file(&mut self, file: File) -> u32 {
+        self.last_handle += 1;
+        self.files.insert(self.last_handle, file);
+        self.last_handle
+    }
+
+    fn file(&mut self, handle: u32) -> anyhow::Result<&mut File> {
+        if !self.mounted() {
+            bail!("there's no SD card inserted");
+        }
+        self.files
+            .get_mut(&handle)
+            .with_context(|| format!("{handle:#x} isn't an open file"))
+    }
+
+    pub fn close(&mut self, handle: u32) -> anyhow::Result<()> {
+        self.file(handle)?;
+        self.files.remove(&handle);
+        Ok(())
+    }
+
+    /// Reads up to `len` bytes from an open file at its current offset, which is moved past them.
+    ///
+    /// Only the bytes that are read are held in memory, so programs can stream files larger than the host would
+    /// like to load at once. Fewer than `len` bytes are returned when the end of the file is reached.
+    pub fn read(&mut self, handle: u32, len: u64) -> anyhow::Result<Vec<u8>> {
+        let mut chunk = Vec::new();
+        self.file(handle)?.take(len).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+
+    pub fn write(&mut self, handle: u32, bytes: &[u8]) -> anyhow::Result<()> {
+        self.file(handle)?.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn set_write_delay(&mut self, per_byte: Duration) {
+        self.write_delay = Some(per_byte);
+    }
+
+    /// How long writing `len` bytes takes, if writes are slowed down.
+    pub fn write_delay(&self, len: u32) -> Option<Duration> {
+        self.write_delay
+            .map(|per_byte| per_byte.saturating_mul(len))
+    }
+
+    pub fn size(&mut self, handle: u32) -> anyhow::Result<u64> {
+        Ok(self.file(handle)?.metadata()?.len())
+    }
+
+    pub fn tell(&mut self, handle: u32) -> anyhow::Result<u64> {
+        Ok(self.file(handle)?.stream_position()?)
+    }
+
+    /// Returns whether a path on the SD card is a file, a directory or doesn't exist.
+    pub fn status(&self, path: &str) -> FileStatus {
+        match self.resolve(path) {
+            Ok(host) if host.is_dir() => FileStatus::Directory,
+            Ok(_) => FileStatus::File,
+            Err(_) => FileStatus::Missing,
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+use v5wasm::protocol::{
+    ChannelTransport, Inbound, Outbound, Protocol, ProtocolError, ProtocolFormat, SimCommand,
+    TcpTransport,
+};
+use vexide_simulator_protocol::{Command, Event};
+
+#[test]
+fn handshake_over_channels() {
+    let (transport, commands, events) = ChannelTransport::new();
+    let mut protocol = Protocol::new(transport);
+
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    protocol.handshake(false).unwrap();
+
+    assert!(matches!(
+        events.try_recv(),
+        Ok(Outbound::Event(Event::Handshake { version: 1, .. }))
+    ));
+    assert!(matches!(
+        protocol.try_next(),
+        Ok(Some(Inbound::Command(Command::StartExecution)))
+    ));
+    assert!(matches!(protocol.try_next(), Ok(None)));
+}
+
+#[test]
+fn rejects_second_handshake() {
+    let (transport, commands, _events) = ChannelTransport::new();
+    let mut protocol = Protocol::new(transport);
+    protocol.handshake(true).unwrap();
+
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    assert!(matches!(
+        protocol.next(),
+        Err(ProtocolError::ReceivedHandshakeAttemptAfterHandshakeFinished)
+    ));
+}
+
+#[test]
+fn stops_when_frontend_disconnects() {
+    let (transport, commands, _events) = ChannelTransport::new();
+    let mut protocol = Protocol::new(transport);
+    drop(commands);
+
+    assert!(matches!(
+        protocol.next(),
+        Err(ProtocolError::RecvWorkerStopped)
+    ));
+}
+
+#[test]
+fn handshake_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut protocol = Protocol::new(TcpTransport::new(server, ProtocolFormat::Json).unwrap());
+
+    writeln!(client, r#"{{"Handshake":{{"version":1,"extensions":[]}}}}"#).unwrap();
+    protocol.handshake(false).unwrap();
+
+    let mut response = String::new();
+    BufReader::new(client).read_line(&mut response).unwrap();
+    assert!(response.starts_with(r#"{"Handshake":"#));
+}
+
+#[test]
+fn handshake_over_tcp_with_msgpack() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let format = ProtocolFormat::MessagePack;
+    let mut protocol = Protocol::new(TcpTransport::new(server, format).unwrap());
+
+    let handshake = Command::Handshake {
+        version: 1,
+        extensions: vec!["msgpack".to_owned()],
+    };
+    format.write(&mut client, &handshake).unwrap();
+    format.write(&mut client, &Command::StartExecution).unwrap();
+    protocol.handshake(false).unwrap();
+    assert!(matches!(
+        protocol.next(),
+        Ok(Inbound::Command(Command::StartExecution))
+    ));
+
+    let response: Event = format.read(&mut BufReader::new(client)).unwrap().unwrap();
+    assert!(matches!(response, Event::Handshake { extensions, .. } if extensions == ["msgpack"]));
+}
+
+#[test]
+fn msgpack_requires_frontend_extension() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let format = ProtocolFormat::MessagePack;
+    let mut protocol = Protocol::new(TcpTransport::new(server, format).unwrap());
+
+    let handshake = Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    };
+    format.write(&mut client, &handshake).unwrap();
+    assert!(matches!(
+        protocol.handshake(false),
+        Err(ProtocolError::MissingFrontendExtension { .. })
+    ));
+}
+
+#[test]
+fn malformed_messages_are_logged_and_skipped() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut protocol = Protocol::new(TcpTransport::new(server, ProtocolFormat::Json).unwrap());
+    protocol.handshake(true).unwrap();
+
+    writeln!(client, "{{not json").unwrap();
+    writeln!(client, r#""StartExecution""#).unwrap();
+    assert!(matches!(
+        protocol.next(),
+        Ok(Inbound::Command(Command::StartExecution))
+    ));
+
+    let mut log = String::new();
+    BufReader::new(client).read_line(&mut log).unwrap();
+    assert!(log.contains("Error"));
+    assert!(log.contains("{not json"));
+}
+
+#[test]
+fn handshake_times_out() {
+    let (transport, _commands, _events) = ChannelTransport::new();
+    let mut protocol = Protocol::new(transport);
+    protocol.set_handshake_timeout(Some(Duration::from_millis(50)));
+
+    assert!(matches!(
+        protocol.handshake(false),
+        Err(ProtocolError::HandshakeTimedOut { .. })
+    ));
+}
+
+#[test]
+fn deferred_commands_keep_arrival_order() {
+    let (transport, commands, _events) = ChannelTransport::new();
+    let mut protocol = Protocol::new(transport);
+    protocol.handshake(true).unwrap();
+
+    let time_scale = |factor| SimCommand::SetTimeScale { factor };
+    let factor = |msg: &Inbound| match msg {
+        Inbound::Sim(SimCommand::SetTimeScale { factor }) => Some(*factor),
+        _ => None,
+    };
+
+    commands.send_sim(time_scale(1.0));
+    commands.send_sim(time_scale(2.0));
+    commands.send(Command::StartExecution);
+    let msg = protocol
+        .wait_for_command(|msg| matches!(msg, Inbound::Command(Command::StartExecution)))
+        .unwrap();
+    assert!(matches!(msg, Inbound::Command(Command::StartExecution)));
+
+    // A command that was already deferred is found without waiting for a new one.
+    let msg = protocol
+        .wait_for_command(|msg| factor(msg) == Some(2.0))
+        .unwrap();
+    assert_eq!(factor(&msg), Some(2.0));
+
+    commands.send_sim(time_scale(3.0));
+    let order: Vec<Option<f64>> = std::iter::from_fn(|| protocol.try_next().unwrap())
+        .map(|msg| factor(&msg))
+        .collect();
+    assert_eq!(order, [Some(1.0), Some(3.0)]);
+}
@@ -0,0 +1,3602 @@
+use std::time::Duration;
+
+use rgb::RGB8;
+use v5wasm::{
+    protocol::{
+        BrainButton, ControllerAxis, DeviceKind, ExitReason, FontMetrics, Inbound, Outbound,
+        QueryTopic, SimCommand, SimEvent,
+    },
+    ControllerPreset, Simulator, SimulatorConfig,
+};
+use vexide_simulator_protocol::{
+    Command, CompMode, CompetitionMode, ControllerState, ControllerUpdate, Device, DrawCommand,
+    Event, LogLevel, MotorGearSet, Port, SerialData, Shape, TextLocation, TextMetrics,
+    V5FontFamily, V5FontSize, V5Text,
+};
+
+/// The code signature option that kills the program's other tasks when `_entry` returns.
+const KILL_THREADS_WHEN_MAIN_EXITS: u32 = 2;
+
+/// The `.cold_magic` section that signs a test program as a user program with the given code signature options.
+fn cold_magic(options: u32) -> String {
+    let options: String = options
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("\\{byte:02x}"))
+        .collect();
+    format!(
+        r#"(@custom ".cold_magic"
+        "XVX5"
+        "\00\00\00\00" ;; program type
+        "\02\00\00\00" ;; owner
+        "{options}" ;; options
+        "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")"#
+    )
+}
+
+/// Signs a test program's module with [`cold_magic`] and assembles it.
+fn parse(program: &str, options: u32) -> Vec<u8> {
+    let end = program
+        .rfind(')')
+        .expect("test programs should be a single module");
+    wat::parse_str(format!("{}    {}\n)", &program[..end], cold_magic(options))).unwrap()
+}
+
+/// Runs a program to completion after a handshake with no extensions and `commands`, returning the simulator so
+/// its events can be inspected.
+fn run_with(program: &[u8], commands: impl IntoIterator<Item = Inbound>) -> Simulator {
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let sender = simulator.command_sender().unwrap();
+    sender.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    for command in commands {
+        match command {
+            Inbound::Command(command) => sender.send(command),
+            Inbound::Sim(command) => sender.send_sim(command),
+        };
+    }
+    sender.send(Command::StartExecution);
+    drop(sender);
+
+    let reason = simulator.run(program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+    simulator
+}
+
+/// Writes "hello" to serial through the jump table entry for `vexSerialWriteBuffer`, then returns.
+const HELLO_PROGRAM: &str = r#"
+(module
+    (type $vexSerialWriteBuffer (func (param i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "hello")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vexSerialWriteBuffer)
+                (i32.const 1)
+                (i32.const 16)
+                (i32.const 5)
+                (i32.load (i32.const 0x037FC89C)))))
+)
+"#;
+
+#[test]
+fn runs_program_and_collects_events() {
+    let program = parse(HELLO_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    assert!(commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    }));
+    assert!(commands.send(Command::StartExecution));
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(matches!(
+        events.first(),
+        Some(Outbound::Event(Event::Handshake { .. }))
+    ));
+    let serial = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data),
+            _ => None,
+        })
+        .expect("the program's serial output should have been flushed");
+    assert_eq!(serial.channel, 1);
+    assert_eq!(serial.to_bytes().unwrap(), b"hello");
+    assert!(matches!(
+        events.last(),
+        Some(Outbound::Sim(SimEvent::Exited {
+            reason: ExitReason::Completed
+        }))
+    ));
+}
+
+#[test]
+fn answers_state_queries() {
+    let simulator = run_with(
+        &parse(HELLO_PROGRAM, 0),
+        [Inbound::Sim(SimCommand::Query {
+            what: vec![QueryTopic::Devices, QueryTopic::Display],
+        })],
+    );
+
+    let state = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Sim(SimEvent::State(state)) => Some(state),
+            _ => None,
+        })
+        .expect("the query should have been answered");
+    assert_eq!(state.devices.unwrap().len(), 0);
+    assert_eq!(state.display.unwrap().width, 480);
+    assert!(state.competition.is_none());
+    assert!(state.elapsed_micros.is_none());
+}
+
+/// Decodes a 2x2 BMP into a `V5Image` whose `data` pointer leaves only 8 bytes before the end of memory.
+const BMP_OVERFLOW_PROGRAM: &str = r#"
+(module
+    (type $vexImageBmpRead (func (param i32 i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16)
+        "BM"
+        "\46\00\00\00" ;; file size
+        "\00\00\00\00"
+        "\36\00\00\00" ;; pixel data offset
+        "\28\00\00\00" ;; info header size
+        "\02\00\00\00" ;; width
+        "\02\00\00\00" ;; height
+        "\01\00"       ;; planes
+        "\18\00"       ;; bits per pixel
+        "\00\00\00\00" ;; compression
+        "\10\00\00\00" ;; image size
+        "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00"
+        "\ff\ff\ff\00\00\00\00\00"
+        "\ff\ff\ff\00\00\00\00\00")
+    ;; V5Image { width: 0, height: 0, data: 0xfff8, p: 0 }
+    (data (i32.const 128) "\00\00\00\00\f8\ff\00\00\00\00\00\00")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vexImageBmpRead)
+                (i32.const 16)
+                (i32.const 128)
+                (i32.const 16)
+                (i32.const 16)
+                (i32.load (i32.const 0x037FC990)))))
+)
+"#;
+
+#[test]
+fn rejects_bmp_output_past_end_of_memory() {
+    let simulator = run_with(&parse(BMP_OVERFLOW_PROGRAM, 0), []);
+
+    assert!(simulator.events().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { message, .. })
+            if message.starts_with("vexImageBmpRead: Out of bounds memory write")
+    )));
+}
+
+#[test]
+fn checks_programs_without_running_them() {
+    let program = parse(HELLO_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+    simulator.check(&program).unwrap();
+    assert!(!simulator
+        .events()
+        .any(|event| matches!(event, Outbound::Event(Event::Serial(_)))));
+
+    let unsigned = wat::parse_str(r#"(module (memory (export "memory") 1))"#).unwrap();
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+    assert!(simulator.check(&unsigned).is_err());
+}
+
+/// Calls `vexBatteryVoltageGet`, which the simulator doesn't implement.
+const UNIMPLEMENTED_PROGRAM: &str = r#"
+(module
+    (type $vexBatteryVoltageGet (func (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vexBatteryVoltageGet)
+                (i32.load (i32.const 0x037FCA00)))))
+)
+"#;
+
+#[test]
+fn stubs_unimplemented_sdk_functions() {
+    let simulator = run_with(&parse(UNIMPLEMENTED_PROGRAM, 0), []);
+    assert!(simulator.events().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "called unimplemented SDK function at 0xa00 (vexBatteryVoltageGet)"
+    )));
+}
+
+/// Reads a character from the empty stdin buffer, then from a channel that doesn't exist.
+const SERIAL_READ_PROGRAM: &str = r#"
+(module
+    (type $vexSerialReadChar (func (param i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func $read (param $channel i32)
+        (drop
+            (call_indirect (type $vexSerialReadChar)
+                (local.get $channel)
+                (i32.load (i32.const 0x037FC8A0)))))
+    (func (export "_entry")
+        (call $read (i32.const 1))
+        (call $read (i32.const 5)))
+)
+"#;
+
+#[test]
+fn only_warns_about_serial_reads_from_invalid_channels() {
+    let simulator = run_with(&parse(SERIAL_READ_PROGRAM, 0), []);
+
+    let warnings: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Log {
+                level: LogLevel::Warn,
+                message,
+            }) if message.starts_with("vexSerialReadChar") => Some(message),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(warnings, ["vexSerialReadChar: Invalid channel"]);
+}
+
+#[test]
+fn summarizes_repeated_warnings_when_the_program_exits() {
+    // Every read is from an invalid channel, each from a different place in the program.
+    let simulator = run_with(
+        &parse(
+            &SERIAL_READ_PROGRAM.replace(
+                "(call $read (i32.const 1))",
+                "(call $read (i32.const 5)) (call $read (i32.const 5))",
+            ),
+            0,
+        ),
+        [],
+    );
+
+    let warnings: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Log {
+                level: LogLevel::Warn,
+                message,
+            }) if message.starts_with("vexSerialReadChar") => Some(message),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        warnings,
+        [
+            "vexSerialReadChar: Invalid channel",
+            "vexSerialReadChar: Invalid channel (repeated 2 times)"
+        ]
+    );
+}
+
+/// Peeks at the first character of serial input, then echoes three characters back in the order they're read.
+const SERIAL_ECHO_PROGRAM: &str = r#"
+(module
+    (type $vexSerialWriteChar (func (param i32 i32) (result i32)))
+    (type $vexSerialReadChar (func (param i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func $echo (param $char i32)
+        (drop
+            (call_indirect (type $vexSerialWriteChar)
+                (i32.const 1) (local.get $char)
+                (i32.load (i32.const 0x037FC898)))))
+    (func $read (param $address i32)
+        (call $echo
+            (call_indirect (type $vexSerialReadChar)
+                (i32.const 1)
+                (i32.load (local.get $address)))))
+    (func (export "_entry")
+        (call $read (i32.const 0x037FC8A4))
+        (call $read (i32.const 0x037FC8A0))
+        (call $read (i32.const 0x037FC8A0))
+        (call $read (i32.const 0x037FC8A0)))
+)
+"#;
+
+#[test]
+fn reads_serial_input_in_the_order_it_was_sent() {
+    let simulator = run_with(
+        &parse(SERIAL_ECHO_PROGRAM, 0),
+        [
+            Inbound::Command(Command::Serial(SerialData::new(1, b"ab"))),
+            Inbound::Command(Command::Serial(SerialData::new(1, b"c"))),
+        ],
+    );
+    assert_eq!(serial_output(&simulator), b"aabc");
+}
+
+#[test]
+fn lists_the_functions_in_the_jump_table() {
+    let simulator = run_with(&parse(HELLO_PROGRAM, 0), []);
+
+    let functions = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Sim(SimEvent::JumpTable { functions }) => Some(functions),
+            _ => None,
+        })
+        .expect("the jump table should have been listed");
+    assert!(functions
+        .windows(2)
+        .all(|pair| pair[0].address < pair[1].address));
+    let find = |name: &str| {
+        functions
+            .iter()
+            .find(|function| function.name == name)
+            .map(|function| (function.address, function.implemented))
+    };
+    assert_eq!(find("vexTasksRun"), Some((0x05c, true)));
+    assert_eq!(find("vexBatteryVoltageGet"), Some((0xa00, false)));
+}
+
+#[test]
+fn only_registers_the_sdk_groups_the_frontend_requested() {
+    let program = parse(HELLO_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec!["sdk-controller".to_owned()],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Handshake { extensions, .. }) if extensions == &["sdk-controller"]
+    )));
+    let functions = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Sim(SimEvent::JumpTable { functions }) => Some(functions),
+            _ => None,
+        })
+        .expect("the jump table should have been listed");
+    let implemented = |name: &str| {
+        functions
+            .iter()
+            .any(|function| function.name == name && function.implemented)
+    };
+    assert!(implemented("vexDisplayRender"));
+    assert!(implemented("vexSerialWriteBuffer"));
+    assert!(implemented("vexControllerGet"));
+    assert!(!implemented("vexDeviceGetByIndex"));
+    assert!(!implemented("vexDeviceMotorVelocitySet"));
+    assert!(!implemented("vexFileOpen"));
+    let missing_extension = |name: &str| {
+        functions
+            .iter()
+            .find(|function| function.name == name)
+            .and_then(|function| function.missing_extension.as_deref())
+    };
+    assert_eq!(missing_extension("vexControllerGet"), None);
+    assert_eq!(
+        missing_extension("vexDeviceMotorVelocitySet"),
+        Some("sdk-motor")
+    );
+    assert_eq!(missing_extension("vexFileOpen"), Some("sdk-usd"));
+}
+
+#[test]
+fn traps_on_calls_to_sdk_groups_the_frontend_didnt_request() {
+    let program = parse(USD_LISTING_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec!["sdk-controller".to_owned()],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let err = simulator.run(&program).unwrap_err();
+    assert!(
+        format!("{err:?}").contains(
+            "vexFileDriveStatus is part of the \"sdk-usd\" extension, which the frontend didn't request"
+        ),
+        "unexpected error: {err:?}"
+    );
+}
+
+/// Prints whether the SD card is inserted, then the result of listing its root directory and the listing itself.
+const USD_LISTING_PROGRAM: &str = r#"
+(module
+    (type $vexFileDriveStatus (func (param i32) (result i32)))
+    (type $vexFileDirectoryGet (func (param i32 i32 i32) (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d %s\00")
+    (data (i32.const 32) "/usd\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexFileDriveStatus)
+                (i32.const 0)
+                (i32.load (i32.const 0x037FC7FC))))
+        (i32.store (i32.const 68)
+            (call_indirect (type $vexFileDirectoryGet)
+                (i32.const 32)
+                (i32.const 256)
+                (i32.const 128)
+                (i32.load (i32.const 0x037FC7D4))))
+        (i32.store (i32.const 72) (i32.const 256))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+fn usd_listing_with(usd: Option<std::path::PathBuf>) -> String {
+    let program = parse(USD_LISTING_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        usd,
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+    String::from_utf8(serial_output(&simulator)).unwrap()
+}
+
+#[test]
+fn lists_the_mounted_sd_card() {
+    let root = std::env::temp_dir().join(format!("v5wasm-usd-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("logs")).unwrap();
+    std::fs::write(root.join("config.txt"), "").unwrap();
+
+    let listing = usd_listing_with(Some(root.clone()));
+    _ = std::fs::remove_dir_all(&root);
+    assert_eq!(listing, "1 0 config.txt\nlogs\n");
+
+    // Without a mounted directory, there's no card to list rather than an error.
+    assert!(usd_listing_with(None).starts_with("0 3 "));
+}
+
+/// Opens `/usd/log.txt` and reads it 8 bytes at a time, printing how many bytes each of three reads returned and
+/// what the first two read.
+const USD_READ_PROGRAM: &str = r#"
+(module
+    (type $vexFileOpen (func (param i32 i32) (result i32)))
+    (type $vexFileRead (func (param i32 i32 i32 i32) (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d %d %s|%s\00")
+    (data (i32.const 48) "/usd/log.txt\00")
+    (func $read (param $file i32) (param $buf i32) (result i32)
+        (call_indirect (type $vexFileRead)
+            (local.get $buf)
+            (i32.const 1)
+            (i32.const 8)
+            (local.get $file)
+            (i32.load (i32.const 0x037FC7F8))))
+    (func (export "_entry")
+        (local $file i32)
+        (local.set $file
+            (call_indirect (type $vexFileOpen)
+                (i32.const 48)
+                (i32.const 15)
+                (i32.load (i32.const 0x037FC7D8))))
+        (i32.store (i32.const 64) (call $read (local.get $file) (i32.const 256)))
+        (i32.store (i32.const 68) (call $read (local.get $file) (i32.const 272)))
+        (i32.store (i32.const 72) (call $read (local.get $file) (i32.const 288)))
+        (i32.store (i32.const 76) (i32.const 256))
+        (i32.store (i32.const 80) (i32.const 272))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reads_files_from_the_sd_card_in_chunks() {
+    let root = std::env::temp_dir().join(format!("v5wasm-usd-read-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("log.txt"), "hello world").unwrap();
+
+    let program = parse(USD_READ_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        usd: Some(root.clone()),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    _ = std::fs::remove_dir_all(&root);
+    assert_eq!(reason, ExitReason::Completed);
+    // The second read stops short at the end of the file, and the third has nothing left to read.
+    assert_eq!(serial_output(&simulator), b"8 3 0 hello wo|rld");
+}
+
+/// Creates `/usd/out.txt` and writes 100 bytes to it, printing how many microseconds of simulated time the write took.
+const USD_WRITE_PROGRAM: &str = r#"
+(module
+    (type $vexFileOpenCreate (func (param i32) (result i32)))
+    (type $vexFileWrite (func (param i32 i32 i32 i32) (result i32)))
+    (type $vexSystemHighResTimeGet (func (result i64)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (data (i32.const 32) "/usd/out.txt\00")
+    (func $now (result i64)
+        (call_indirect (type $vexSystemHighResTimeGet) (i32.load (i32.const 0x037FC134))))
+    (func (export "_entry")
+        (local $file i32)
+        (local $start i64)
+        (local.set $file
+            (call_indirect (type $vexFileOpenCreate)
+                (i32.const 32)
+                (i32.load (i32.const 0x037FC7E0))))
+        (local.set $start (call $now))
+        (drop
+            (call_indirect (type $vexFileWrite)
+                (i32.const 256)
+                (i32.const 10)
+                (i32.const 10)
+                (local.get $file)
+                (i32.load (i32.const 0x037FC7EC))))
+        (i32.store (i32.const 64) (i32.wrap_i64 (i64.sub (call $now) (local.get $start))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn slows_sd_card_writes_down_by_the_configured_delay() {
+    let root = std::env::temp_dir().join(format!("v5wasm-usd-write-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+
+    let program = parse(USD_WRITE_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        usd: Some(root.clone()),
+        usd_write_delay: Some(Duration::from_millis(1)),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    let written = std::fs::read(root.join("out.txt"));
+    _ = std::fs::remove_dir_all(&root);
+    assert_eq!(reason, ExitReason::Completed);
+    assert_eq!(written.unwrap(), [0; 100]);
+
+    let output = String::from_utf8(serial_output(&simulator)).unwrap();
+    let elapsed_us: u64 = output.parse().unwrap();
+    assert!(
+        (100_000..150_000).contains(&elapsed_us),
+        "writing 100 bytes took {elapsed_us}us"
+    );
+}
+
+/// Logs "a" to `/usd/log.txt`, yields, tries to log "b" and checks whether the card is mounted, yields again, then
+/// reopens the log to append "c". Prints what each of those returned.
+const USD_EJECT_PROGRAM: &str = r#"
+(module
+    (type $vexFileOpenCreate (func (param i32) (result i32)))
+    (type $vexFileOpenWrite (func (param i32) (result i32)))
+    (type $vexFileWrite (func (param i32 i32 i32 i32) (result i32)))
+    (type $vexFileMountSD (func (result i32)))
+    (type $vexTasksRun (func))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d %d %d\00")
+    (data (i32.const 32) "/usd/log.txt\00")
+    (data (i32.const 48) "abc")
+    (func $write (param $file i32) (param $buf i32) (result i32)
+        (call_indirect (type $vexFileWrite)
+            (local.get $buf)
+            (i32.const 1)
+            (i32.const 1)
+            (local.get $file)
+            (i32.load (i32.const 0x037FC7EC))))
+    (func $yield
+        (call_indirect (type $vexTasksRun) (i32.load (i32.const 0x037FC05C))))
+    (func (export "_entry")
+        (local $file i32)
+        (local.set $file
+            (call_indirect (type $vexFileOpenCreate)
+                (i32.const 32)
+                (i32.load (i32.const 0x037FC7E0))))
+        (i32.store (i32.const 64) (call $write (local.get $file) (i32.const 48)))
+        (call $yield)
+        (i32.store (i32.const 68) (call $write (local.get $file) (i32.const 49)))
+        (i32.store (i32.const 72)
+            (call_indirect (type $vexFileMountSD) (i32.load (i32.const 0x037FC7D0))))
+        (call $yield)
+        (local.set $file
+            (call_indirect (type $vexFileOpenWrite)
+                (i32.const 32)
+                (i32.load (i32.const 0x037FC7DC))))
+        (i32.store (i32.const 76) (call $write (local.get $file) (i32.const 50)))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn ejects_and_reinserts_the_sd_card() {
+    let root = std::env::temp_dir().join(format!("v5wasm-usd-eject-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+
+    #[derive(serde::Serialize)]
+    struct Line {
+        tick: u64,
+        command: Inbound,
+    }
+
+    // The card is removed when the program first yields, and put back when it yields again.
+    let mut recording = Vec::new();
+    let handshake = Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    };
+    for (tick, command) in [
+        (0, handshake),
+        (0, Command::StartExecution),
+        (1, Command::USD { root: None }),
+        (
+            2,
+            Command::USD {
+                root: Some(root.clone()),
+            },
+        ),
+    ] {
+        let command = Inbound::Command(command);
+        jsonl::write(&mut recording, &Line { tick, command }).unwrap();
+    }
+
+    let program = parse(USD_EJECT_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let config = SimulatorConfig {
+        usd: Some(root.clone()),
+        ..Default::default()
+    };
+    let mut simulator = Simulator::replay(config, &recording[..]).unwrap();
+    let reason = simulator.run(&program).unwrap();
+    let log = std::fs::read_to_string(root.join("log.txt"));
+    _ = std::fs::remove_dir_all(&root);
+    assert_eq!(reason, ExitReason::Completed);
+    assert_eq!(log.unwrap(), "ac");
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "vexFileWrite: there's no SD card inserted"
+    )));
+    let serial = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the program should have printed its results");
+    assert_eq!(serial, b"1 -1 3 1");
+}
+
+/// Reboots the brain unless `/usd/booted` exists, creating it first so that the program only reboots once. Prints
+/// "started over" if it didn't reboot.
+const REBOOT_ONCE_PROGRAM: &str = r#"
+(module
+    (type $vexFileOpen (func (param i32 i32) (result i32)))
+    (type $vexFileOpenCreate (func (param i32) (result i32)))
+    (type $vexSystemBoot (func))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "started over\00")
+    (data (i32.const 32) "/usd/booted\00")
+    (func (export "_entry")
+        (if (i32.eqz
+                (call_indirect (type $vexFileOpen)
+                    (i32.const 32)
+                    (i32.const 15)
+                    (i32.load (i32.const 0x037FC7D8))))
+            (then
+                (drop
+                    (call_indirect (type $vexFileOpenCreate)
+                        (i32.const 32)
+                        (i32.load (i32.const 0x037FC7E0))))
+                (call_indirect (type $vexSystemBoot)
+                    (i32.load (i32.const 0x037FC174)))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 0)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn exits_or_restarts_when_the_program_reboots() {
+    for restart_on_reboot in [false, true] {
+        let root = std::env::temp_dir().join(format!(
+            "v5wasm-reboot-{restart_on_reboot}-{}",
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        let program = parse(REBOOT_ONCE_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+        let mut simulator = Simulator::new(SimulatorConfig {
+            usd: Some(root.clone()),
+            restart_on_reboot,
+            ..Default::default()
+        });
+
+        let commands = simulator.command_sender().unwrap();
+        commands.send(Command::Handshake {
+            version: 1,
+            extensions: vec![],
+        });
+        commands.send(Command::StartExecution);
+        drop(commands);
+
+        let reason = simulator.run(&program).unwrap();
+        _ = std::fs::remove_dir_all(&root);
+
+        let events: Vec<_> = simulator.events().collect();
+        let reboots = events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    Outbound::Sim(SimEvent::Reboot { restart }) if *restart == restart_on_reboot
+                )
+            })
+            .count();
+        assert_eq!(reboots, 1);
+        let serial: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        if restart_on_reboot {
+            assert_eq!(reason, ExitReason::Completed);
+            assert_eq!(serial, b"started over");
+        } else {
+            assert_eq!(reason, ExitReason::Rebooted);
+            assert!(serial.is_empty());
+        }
+    }
+}
+
+/// Yields to the simulator once through `vexTasksRun`.
+const TASKS_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call_indirect (type $vexTasksRun)
+            (i32.load (i32.const 0x037FC05C))))
+)
+"#;
+
+#[test]
+fn takes_and_restores_checkpoints() {
+    let program = parse(TASKS_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    commands.send_sim(SimCommand::Snapshot);
+    commands.send_sim(SimCommand::Restore { id: 0 });
+    commands.send_sim(SimCommand::Restore { id: 7 });
+    commands.send_sim(SimCommand::DropSnapshot { id: 0 });
+    commands.send_sim(SimCommand::Restore { id: 0 });
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, Outbound::Sim(SimEvent::SnapshotTaken { id: 0 }))));
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, Outbound::Sim(SimEvent::Restored { id: 0 }))));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "Ignoring request to restore unknown checkpoint 7"
+    )));
+    // The checkpoint can't be restored again once it's been dropped.
+    let restored = events
+        .iter()
+        .filter(|event| matches!(event, Outbound::Sim(SimEvent::Restored { id: 0 })))
+        .count();
+    assert_eq!(restored, 1);
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "Ignoring request to restore unknown checkpoint 0"
+    )));
+}
+
+#[test]
+fn places_jump_table_after_large_function_tables() {
+    run_with(
+        &parse(
+            &HELLO_PROGRAM.replace("(table 0 funcref)", "(table 5000 funcref)"),
+            0,
+        ),
+        [],
+    );
+
+    let bounded = parse(
+        &HELLO_PROGRAM.replace("(table 0 funcref)", "(table 5000 5001 funcref)"),
+        0,
+    );
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+    let err = simulator.check(&bounded).unwrap_err();
+    assert!(format!("{err:?}").contains("limited to 5001 entries"));
+}
+
+#[test]
+fn requires_memory_export() {
+    let program = parse(
+        &HELLO_PROGRAM.replace(r#"(memory (export "memory") 1)"#, "(memory 1)"),
+        0,
+    );
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+    let err = simulator.check(&program).unwrap_err();
+    assert!(format!("{err:?}").contains("doesn't export its memory as `memory`"));
+}
+
+#[test]
+fn provides_imported_memory() {
+    let simulator = run_with(
+        &parse(
+            &HELLO_PROGRAM.replace(
+                r#"(memory (export "memory") 1)"#,
+                r#"(import "env" "memory" (memory 1))"#,
+            ),
+            0,
+        ),
+        [],
+    );
+    assert_eq!(serial_output(&simulator), b"hello");
+}
+
+/// Copies a 160x2 buffer to a rect that hangs 80px off the right edge of the display.
+const COPY_RECT_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayCopyRect (func (param i32 i32 i32 i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call_indirect (type $vexDisplayCopyRect)
+            (i32.const 400)
+            (i32.const 100)
+            (i32.const 560)
+            (i32.const 102)
+            (i32.const 0xFB00)
+            (i32.const 160)
+            (i32.load (i32.const 0x037FC654))))
+)
+"#;
+
+#[test]
+fn clips_copied_rects_to_the_display() {
+    let simulator = run_with(&parse(COPY_RECT_PROGRAM, 0), []);
+
+    let (top_left, bottom_right) = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command:
+                    DrawCommand::CopyBuffer {
+                        top_left,
+                        bottom_right,
+                        ..
+                    },
+                ..
+            }) => Some((top_left, bottom_right)),
+            _ => None,
+        })
+        .expect("the visible part of the rect should have been drawn");
+    assert_eq!((top_left.x, top_left.y), (400, 100));
+    assert_eq!((bottom_right.x, bottom_right.y), (480, 102));
+}
+
+/// Reads the primary controller's left X axis and writes the value to serial as a little-endian `i32`.
+const CONTROLLER_PROGRAM: &str = r#"
+(module
+    (type $vexControllerGet (func (param i32 i32) (result i32)))
+    (type $vexSerialWriteBuffer (func (param i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (i32.store (i32.const 16)
+            (call_indirect (type $vexControllerGet)
+                (i32.const 0)
+                (i32.const 0)
+                (i32.load (i32.const 0x037FC1A4))))
+        (drop
+            (call_indirect (type $vexSerialWriteBuffer)
+                (i32.const 1)
+                (i32.const 16)
+                (i32.const 4)
+                (i32.load (i32.const 0x037FC89C)))))
+)
+"#;
+
+#[test]
+fn clamps_controller_axes() {
+    let simulator = run_with(
+        &parse(CONTROLLER_PROGRAM, 0),
+        [Inbound::Command(Command::ControllerUpdate(
+            Some(ControllerUpdate::Raw(ControllerState {
+                axis1: 200,
+                axis2: 0,
+                axis3: 0,
+                axis4: 0,
+                button_l1: false,
+                button_l2: false,
+                button_r1: false,
+                button_r2: false,
+                button_up: false,
+                button_down: false,
+                button_left: false,
+                button_right: false,
+                button_x: false,
+                button_b: false,
+                button_y: false,
+                button_a: false,
+                button_sel: false,
+                battery_level: 0,
+                button_all: false,
+                flags: 0,
+                battery_capacity: 0,
+            })),
+            None,
+        ))],
+    );
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data),
+            _ => None,
+        })
+        .expect("the program should have written the axis value to serial");
+    assert_eq!(serial.to_bytes().unwrap(), 127i32.to_le_bytes());
+}
+
+#[test]
+fn moves_single_controller_axes() {
+    let program = parse(CONTROLLER_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        controller_preset: Some(ControllerPreset::FullForward),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send_sim(SimCommand::ControllerAxis {
+        id: 0,
+        axis: ControllerAxis::Axis1,
+        value: 42,
+    });
+    commands.send_sim(SimCommand::ControllerAxis {
+        id: 1,
+        axis: ControllerAxis::Axis1,
+        value: 42,
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "Couldn't move Axis1 of controller 1: the controller isn't connected"
+    )));
+    let serial = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data),
+            _ => None,
+        })
+        .expect("the program should have written the axis value to serial");
+    assert_eq!(serial.to_bytes().unwrap(), 42i32.to_le_bytes());
+}
+
+/// Clears the first line of the primary controller's screen, then writes "hi" to it.
+const CONTROLLER_SCREEN_PROGRAM: &str = r#"
+(module
+    (type $vexControllerTextSet (func (param i32 i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "                   \00")
+    (data (i32.const 48) "hi\00")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vexControllerTextSet)
+                (i32.const 0)
+                (i32.const 1)
+                (i32.const 1)
+                (i32.const 16)
+                (i32.load (i32.const 0x037FC1AC))))
+        (drop
+            (call_indirect (type $vexControllerTextSet)
+                (i32.const 0)
+                (i32.const 1) ;; line
+                (i32.const 1)
+                (i32.const 48)
+                (i32.load (i32.const 0x037FC1AC)))))
+)
+"#;
+
+#[test]
+fn writes_to_the_controller_screen() {
+    let simulator = run_with(&parse(CONTROLLER_SCREEN_PROGRAM, 0), []);
+
+    let lines: Vec<(u32, u32, String)> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::ControllerScreen { id, line, text }) => Some((id, line, text)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(lines, [(0, 1, String::new()), (0, 1, String::from("hi"))]);
+}
+
+/// [`CONTROLLER_SCREEN_PROGRAM`] writing `text` on `line` instead of "hi" on line 1.
+fn controller_text_program(text: &str, line: u32) -> Vec<u8> {
+    let program = CONTROLLER_SCREEN_PROGRAM
+        .replace(r#""hi\00""#, &format!(r#""{text}\00""#))
+        .replace("(i32.const 1) ;; line", &format!("(i32.const {line})"));
+    parse(&program, 0)
+}
+
+#[test]
+fn sends_rumble_patterns_separately_from_text() {
+    let simulator = run_with(&controller_text_program(".- .", 0), []);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Sim(SimEvent::ControllerRumble { id: 0, pattern }) if pattern == ".- ."
+    )));
+    let screen_updates = events
+        .iter()
+        .filter(|event| matches!(event, Outbound::Sim(SimEvent::ControllerScreen { .. })))
+        .count();
+    assert_eq!(screen_updates, 1);
+}
+
+#[test]
+fn warns_about_text_for_invalid_controllers() {
+    let simulator = run_with(
+        &parse(
+            &CONTROLLER_SCREEN_PROGRAM.replace("(i32.const 0)", "(i32.const 5)"),
+            0,
+        ),
+        [],
+    );
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "vexControllerTextSet: Invalid controller id"
+    )));
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Outbound::Sim(SimEvent::ControllerScreen { .. }))));
+}
+
+#[test]
+fn writes_negative_numbers_to_the_controller_screen() {
+    let simulator = run_with(&controller_text_program("-12.5 deg", 1), []);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Outbound::Sim(SimEvent::ControllerRumble { .. }))));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Sim(SimEvent::ControllerScreen { id: 0, line: 1, text }) if text == "-12.5 deg"
+    )));
+}
+
+#[test]
+fn writes_text_made_of_dots_and_dashes_to_the_controller_screen() {
+    let simulator = run_with(&controller_text_program("--------", 1), []);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Outbound::Sim(SimEvent::ControllerRumble { .. }))));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Sim(SimEvent::ControllerScreen { id: 0, line: 1, text }) if text == "--------"
+    )));
+}
+
+#[test]
+fn warns_about_empty_rumble_patterns() {
+    let simulator = run_with(&controller_text_program("", 0), []);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "vexControllerTextSet: empty rumble pattern"
+    )));
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Outbound::Sim(SimEvent::ControllerRumble { .. }))));
+}
+
+#[test]
+fn boots_before_running_the_program() {
+    let program = parse(HELLO_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        boot_delay: Some(Duration::from_millis(120)),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    let boot_progress: Vec<f64> = events
+        .iter()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::Booting { progress }) => Some(*progress),
+            _ => None,
+        })
+        .collect();
+    assert!(boot_progress.len() > 1);
+    assert_eq!(boot_progress.last(), Some(&1.0));
+
+    let last_boot = events
+        .iter()
+        .rposition(|event| matches!(event, Outbound::Sim(SimEvent::Booting { .. })))
+        .unwrap();
+    let first_serial = events
+        .iter()
+        .position(|event| matches!(event, Outbound::Event(Event::Serial(_))))
+        .unwrap();
+    assert!(last_boot < first_serial);
+}
+
+#[test]
+fn reports_memory_usage() {
+    let simulator = run_with(
+        &parse(HELLO_PROGRAM, 0),
+        [Inbound::Sim(SimCommand::Query {
+            what: vec![QueryTopic::Memory],
+        })],
+    );
+
+    let memory = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Sim(SimEvent::State(state)) => state.memory,
+            _ => None,
+        })
+        .expect("the query should have reported memory usage");
+    // The memory is grown to fit the jump table before the program starts.
+    assert_eq!(memory.size, 0x700 * 0x10000);
+    assert_eq!(memory.maximum, None);
+}
+
+/// Renders an empty frame, then erases the display twice and renders another frame.
+const RENDER_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayRender (func (param i32 i32)))
+    (type $vexDisplayErase (func))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func $render
+        (call_indirect (type $vexDisplayRender)
+            (i32.const 0)
+            (i32.const 0)
+            (i32.load (i32.const 0x037FC7A0))))
+    (func $erase
+        (call_indirect (type $vexDisplayErase)
+            (i32.load (i32.const 0x037FC648))))
+    (func (export "_entry")
+        (call $render)
+        (call $erase)
+        (call $erase)
+        (call $render))
+)
+"#;
+
+#[test]
+fn reports_frame_stats() {
+    let simulator = run_with(&parse(RENDER_PROGRAM, 0), []);
+
+    let frames: Vec<(u64, u32)> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::FrameStats {
+                frame_time_us,
+                draw_count,
+            }) => Some((frame_time_us, draw_count)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0], (0, 0));
+    assert_eq!(frames[1].1, 2);
+}
+
+/// Writes "hello" to serial, runs tasks and renders a frame, 50 times in a row.
+const RAPID_OUTPUT_PROGRAM: &str = r#"
+(module
+    (type $vexSerialWriteBuffer (func (param i32 i32 i32) (result i32)))
+    (type $vexTasksRun (func))
+    (type $vexDisplayRender (func (param i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "hello")
+    (func (export "_entry")
+        (local $i i32)
+        (loop $frame
+            (drop
+                (call_indirect (type $vexSerialWriteBuffer)
+                    (i32.const 1)
+                    (i32.const 16)
+                    (i32.const 5)
+                    (i32.load (i32.const 0x037FC89C))))
+            (call_indirect (type $vexTasksRun)
+                (i32.load (i32.const 0x037FC05C)))
+            (call_indirect (type $vexDisplayRender)
+                (i32.const 0)
+                (i32.const 0)
+                (i32.load (i32.const 0x037FC7A0)))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br_if $frame (i32.lt_u (local.get $i) (i32.const 50)))))
+)
+"#;
+
+#[test]
+fn throttles_renders_without_dropping_serial_output() {
+    let program = parse(RAPID_OUTPUT_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        max_event_rate: Some(1.0),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    let renders = events
+        .iter()
+        .filter(|event| matches!(event, Outbound::Event(Event::ScreenRender)))
+        .count();
+    // The first frame is sent right away and the latest one when the program exits.
+    assert_eq!(renders, 2);
+
+    let serial: Vec<u8> = events
+        .iter()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    assert_eq!(serial, b"hello".repeat(50));
+}
+
+/// Writes "hello" centered on line 2 with `vexDisplayVCenteredString`.
+const CENTERED_STRING_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayVCenteredString (func (param i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "hello\00")
+    (func (export "_entry")
+        (call_indirect (type $vexDisplayVCenteredString)
+            (i32.const 2)
+            (i32.const 16)
+            (i32.const 32)
+            (i32.load (i32.const 0x037FC694))))
+)
+"#;
+
+#[test]
+fn centers_strings_on_a_line() {
+    let program = parse(CENTERED_STRING_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        local_text_metrics: true,
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let (text, point) = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command:
+                    DrawCommand::Write {
+                        text,
+                        location: TextLocation::Coordinates { point },
+                        ..
+                    },
+                ..
+            }) => Some((text, point)),
+            _ => None,
+        })
+        .expect("the centered string should have been written");
+    assert_eq!(text.data, "hello");
+    // Local metrics make "hello" 50 pixels wide, so it spans 215..265 around the midline at 240.
+    assert_eq!((point.x, point.y), (215, 74));
+}
+
+#[test]
+fn estimates_text_metrics_when_the_frontend_does_not_respond() {
+    let program = parse(CENTERED_STRING_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        text_metrics_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    // The frontend stays connected without answering the request for text metrics.
+    let frontend = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(500));
+        drop(commands);
+    });
+
+    let reason = simulator.run(&program).unwrap();
+    frontend.join().unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.starts_with("The frontend didn't send text metrics for \"hello\"")
+    )));
+    let point = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command:
+                    DrawCommand::Write {
+                        location: TextLocation::Coordinates { point },
+                        ..
+                    },
+                ..
+            }) => Some(point),
+            _ => None,
+        })
+        .expect("the centered string should have been written");
+    assert_eq!((point.x, point.y), (215, 74));
+}
+
+/// Prints doubles that lie exactly halfway between two decimals with `vex_vprintf`.
+const PRINTF_ROUNDING_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%.2f %.0f %.0f %.2f\00")
+    ;; 0.125, 2.5, -2.5, 2.675
+    (data (i32.const 64)
+        "\00\00\00\00\00\00\c0\3f"
+        "\00\00\00\00\00\00\04\40"
+        "\00\00\00\00\00\00\04\c0"
+        "\66\66\66\66\66\66\05\40")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn rounds_printf_doubles_like_c() {
+    let simulator = run_with(&parse(PRINTF_ROUNDING_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the formatted doubles should have been written to serial");
+    // 2.675 is really 2.67499999..., so it isn't a tie and rounds down.
+    assert_eq!(&serial[..], b"0.13 3 -3 2.67");
+}
+
+/// Prints NaN and infinities with `vex_vprintf` using a variety of flags and specifiers.
+const PRINTF_NON_FINITE_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%f %+f % f %e %F %+E %.3f %6f|%-6f|%E\00")
+    ;; NaN, inf, inf, -inf, NaN, inf, inf, NaN, -inf, -inf
+    (data (i32.const 64)
+        "\00\00\00\00\00\00\f8\7f"
+        "\00\00\00\00\00\00\f0\7f"
+        "\00\00\00\00\00\00\f0\7f"
+        "\00\00\00\00\00\00\f0\ff"
+        "\00\00\00\00\00\00\f8\7f"
+        "\00\00\00\00\00\00\f0\7f"
+        "\00\00\00\00\00\00\f0\7f"
+        "\00\00\00\00\00\00\f8\7f"
+        "\00\00\00\00\00\00\f0\ff"
+        "\00\00\00\00\00\00\f0\ff")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn formats_non_finite_doubles_like_c() {
+    let simulator = run_with(&parse(PRINTF_NON_FINITE_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the formatted doubles should have been written to serial");
+    assert_eq!(
+        &serial[..],
+        b"nan +inf  inf -inf NAN +INF inf    nan|-inf  |-INF"
+    );
+}
+
+/// Formats a width that overflows `int` with `vex_vsnprintf`, then prints negative `*` fields and the length of the
+/// oversized output with `vex_vprintf`.
+const PRINTF_FIELD_WIDTH_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (type $vex_vsnprintf (func (param i32 i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%2147483648d\00")
+    (data (i32.const 32) "%*d|%.*d|\00")
+    (data (i32.const 48) "%d\00")
+    ;; 5
+    (data (i32.const 64) "\05\00\00\00")
+    ;; -4, 7, -1, 3
+    (data (i32.const 80) "\fc\ff\ff\ff\07\00\00\00\ff\ff\ff\ff\03\00\00\00")
+    (func (export "_entry")
+        (i32.store (i32.const 96)
+            (call_indirect (type $vex_vsnprintf)
+                (i32.const 256)
+                (i32.const 8)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F8))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 32)
+                (i32.const 80)
+                (i32.load (i32.const 0x037FC0F0))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 48)
+                (i32.const 96)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn clamps_oversized_printf_fields() {
+    let simulator = run_with(&parse(PRINTF_FIELD_WIDTH_PROGRAM, 0), []);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "printf width or precision is larger than 65536, so it was clamped"
+    )));
+    let serial: Vec<u8> = events
+        .iter()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    // A negative width from `*` left-aligns, and a negative precision is ignored.
+    assert_eq!(serial, b"7   |3|65536");
+}
+
+/// Prints 5 in binary with `vex_vprintf` using a variety of flags.
+const PRINTF_BINARY_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%#b %b %08b %#B %.4b|%-6b|\00")
+    (data (i32.const 64)
+        "\05\00\00\00\05\00\00\00\05\00\00\00\05\00\00\00\05\00\00\00\05\00\00\00")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn formats_binary_integers() {
+    let simulator = run_with(&parse(PRINTF_BINARY_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the formatted integers should have been written to serial");
+    assert_eq!(&serial[..], b"0b101 101 00000101 0B101 0101|101   |");
+}
+
+/// Prints a format string with an unknown specifier, escaped percent signs and a trailing `%` using `vex_vprintf`.
+const PRINTF_MALFORMED_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%q|%%|%d%%|%\00")
+    (data (i32.const 64) "\07\00\00\00")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn passes_through_malformed_printf_specifiers() {
+    let simulator = run_with(&parse(PRINTF_MALFORMED_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the malformed format string should still have been printed");
+    // `%q` doesn't consume the argument, so the 7 goes to `%d`.
+    assert_eq!(&serial[..], b"%q|%|7%|%");
+}
+
+/// Prints a 32-bit `wchar_t` string and characters with `vex_vprintf`, including an invalid code point.
+const PRINTF_WIDE_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%ls|%lc|%lc\00")
+    ;; A pointer to the wide string, then U+1F600 and the lone surrogate U+D800
+    (data (i32.const 64) "\80\00\00\00\00\f6\01\00\00\d8\00\00")
+    ;; L"hé✓"
+    (data (i32.const 128) "\68\00\00\00\e9\00\00\00\13\27\00\00\00\00\00\00")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn formats_wide_strings() {
+    let simulator = run_with(&parse(PRINTF_WIDE_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the wide string should have been written to serial");
+    assert_eq!(&serial[..], "hé✓|😀|\u{FFFD}".as_bytes());
+}
+
+/// Prints with the same format string twice using `vex_vprintf`, changing its first character in between.
+const PRINTF_REUSED_FORMAT_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "a%d\00")
+    (data (i32.const 64) "\01\00\00\00\02\00\00\00")
+    (func (export "_entry")
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0))))
+        (i32.store8 (i32.const 16) (i32.const 0x62))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 68)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reparses_format_strings_that_change() {
+    let simulator = run_with(&parse(PRINTF_REUSED_FORMAT_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the formatted strings should have been written to serial");
+    assert_eq!(&serial[..], b"a1a1b2");
+}
+
+#[test]
+fn reports_program_details_when_verbose() {
+    let program = parse(HELLO_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        verbose: true,
+        ..Default::default()
+    });
+
+    simulator.check(&program).unwrap();
+
+    let messages: Vec<String> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Log {
+                level: LogLevel::Info,
+                message,
+            }) => Some(message),
+            _ => None,
+        })
+        .collect();
+    for expected in [
+        "Program type: 0, owner: 2",
+        "Program options: invert default graphics colors: false, kill threads when main exits: false, invert graphics based on theme: false",
+        "Imported function table: 0 entries, maximum unlimited",
+        "Exports: 2",
+        "Initial memory: 1 pages",
+    ] {
+        assert!(
+            messages.iter().any(|message| message == expected),
+            "missing {expected:?} in {messages:?}"
+        );
+    }
+    assert!(messages
+        .iter()
+        .any(|message| message.starts_with("Code signature: ")));
+}
+
+/// Writes "hi" to serial from a WASI-style `_start` export instead of `_entry`.
+const START_PROGRAM: &str = r#"
+(module
+    (type $vexSerialWriteBuffer (func (param i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "hi")
+    (func (export "_start")
+        (drop
+            (call_indirect (type $vexSerialWriteBuffer)
+                (i32.const 1)
+                (i32.const 16)
+                (i32.const 2)
+                (i32.load (i32.const 0x037FC89C)))))
+)
+"#;
+
+#[test]
+fn falls_back_to_start_entrypoint() {
+    let program = parse(START_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        entry: Some("main".to_string()),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the program should have run from `_start`");
+    assert_eq!(&serial[..], b"hi");
+}
+
+#[test]
+fn lists_tried_entrypoints() {
+    let program = parse(&START_PROGRAM.replace("_start", "begin"), 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        entry: Some("main".to_string()),
+        ..Default::default()
+    });
+
+    let err = simulator.check(&program).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The program doesn't export an entrypoint (tried `main`, `_entry`, `_start`)"
+    );
+}
+
+/// Writes 3000 bytes to serial in a single `vexSerialWriteBuffer` call, which is more than the output buffer holds.
+const LARGE_WRITE_PROGRAM: &str = r#"
+(module
+    (type $vexSerialWriteBuffer (func (param i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (memory.fill (i32.const 1024) (i32.const 0x78) (i32.const 3000))
+        (drop
+            (call_indirect (type $vexSerialWriteBuffer)
+                (i32.const 1)
+                (i32.const 1024)
+                (i32.const 3000)
+                (i32.load (i32.const 0x037FC89C)))))
+)
+"#;
+
+#[test]
+fn flushes_to_fit_large_serial_writes() {
+    let simulator = run_with(&parse(LARGE_WRITE_PROGRAM, 0), []);
+
+    let serial: Vec<u8> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    assert_eq!(serial, vec![b'x'; 3000]);
+}
+
+/// Runs simulator tasks, then prints the bits returned by `vexCompetitionStatus` with `vex_vprintf`.
+const COMPETITION_STATUS_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (type $vexCompetitionStatus (func (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (call_indirect (type $vexTasksRun)
+            (i32.load (i32.const 0x037FC05C)))
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexCompetitionStatus)
+                (i32.load (i32.const 0x037FC9D8))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+/// Runs [`COMPETITION_STATUS_PROGRAM`] on an enabled field in driver control after sending `field_command`, returning
+/// the status bits it printed.
+fn competition_status_after(field_command: SimCommand) -> String {
+    let program = parse(COMPETITION_STATUS_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    commands.send(Command::CompetitionMode(CompetitionMode {
+        connected: true,
+        mode: CompMode::Driver,
+        enabled: true,
+        is_competition: true,
+    }));
+    commands.send_sim(field_command);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the competition status should have been printed");
+    String::from_utf8(serial.to_vec()).unwrap()
+}
+
+#[test]
+fn simulates_field_disconnects() {
+    // Disconnecting clears CONNECTED and SYSTEM, and sets DISABLED.
+    let status = competition_status_after(SimCommand::FieldConnection {
+        connected: false,
+        keep_enabled: false,
+    });
+    assert_eq!(status, "1");
+
+    let status = competition_status_after(SimCommand::FieldConnection {
+        connected: false,
+        keep_enabled: true,
+    });
+    assert_eq!(status, "0");
+
+    let status = competition_status_after(SimCommand::FieldConnection {
+        connected: true,
+        keep_enabled: false,
+    });
+    assert_eq!(status, "12");
+}
+
+/// Inverts a rectangle given with its corners swapped, through the simulator's `sim_display_invert_rect` import.
+const INVERT_RECT_PROGRAM: &str = r#"
+(module
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_display_invert_rect" (func $invert (param i32 i32 i32 i32)))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call $invert (i32.const 100) (i32.const 80) (i32.const 20) (i32.const 40)))
+)
+"#;
+
+#[test]
+fn inverts_rects_within_the_clip_region() {
+    let simulator = run_with(&parse(INVERT_RECT_PROGRAM, 0), []);
+
+    let (rect, clip_region) = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Sim(SimEvent::ScreenInvert { rect, clip_region }) => {
+                Some((rect, clip_region))
+            }
+            _ => None,
+        })
+        .expect("the rect should have been inverted");
+    assert_eq!((rect.top_left.x, rect.top_left.y), (20, 40));
+    assert_eq!((rect.bottom_right.x, rect.bottom_right.y), (100, 80));
+    assert_eq!((clip_region.top_left.x, clip_region.top_left.y), (0, 32));
+    assert_eq!(
+        (clip_region.bottom_right.x, clip_region.bottom_right.y),
+        (480, 272)
+    );
+}
+
+/// Fills a rectangle at half opacity, then sets the opacity back to fully opaque.
+const TRANSLUCENT_RECT_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayRectFill (func (param i32 i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_display_set_opacity" (func $set_opacity (param i32)))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call $set_opacity (i32.const 128))
+        (call $set_opacity (i32.const 128))
+        (call_indirect (type $vexDisplayRectFill)
+            (i32.const 10) (i32.const 40) (i32.const 50) (i32.const 80)
+            (i32.load (i32.const 0x037FC670)))
+        (call $set_opacity (i32.const 1000)))
+)
+"#;
+
+#[test]
+fn sends_opacity_changes_before_draws() {
+    let simulator = run_with(&parse(TRANSLUCENT_RECT_PROGRAM, 0), []);
+
+    let draws: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::ScreenOpacity { opacity }) => Some(Some(opacity)),
+            Outbound::Event(Event::ScreenDraw {
+                command: DrawCommand::Fill { .. },
+                ..
+            }) => Some(None),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(draws, [Some(128), None, Some(255)]);
+}
+
+/// Sets the screen's brightness below and above the valid range, to full brightness again, then dims it.
+const BRIGHTNESS_PROGRAM: &str = r#"
+(module
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_display_set_brightness" (func $set_brightness (param i32)))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call $set_brightness (i32.const -20))
+        (call $set_brightness (i32.const 250))
+        (call $set_brightness (i32.const 100))
+        (call $set_brightness (i32.const 40)))
+)
+"#;
+
+#[test]
+fn sends_clamped_brightness_changes() {
+    let simulator = run_with(&parse(BRIGHTNESS_PROGRAM, 0), []);
+
+    let levels: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::ScreenBrightness { level }) => Some(level),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(levels, [0, 100, 40]);
+}
+
+/// Writes a normal and a big string on the same line.
+const MIXED_FONT_LINE_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayVString (func (param i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "normal\00")
+    (data (i32.const 24) "big\00")
+    (func (export "_entry")
+        (call_indirect (type $vexDisplayVString)
+            (i32.const 1) (i32.const 16) (i32.const 32)
+            (i32.load (i32.const 0x037FC684)))
+        (call_indirect (type $vexDisplayVString)
+            (i32.const 1) (i32.const 24) (i32.const 32)
+            (i32.load (i32.const 0x037FC68C))))
+)
+"#;
+
+#[test]
+fn aligns_baselines_of_mixed_font_sizes() {
+    let metrics = [(V5FontSize::Normal, 15, 5), (V5FontSize::Large, 25, 8)].map(
+        |(font_size, ascent, descent)| {
+            Inbound::Sim(SimCommand::SetFontMetrics {
+                font_family: V5FontFamily::default(),
+                font_size,
+                metrics: FontMetrics { ascent, descent },
+            })
+        },
+    );
+    let simulator = run_with(&parse(MIXED_FONT_LINE_PROGRAM, 0), metrics);
+
+    let locations = written_locations(&simulator);
+    let [(normal, normal_location), (big, big_location)] = &locations[..] else {
+        panic!("expected two strings to be written, got {locations:?}");
+    };
+    assert_eq!((normal.as_str(), big.as_str()), ("normal", "big"));
+    assert!(matches!(normal_location, TextLocation::Line { line: 1 }));
+    // Line 1 starts at y = 54, and the normal font's ascent is 15, so the big font's 25 pixel ascent has to
+    // start 10 pixels higher to share its baseline.
+    let TextLocation::Coordinates { point } = big_location else {
+        panic!("the big string should have been moved to its baseline, got {big_location:?}");
+    };
+    assert_eq!((point.x, point.y), (0, 44));
+}
+
+#[test]
+fn leaves_mixed_font_sizes_on_their_lines_without_font_metrics() {
+    let simulator = run_with(&parse(MIXED_FONT_LINE_PROGRAM, 0), []);
+
+    let locations = written_locations(&simulator);
+    assert!(
+        matches!(
+            &locations[..],
+            [
+                (_, TextLocation::Line { line: 1 }),
+                (_, TextLocation::Line { line: 1 })
+            ]
+        ),
+        "both strings should have been left on line 1, got {locations:?}"
+    );
+}
+
+/// The text and location of every string written to the display.
+fn written_locations(simulator: &Simulator) -> Vec<(String, TextLocation)> {
+    simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command: DrawCommand::Write { text, location, .. },
+                ..
+            }) => Some((text.data, location)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn uses_preloaded_text_metrics() {
+    let simulator = run_with(
+        &parse(CENTERED_STRING_PROGRAM, 0),
+        [Inbound::Sim(SimCommand::SetTextMetricsBatch {
+            entries: vec![(
+                V5Text {
+                    data: "hello".to_string(),
+                    font_family: V5FontFamily::UserMono,
+                    font_size: V5FontSize::Normal,
+                },
+                TextMetrics {
+                    width: 100,
+                    height: 20,
+                },
+            )],
+        })],
+    );
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Outbound::Event(Event::TextMetricsRequest { .. }))));
+    let point = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command:
+                    DrawCommand::Write {
+                        location: TextLocation::Coordinates { point },
+                        ..
+                    },
+                ..
+            }) => Some(point),
+            _ => None,
+        })
+        .expect("the centered string should have been written");
+    assert_eq!((point.x, point.y), (190, 74));
+}
+
+/// Centers "hello" in the large font on line 2 with `vexDisplayVBigCenteredString`, given `font_metrics` for the
+/// normal and large fonts, and returns where it was written.
+fn big_centered_string_with(font_metrics: &[(V5FontSize, u32, u32)]) -> (i32, i32) {
+    let program = CENTERED_STRING_PROGRAM.replace("0x037FC694", "0x037FC698");
+    let text_metrics = Inbound::Sim(SimCommand::SetTextMetricsBatch {
+        entries: vec![(
+            V5Text {
+                data: "hello".to_string(),
+                font_family: V5FontFamily::UserMono,
+                font_size: V5FontSize::Large,
+            },
+            TextMetrics {
+                width: 100,
+                height: 33,
+            },
+        )],
+    });
+    let font_metrics = font_metrics.iter().map(|&(font_size, ascent, descent)| {
+        Inbound::Sim(SimCommand::SetFontMetrics {
+            font_family: V5FontFamily::UserMono,
+            font_size,
+            metrics: FontMetrics { ascent, descent },
+        })
+    });
+    let simulator = run_with(
+        &parse(&program, 0),
+        std::iter::once(text_metrics).chain(font_metrics),
+    );
+
+    simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command:
+                    DrawCommand::Write {
+                        location: TextLocation::Coordinates { point },
+                        ..
+                    },
+                ..
+            }) => Some((point.x, point.y)),
+            _ => None,
+        })
+        .expect("the centered string should have been written")
+}
+
+#[test]
+fn aligns_baselines_of_centered_strings() {
+    // The large font's ascent is 10 pixels taller, so its top moves up from line 2's top at y = 74.
+    assert_eq!(
+        big_centered_string_with(&[(V5FontSize::Normal, 15, 5), (V5FontSize::Large, 25, 8)]),
+        (190, 64)
+    );
+    // Without the normal font's metrics there's no baseline to line up with, so it stays at the line's top.
+    assert_eq!(
+        big_centered_string_with(&[(V5FontSize::Large, 25, 8)]),
+        (190, 74)
+    );
+}
+
+/// Fills a rectangle with a clip region set, then fills another after clearing it.
+const CLIP_REGION_CLEAR_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayClipRegionSet (func (param i32 i32 i32 i32)))
+    (type $vexDisplayClipRegionClear (func))
+    (type $vexDisplayRectFill (func (param i32 i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call_indirect (type $vexDisplayClipRegionSet)
+            (i32.const 10) (i32.const 50) (i32.const 100) (i32.const 100)
+            (i32.load (i32.const 0x037FC794)))
+        (call_indirect (type $vexDisplayRectFill)
+            (i32.const 0) (i32.const 0) (i32.const 480) (i32.const 272)
+            (i32.load (i32.const 0x037FC670)))
+        (call_indirect (type $vexDisplayClipRegionClear)
+            (i32.load (i32.const 0x037FC798)))
+        (call_indirect (type $vexDisplayRectFill)
+            (i32.const 0) (i32.const 0) (i32.const 480) (i32.const 272)
+            (i32.load (i32.const 0x037FC670))))
+)
+"#;
+
+#[test]
+fn clears_the_clip_region() {
+    let simulator = run_with(&parse(CLIP_REGION_CLEAR_PROGRAM, 0), []);
+
+    let clip_regions: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command: DrawCommand::Fill { .. },
+                clip_region,
+                ..
+            }) => Some((
+                clip_region.top_left.x,
+                clip_region.top_left.y,
+                clip_region.bottom_right.x,
+                clip_region.bottom_right.y,
+            )),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(clip_regions, [(10, 50, 100, 100), (0, 32, 480, 272)]);
+}
+
+/// Sets the colors and clip region, then erases the display and fills a circle.
+const ERASE_AND_CIRCLE_PROGRAM: &str = r#"
+(module
+    (type $set_color (func (param i32)))
+    (type $vexDisplayClipRegionSet (func (param i32 i32 i32 i32)))
+    (type $vexDisplayErase (func))
+    (type $vexDisplayCircleFill (func (param i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        ;; vexDisplayForegroundColor
+        (call_indirect (type $set_color) (i32.const 0xFF0000) (i32.load (i32.const 0x037FC640)))
+        ;; vexDisplayBackgroundColor
+        (call_indirect (type $set_color) (i32.const 0x0000FF) (i32.load (i32.const 0x037FC644)))
+        (call_indirect (type $vexDisplayClipRegionSet)
+            (i32.const 10) (i32.const 40) (i32.const 200) (i32.const 150)
+            (i32.load (i32.const 0x037FC794)))
+        (call_indirect (type $vexDisplayErase) (i32.load (i32.const 0x037FC648)))
+        (call_indirect (type $vexDisplayCircleFill)
+            (i32.const 100) (i32.const 90) (i32.const 30)
+            (i32.load (i32.const 0x037FC67C))))
+)
+"#;
+
+/// Collects the draw events a simulator sent to the frontend, in the order they were sent.
+fn screen_draws(simulator: &Simulator) -> Vec<Event> {
+    simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(event @ Event::ScreenDraw { .. }) => Some(event),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn erases_and_fills_circles_with_the_current_colors() {
+    let simulator = run_with(&parse(ERASE_AND_CIRCLE_PROGRAM, 0), []);
+
+    let draws = screen_draws(&simulator);
+    let [erase, circle] = &draws[..] else {
+        panic!("expected an erase and a circle, but got {draws:?}");
+    };
+
+    let Event::ScreenDraw {
+        command:
+            DrawCommand::Fill {
+                shape:
+                    Shape::Rectangle {
+                        top_left,
+                        bottom_right,
+                    },
+            },
+        color,
+        clip_region,
+    } = erase
+    else {
+        panic!("expected the display to be filled, but got {erase:?}");
+    };
+    assert_eq!((top_left.x, top_left.y), (0, 0));
+    assert_eq!((bottom_right.x, bottom_right.y), (480, 272));
+    assert_eq!(*color, RGB8::new(0x00, 0x00, 0xFF).into());
+    assert_eq!((clip_region.top_left.x, clip_region.top_left.y), (10, 40));
+    assert_eq!(
+        (clip_region.bottom_right.x, clip_region.bottom_right.y),
+        (200, 150)
+    );
+
+    let Event::ScreenDraw {
+        command: DrawCommand::Fill {
+            shape: Shape::Circle { center, radius },
+        },
+        color,
+        clip_region,
+    } = circle
+    else {
+        panic!("expected a filled circle, but got {circle:?}");
+    };
+    assert_eq!((center.x, center.y, *radius), (100, 90, 30));
+    assert_eq!(*color, RGB8::new(0xFF, 0x00, 0x00).into());
+    assert_eq!((clip_region.top_left.x, clip_region.top_left.y), (10, 40));
+    assert_eq!(
+        (clip_region.bottom_right.x, clip_region.bottom_right.y),
+        (200, 150)
+    );
+}
+
+/// Draws a line that starts and ends at the same point, then strokes and fills circles with a radius of 0.
+const DEGENERATE_SHAPES_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayLineDraw (func (param i32 i32 i32 i32)))
+    (type $circle (func (param i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call_indirect (type $vexDisplayLineDraw)
+            (i32.const 5) (i32.const 50) (i32.const 5) (i32.const 50)
+            (i32.load (i32.const 0x037FC660)))
+        ;; vexDisplayCircleDraw
+        (call_indirect (type $circle)
+            (i32.const 20) (i32.const 60) (i32.const 0)
+            (i32.load (i32.const 0x037FC674)))
+        ;; vexDisplayCircleFill
+        (call_indirect (type $circle)
+            (i32.const 30) (i32.const 70) (i32.const 0)
+            (i32.load (i32.const 0x037FC67C))))
+)
+"#;
+
+#[test]
+fn draws_shapes_with_no_size_as_single_pixels() {
+    let simulator = run_with(&parse(DEGENERATE_SHAPES_PROGRAM, 0), []);
+
+    let pixels: Vec<_> = screen_draws(&simulator)
+        .into_iter()
+        .map(|draw| match draw {
+            Event::ScreenDraw {
+                command:
+                    DrawCommand::Fill {
+                        shape:
+                            Shape::Rectangle {
+                                top_left,
+                                bottom_right,
+                            },
+                    },
+                ..
+            } if top_left == bottom_right => (top_left.x, top_left.y),
+            draw => panic!("expected a single pixel, but got {draw:?}"),
+        })
+        .collect();
+    assert_eq!(pixels, [(5, 50), (20, 60), (30, 70)]);
+}
+
+/// Sets the competition control word to autonomous on a competition switch, then prints the competition status.
+const COMPETITION_CONTROL_PROGRAM: &str = r#"
+(module
+    (type $vexCompetitionControl (func (param i32)))
+    (type $vexCompetitionStatus (func (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (call_indirect (type $vexCompetitionControl)
+            (i32.const 6)
+            (i32.load (i32.const 0x037FC9DC)))
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexCompetitionStatus)
+                (i32.load (i32.const 0x037FC9D8))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reports_the_competition_control_word_as_status() {
+    let simulator = run_with(&parse(COMPETITION_CONTROL_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the competition status should have been printed");
+    assert_eq!(&serial[..], b"6");
+}
+
+/// Prints the primary controller's left and right Y axes.
+const CONTROLLER_AXES_PROGRAM: &str = r#"
+(module
+    (type $vexControllerGet (func (param i32 i32) (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexControllerGet)
+                (i32.const 0) (i32.const 1)
+                (i32.load (i32.const 0x037FC1A4))))
+        (i32.store (i32.const 68)
+            (call_indirect (type $vexControllerGet)
+                (i32.const 0) (i32.const 3)
+                (i32.load (i32.const 0x037FC1A4))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn starts_with_the_controller_preset() {
+    let program = parse(CONTROLLER_AXES_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        controller_preset: Some(ControllerPreset::FullForward),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the controller axes should have been printed");
+    assert_eq!(&serial[..], b"127 127");
+}
+
+#[test]
+fn acknowledges_configured_devices() {
+    let motor = Device::Motor {
+        physical_gearset: MotorGearSet::Green,
+        moment_of_inertia: 1.0,
+    };
+    let simulator = run_with(
+        &parse(HELLO_PROGRAM, 0),
+        [1, 40].map(|port| {
+            Inbound::Command(Command::ConfigureDevice {
+                port: Port::Smart(port),
+                device: motor.clone(),
+            })
+        }),
+    );
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Sim(SimEvent::DeviceConfigured {
+            port: 1,
+            device_type: DeviceKind::Motor
+        })
+    )));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "Couldn't configure a Motor on port 40: Port 40 doesn't exist"
+    )));
+}
+
+/// Drives a motor forward, reverses it partway through, and then resets its position. Prints whether the position
+/// stayed put when it was reversed, whether the velocity was mirrored, the reverse flag, and whether the position
+/// reads zero after the reset.
+const MOTOR_REVERSAL_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (type $set (func (param i32 i32)))
+    (type $get_f64 (func (param i32) (result f64)))
+    (type $get_i32 (func (param i32) (result i32)))
+    (type $vexDeviceMotorPositionReset (func (param i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d %d %d\00")
+    (func $position (result f64)
+        (call_indirect (type $get_f64) (i32.const 1) (i32.load (i32.const 0x037FC338))))
+    (func $velocity (result f64)
+        (call_indirect (type $get_f64) (i32.const 1) (i32.load (i32.const 0x037FC2D8))))
+    (func $run_until_moving
+        (loop $wait
+            (call_indirect (type $vexTasksRun) (i32.load (i32.const 0x037FC05C)))
+            (br_if $wait (f64.lt (call $velocity) (f64.const 100)))))
+    (func (export "_entry")
+        (local $position f64)
+        (local $velocity f64)
+        ;; vexDeviceMotorVoltageSet
+        (call_indirect (type $set)
+            (i32.const 1) (i32.const 12000)
+            (i32.load (i32.const 0x037FC35C)))
+        (call $run_until_moving)
+        (local.set $position (call $position))
+        (local.set $velocity (call $velocity))
+        ;; vexDeviceMotorReverseFlagSet
+        (call_indirect (type $set)
+            (i32.const 1) (i32.const 1)
+            (i32.load (i32.const 0x037FC31C)))
+        (i32.store (i32.const 64)
+            (f64.lt (f64.abs (f64.sub (call $position) (local.get $position))) (f64.const 30)))
+        (i32.store (i32.const 68)
+            (f64.lt (f64.abs (f64.add (call $velocity) (local.get $velocity))) (f64.const 30)))
+        ;; The voltage is mirrored too, so the motor comes back up to speed in the program's direction.
+        (call $run_until_moving)
+        ;; vexDeviceMotorReverseFlagGet
+        (i32.store (i32.const 72)
+            (call_indirect (type $get_i32) (i32.const 1) (i32.load (i32.const 0x037FC320))))
+        (call_indirect (type $vexDeviceMotorPositionReset)
+            (i32.const 1)
+            (i32.load (i32.const 0x037FC340)))
+        (i32.store (i32.const 76)
+            (f64.lt (f64.abs (call $position)) (f64.const 30)))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reverses_and_resets_motors() {
+    // Slow time down so the motor barely moves between the program's calls.
+    let simulator = run_with(
+        &parse(MOTOR_REVERSAL_PROGRAM, 0),
+        [Inbound::Sim(SimCommand::SetTimeScale { factor: 0.1 })],
+    );
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the results should have been printed");
+    assert_eq!(&serial[..], b"1 1 1 1");
+}
+
+/// Configures a quadrature encoder on the brain's three-wire ports A and B, tries to put something else on port B,
+/// and prints the encoder's reading.
+const ADI_ENCODER_PROGRAM: &str = r#"
+(module
+    (type $vexDeviceAdiPortConfigSet (func (param i32 i32 i32)))
+    (type $vexDeviceAdiValueGet (func (param i32 i32) (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (call_indirect (type $vexDeviceAdiPortConfigSet)
+            (i32.const 22) (i32.const 0) (i32.const 14)
+            (i32.load (i32.const 0x037FC208)))
+        (call_indirect (type $vexDeviceAdiPortConfigSet)
+            (i32.const 22) (i32.const 1) (i32.const 2)
+            (i32.load (i32.const 0x037FC208)))
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexDeviceAdiValueGet)
+                (i32.const 22) (i32.const 0)
+                (i32.load (i32.const 0x037FC214))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reads_adi_encoders_from_the_frontend() {
+    let simulator = run_with(
+        &parse(ADI_ENCODER_PROGRAM, 0),
+        [Inbound::Sim(SimCommand::SetAdiValue {
+            port: 22,
+            adi_port: 0,
+            value: 360,
+        })],
+    );
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.starts_with(
+                "vexDeviceAdiPortConfigSet: port B is the second port of the quadrature encoder on port A"
+            )
+    )));
+    let serial = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the encoder's reading should have been printed");
+    assert_eq!(&serial[..], b"360");
+}
+
+/// Prints the reading of the brain's three-wire port C.
+const ADI_VALUE_PROGRAM: &str = r#"
+(module
+    (type $vexDeviceAdiValueGet (func (param i32 i32) (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexDeviceAdiValueGet)
+                (i32.const 22) (i32.const 2)
+                (i32.load (i32.const 0x037FC214))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reads_adi_voltages_from_the_frontend() {
+    let simulator = run_with(
+        &parse(ADI_VALUE_PROGRAM, 0),
+        [
+            Inbound::Command(Command::AdiInput {
+                port: 2,
+                voltage: 2.5,
+            }),
+            Inbound::Command(Command::AdiInput {
+                port: 9,
+                voltage: 5.0,
+            }),
+        ],
+    );
+
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message == "Couldn't set the voltage of the brain's three-wire port: Invalid ADI port 9"
+    )));
+    let serial = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the port's reading should have been printed");
+    // Half of the 5V range is half of the 12-bit reading.
+    assert_eq!(&serial[..], b"2048");
+}
+
+/// Prints the brain's buttons, read through the simulator's `sim_brain_buttons` import.
+const BRAIN_BUTTONS_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_brain_buttons" (func $brain_buttons (result i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64) (call $brain_buttons))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn reads_brain_buttons_pressed_by_the_frontend() {
+    let presses = [
+        (BrainButton::Up, true),
+        (BrainButton::Down, true),
+        (BrainButton::Up, false),
+    ]
+    .map(|(button, pressed)| Inbound::Sim(SimCommand::BrainButton { button, pressed }));
+    let simulator = run_with(&parse(BRAIN_BUTTONS_PROGRAM, 0), presses);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the brain buttons should have been printed");
+    assert_eq!(&serial[..], b"4");
+}
+
+/// Sets the brain's status LED to orange twice, then turns it off.
+const BRAIN_LED_PROGRAM: &str = r#"
+(module
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_brain_led_set" (func $brain_led_set (param i32)))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (call $brain_led_set (i32.const 0xFF8000))
+        (call $brain_led_set (i32.const 0xFF8000))
+        (call $brain_led_set (i32.const 0)))
+)
+"#;
+
+#[test]
+fn sends_brain_led_changes() {
+    let simulator = run_with(&parse(BRAIN_LED_PROGRAM, 0), []);
+
+    let colors: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::BrainLed { rgb }) => Some(rgb),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(colors, [[0xFF, 0x80, 0x00], [0, 0, 0]]);
+}
+
+/// Sets up the system timer and watchdog like an RTOS would at startup, then prints what the setup calls returned and
+/// the watchdog's reading.
+const SYSTEM_TIMER_PROGRAM: &str = r#"
+(module
+    (type $vexSystemTimerStop (func))
+    (type $vexSystemTimerReinitForRtos (func (param i32 i32) (result i32)))
+    (type $vexSystemWatchdogReinitRtos (func (result i32)))
+    (type $vexSystemWatchdogGet (func (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d %u\00")
+    (func (export "_entry")
+        (call_indirect (type $vexSystemTimerStop)
+            (i32.load (i32.const 0x037FC158)))
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexSystemTimerReinitForRtos)
+                (i32.const 0) (i32.const 0)
+                (i32.load (i32.const 0x037FC160))))
+        (i32.store (i32.const 68)
+            (call_indirect (type $vexSystemWatchdogReinitRtos)
+                (i32.load (i32.const 0x037FC168))))
+        (i32.store (i32.const 72)
+            (call_indirect (type $vexSystemWatchdogGet)
+                (i32.load (i32.const 0x037FC16C))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn sets_up_the_system_timer() {
+    let simulator = run_with(&parse(SYSTEM_TIMER_PROGRAM, 0), []);
+
+    let serial = simulator
+        .events()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the setup results should have been printed");
+    let serial = String::from_utf8(serial.to_vec()).unwrap();
+    let watchdog = serial
+        .strip_prefix("0 0 ")
+        .expect("the setup calls should have succeeded");
+    // The watchdog was read right after it was reinitialized.
+    assert!(watchdog.parse::<u32>().unwrap() < 1000);
+}
+
+/// Runs `program` to completion and collects the info messages it logged.
+fn info_messages(program: &[u8]) -> Vec<String> {
+    let simulator = run_with(program, []);
+
+    simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Log {
+                level: LogLevel::Info,
+                message,
+            }) => Some(message),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn keeps_running_unless_threads_are_killed_when_main_exits() {
+    let keeps_running = |messages: &[String]| {
+        messages
+            .iter()
+            .any(|message| message.contains("it keeps running"))
+    };
+
+    let program = parse(HELLO_PROGRAM, 0);
+    assert!(keeps_running(&info_messages(&program)));
+
+    let program = parse(HELLO_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    assert!(!keeps_running(&info_messages(&program)));
+}
+
+/// Yields to the simulator with `vexTasksRun`, then writes the 5 bytes at address 16 to serial.
+const YIELD_THEN_WRITE_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (type $vexSerialWriteBuffer (func (param i32 i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "hello")
+    (func (export "_entry")
+        (call_indirect (type $vexTasksRun)
+            (i32.load (i32.const 0x037FC05C)))
+        (drop
+            (call_indirect (type $vexSerialWriteBuffer)
+                (i32.const 1)
+                (i32.const 16)
+                (i32.const 5)
+                (i32.load (i32.const 0x037FC89C)))))
+)
+"#;
+
+#[test]
+fn reads_and_writes_memory_with_debug_memory() {
+    let program = parse(YIELD_THEN_WRITE_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        debug_memory: true,
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send_sim(SimCommand::WriteMemory {
+        addr: 16,
+        bytes_base64: "SEVMTE8=".to_string(), // "HELLO"
+    });
+    commands.send_sim(SimCommand::ReadMemory { addr: 16, len: 5 });
+    commands.send_sim(SimCommand::ReadMemory {
+        addr: u32::MAX,
+        len: 5,
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    let contents: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::MemoryContents { addr, bytes_base64 }) => {
+                Some((*addr, bytes_base64.as_str()))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(contents, [(16, "SEVMTE8=")]);
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.contains("outside the program's memory")
+    )));
+    let serial = events
+        .iter()
+        .find_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .expect("the program should have written its buffer");
+    assert_eq!(&serial[..], b"HELLO");
+}
+
+/// Writes a string that's too wide for a 100 pixel wide clip region on line 1.
+const LONG_STRING_PROGRAM: &str = r#"
+(module
+    (type $vexDisplayClipRegionSet (func (param i32 i32 i32 i32)))
+    (type $vexDisplayVString (func (param i32 i32 i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "the quick brownfoxjumpsover\00")
+    (func (export "_entry")
+        (call_indirect (type $vexDisplayClipRegionSet)
+            (i32.const 0) (i32.const 0) (i32.const 100) (i32.const 272)
+            (i32.load (i32.const 0x037FC794)))
+        (call_indirect (type $vexDisplayVString)
+            (i32.const 1) (i32.const 16) (i32.const 64)
+            (i32.load (i32.const 0x037FC684))))
+)
+"#;
+
+#[test]
+fn wraps_long_strings_onto_the_following_lines() {
+    let program = parse(LONG_STRING_PROGRAM, 0);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        local_text_metrics: true,
+        wrap_text: true,
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    // Normal text is estimated to be 10 pixels per character, so 10 characters fit on each line.
+    let lines: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::ScreenDraw {
+                command:
+                    DrawCommand::Write {
+                        text,
+                        location: TextLocation::Line { line },
+                        ..
+                    },
+                ..
+            }) => Some((text.data, line)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            ("the quick".to_string(), 1),
+            ("brownfoxju".to_string(), 2),
+            ("mpsover".to_string(), 3),
+        ]
+    );
+}
+
+/// Collects everything a simulator wrote to serial.
+fn serial_output(simulator: &Simulator) -> Vec<u8> {
+    simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+#[test]
+fn replays_recorded_sessions() {
+    let program = parse(HELLO_PROGRAM, 0);
+    let path = std::env::temp_dir().join(format!("v5wasm-session-{}.jsonl", std::process::id()));
+    _ = std::fs::remove_file(&path);
+
+    let mut simulator = Simulator::new(SimulatorConfig {
+        record: Some(path.clone()),
+        ..Default::default()
+    });
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+    simulator.run(&program).unwrap();
+    let recorded = serial_output(&simulator);
+    drop(simulator);
+
+    let recording = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+    let mut simulator = Simulator::replay(SimulatorConfig::default(), recording).unwrap();
+    let reason = simulator.run(&program).unwrap();
+    _ = std::fs::remove_file(&path);
+
+    assert_eq!(reason, ExitReason::Completed);
+    assert_eq!(recorded, b"hello");
+    assert_eq!(serial_output(&simulator), recorded);
+}
+
+#[test]
+fn flags_malformed_code_signatures() {
+    let program = parse(HELLO_PROGRAM, 0x100);
+
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+    simulator.check(&program).unwrap();
+    assert!(simulator.events().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.ends_with("looks malformed: unknown option bits 0x100")
+    )));
+
+    let mut simulator = Simulator::new(SimulatorConfig {
+        strict_code_sig: true,
+        ..Default::default()
+    });
+    assert!(simulator.check(&program).is_err());
+    assert!(!simulator
+        .events()
+        .any(|event| matches!(event, Outbound::Event(Event::VCodeSig(_)))));
+}
+
+/// Prints the value of `vexSystemTimeGet` as soon as the program starts.
+const START_TIME_PROGRAM: &str = r#"
+(module
+    (type $vexSystemTimeGet (func (result i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%u\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64)
+            (call_indirect (type $vexSystemTimeGet)
+                (i32.load (i32.const 0x037FC118))))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+/// Renders 30 frames, waiting for vsync after each one, then prints how many milliseconds that took.
+const VSYNC_PROGRAM: &str = r#"
+(module
+    (type $vexSystemTimeGet (func (result i32)))
+    (type $vexDisplayRender (func (param i32 i32)))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%u\00")
+    (func $time (result i32)
+        (call_indirect (type $vexSystemTimeGet) (i32.load (i32.const 0x037FC118))))
+    (func (export "_entry")
+        (local $start i32)
+        (local $frames i32)
+        (local.set $start (call $time))
+        (loop $render
+            (call_indirect (type $vexDisplayRender)
+                (i32.const 1)
+                (i32.const 0)
+                (i32.load (i32.const 0x037FC7A0)))
+            (local.set $frames (i32.add (local.get $frames) (i32.const 1)))
+            (br_if $render (i32.lt_u (local.get $frames) (i32.const 30))))
+        (i32.store (i32.const 64) (i32.sub (call $time) (local.get $start)))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn holds_a_steady_frame_rate_when_waiting_for_vsync() {
+    let simulator = run_with(&parse(VSYNC_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS), []);
+
+    // 30 frames at 60 FPS take 500ms. Waking up late from one frame shouldn't push back the ones after it.
+    let elapsed: u32 = String::from_utf8(serial_output(&simulator))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((499..510).contains(&elapsed), "rendering took {elapsed}ms");
+}
+
+#[test]
+fn resets_the_clock_when_execution_starts() {
+    let program = parse(START_TIME_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        reset_clock_on_start: true,
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    let starter = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        commands.send(Command::StartExecution);
+    });
+
+    let reason = simulator.run(&program).unwrap();
+    starter.join().unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let millis: u32 = String::from_utf8(serial_output(&simulator))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(
+        millis < 300,
+        "{millis} ms had passed when the program started"
+    );
+}
+
+/// Calls `vexTasksRun` forever.
+const SPIN_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (func (export "_entry")
+        (loop $spin
+            (call_indirect (type $vexTasksRun)
+                (i32.load (i32.const 0x037FC05C)))
+            (br $spin)))
+)
+"#;
+
+#[test]
+fn switches_to_another_program() {
+    let program = parse(SPIN_PROGRAM, 0);
+    let path = std::env::temp_dir().join(format!("v5wasm-switch-{}.wasm", std::process::id()));
+    std::fs::write(&path, parse(HELLO_PROGRAM, 0)).unwrap();
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    let switcher = std::thread::spawn({
+        let path = path.clone();
+        move || {
+            std::thread::sleep(Duration::from_millis(100));
+            commands.send_sim(SimCommand::LoadProgram { path });
+            commands.send(Command::StartExecution);
+        }
+    });
+
+    let reason = simulator.run(&program).unwrap();
+    switcher.join().unwrap();
+    _ = std::fs::remove_file(&path);
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    let reasons: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::Exited { reason }) => Some(*reason),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        reasons,
+        [ExitReason::ProgramSwitched, ExitReason::Completed]
+    );
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Serial(data)) if data.to_bytes().unwrap() == b"hello"
+    )));
+}
+
+/// Grows its memory by a page twice, then prints what `memory.grow` returned each time.
+const GROW_MEMORY_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %d\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64) (memory.grow (i32.const 1)))
+        (i32.store (i32.const 68) (memory.grow (i32.const 1)))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn warns_when_memory_runs_out() {
+    const PAGE_SIZE: usize = 0x10000;
+    const JUMP_TABLE_PAGES: usize = 0x700;
+
+    let program = parse(GROW_MEMORY_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        max_memory: Some((JUMP_TABLE_PAGES + 1) * PAGE_SIZE),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.starts_with("Out of memory")
+    )));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Serial(data)) if data.to_bytes().unwrap() == b"1792 -1"
+    )));
+}
+
+/// Tries to grow its function table by 100000 elements, then prints what `table.grow` returned.
+const GROW_TABLE_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table $table 0 funcref))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64)
+            (table.grow $table (ref.null func) (i32.const 100000)))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn denies_table_growth_past_the_limit() {
+    let program = parse(GROW_TABLE_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        max_table_elements: Some(4096),
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+
+    let events: Vec<Outbound> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.starts_with("Table limit reached")
+    )));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Serial(data)) if data.to_bytes().unwrap() == b"-1"
+    )));
+}
+
+#[test]
+fn sends_competition_phase_changes() {
+    let mode = CompetitionMode {
+        connected: true,
+        mode: CompMode::Auto,
+        enabled: true,
+        is_competition: true,
+    };
+    let simulator = run_with(
+        &parse(HELLO_PROGRAM, 0),
+        [
+            Inbound::Command(Command::CompetitionMode(mode)),
+            Inbound::Command(Command::CompetitionMode(mode)),
+            Inbound::Sim(SimCommand::FieldConnection {
+                connected: false,
+                keep_enabled: false,
+            }),
+        ],
+    );
+
+    let phases: Vec<_> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Sim(SimEvent::CompetitionPhase {
+                enabled,
+                mode,
+                connected,
+            }) => Some((enabled, mode == CompMode::Auto, connected)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(phases, [(true, true, true), (false, true, false)]);
+}
+
+/// Waits for a brain button to be pressed, running the simulator's tasks in between, then prints the buttons.
+const WAIT_FOR_BRAIN_BUTTONS_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_brain_buttons" (func $brain_buttons (result i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d\00")
+    (func (export "_entry")
+        (loop $wait
+            (call_indirect (type $vexTasksRun) (i32.load (i32.const 0x037FC05C)))
+            (br_if $wait (i32.eqz (call $brain_buttons))))
+        (i32.store (i32.const 64) (call $brain_buttons))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+/// Times a delay of 20ms with `sim_mark_time`.
+const MARK_TIME_PROGRAM: &str = r#"
+(module
+    (type $vexTasksRun (func))
+    (type $vexSystemTimeGet (func (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_mark_time" (func $mark_time (param i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "delay\00")
+    (func $now (result i32)
+        (call_indirect (type $vexSystemTimeGet) (i32.load (i32.const 0x037FC118))))
+    (func (export "_entry")
+        (local $end i32)
+        (call $mark_time (i32.const 16))
+        (local.set $end (i32.add (call $now) (i32.const 20)))
+        (loop $wait
+            (call_indirect (type $vexTasksRun) (i32.load (i32.const 0x037FC05C)))
+            (br_if $wait (i32.lt_u (call $now) (local.get $end))))
+        (call $mark_time (i32.const 16)))
+)
+"#;
+
+#[test]
+fn logs_how_long_marked_sections_took() {
+    let simulator = run_with(&parse(MARK_TIME_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS), []);
+
+    let elapsed_us: Vec<u64> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Log {
+                level: LogLevel::Info,
+                message,
+            }) => message
+                .strip_prefix("delay took ")?
+                .strip_suffix("us")?
+                .parse()
+                .ok(),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(elapsed_us.len(), 1);
+    assert!(
+        elapsed_us[0] >= 19_000,
+        "the delay took {}us",
+        elapsed_us[0]
+    );
+}
+
+/// Passes one assertion through `sim_assert`, then fails another.
+const ASSERT_PROGRAM: &str = r#"
+(module
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_assert" (func $assert (param i32 i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "the robot is on the field\00")
+    (data (i32.const 48) "the robot is moving\00")
+    (func (export "_entry")
+        (call $assert (i32.const 1) (i32.const 16))
+        (call $assert (i32.const 0) (i32.const 48)))
+)
+"#;
+
+#[test]
+fn stops_on_failed_assertions() {
+    let program = parse(ASSERT_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig::default());
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let err = simulator.run(&program).unwrap_err();
+    assert!(format!("{err:?}").contains("Assertion failed: the robot is moving"));
+
+    let errors: Vec<String> = simulator
+        .events()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Log {
+                level: LogLevel::Error,
+                message,
+            }) => Some(message),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(errors[0], "Assertion failed: the robot is moving");
+    assert_eq!(
+        errors.len(),
+        2,
+        "the failure should be followed by a backtrace"
+    );
+}
+
+/// Prints the length and value of the `speed` argument read through `sim_get_arg` with a 4-byte buffer, then whether
+/// a missing argument and one with a key that isn't UTF-8 were found.
+const PROGRAM_ARGS_PROGRAM: &str = r#"
+(module
+    (type $vex_vprintf (func (param i32 i32) (result i32)))
+    (import "env" "__indirect_function_table" (table 0 funcref))
+    (import "env" "sim_get_arg" (func $get_arg (param i32 i32 i32) (result i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "%d %s %d %d\00")
+    (data (i32.const 32) "speed\00")
+    (data (i32.const 40) "missing\00")
+    (data (i32.const 48) "\ff\00")
+    (func (export "_entry")
+        (i32.store (i32.const 64) (call $get_arg (i32.const 32) (i32.const 128) (i32.const 4)))
+        (i32.store (i32.const 68) (i32.const 128))
+        (i32.store (i32.const 72) (call $get_arg (i32.const 40) (i32.const 128) (i32.const 4)))
+        (i32.store (i32.const 76) (call $get_arg (i32.const 48) (i32.const 128) (i32.const 4)))
+        (drop
+            (call_indirect (type $vex_vprintf)
+                (i32.const 16)
+                (i32.const 64)
+                (i32.load (i32.const 0x037FC0F0)))))
+)
+"#;
+
+#[test]
+fn passes_arguments_to_the_program() {
+    let program = parse(PROGRAM_ARGS_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::new(SimulatorConfig {
+        args: vec![
+            "speed=slow".parse().unwrap(),
+            "speed=12345".parse().unwrap(),
+        ],
+        ..Default::default()
+    });
+
+    let commands = simulator.command_sender().unwrap();
+    commands.send(Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    });
+    commands.send(Command::StartExecution);
+    drop(commands);
+
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+    let events: Vec<_> = simulator.events().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Outbound::Event(Event::Log { level: LogLevel::Warn, message })
+            if message.starts_with("sim_get_arg: the key") && message.ends_with("isn't valid UTF-8")
+    )));
+    let serial: Vec<u8> = events
+        .iter()
+        .filter_map(|event| match event {
+            Outbound::Event(Event::Serial(data)) => Some(data.to_bytes().unwrap()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    // The last value for a key wins, and it's truncated to fit the buffer along with its NUL terminator.
+    assert_eq!(serial, b"5 123 -1 -1");
+}
+
+/// Replays three brain button presses that arrive at once the first time the program yields, and returns the buttons
+/// the program saw pressed first.
+fn brain_buttons_seen_with(config: SimulatorConfig) -> String {
+    #[derive(serde::Serialize)]
+    struct Line {
+        tick: u64,
+        command: Inbound,
+    }
+
+    let mut recording = Vec::new();
+    let handshake = Command::Handshake {
+        version: 1,
+        extensions: vec![],
+    };
+    for command in [
+        Inbound::Command(handshake),
+        Inbound::Command(Command::StartExecution),
+    ] {
+        jsonl::write(&mut recording, &Line { tick: 0, command }).unwrap();
+    }
+    for button in [BrainButton::Power, BrainButton::Up, BrainButton::Down] {
+        let command = Inbound::Sim(SimCommand::BrainButton {
+            button,
+            pressed: true,
+        });
+        jsonl::write(&mut recording, &Line { tick: 1, command }).unwrap();
+    }
+
+    let program = parse(WAIT_FOR_BRAIN_BUTTONS_PROGRAM, KILL_THREADS_WHEN_MAIN_EXITS);
+    let mut simulator = Simulator::replay(config, &recording[..]).unwrap();
+    let reason = simulator.run(&program).unwrap();
+    assert_eq!(reason, ExitReason::Completed);
+    String::from_utf8(serial_output(&simulator)).unwrap()
+}
+
+#[test]
+fn limits_commands_processed_per_tick() {
+    assert_eq!(brain_buttons_seen_with(SimulatorConfig::default()), "7");
+    let limited = SimulatorConfig {
+        commands_per_tick: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(brain_buttons_seen_with(limited), "1");
+}